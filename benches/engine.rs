@@ -0,0 +1,223 @@
+//! Runs of the engine over generated workloads in memory
+//! Run with `cargo bench --bench engine`, the groups of workers and of rows grouped by client on a machine with
+//! spare cores
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    runtime::Runtime,
+};
+use transaction_handler::{
+    cli,
+    config::Config,
+    engine::{bench, Engine},
+    generate::{workload, Scenario},
+    io::{reader::Reader, writer::Writer},
+};
+
+/// Rows of each generated workload
+const ROWS: u64 = 200_000;
+
+/// Runs an engine configured by the flags over the input, returning it with the accounts it holds
+async fn run<R>(flags: &[&str], input: R) -> Engine
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let mut engine = Engine::new(cli::config(flags).unwrap());
+    let mut writer = Writer::from_inner(tokio::io::sink());
+    match flags.contains(&"--pipeline") {
+        true => engine
+            .process_pipelined(Reader::from_reader(input), &mut writer)
+            .await
+            .unwrap(),
+        false => engine
+            .process(&mut Reader::from_reader(input), &mut writer)
+            .await
+            .unwrap(),
+    }
+    engine
+}
+
+/// Input of a run, owned so it can be read on a task of its own
+fn source(input: &[u8]) -> io::Cursor<Vec<u8>> {
+    io::Cursor::new(input.to_vec())
+}
+
+/// Source answering each read after a delay, like a slow disk or network share
+/// The delay blocks the thread, as a read of a file does
+struct SlowSource {
+    input: Vec<u8>,
+    position: usize,
+    delay: Duration,
+}
+
+impl AsyncRead for SlowSource {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        std::thread::sleep(self.delay);
+        let end = self.input.len().min(self.position + buf.remaining());
+        buf.put_slice(&self.input[self.position..end]);
+        self.position = end;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Parsing alone, applying alone and the whole run on each generated workload
+fn workloads(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("workloads");
+    group.throughput(Throughput::Elements(ROWS));
+    for scenario in Scenario::ALL {
+        let input = workload(scenario, ROWS, 7);
+        group.bench_function(BenchmarkId::new("parse", scenario.as_str()), |b| {
+            b.iter(|| runtime.block_on(bench::parse(&input)))
+        });
+        group.bench_function(BenchmarkId::new("apply", scenario.as_str()), |b| {
+            b.iter_batched(
+                || runtime.block_on(bench::parse(&input)),
+                |parsed| bench::apply(Config::default(), parsed),
+                BatchSize::PerIteration,
+            )
+        });
+        group.bench_function(BenchmarkId::new("end_to_end", scenario.as_str()), |b| {
+            b.iter(|| runtime.block_on(run(&[], source(&input))))
+        });
+    }
+    group.finish();
+}
+
+/// A run over every client id with and without capacity hints
+fn capacity_hints(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let input = workload(Scenario::ManyClients, ROWS, 7);
+    let per_client = (ROWS / (u16::MAX as u64 + 1)).max(1).to_string();
+    let hints = [
+        "--expected-clients",
+        "65536",
+        "--expected-txs-per-client",
+        &per_client,
+    ];
+    let mut group = c.benchmark_group("capacity_hints");
+    group.throughput(Throughput::Elements(ROWS));
+    for (name, flags) in [("none", &[][..]), ("hints", &hints[..])] {
+        group.bench_function(name, |b| {
+            b.iter(|| runtime.block_on(run(flags, source(&input))))
+        });
+    }
+    group.finish();
+}
+
+/// Rows read one at a time and in batches
+fn batches(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("batches");
+    group.throughput(Throughput::Elements(ROWS));
+    for scenario in [Scenario::Deposits, Scenario::Disputes] {
+        let input = workload(scenario, ROWS, 7);
+        for batch_size in ["1", "64", "4096"] {
+            let id = BenchmarkId::new(scenario.as_str(), batch_size);
+            group.bench_function(id, |b| {
+                b.iter(|| runtime.block_on(run(&["--batch-size", batch_size], source(&input))))
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Rows applied in input order against rows grouped by client, on many clients with few rows each
+fn group_by_client(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let input = workload(Scenario::ManyClients, ROWS, 0);
+    let mut group = c.benchmark_group("group_by_client");
+    group.throughput(Throughput::Elements(ROWS));
+    for (name, flags) in [
+        ("input_order", &[][..]),
+        ("grouped", &["--group-by-client"]),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter(|| runtime.block_on(run(flags, source(&input))))
+        });
+    }
+    group.finish();
+}
+
+/// A rate-limited source read directly and by a pipelined reader
+fn pipeline(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap();
+    let input = workload(Scenario::Disputes, ROWS, 7);
+    let mut group = c.benchmark_group("pipeline");
+    group.throughput(Throughput::Elements(ROWS));
+    for (name, flags) in [("direct", &[][..]), ("pipelined", &["--pipeline"])] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                //about as long to read as to apply, csv reads 8KB at a time
+                let source = SlowSource {
+                    input: input.clone(),
+                    position: 0,
+                    delay: Duration::from_micros(300),
+                };
+                runtime.block_on(run(flags, source))
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Rows of a dispute heavy input spread over workers by client
+fn workers(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let input = workload(Scenario::Disputes, ROWS, 7);
+    let mut group = c.benchmark_group("workers");
+    group.throughput(Throughput::Elements(ROWS));
+    for workers in ["1", "2", "4", "8"] {
+        group.bench_function(workers, |b| {
+            b.iter(|| runtime.block_on(run(&["--workers", workers], source(&input))))
+        });
+    }
+    group.finish();
+}
+
+/// Dropping the engine at the end of a run
+fn teardown(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("teardown");
+    for scenario in [
+        Scenario::Deposits,
+        Scenario::Disputes,
+        Scenario::ManyClients,
+    ] {
+        let input = workload(scenario, ROWS, 0);
+        group.bench_function(scenario.as_str(), |b| {
+            b.iter_batched(
+                || runtime.block_on(run(&[], source(&input))),
+                drop,
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = workloads, capacity_hints, batches, group_by_client, pipeline, workers, teardown
+}
+criterion_main!(benches);
@@ -0,0 +1,155 @@
+//! Sets of transaction ids and their owners
+//! Run with `cargo bench --bench ids`, the id_index group writes to the temporary directory
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use transaction_handler::{
+    engine::bench::{IndexedOwners, Owners},
+    generate::Rng,
+    hash::{IdMap, IdSet},
+    ids::IncreasingIds,
+};
+
+/// Ids added to each set
+const IDS: u32 = 1_000_000;
+
+/// The ids of an account against a hash set on sequential ids, each looked up before it is added as the duplicate
+/// check does, then every seventh id looked up again
+fn increasing_ids(c: &mut Criterion) {
+    let mut group = c.benchmark_group("increasing_ids");
+    group.throughput(Throughput::Elements(IDS as u64));
+    group.bench_function("increasing_ids", |b| {
+        b.iter(|| {
+            let mut ids = IncreasingIds::default();
+            for id in 1..=IDS {
+                assert!(!ids.contains(id));
+                ids.insert(id);
+            }
+            assert_eq!(
+                (1..=IDS).step_by(7).filter(|id| ids.contains(*id)).count(),
+                142_858
+            );
+            ids
+        })
+    });
+    group.bench_function("hash_set", |b| {
+        b.iter(|| {
+            let mut set = IdSet::default();
+            for id in 1..=IDS {
+                assert!(!set.contains(&id));
+                set.insert(id);
+            }
+            assert_eq!(
+                (1..=IDS).step_by(7).filter(|id| set.contains(id)).count(),
+                142_858
+            );
+            set
+        })
+    });
+    group.finish();
+}
+
+/// The owners of ids against a hash map of them, on ids handed out in sequence and on ids spread over the whole
+/// range, each looked up before it is claimed as the engine does, then every id looked up again
+/// The bytes each takes are printed before the group runs
+fn owners(c: &mut Criterion) {
+    let mut rng = Rng::new(7);
+    let spread: Vec<u32> = (0..IDS).map(|_| rng.below(1 << 32) as u32).collect();
+    let sequential: Vec<u32> = (1..=IDS).collect();
+    let mut group = c.benchmark_group("owners");
+    group.throughput(Throughput::Elements(IDS as u64));
+    for (name, ids) in [("sequential", &sequential), ("spread", &spread)] {
+        let mut owners = Owners::default();
+        for id in ids {
+            owners.claim(*id, *id as u16);
+        }
+        eprintln!(
+            "owners of {} {} ids: {} bytes, as a hash map: {} bytes",
+            IDS,
+            name,
+            owners.memory(),
+            owners.map_memory()
+        );
+        group.bench_with_input(BenchmarkId::new("containers", name), ids, |b, ids| {
+            b.iter(|| {
+                let mut owners = Owners::default();
+                for id in ids {
+                    if owners.get(*id).is_none() {
+                        owners.claim(*id, *id as u16);
+                    }
+                }
+                assert!(ids.iter().all(|id| owners.get(*id).is_some()));
+                owners
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("hash_map", name), ids, |b, ids| {
+            b.iter(|| {
+                let mut owners = IdMap::default();
+                for id in ids {
+                    if !owners.contains_key(id) {
+                        owners.insert(*id, *id as u16);
+                    }
+                }
+                assert!(ids.iter().all(|id| owners.contains_key(id)));
+                owners
+            })
+        });
+    }
+    group.finish();
+}
+
+/// The owners of --id-index-dir against a sled tree, on ids spread over the whole range, each looked up before it is
+/// claimed as the engine does, then every id looked up again
+/// The filter in front of the index is left out, so every lookup reaches the files
+/// The bytes each takes on disk are printed after the group runs
+fn id_index(c: &mut Criterion) {
+    let mut rng = Rng::new(7);
+    let ids: Vec<u32> = (0..IDS).map(|_| rng.below(1 << 32) as u32).collect();
+    let dir = std::env::temp_dir().join("bench_id_index");
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut group = c.benchmark_group("id_index");
+    group.throughput(Throughput::Elements(IDS as u64));
+    group.sample_size(10);
+    let mut index_bytes = 0;
+    group.bench_function("index", |b| {
+        b.iter(|| {
+            let mut owners = IndexedOwners::create(&dir);
+            for id in &ids {
+                if owners.get(*id).is_none() {
+                    owners.claim(*id, *id as u16);
+                }
+            }
+            assert!(ids.iter().all(|id| owners.get(*id).is_some()));
+            index_bytes = owners.bytes();
+        })
+    });
+    let mut sled_bytes = 0;
+    group.bench_function("sled", |b| {
+        b.iter(|| {
+            let owners = sled::Config::new()
+                .path(dir.join("sled"))
+                .temporary(true)
+                .open()
+                .unwrap();
+            for id in &ids {
+                let key = id.to_be_bytes();
+                if owners.get(key).unwrap().is_none() {
+                    owners.insert(key, &(*id as u16).to_le_bytes()).unwrap();
+                }
+            }
+            assert!(ids
+                .iter()
+                .all(|id| owners.get(id.to_be_bytes()).unwrap().is_some()));
+            owners.flush().unwrap();
+            sled_bytes = owners.size_on_disk().unwrap();
+        })
+    });
+    group.finish();
+    eprintln!(
+        "owners of {} spread ids on disk: {} bytes in the index, {} bytes in sled",
+        IDS, index_bytes, sled_bytes
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+criterion_group!(benches, increasing_ids, owners, id_index);
+criterion_main!(benches);
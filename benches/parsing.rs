@@ -0,0 +1,108 @@
+//! Reading rows and amounts
+//! Run with `cargo bench --bench parsing`, before and after a change to the reader
+
+use std::{fmt::Write, path::Path, process::Command, str::FromStr};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_decimal::Decimal;
+use transaction_handler::{
+    engine::bench,
+    generate::{workload, Rng, Scenario},
+};
+
+/// Rows of the input the binary reads
+const ROWS: u64 = 1_000_000;
+/// Amounts of each corpus
+const AMOUNTS: usize = 100_000;
+
+/// Parse heavy input, deposits and withdrawals with a spread of ids and amount scales, few of them rejected
+fn input(rows: u64) -> String {
+    let mut input = String::from("type,client,tx,amount\n");
+    for tx in 1..=rows {
+        let client = tx % 65_000;
+        let row = match tx % 4 {
+            0..=2 => format!("deposit,{},{},{}.{:04}", client, tx, tx % 10_000, tx % 7),
+            _ => format!("withdrawal,{},{},0.{}", client, tx, tx % 10),
+        };
+        writeln!(input, "{}", row).unwrap();
+    }
+    input
+}
+
+/// Runs the binary over the input with the flags
+fn run(input_path: &Path, flags: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_transaction-handler"))
+        .arg(input_path)
+        .args(flags)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+}
+
+/// The binary over rows that are almost only parsed, on the tokio runtime and with --sync
+fn binary(c: &mut Criterion) {
+    let input_path = std::env::temp_dir().join("bench_parsing.csv");
+    std::fs::write(&input_path, input(ROWS)).unwrap();
+    let mut group = c.benchmark_group("binary");
+    group.throughput(Throughput::Elements(ROWS));
+    for (name, flags) in [("tokio", &[][..]), ("sync", &["--sync"])] {
+        group.bench_function(name, |b| b.iter(|| run(&input_path, flags)));
+    }
+    group.finish();
+    std::fs::remove_file(&input_path).unwrap();
+}
+
+/// Amounts read from the bytes against Decimal::from_str, for the amounts of the deposits workload and for the
+/// forms exporters write that need more than an i64 or have no digit before the point
+fn amounts(c: &mut Criterion) {
+    let input = String::from_utf8(workload(Scenario::Deposits, AMOUNTS as u64, 7)).unwrap();
+    let deposits: Vec<&str> = input
+        .lines()
+        .skip(1)
+        .filter_map(|row| row.rsplit(',').next())
+        .collect();
+    let mut rng = Rng::new(7);
+    let wide: Vec<String> = (0..AMOUNTS)
+        .map(|_| {
+            format!(
+                "{}.{:018}",
+                rng.below(1_000_000),
+                rng.below(1_000_000_000_000_000_000)
+            )
+        })
+        .collect();
+    let fractions: Vec<String> = (0..AMOUNTS)
+        .map(|_| format!(".{:04}", rng.below(10_000)))
+        .collect();
+    let corpora: [(&str, Vec<&str>); 3] = [
+        ("deposits", deposits),
+        ("wide", wide.iter().map(String::as_str).collect()),
+        ("fractions", fractions.iter().map(String::as_str).collect()),
+    ];
+    let mut group = c.benchmark_group("amounts");
+    for (name, amounts) in &corpora {
+        group.throughput(Throughput::Elements(amounts.len() as u64));
+        group.bench_with_input(BenchmarkId::new("bytes", name), amounts, |b, amounts| {
+            b.iter(|| {
+                for amount in amounts {
+                    std::hint::black_box(bench::parse_amount(amount.as_bytes()).unwrap());
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("from_str", name), amounts, |b, amounts| {
+            b.iter(|| {
+                for amount in amounts {
+                    std::hint::black_box(Decimal::from_str(amount).unwrap());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = binary, amounts
+}
+criterion_main!(benches);
@@ -0,0 +1,100 @@
+//! Accounts shared by the workers of --shared-accounts
+//!
+//! Clients are spread over shards by id, each a map behind a lock of its own, held only to find or open an account,
+//! as dashmap does. Every account is behind a lock of its own as well, held while a row is applied to it, so workers
+//! applying rows of different clients do not wait for each other. A row naming two clients, such as a transfer,
+//! locks both accounts in increasing order of client id: two workers wanting the same pair both wait on the lower id
+//! first, so neither can hold the account the other is waiting for and no set of workers can deadlock.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::{engine::ClientId, hash::IdMap};
+
+/// Accounts of a shard, each behind a lock of its own
+type Shard<A> = Mutex<IdMap<ClientId, Arc<Mutex<A>>>>;
+
+/// Clones refer to the same accounts
+#[derive(Debug)]
+pub(crate) struct SharedAccounts<A> {
+    shards: Arc<[Shard<A>]>,
+}
+
+impl<A> Clone for SharedAccounts<A> {
+    fn clone(&self) -> Self {
+        Self {
+            shards: self.shards.clone(),
+        }
+    }
+}
+
+impl<A> SharedAccounts<A> {
+    pub(crate) fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1))
+                .map(|_| Mutex::new(IdMap::default()))
+                .collect(),
+        }
+    }
+
+    fn shard(&self, client_id: ClientId) -> MutexGuard<'_, IdMap<ClientId, Arc<Mutex<A>>>> {
+        //a worker that panicked while holding the lock stops the run anyway
+        self.shards[client_id as usize % self.shards.len()]
+            .lock()
+            .expect("accounts shard lock is poisoned")
+    }
+
+    /// Accounts of the clients, each once and in increasing order of client id, the order they are locked in
+    /// Clients without one get the account open returns
+    pub(crate) fn ordered(
+        &self,
+        clients: &[ClientId],
+        mut open: impl FnMut(ClientId) -> A,
+    ) -> Vec<(ClientId, Arc<Mutex<A>>)> {
+        let mut clients = clients.to_vec();
+        clients.sort_unstable();
+        clients.dedup();
+        clients
+            .into_iter()
+            .map(|client_id| {
+                let account = self
+                    .shard(client_id)
+                    .entry(client_id)
+                    .or_insert_with(|| Arc::new(Mutex::new(open(client_id))))
+                    .clone();
+                (client_id, account)
+            })
+            .collect()
+    }
+
+    /// Takes every account out, once no worker holds any of them
+    pub(crate) fn take(&self) -> Vec<(ClientId, A)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                std::mem::take(&mut *shard.lock().expect("accounts shard lock is poisoned"))
+            })
+            .map(|(client_id, account)| {
+                let account = Arc::try_unwrap(account)
+                    .unwrap_or_else(|_| panic!("account {} is still held", client_id));
+                let account = account.into_inner().expect("account lock is poisoned");
+                (client_id, account)
+            })
+            .collect()
+    }
+}
+
+/// Locks the accounts in the order given, which must be that of ordered
+pub(crate) fn lock_all<A>(
+    accounts: &[(ClientId, Arc<Mutex<A>>)],
+) -> Vec<(ClientId, MutexGuard<'_, A>)> {
+    debug_assert!(accounts.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    accounts
+        .iter()
+        .map(|(client_id, account)| {
+            (
+                *client_id,
+                account.lock().expect("account lock is poisoned"),
+            )
+        })
+        .collect()
+}
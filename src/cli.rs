@@ -0,0 +1,608 @@
+//! Command line of the binary, reading the options and running the engine they configure
+
+use crate::config::{
+    parse_count, parse_duration, parse_error_rate, parse_size, Config, IdFilter, PartialOutput,
+    Tiers, UnknownAction, VelocityLimit, Window,
+};
+use crate::engine::Engine;
+use crate::error::CustomError;
+use crate::generate::{generate, Scenario};
+use crate::io::{
+    reader::{BlockingReader, Reader},
+    sidecar::read_client_values,
+    writer::Writer,
+};
+use crate::policy::SharedPolicy;
+use log::error;
+use rust_decimal::Decimal;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+use structopt::{clap::ArgGroup, StructOpt};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "transaction-handler", group = ArgGroup::with_name("velocity-window"))]
+struct Opt {
+    #[structopt(parse(from_os_str))]
+    transaction_path: PathBuf,
+    /// More inputs, read at the same time as the first one, with --parallel-files
+    #[structopt(parse(from_os_str), requires = "parallel-files")]
+    more_transaction_paths: Vec<PathBuf>,
+    /// Read every input at the same time on an engine of its own, for inputs that share no client unless
+    /// --shared-accounts is given
+    /// Cannot be used with --rejects, --enforce-chronology, --require-monotonic-tx-ids, --require-halt or --auto-resolve-after
+    #[structopt(long)]
+    parallel_files: bool,
+    /// Let the inputs of --parallel-files share clients: their rows go to --workers workers by client, applied to
+    /// accounts each behind a lock of its own, so transfers between clients of different workers are allowed
+    /// Cannot be used with interest, --dispute-window-records, --spill-dir, --max-memory,
+    /// --transfer-chargeback-refunds-sender or --allow-tx-id-reuse
+    #[structopt(long, requires = "parallel-files")]
+    shared_accounts: bool,
+    /// Allow different clients to reuse the same transaction id (legacy files)
+    #[structopt(long)]
+    allow_tx_id_reuse: bool,
+    /// Allow an unlock row to clear a lock that was caused by a chargeback
+    #[structopt(long)]
+    allow_unlock_chargeback: bool,
+    /// How many times a resolved transaction may be disputed again (unlimited by default)
+    #[structopt(long)]
+    max_redisputes: Option<u32>,
+    /// Allow withdrawals to be disputed, in addition to deposits
+    #[structopt(long)]
+    allow_withdrawal_disputes: bool,
+    /// Reject disputes more than N records after the disputed transaction
+    #[structopt(
+        long,
+        conflicts_with = "dispute-window-duration",
+        parse(try_from_str = parse_count)
+    )]
+    dispute_window_records: Option<u64>,
+    /// Reject disputes older than this duration (e.g. 60d), using the timestamp column
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    dispute_window_duration: Option<u64>,
+    /// Reject rows whose timestamp goes back in time
+    #[structopt(long)]
+    enforce_chronology: bool,
+    /// Number of rows buffered to sort small timestamp inversions, with --enforce-chronology
+    #[structopt(long, requires = "enforce-chronology")]
+    reorder_buffer: Option<usize>,
+    /// Give the fee of a charged back transaction back to the client
+    #[structopt(long)]
+    refund_fee_on_chargeback: bool,
+    /// Add optional columns (fees, closed, chargeback_count, frozen, dispute counters) to the output
+    #[structopt(long)]
+    extra_columns: bool,
+    /// Csv of client,limit allowing clients to withdraw below zero down to -limit
+    #[structopt(long, parse(from_os_str))]
+    credit_limits: Option<PathBuf>,
+    /// Close accounts with held funds once the funds are settled, instead of rejecting the close
+    #[structopt(long)]
+    defer_close: bool,
+    /// Leave closed accounts out of the output
+    #[structopt(long)]
+    omit_closed: bool,
+    /// Reject deposits and withdrawals above this amount
+    #[structopt(long)]
+    max_transaction_amount: Option<Decimal>,
+    /// Write rejected rows and their errors to this csv file
+    #[structopt(long, parse(from_os_str))]
+    rejects: Option<PathBuf>,
+    /// Write the counts of the run, rejected rows by reason included, to this csv file
+    #[structopt(long, parse(from_os_str))]
+    report: Option<PathBuf>,
+    /// Write the audit trail, every chargeback with the reinstate row that overturned it, to this csv file
+    #[structopt(long, parse(from_os_str))]
+    audit: Option<PathBuf>,
+    /// Reject withdrawals once a client made this many within the velocity window
+    #[structopt(long, requires = "velocity-window")]
+    max_withdrawals: Option<usize>,
+    /// Velocity window as a number of records, with --max-withdrawals
+    #[structopt(long, group = "velocity-window", requires = "max-withdrawals")]
+    velocity_window_records: Option<u64>,
+    /// Velocity window in minutes using the timestamp column, with --max-withdrawals
+    #[structopt(long, group = "velocity-window", requires = "max-withdrawals")]
+    velocity_window_minutes: Option<u64>,
+    /// Credit a charged back deposit back to the client when a reinstate row overturns the chargeback
+    #[structopt(long)]
+    reinstate_refunds: bool,
+    /// Transfer released escrow funds out of the account instead of returning them to available
+    #[structopt(long)]
+    escrow_release_transfers: bool,
+    /// Allow an escrow to be released while the account is locked
+    #[structopt(long)]
+    allow_locked_escrow_release: bool,
+    /// Csv of client,tier capping deposits and withdrawals by KYC verification tier
+    #[structopt(long, parse(from_os_str))]
+    tiers: Option<PathBuf>,
+    /// Tier of clients that are not in the tiers file (0 by default), with --tiers
+    #[structopt(long, requires = "tiers")]
+    default_tier: Option<u8>,
+    /// Reject withdrawals that would take available below this amount
+    #[structopt(long)]
+    min_balance: Option<Decimal>,
+    /// Csv of client,floor overriding --min-balance per client
+    #[structopt(long, parse(from_os_str))]
+    min_balances: Option<PathBuf>,
+    /// Resolve disputes still open at the end of the run more than N records after they were opened
+    #[structopt(long, conflicts_with = "auto-resolve-after-duration")]
+    auto_resolve_after_records: Option<u64>,
+    /// Resolve disputes still open at the end of the run older than this duration (e.g. 90d), using the timestamp column
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    auto_resolve_after_duration: Option<u64>,
+    /// Post interest at this rate (e.g. 0.01) to every account at the end of the run
+    #[structopt(long)]
+    accrue_interest: Option<Decimal>,
+    /// Let locked accounts accrue interest
+    #[structopt(long)]
+    accrue_interest_on_locked: bool,
+    /// Let frozen accounts accrue interest
+    #[structopt(long)]
+    accrue_interest_on_frozen: bool,
+    /// Reject deposits and withdrawals whose id does not increase
+    #[structopt(long)]
+    require_monotonic_tx_ids: bool,
+    /// Stop the run instead of rejecting the row when the input looks corrupt
+    #[structopt(long)]
+    strict: bool,
+    /// Reject deposits and withdrawals of an account already storing N transactions (unlimited by default)
+    #[structopt(long)]
+    max_txs_per_account: Option<usize>,
+    /// Fail when the input does not end with a halt row
+    #[structopt(long)]
+    require_halt: bool,
+    /// Credit a charged back transfer back to its sender
+    #[structopt(long)]
+    transfer_chargeback_refunds_sender: bool,
+    /// Rules for disputes, resolves and chargebacks: standard, or conservative which never disputes
+    /// withdrawals and never takes available below zero
+    #[structopt(long, default_value = "standard")]
+    dispute_policy: SharedPolicy,
+    /// Skip rows that cannot be read, logging their line, instead of stopping the run
+    #[structopt(long)]
+    skip_malformed_rows: bool,
+    /// Leave an account whose balances became inconsistent out of the output and go on, instead of stopping the run
+    #[structopt(long)]
+    quarantine_invariant_violations: bool,
+    /// What to do with rows of an unknown type: abort the run, or skip them counting each type
+    #[structopt(long, default_value = "abort")]
+    unknown_action: UnknownAction,
+    /// Treat amounts on dispute, resolve and chargeback rows as echoes of the transaction amount,
+    /// rejecting rows where they differ, instead of partial disputes
+    #[structopt(long)]
+    validate_dispute_amounts: bool,
+    /// Output of a run that stops on an error midway: never written, written with a closing
+    /// `# partial output` line naming the error (mark), or written as is (write)
+    #[structopt(long, default_value = "never")]
+    partial_output: PartialOutput,
+    /// Reject rows whose amount or fee has more than four decimal places, instead of rounding them half up
+    #[structopt(long)]
+    strict_precision: bool,
+    /// Drop a last record cut off before its line break, with a warning, instead of failing the run
+    #[structopt(long)]
+    tolerate_truncated_tail: bool,
+    /// Accept amounts and fees in exponent notation such as `1.5e3`, which are rejected by default
+    #[structopt(long)]
+    allow_scientific: bool,
+    /// Apply rows on N workers in parallel, each owning the accounts of some clients
+    /// Cannot be used with transfers, --enforce-chronology, --require-monotonic-tx-ids or --allow-tx-id-reuse
+    #[structopt(long, default_value = "1")]
+    workers: usize,
+    /// Read the whole input before applying any row, then apply the rows of each client in input order on a thread
+    /// pool of one thread per core, for inputs of many clients with few rows each
+    /// Cannot be used with transfers, --workers, --enforce-chronology, --require-monotonic-tx-ids,
+    /// --allow-tx-id-reuse, --spill-dir or --max-memory
+    #[structopt(long)]
+    group_by_client: bool,
+    /// Move the oldest transactions to a file in this directory once they take more than --max-memory
+    #[structopt(long, parse(from_os_str), requires = "max-memory")]
+    spill_dir: Option<PathBuf>,
+    /// Keep the owners of transaction ids in files of this directory, behind a Bloom filter in memory that tells
+    /// most new ids apart without reading them, for inputs with more ids than fit in memory
+    /// Cannot be used with --workers, --parallel-files, --group-by-client or checkpoints
+    #[structopt(long, parse(from_os_str))]
+    id_index_dir: Option<PathBuf>,
+    /// Ids the Bloom filter of --id-index-dir is sized for (100000000 by default)
+    #[structopt(long, requires = "id-index-dir")]
+    id_filter_ids: Option<u64>,
+    /// Share of new ids the Bloom filter of --id-index-dir takes for claimed ones, each read from the index to be
+    /// sure, once it holds --id-filter-ids ids (0.01 by default)
+    #[structopt(long, requires = "id-index-dir", parse(try_from_str = parse_error_rate))]
+    id_filter_error_rate: Option<f64>,
+    /// Memory for stored transactions before they spill to --spill-dir (e.g. 8G)
+    /// Without --spill-dir the run stops once the accounts are estimated to take more
+    #[structopt(long, parse(try_from_str = parse_size))]
+    max_memory: Option<u64>,
+    /// Print a progress line to stderr every N records, with the memory the accounts are estimated to take
+    #[structopt(long)]
+    progress_every: Option<u64>,
+    /// Save the state of the run every N records to --checkpoint-dir, so it can be resumed
+    /// Cannot be used with --workers, --spill-dir, --enforce-chronology or --rejects
+    #[structopt(long, requires = "checkpoint-dir")]
+    checkpoint_every: Option<u64>,
+    /// Directory receiving the checkpoints, with --checkpoint-every
+    #[structopt(long, parse(from_os_str), requires = "checkpoint-every")]
+    checkpoint_dir: Option<PathBuf>,
+    /// Newest checkpoints kept in --checkpoint-dir (2 by default)
+    #[structopt(long, requires = "checkpoint-every")]
+    checkpoint_keep: Option<usize>,
+    /// Resume a run that stopped from the newest valid checkpoint in this directory
+    /// The input and options must be those of the run that stopped
+    #[structopt(long, parse(from_os_str))]
+    resume_from: Option<PathBuf>,
+    /// Only process the rows appended since the last run, continuing from the state it saved in --state-dir
+    /// An input rotated or rewritten since then is processed from the start again, with a warning
+    /// Cannot be used with what --checkpoint-every cannot, nor with --two-pass
+    #[structopt(long, requires = "state-dir")]
+    incremental: bool,
+    /// Directory holding the state of the last run, with --incremental
+    #[structopt(long, parse(from_os_str), requires = "incremental")]
+    state_dir: Option<PathBuf>,
+    /// Distinct clients the input is expected to have, so their accounts are allocated up front
+    #[structopt(long)]
+    expected_clients: Option<usize>,
+    /// Transactions each client is expected to have, so accounts are allocated with room for them
+    /// Reservations stop at 256MB however large the hints are
+    #[structopt(long)]
+    expected_txs_per_client: Option<usize>,
+    /// Read and parse the input on a task of its own while rows are applied
+    /// Cannot be used with --checkpoint-every or --parallel-files
+    #[structopt(long)]
+    pipeline: bool,
+    /// Batches of 256 parsed rows that may wait for the engine (4 by default), with --pipeline
+    #[structopt(long, requires = "pipeline")]
+    pipeline_depth: Option<usize>,
+    /// Parse the input file on N threads, each taking parts of about 256KB split at line breaks, while its rows are
+    /// applied in input order. Needs an uncompressed file it can seek in
+    /// Cannot be used with --pipeline, --parallel-files or checkpoints
+    #[structopt(long, default_value = "1")]
+    parse_threads: usize,
+    /// Rows read and parsed before they are applied, so the reader is not awaited on every row
+    #[structopt(long, default_value = "4096")]
+    batch_size: usize,
+    /// Add the memory taken by the owners of transaction ids to the run summary, against that of a hash map
+    #[structopt(long)]
+    verbose: bool,
+    /// Read the input twice: first for the ids later rows refer to, then to apply it keeping only those deposits
+    /// whole, the others as ids. Needs an input it can seek in, and cannot be used with --parallel-files
+    #[structopt(long)]
+    two_pass: bool,
+    /// Run on this thread without the tokio runtime, reading and writing with blocking calls
+    /// Cannot be used with --workers, --parallel-files or --pipeline
+    #[structopt(long)]
+    sync: bool,
+    /// Write a generated workload to the path instead of reading it
+    /// (deposits, disputes, many-clients, few-clients or duplicate-ids)
+    #[structopt(long)]
+    generate: Option<Scenario>,
+    /// Rows of the generated workload (1000000 by default), with --generate
+    #[structopt(long, requires = "generate")]
+    rows: Option<u64>,
+    /// Seed of the generated workload, the same seed writes the same rows (0 by default), with --generate
+    #[structopt(long, requires = "generate")]
+    seed: Option<u64>,
+}
+
+impl Opt {
+    /// Builds the engine config, loading any sidecar files
+    fn to_config(&self) -> Result<Config, CustomError> {
+        let credit_limits = match &self.credit_limits {
+            None => HashMap::new(),
+            Some(path) => read_client_values(path.clone())?,
+        };
+        let min_balances = match &self.min_balances {
+            None => HashMap::new(),
+            Some(path) => read_client_values(path.clone())?,
+        };
+        let tiers = match &self.tiers {
+            None => None,
+            Some(path) => Some(Tiers {
+                clients: read_client_values(path.clone())?,
+                default_tier: self.default_tier.unwrap_or_default(),
+            }),
+        };
+        Ok(Config {
+            allow_tx_id_reuse: self.allow_tx_id_reuse,
+            allow_unlock_chargeback: self.allow_unlock_chargeback,
+            max_redisputes: self.max_redisputes,
+            allow_withdrawal_disputes: self.allow_withdrawal_disputes,
+            dispute_window: self
+                .dispute_window_records
+                .map(Window::Records)
+                .or_else(|| self.dispute_window_duration.map(Window::Duration)),
+            enforce_chronology: self.enforce_chronology,
+            reorder_buffer: self.reorder_buffer.unwrap_or_default(),
+            refund_fee_on_chargeback: self.refund_fee_on_chargeback,
+            extra_columns: self.extra_columns,
+            credit_limits,
+            defer_close: self.defer_close,
+            omit_closed: self.omit_closed,
+            max_transaction_amount: self.max_transaction_amount,
+            rejects_path: self.rejects.clone(),
+            report_path: self.report.clone(),
+            audit_path: self.audit.clone(),
+            velocity_limit: self.max_withdrawals.and_then(|max_withdrawals| {
+                let window = self
+                    .velocity_window_records
+                    .map(Window::Records)
+                    .or_else(|| {
+                        self.velocity_window_minutes
+                            .map(|minutes| Window::Duration(minutes * 60))
+                    })?;
+                Some(VelocityLimit {
+                    max_withdrawals,
+                    window,
+                })
+            }),
+            reinstate_refunds: self.reinstate_refunds,
+            escrow_release_transfers: self.escrow_release_transfers,
+            allow_locked_escrow_release: self.allow_locked_escrow_release,
+            tiers,
+            min_balance: self.min_balance,
+            min_balances,
+            auto_resolve_after: self
+                .auto_resolve_after_records
+                .map(Window::Records)
+                .or_else(|| self.auto_resolve_after_duration.map(Window::Duration)),
+            accrue_interest: self.accrue_interest,
+            accrue_interest_on_locked: self.accrue_interest_on_locked,
+            accrue_interest_on_frozen: self.accrue_interest_on_frozen,
+            require_monotonic_tx_ids: self.require_monotonic_tx_ids,
+            strict: self.strict,
+            max_transactions_per_account: self.max_txs_per_account,
+            require_halt: self.require_halt,
+            transfer_chargeback_refunds_sender: self.transfer_chargeback_refunds_sender,
+            dispute_policy: self.dispute_policy.clone(),
+            skip_malformed_rows: self.skip_malformed_rows,
+            quarantine_invariant_violations: self.quarantine_invariant_violations,
+            unknown_action: self.unknown_action,
+            validate_dispute_amounts: self.validate_dispute_amounts,
+            partial_output: self.partial_output,
+            strict_precision: self.strict_precision,
+            tolerate_truncated_tail: self.tolerate_truncated_tail,
+            allow_scientific: self.allow_scientific,
+            workers: self.workers,
+            shared_accounts: self.shared_accounts,
+            group_by_client: self.group_by_client,
+            spill_dir: self.spill_dir.clone(),
+            id_filter: self.id_index_dir.clone().map(|dir| IdFilter {
+                dir,
+                expected_ids: self.id_filter_ids.unwrap_or(100_000_000),
+                error_rate: self.id_filter_error_rate.unwrap_or(0.01),
+            }),
+            max_memory: self.max_memory.unwrap_or_default(),
+            progress_every: self.progress_every.filter(|every| *every > 0),
+            checkpoint_every: self.checkpoint_every,
+            checkpoint_dir: self.checkpoint_dir.clone(),
+            checkpoint_keep: self.checkpoint_keep.unwrap_or(2),
+            resume_from: self.resume_from.clone(),
+            state_dir: self.state_dir.clone(),
+            expected_clients: self.expected_clients,
+            expected_transactions_per_client: self.expected_txs_per_client,
+            pipeline: self.pipeline.then(|| self.pipeline_depth.unwrap_or(4)),
+            parse_threads: self.parse_threads,
+            batch_size: self.batch_size,
+            verbose: self.verbose,
+            referenced_ids: None,
+        })
+    }
+}
+
+/// Exit code of a fatal error, such as an input that cannot be opened or read
+const FATAL_EXIT_CODE: u8 = 1;
+/// Exit code when the output could not be written, EX_IOERR of sysexits
+const OUTPUT_FAILURE_EXIT_CODE: u8 = 74;
+
+/// Reports a fatal error and gives the exit code of the run
+/// The error goes to stderr as well, since the log may not be set up
+fn fail(err: &CustomError, code: u8) -> ExitCode {
+    error!("{:?}", err);
+    eprintln!("error: {}", err);
+    ExitCode::from(code)
+}
+
+/// Writes a generated workload to the path
+fn write_workload(
+    path: &Path,
+    scenario: Scenario,
+    rows: u64,
+    seed: u64,
+) -> Result<(), CustomError> {
+    let mut out = BufWriter::new(File::create(path)?);
+    generate(scenario, rows, seed, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Runs the binary with the arguments of the process, returning how it exits
+pub fn main() -> ExitCode {
+    let opt = Opt::from_args();
+    if let Some(scenario) = opt.generate {
+        let rows = opt.rows.unwrap_or(1_000_000);
+        return match write_workload(&opt.transaction_path, scenario, rows, opt.seed.unwrap_or(0)) {
+            Err(err) => fail(&err, FATAL_EXIT_CODE),
+            Ok(()) => ExitCode::SUCCESS,
+        };
+    }
+    if opt.sync {
+        //nothing is spawned and every read and write blocks, so this thread is all the run needs
+        return run_sync(opt);
+    }
+    tokio::runtime::Runtime::new()
+        .expect("the tokio runtime could not start")
+        .block_on(run_async(opt))
+}
+
+/// Builds the config the flags give the binary, for engines run over inputs of the caller such as the benches
+pub fn config(flags: &[&str]) -> Result<Config, CustomError> {
+    //the input is only read by execute, which is not run
+    let args = ["transaction-handler", "-"].iter().chain(flags);
+    Opt::from_iter(args).to_config()
+}
+
+/// Runs on the tokio runtime, reading and writing through tokio
+async fn run_async(opt: Opt) -> ExitCode {
+    let engine = match opt.to_config() {
+        //sidecar files could not be loaded
+        Err(err) => return fail(&err, FATAL_EXIT_CODE),
+        Ok(config) => Engine::new(config),
+    };
+    let mut readers = Vec::with_capacity(1 + opt.more_transaction_paths.len());
+    for path in std::iter::once(&opt.transaction_path).chain(&opt.more_transaction_paths) {
+        match Reader::new(path.clone()).await {
+            Err(err) => return fail(&err, FATAL_EXIT_CODE),
+            Ok(reader) => readers.push(reader),
+        }
+    }
+    execute(&opt, engine, readers, Writer::new()).await //write to std::out
+}
+
+/// Runs on the current thread without a runtime, reading with the csv crate and writing with blocking std calls
+fn run_sync(opt: Opt) -> ExitCode {
+    //these spawn tasks, which need the runtime
+    for (feature, is_used) in [
+        ("--workers", opt.workers > 1),
+        ("--parallel-files", opt.parallel_files),
+        ("--pipeline", opt.pipeline),
+    ] {
+        if is_used {
+            return fail(
+                &CustomError::UnsupportedWithSync { feature },
+                FATAL_EXIT_CODE,
+            );
+        }
+    }
+    let engine = match opt.to_config() {
+        Err(err) => return fail(&err, FATAL_EXIT_CODE),
+        Ok(config) => Engine::new(config),
+    };
+    let reader = match BlockingReader::open(&opt.transaction_path) {
+        Err(err) => return fail(&err, FATAL_EXIT_CODE),
+        Ok(reader) => reader,
+    };
+    execute_blocking(&opt, engine, reader, Writer::blocking())
+}
+
+/// Processes the inputs and writes the accounts, returning how the process exits
+async fn execute<R, W>(
+    opt: &Opt,
+    mut engine: Engine,
+    mut readers: Vec<Reader<R>>,
+    mut writer: Writer<W>,
+) -> ExitCode
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin,
+{
+    if let Err(err) = check_two_pass(opt) {
+        return fail(&err, FATAL_EXIT_CODE);
+    }
+    let result = if opt.parallel_files {
+        engine.process_files(readers, &mut writer).await
+    } else if opt.incremental {
+        engine
+            .process_incremental(&mut readers[0], &mut writer)
+            .await
+    } else {
+        //the first pass of --two-pass leaves the reader back at the first row
+        let scanned = match opt.two_pass {
+            true => engine.scan_references(&mut readers[0]).await,
+            false => Ok(()),
+        };
+        let resumed = match scanned {
+            Ok(()) => engine.resume(&mut readers[0]).await,
+            Err(err) => Err(err),
+        };
+        match resumed {
+            Ok(_) if opt.parse_threads > 1 => {
+                engine
+                    .process_split(&opt.transaction_path, &mut writer)
+                    .await
+            }
+            Ok(_) if opt.pipeline => {
+                engine
+                    .process_pipelined(readers.remove(0), &mut writer)
+                    .await
+            }
+            Ok(_) => engine.process(&mut readers[0], &mut writer).await,
+            Err(err) => Err(err),
+        }
+    };
+    exit_code(&engine, result)
+}
+
+/// Processes the input of --sync like execute, with blocking calls
+fn execute_blocking<W>(
+    opt: &Opt,
+    mut engine: Engine,
+    mut reader: BlockingReader,
+    mut writer: Writer<W>,
+) -> ExitCode
+where
+    W: std::io::Write,
+{
+    if let Err(err) = check_two_pass(opt) {
+        return fail(&err, FATAL_EXIT_CODE);
+    }
+    let result = if opt.incremental {
+        engine.process_incremental_blocking(&mut reader, &mut writer)
+    } else {
+        let scanned = match opt.two_pass {
+            true => engine.scan_references_blocking(&mut reader),
+            false => Ok(()),
+        };
+        match scanned.and_then(|()| engine.resume_blocking(&mut reader)) {
+            Ok(_) if opt.parse_threads > 1 => {
+                engine.process_split_blocking(&opt.transaction_path, &mut writer)
+            }
+            Ok(_) => engine.process_blocking(&mut reader, &mut writer),
+            Err(err) => Err(err),
+        }
+    };
+    exit_code(&engine, result)
+}
+
+/// Refuses --two-pass with the modes it cannot run in
+fn check_two_pass(opt: &Opt) -> Result<(), CustomError> {
+    if !opt.two_pass {
+        return Ok(());
+    }
+    if opt.parallel_files {
+        return Err(CustomError::UnsupportedWithParallelFiles {
+            feature: "--two-pass",
+        });
+    }
+    //deposits the first pass found unreferenced may be referenced by rows appended later
+    if opt.incremental {
+        return Err(CustomError::UnsupportedWithCheckpoints {
+            feature: "--two-pass",
+        });
+    }
+    Ok(())
+}
+
+/// How the process exits once the run ended with result, printing the summary or the error
+fn exit_code(engine: &Engine, result: Result<(), CustomError>) -> ExitCode {
+    match result {
+        //the reader went away, as `| head` does, which like other unix tools is not a failure
+        Err(CustomError::OutputWrite { source, .. })
+            if source.kind() == std::io::ErrorKind::BrokenPipe =>
+        {
+            ExitCode::SUCCESS
+        }
+        //a consumer of the output must not mistake a partial output for a complete one
+        Err(err @ CustomError::OutputWrite { .. }) => fail(&err, OUTPUT_FAILURE_EXIT_CODE),
+        //some irrecoverable happend, the output is incomplete or missing
+        Err(err) => fail(&err, FATAL_EXIT_CODE),
+        //the output is written, so the summary cannot interleave with it
+        Ok(()) => {
+            eprint!("{}", engine.report());
+            ExitCode::SUCCESS
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use std::{collections::HashMap, path::PathBuf, str::FromStr, sync::Arc};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    engine::{ClientId, TransactionId},
+    hash::IdSet,
+    policy::SharedPolicy,
+};
+
+/// Policies that relax or tighten how the engine treats input
+/// Default values follow the input contract strictly
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    /// Allow different clients to reuse the same transaction id, for legacy files
+    /// that only guarantee uniqueness per client
+    pub(crate) allow_tx_id_reuse: bool,
+    /// Allow an Unlock action to clear a lock that was caused by a chargeback
+    pub(crate) allow_unlock_chargeback: bool,
+    /// How many times a resolved transaction may be disputed again, unlimited when None
+    pub(crate) max_redisputes: Option<u32>,
+    /// Allow withdrawals to be disputed, in addition to deposits
+    pub(crate) allow_withdrawal_disputes: bool,
+    /// How old a transaction may be to still be disputed, unlimited when None
+    pub(crate) dispute_window: Option<Window>,
+    /// Reject rows whose timestamp is older than a row already applied
+    /// No-op when the input has no timestamp column
+    pub(crate) enforce_chronology: bool,
+    /// Number of rows held back to sort small timestamp inversions when chronology is enforced
+    pub(crate) reorder_buffer: usize,
+    /// Give the fee of a charged back transaction back to the client
+    pub(crate) refund_fee_on_chargeback: bool,
+    /// Add the optional columns (fees, closed, chargeback_count, frozen, dispute counters) to the output
+    pub(crate) extra_columns: bool,
+    /// How far below zero each client's available funds may go on withdrawal, zero when absent
+    pub(crate) credit_limits: HashMap<ClientId, Decimal>,
+    /// Let a Close action on an account with held funds wait until they are settled, instead of rejecting it
+    pub(crate) defer_close: bool,
+    /// Leave closed accounts out of the output
+    pub(crate) omit_closed: bool,
+    /// Largest amount a single deposit or withdrawal may move, unlimited when None
+    pub(crate) max_transaction_amount: Option<Decimal>,
+    /// Csv file receiving every rejected row with its error
+    pub(crate) rejects_path: Option<PathBuf>,
+    /// Csv file receiving the counts of the run, rejected rows broken down by reason
+    pub(crate) report_path: Option<PathBuf>,
+    /// Csv file receiving the audit trail, every chargeback and whether it was reinstated
+    pub(crate) audit_path: Option<PathBuf>,
+    /// Limit on how many withdrawals a client may make in a short span, unlimited when None
+    pub(crate) velocity_limit: Option<VelocityLimit>,
+    /// Credit a charged back deposit back to the client when its chargeback is reinstated
+    pub(crate) reinstate_refunds: bool,
+    /// Transfer released escrow funds out of the account instead of returning them to available
+    pub(crate) escrow_release_transfers: bool,
+    /// Allow an escrow to be released while the account is locked
+    pub(crate) allow_locked_escrow_release: bool,
+    /// KYC verification tier of each client, no tier caps are enforced when None
+    pub(crate) tiers: Option<Tiers>,
+    /// Available funds every account must keep after a withdrawal, no floor when None
+    pub(crate) min_balance: Option<Decimal>,
+    /// Per-client floors, overriding min_balance
+    pub(crate) min_balances: HashMap<ClientId, Decimal>,
+    /// Age past which an open dispute is resolved at the end of the run, never when None
+    pub(crate) auto_resolve_after: Option<Window>,
+    /// Interest rate posted to every account at the end of the run, none when None
+    pub(crate) accrue_interest: Option<Decimal>,
+    /// Let locked accounts accrue interest
+    pub(crate) accrue_interest_on_locked: bool,
+    /// Let frozen accounts accrue interest
+    pub(crate) accrue_interest_on_frozen: bool,
+    /// Reject deposits and withdrawals whose id is not above every deposit or withdrawal id before them
+    pub(crate) require_monotonic_tx_ids: bool,
+    /// Stop the run instead of rejecting the row when the input looks corrupt
+    pub(crate) strict: bool,
+    /// Number of transactions an account may store before new deposits and withdrawals are rejected,
+    /// unlimited when None
+    pub(crate) max_transactions_per_account: Option<usize>,
+    /// Treat an input that ends without a halt row as truncated, and stop the run
+    pub(crate) require_halt: bool,
+    /// Credit a charged back transfer back to its sender
+    pub(crate) transfer_chargeback_refunds_sender: bool,
+    /// Rules deciding what disputes, resolves and chargebacks do to an account
+    pub(crate) dispute_policy: SharedPolicy,
+    /// Parse-error policy, skip rows that cannot be read instead of stopping the run
+    pub(crate) skip_malformed_rows: bool,
+    /// Set aside an account whose balances became inconsistent instead of stopping the run
+    pub(crate) quarantine_invariant_violations: bool,
+    /// What a row of a type the engine does not know does to the run
+    pub(crate) unknown_action: UnknownAction,
+    /// Amounts on dispute, resolve and chargeback rows are echoes of the amount they refer to
+    /// and must match it, instead of naming the part disputed
+    pub(crate) validate_dispute_amounts: bool,
+    /// What is written to the output when the run stops on an error midway
+    pub(crate) partial_output: PartialOutput,
+    /// Reject amounts with more than four decimal places instead of rounding them
+    pub(crate) strict_precision: bool,
+    /// Drop a last record cut off by the end of the input instead of stopping the run
+    pub(crate) tolerate_truncated_tail: bool,
+    /// Accept amounts and fees in exponent notation, which the input contract leaves out
+    pub(crate) allow_scientific: bool,
+    /// Workers applying rows in parallel, each owning the accounts of some clients
+    /// The reader applies every row itself when it is 0 or 1
+    pub(crate) workers: usize,
+    /// Read every row before applying any, then apply the rows of each client in input order on a rayon thread
+    pub(crate) group_by_client: bool,
+    /// Let the inputs read at the same time share clients, their rows going to the workers by client and applied
+    /// to accounts each behind a lock of its own
+    pub(crate) shared_accounts: bool,
+    /// Directory receiving the transactions moved out of memory, which stay in memory when None
+    pub(crate) spill_dir: Option<PathBuf>,
+    /// Owners of transaction ids kept in files behind a Bloom filter, they stay in memory when None
+    pub(crate) id_filter: Option<IdFilter>,
+    /// Bytes of stored transactions kept in memory before the oldest are moved to the spill directory,
+    /// or without one, estimated bytes of the accounts at which the run stops, 0 for no limit
+    /// Shared evenly by the workers
+    pub(crate) max_memory: u64,
+    /// Records between two progress lines printed to stderr, none are printed when None
+    pub(crate) progress_every: Option<u64>,
+    /// Records between two checkpoints of the run, none are taken when None
+    pub(crate) checkpoint_every: Option<u64>,
+    /// Directory receiving the checkpoints, with checkpoint_every
+    pub(crate) checkpoint_dir: Option<PathBuf>,
+    /// Newest checkpoints kept in the directory, older ones are removed
+    pub(crate) checkpoint_keep: usize,
+    /// Directory of the checkpoints of a run that stopped, the run starts from the newest valid one
+    pub(crate) resume_from: Option<PathBuf>,
+    /// Directory holding the state of the last --incremental run, which the next one continues from
+    pub(crate) state_dir: Option<PathBuf>,
+    /// Distinct clients the input is expected to have, their accounts are reserved up front
+    pub(crate) expected_clients: Option<usize>,
+    /// Stored transactions each account is expected to keep, reserved as the account opens
+    pub(crate) expected_transactions_per_client: Option<usize>,
+    /// Batches of parsed rows that may wait for the engine when the input is read on a task of its own
+    pub(crate) pipeline: Option<usize>,
+    /// Threads parsing parts of the input at the same time, it is read on one thread when 0 or 1
+    pub(crate) parse_threads: usize,
+    /// Rows read and parsed before they are applied, one at a time when 0 or 1
+    pub(crate) batch_size: usize,
+    /// Report the memory of the owners of transaction ids in the summary
+    pub(crate) verbose: bool,
+    /// Ids a later row refers to, found by the first pass of --two-pass
+    /// When set, deposits with any other id are only kept as ids
+    pub(crate) referenced_ids: Option<Arc<IdSet<TransactionId>>>,
+}
+
+impl Config {
+    /// Minimum balance of the client, its own floor first and then the global one
+    pub(crate) fn min_balance(&self, client_id: ClientId) -> Option<Decimal> {
+        self.min_balances
+            .get(&client_id)
+            .copied()
+            .or(self.min_balance)
+    }
+
+    /// Caps of the client's verification tier, unlimited when tiers are not configured
+    pub(crate) fn tier_limits(&self, client_id: ClientId) -> TierLimits {
+        match &self.tiers {
+            None => TierLimits::default(),
+            Some(tiers) => TierLimits::of(
+                tiers
+                    .clients
+                    .get(&client_id)
+                    .copied()
+                    .unwrap_or(tiers.default_tier),
+            ),
+        }
+    }
+}
+
+/// Verification tiers loaded from a sidecar csv
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Tiers {
+    pub(crate) clients: HashMap<ClientId, u8>,
+    /// Tier of clients that are not in the sidecar
+    pub(crate) default_tier: u8,
+}
+
+/// Caps that apply to deposits and withdrawals of a tier, None is unlimited
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct TierLimits {
+    /// Most the account may hold in total after a deposit
+    pub(crate) max_total: Option<Decimal>,
+    /// Largest single withdrawal
+    pub(crate) max_withdrawal: Option<Decimal>,
+}
+
+impl TierLimits {
+    /// Built-in table: tier 0 may hold 500 and withdraw 100 at a time,
+    /// tier 1 may hold 10000 and withdraw 2500, tier 2 and above are unlimited
+    pub(crate) fn of(tier: u8) -> Self {
+        match tier {
+            0 => Self {
+                max_total: Some(Decimal::new(500, 0)),
+                max_withdrawal: Some(Decimal::new(100, 0)),
+            },
+            1 => Self {
+                max_total: Some(Decimal::new(10000, 0)),
+                max_withdrawal: Some(Decimal::new(2500, 0)),
+            },
+            _ => Self::default(),
+        }
+    }
+}
+
+/// Span of input, measured in records or in seconds of the timestamp column
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Window {
+    /// Number of records
+    Records(u64),
+    /// Seconds between timestamps
+    /// Not enforced for rows without a timestamp
+    Duration(u64),
+}
+
+impl Window {
+    /// Position of a row along the window, None when a duration window meets a row without timestamp
+    pub(crate) fn position(&self, record_index: u64, timestamp: Option<u64>) -> Option<u64> {
+        match self {
+            Window::Records(_) => Some(record_index),
+            Window::Duration(_) => timestamp,
+        }
+    }
+
+    pub(crate) fn length(&self) -> u64 {
+        match *self {
+            Window::Records(length) | Window::Duration(length) => length,
+        }
+    }
+}
+
+/// Handling of rows whose type the engine does not know, such as a type newly introduced upstream
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UnknownAction {
+    /// Stop the run, the input contract may have changed
+    #[default]
+    Abort,
+    /// Skip the row and count it by type
+    Skip,
+}
+
+impl FromStr for UnknownAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(UnknownAction::Abort),
+            "skip" => Ok(UnknownAction::Skip),
+            _ => Err(format!(
+                "unknown action policy {}, expected abort or skip",
+                s
+            )),
+        }
+    }
+}
+
+/// Output of a run that stopped on an error midway, its accounts only reflect the rows before the error
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PartialOutput {
+    /// Write nothing
+    #[default]
+    Never,
+    /// Write the accounts, followed by a comment line naming the error
+    Mark,
+    /// Write the accounts as if the run had succeeded, only the exit code tells them apart
+    Write,
+}
+
+impl FromStr for PartialOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(PartialOutput::Never),
+            "mark" => Ok(PartialOutput::Mark),
+            "write" => Ok(PartialOutput::Write),
+            _ => Err(format!(
+                "unknown partial output policy {}, expected never, mark or write",
+                s
+            )),
+        }
+    }
+}
+
+/// At most max_withdrawals withdrawals per client within the window
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct VelocityLimit {
+    pub(crate) max_withdrawals: usize,
+    pub(crate) window: Window,
+}
+
+/// Files keeping the owners of transaction ids, and the Bloom filter telling most new ids apart without reading them
+#[derive(Debug, Clone)]
+pub(crate) struct IdFilter {
+    pub(crate) dir: PathBuf,
+    /// Ids the filter is sized for
+    pub(crate) expected_ids: u64,
+    /// Share of new ids the filter takes for claimed ones once it holds expected_ids
+    pub(crate) error_rate: f64,
+}
+
+/// Parses a size such as `8G`, `512M` or `64K` into bytes, units are powers of 1024
+/// A bare number is taken as bytes
+pub(crate) fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, unit) = match s.char_indices().last() {
+        Some((index, unit)) if unit.is_ascii_alphabetic() => (&s[..index], unit),
+        _ => (s, 'B'),
+    };
+    let multiplier: u64 = match unit.to_ascii_uppercase() {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        'T' => 1 << 40,
+        _ => return Err(format!("unknown size unit in {}", s)),
+    };
+    number
+        .parse::<u64>()
+        .map_err(|err| format!("invalid size {}: {}", s, err))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size {} is too large", s))
+}
+
+/// Parses a duration such as `60d`, `12h`, `30m` or `45s` into seconds
+/// A bare number is taken as seconds
+pub(crate) fn parse_duration(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, unit) = match s.char_indices().last() {
+        Some((index, unit)) if unit.is_ascii_alphabetic() => (&s[..index], unit),
+        _ => (s, 's'),
+    };
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return Err(format!("unknown duration unit in {}", s)),
+    };
+    number
+        .parse::<u64>()
+        .map(|number| number * multiplier)
+        .map_err(|err| format!("invalid duration {}: {}", s, err))
+}
+
+/// Parses a count of records that must be above zero, such as the length of a window
+pub(crate) fn parse_count(s: &str) -> Result<u64, String> {
+    match s.trim().parse::<u64>() {
+        Ok(0) => Err(String::from("must be above zero")),
+        Ok(count) => Ok(count),
+        Err(err) => Err(format!("invalid count {}: {}", s, err)),
+    }
+}
+
+/// Parses an error rate of a Bloom filter, strictly between 0 and 1 such as `0.01`
+pub(crate) fn parse_error_rate(s: &str) -> Result<f64, String> {
+    let rate = s
+        .trim()
+        .parse::<f64>()
+        .map_err(|err| format!("invalid error rate {}: {}", s, err))?;
+    if rate > 0.0 && rate < 1.0 {
+        Ok(rate)
+    } else {
+        Err(format!("error rate {} is not between 0 and 1", s))
+    }
+}
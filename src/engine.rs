@@ -1,630 +1,13101 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, BTreeMap, BinaryHeap, HashMap, VecDeque},
+    fmt::{self, Write as _},
+    hash::{Hash, Hasher},
+    io::{Read, Seek},
+    num::{IntErrorKind, ParseIntError},
+    ops::{Deref, DerefMut, Index},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
 
 use crate::{
-    error::CustomError,
-    io::{reader::Reader, writer::Writer},
+    accounts::{lock_all, SharedAccounts},
+    config::{Config, PartialOutput, UnknownAction, Window},
+    error::{CustomError, OriginalTransaction, Severity},
+    hash::{IdMap, IdSet},
+    ids::IncreasingIds,
+    io::{
+        audit::{self, AuditEntry},
+        checkpoint,
+        reader::{compression, split_points, BlockingReader, Reader},
+        rejects::RejectsWriter,
+        report::RunReport,
+        spill::SpillFile,
+        writer::Writer,
+    },
+    owners::{FilteredOwners, IdOwners},
+    policy::{AccountView, DisputeEffect, DisputeStep, DisputedKind, TransactionView},
 };
 use anyhow::Result;
-use csv_async::StringRecord;
-use futures::stream::StreamExt;
-use log::warn;
-use rust_decimal::Decimal;
-use tokio::io::AsyncWriteExt;
+use csv_async::{ByteRecord, StringRecord};
+use log::{debug, error, info, warn};
+use rayon::prelude::*;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{
+    de::{IgnoredAny, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use slab::Slab;
+use tokio::{
+    io::{AsyncRead, AsyncSeek, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    task::JoinHandle,
+};
 
 const PRECISION: u32 = 4;
+/// Longest memo kept, in bytes
+const MEMO_LIMIT: usize = 64;
+/// Approximate memory of a stored transaction in the map of its account, with its share of spare capacity
+const STORED_BYTES: u64 = (std::mem::size_of::<(TransactionId, StoredTransaction)>() + 16) as u64;
+/// Rows applied between two counts of the stored transactions, when they may spill
+const SPILL_CHECK: u32 = 1024;
+/// Length of a stored transaction in the spill file
+const SPILL_RECORD: usize = 103;
+/// Rows sent to a worker at once, so the channel is not paid for on every row
+const SHARD_BATCH: usize = 1024;
+/// Batches that may wait for a worker before the reader waits for it
+const SHARD_QUEUE: usize = 16;
+/// Locks the accounts of --shared-accounts are spread over, each held only to find or open an account
+const ACCOUNT_SHARDS: usize = 64;
+/// Longest raw row text kept for reporting, in bytes
+const RAW_LIMIT: usize = 256;
+/// Bytes of output rows gathered before they are written, so a row does not cost a write of its own
+const OUTPUT_CHUNK: usize = 64 * 1024;
+/// Rows a pipelined reader sends at once, so the channel is not paid for on every row
+const PIPELINE_BATCH: usize = 256;
+/// Bytes of the input a thread of --parse-threads parses at once, a part
+/// A part is read at most this far past its end, a row running further is parsed again on one thread
+const PARSE_PART: u64 = 1 << 18;
+/// Ids claimed with --id-index-dir before they are written to a file of the index
+const ID_INDEX_FLUSH: usize = 1 << 16;
+/// Most memory reserved up front from capacity hints for stored transactions
+/// A larger hint only costs the rehashing it was meant to save, not an allocation out of proportion
+const RESERVED_BYTES: u64 = 256 << 20;
+/// As many accounts as there are client ids
+const MAX_CLIENTS: usize = ClientId::MAX as usize + 1;
 
-type ClientId = u16;
-type TransactionId = u32;
+pub(crate) type ClientId = u16;
+pub(crate) type TransactionId = u32;
 
 /// This is the transaction engine
-pub(crate) struct Engine {
-    clients: HashMap<ClientId, Account>,
+pub struct Engine {
+    config: Config,
+    clients: IdMap<ClientId, Account>,
+    /// Client that originated each applied deposit/withdrawal/escrow/adjustment
+    /// Used to enforce globally unique transaction ids and to reject dispute, resolve and chargeback rows
+    /// that reference another client's transaction
+    /// Ids are kept in containers of 65536 like a roaring bitmap: claiming 100M ids handed out in sequence grew the
+    /// resident memory of a process by 213MB, and inserting them in a hash map by 1.21GB
+    transaction_owners: IdOwners,
+    /// Owners of transaction ids kept in files of --id-index-dir instead of transaction_owners
+    id_filter: Option<FilteredOwners>,
+    /// Sidecar receiving rejected rows, opened by process when configured
+    rejects: Option<RejectsWriter>,
+    /// Replayed dispute, resolve and chargeback rows that were ignored
+    duplicates_ignored: u64,
+    /// Manual balance corrections that were applied, reported apart so they are easy to review
+    adjustments_applied: u64,
+    /// Stale disputes resolved by the engine at the end of the run
+    disputes_auto_resolved: u64,
+    /// Interest postings made to accounts
+    interest_postings: u64,
+    /// Highest deposit or withdrawal id seen, tracked when ids must increase
+    highest_transaction_id: Option<TransactionId>,
+    /// Rows that could not be read and were skipped
+    malformed_rows_skipped: u64,
+    /// Rows read before the end of the input or its halt row, including rejected and skipped ones
+    records_processed: u64,
+    /// Rows that changed the state of the engine
+    records_applied: u64,
+    /// Rejected and skipped rows by the reason of their error
+    rejections: BTreeMap<&'static str, u64>,
+    /// Skipped rows of an unknown type, by type
+    unknown_actions: BTreeMap<String, u64>,
+    /// Header rows repeated inside the input, left by concatenating files, which are skipped
+    repeated_headers: u64,
+    /// Record of the halt row, once seen
+    halted_at: Option<u64>,
+    /// Rows after the halt row, which are not applied
+    records_after_halt: u64,
+    /// Set once process has run, accounts of another input must not be mixed in without a reset
+    has_processed: bool,
+    /// Workers applying the rows while the input is read, when config asks for more than one
+    shards: Option<Shards>,
+    /// Rows read so far by client with --group-by-client, applied once the whole input is read
+    grouped: Option<Grouped>,
+    /// Accounts of a worker of --shared-accounts, shared with the other workers
+    /// Those a row names are moved into clients while it is applied
+    accounts: Option<SharedAccounts<Account>>,
+    /// File receiving the oldest stored transactions once they take more memory than config allows
+    spill: Option<Spill>,
+    /// Transactions moved to the spill file, counting those moved again after being read back
+    spilled: u64,
+    /// Rows that read their transaction back from the spill file
+    spill_reads: u64,
+    /// Size of the spill file, or of those of every worker once they are merged
+    spill_bytes: u64,
+    /// Where the run restored from a checkpoint stood, taken up by the next read of the input
+    resumed: Option<Progress>,
+    /// Stored transactions still to reserve in the accounts opened next
+    reservation: Reservation,
+    /// Rows applied since memory was last estimated
+    since_memory_check: usize,
+    /// Highest memory estimated during the run, in bytes
+    peak_memory: u64,
+    /// State after the last row of an --incremental run, saved once the run succeeds
+    saved_state: Option<SavedState>,
 }
-impl Engine {
-    pub(crate) fn new() -> Self {
-        Self {
-            clients: HashMap::new(),
-        }
+
+/// State of the engine after the last row read, with where the input ends
+struct SavedState {
+    byte: u64,
+    record_index: u64,
+    state: Vec<u8>,
+}
+
+impl SavedState {
+    /// Saves the state in dir with the fingerprint of the input, for the next --incremental run
+    fn write(self, dir: &Path, fingerprint: InputFingerprint) -> Result<(), CustomError> {
+        let mut state = fingerprint.encode().to_vec();
+        state.extend_from_slice(&self.state);
+        checkpoint::replace(dir, self.record_index, &state)
     }
-    pub(crate) async fn process(
-        &mut self,
-        reader: &mut Reader,
-        writer: &mut Writer,
-    ) -> Result<(), CustomError> {
-        while let Some(value) = reader.get_inner().records().next().await {
-            let transaction = Transaction::from_record(value.unwrap()).unwrap();
-            let client_id = transaction.get_client_id();
-            let transaction_id = transaction.transaction_id;
-            let account = self.clients.entry(client_id);
-            match account {
-                std::collections::hash_map::Entry::Vacant(vacant) => {
-                    let mut new_account = Account::new(client_id);
-                    //todo handle error here, should we stop or now depending on the error types
-                    if let Err(err) = new_account.handle_transaction(transaction) {
-                        match err {
-                            CustomError::UndefinedAction
-                            | CustomError::DecimalParseError(_)
-                            | CustomError::IntParseError(_)
-                            | CustomError::FileOpenError(_) => return Err(err),
-                            CustomError::AccountBalanceNotEnough
-                            | CustomError::LockedAccount
-                            | CustomError::UndefinedBehaviour
-                            | CustomError::NonExistingTransactionId
-                            | CustomError::DuplicatedTransactionId
-                            | CustomError::NotUnderDispute => {
-                                //simply log error and continue
-                                warn!("Client id: {}, with transaction_id: {} had following error: {}", client_id, transaction_id, err);
-                                continue;
-                            }
-                        }
-                    }
-                    vacant.insert(new_account);
-                }
-                std::collections::hash_map::Entry::Occupied(mut entry) => {
-                    if let Err(err) = entry.get_mut().handle_transaction(transaction) {
-                        match err {
-                            CustomError::UndefinedAction
-                            | CustomError::DecimalParseError(_)
-                            | CustomError::IntParseError(_)
-                            | CustomError::FileOpenError(_) => return Err(err),
-                            CustomError::AccountBalanceNotEnough
-                            | CustomError::LockedAccount
-                            | CustomError::UndefinedBehaviour
-                            | CustomError::NonExistingTransactionId
-                            | CustomError::DuplicatedTransactionId
-                            | CustomError::NotUnderDispute => {
-                                //simply log error and continue
-                                warn!("Client id: {}, with transaction_id: {} had following error: {}", client_id, transaction_id, err);
-                                continue;
-                            }
-                        }
-                    }
-                }
-            }
-        }
+}
 
-        //now updating state is done
-        // writer header
-        let header = format!("client,available,held,total,locked\n");
-        writer
-            .get_inner()
-            .write_all(header.as_bytes())
-            .await
-            .unwrap();
-        //write out to stdout
-        for (client_id, account) in &self.clients {
-            let output = format!(
-                "{},{},{},{},{}\n",
-                client_id, account.available, account.held, account.total, account.is_locked
+/// The state the last --incremental run saved in dir, with its path and the fingerprint of its input
+/// None when there is none or it is damaged, which leave the input to be processed from the start
+fn last_state(dir: &Path) -> Result<Option<(PathBuf, InputFingerprint, Vec<u8>)>, CustomError> {
+    let path = match checkpoint::list(dir) {
+        Ok(paths) if !paths.is_empty() => paths[0].clone(),
+        _ => {
+            info!(
+                "No state in {}, the input is processed from the start",
+                dir.display()
             );
-            writer
-                .get_inner()
-                .write_all(output.as_bytes())
-                .await
-                .unwrap();
+            return Ok(None);
+        }
+    };
+    match checkpoint::read(&path)?.filter(|state| state.len() >= FINGERPRINT_BYTES) {
+        Some(mut state) => {
+            let fingerprint =
+                InputFingerprint::decode(state[..FINGERPRINT_BYTES].try_into().unwrap());
+            state.drain(..FINGERPRINT_BYTES);
+            Ok(Some((path, fingerprint, state)))
+        }
+        None => {
+            warn!(
+                "State {} is damaged, the input is processed from the start",
+                path.display()
+            );
+            Ok(None)
         }
-
-        Ok(())
     }
 }
 
-#[derive(Debug)]
-struct Transaction {
-    action_type: Action,
-    client_id: ClientId,
-    transaction_id: TransactionId,
-    decimal: Option<Decimal>,
-    is_under_dispute: bool,
-}
+/// Bytes compared at the start of the input and before where the last run stopped
+const OVERLAP: u64 = 4096;
 
-#[derive(Copy, Clone, Debug)]
-enum Action {
-    Deposit,
-    Withdrawal,
-    Dispute,
-    Resolve,
-    Chargeback,
+/// What the input held when its state was saved, so a run can tell the rows it continues after are unchanged
+/// Only the start of the input and the bytes just before where the last run stopped are compared, which a rotated
+/// file or one rewritten as it is appended to change
+#[derive(Debug, PartialEq, Eq)]
+struct InputFingerprint {
+    /// Where the last run stopped
+    byte: u64,
+    /// Checksum of the bytes up to OVERLAP at the start of the input
+    head: u64,
+    /// Checksum of the bytes up to OVERLAP before byte
+    tail: u64,
 }
 
-impl FromStr for Action {
-    type Err = CustomError;
+/// Length of an encoded fingerprint
+const FINGERPRINT_BYTES: usize = 24;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "deposit" => Ok(Action::Deposit),
-            "withdrawal" => Ok(Action::Withdrawal),
-            "dispute" => Ok(Action::Dispute),
-            "resolve" => Ok(Action::Resolve),
-            "chargeback" => Ok(Action::Chargeback),
-            _ => Err(CustomError::UndefinedAction),
-        }
+impl InputFingerprint {
+    /// Fingerprint of the input as it is now, None when it ends before byte
+    async fn read<R>(reader: &mut Reader<R>, byte: u64) -> Result<Option<Self>, CustomError>
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send,
+    {
+        let len = byte.min(OVERLAP);
+        let head = reader.read_raw(0, len).await?;
+        let tail = reader.read_raw(byte - len, len).await?;
+        Ok(Self::from_bytes(byte, &head, &tail))
     }
-}
 
-impl Transaction {
-    fn from_record(record: StringRecord) -> Result<Self, CustomError> {
-        let action_type = Action::from_str(record.get(0).unwrap())?;
-        let client_id = ClientId::from_str(record.get(1).unwrap())?;
-        let transaction_id = TransactionId::from_str(record.get(2).unwrap())?;
-        match action_type {
-            Action::Deposit | Action::Withdrawal => {
-                let decimal = Decimal::from_str(record.get(3).unwrap())?;
-                Ok(Transaction {
-                    action_type,
-                    client_id,
-                    transaction_id,
-                    decimal: Some(decimal),
-                    is_under_dispute: false,
-                })
-            }
-            Action::Dispute | Action::Resolve | Action::Chargeback => Ok(Transaction {
-                action_type,
-                client_id,
-                transaction_id,
-                decimal: None,
-                is_under_dispute: false,
-            }),
+    /// Fingerprint of an input read with blocking calls, like read
+    fn read_blocking<R: Read + Seek>(
+        reader: &mut BlockingReader<R>,
+        byte: u64,
+    ) -> Result<Option<Self>, CustomError> {
+        let len = byte.min(OVERLAP);
+        let head = reader.read_raw(0, len)?;
+        let tail = reader.read_raw(byte - len, len)?;
+        Ok(Self::from_bytes(byte, &head, &tail))
+    }
+
+    /// Fingerprint of the bytes read at the start of the input and before byte, None when the latter are short
+    fn from_bytes(byte: u64, head: &[u8], tail: &[u8]) -> Option<Self> {
+        if tail.len() as u64 != byte.min(OVERLAP) {
+            return None;
         }
+        Some(Self {
+            byte,
+            head: checkpoint::checksum(head),
+            tail: checkpoint::checksum(tail),
+        })
     }
 
-    fn get_action_type(&self) -> Action {
-        self.action_type
+    fn encode(&self) -> [u8; FINGERPRINT_BYTES] {
+        let mut bytes = [0; FINGERPRINT_BYTES];
+        for (chunk, value) in bytes
+            .chunks_exact_mut(8)
+            .zip([self.byte, self.head, self.tail])
+        {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        bytes
     }
 
-    fn get_client_id(&self) -> ClientId {
-        self.client_id
+    fn decode(bytes: &[u8; FINGERPRINT_BYTES]) -> Self {
+        let value = |index: usize| u64::from_le_bytes(bytes[index * 8..][..8].try_into().unwrap());
+        Self {
+            byte: value(0),
+            head: value(1),
+            tail: value(2),
+        }
     }
+}
 
-    /// Only for testing and debugging purpose
-    fn _new(
-        action_type: Action,
-        client_id: ClientId,
-        transaction_id: TransactionId,
-        decimal: Option<Decimal>,
-        is_under_dispute: bool,
-    ) -> Self {
+/// Room for stored transactions handed to accounts as they open, from the capacity hints of config
+#[derive(Debug, Clone, Copy, Default)]
+struct Reservation {
+    /// Transactions reserved by each account
+    per_account: usize,
+    /// Transactions that may still be reserved, across every account
+    remaining: usize,
+}
+
+impl Reservation {
+    fn new(config: &Config) -> Self {
+        let per_account = config.expected_transactions_per_client.unwrap_or_default();
+        let clients = config
+            .expected_clients
+            .unwrap_or(MAX_CLIENTS)
+            .min(MAX_CLIENTS);
+        let limit = reserved_entries(config, STORED_BYTES);
         Self {
-            action_type,
-            client_id,
-            transaction_id,
-            decimal,
-            is_under_dispute,
+            per_account: per_account.min(limit),
+            remaining: per_account.saturating_mul(clients).min(limit),
         }
     }
+
+    /// Transactions the next account reserves
+    fn take(&mut self) -> usize {
+        let capacity = self.per_account.min(self.remaining);
+        self.remaining -= capacity;
+        capacity
+    }
 }
 
-/// Account represents status of individual client
-#[derive(Debug)]
-struct Account {
-    _client_id: ClientId,
-    /// Transactions only keep following actions: Deposit, Withdrawal, Dispute
-    /// Since Resolve and Chargeback cannot be overturned
-    /// Transaction number is unique
-    transactions: HashMap<TransactionId, Transaction>,
-    /// is_locked is set to true only if chargeback takes place
-    is_locked: bool,
-    /// The total funds that are available for trading, staking, withdrawal, etc. This should be equal to the (total - held)
-    available: Decimal,
-    /// The total funds that are held for dispute. This should be equal to (total - available)
-    held: Decimal,
-    /// The total funds that are available or held. This should be equal to (available - held)
-    total: Decimal,
+/// Bytes of a hash table with room for capacity entries of type T, as hashbrown lays it out:
+/// a power of two of buckets kept at most 7/8 full, each with a control byte, and a group of spare control bytes
+pub(crate) fn table_bytes<T>(capacity: usize) -> u64 {
+    if capacity == 0 {
+        return 0;
+    }
+    let buckets = match capacity {
+        0..=7 => capacity + 1,
+        _ => capacity / 7 * 8,
+    };
+    (buckets * (std::mem::size_of::<T>() + 1) + 16) as u64
 }
 
-impl Account {
-    /// Intitializes a new account
-    fn new(client_id: ClientId) -> Self {
+/// Capacity a hash table reaches as it grows to hold len entries, doubling its buckets each time it is 7/8 full
+fn grown_capacity(len: usize) -> usize {
+    match len {
+        0 => 0,
+        1..=3 => 3,
+        4..=7 => 7,
+        _ => (len * 8).div_ceil(7).next_power_of_two() / 8 * 7,
+    }
+}
+
+/// Entries of the given size that fit what may be reserved up front, within the memory limit when one is set
+fn reserved_entries(config: &Config, entry_bytes: u64) -> usize {
+    let bytes = match config.max_memory {
+        0 => RESERVED_BYTES,
+        //a table rounds its room up to as much as twice, and the rows need the rest of the limit
+        max_memory => RESERVED_BYTES.min(max_memory / 4),
+    };
+    (bytes / entry_bytes) as usize
+}
+
+/// Where a run stands between two rows, beyond the accounts and counters
+#[derive(Debug, Clone, Copy)]
+struct Progress {
+    /// Records read so far
+    record_index: u64,
+    /// Latest timestamp read so far
+    latest_timestamp: Option<u64>,
+}
+
+/// Where reading the rows of the input stands, between two rows
+struct RowsRead {
+    chronology: Chronology,
+    /// 1-based index of the current record, not counting the header
+    record_index: u64,
+    /// Latest timestamp of the input, where a duration window for stale disputes ends
+    latest_timestamp: Option<u64>,
+    /// Position of the halt row, rows after it come from a corrupt concatenation
+    halted_at: Option<u64>,
+    records_after_halt: u64,
+    checkpointed_at: u64,
+    /// Start of a last row still being appended, left for the next --incremental run
+    left_at: Option<csv_async::Position>,
+}
+
+/// What the engine did with a row it took
+enum Taken {
+    /// The row is done with, the next one can be taken
+    Next,
+    /// The row goes to the worker of its client
+    Dispatch(Transaction),
+    /// No more rows are taken
+    Stop,
+}
+impl Engine {
+    pub fn new(config: Config) -> Self {
         Self {
-            _client_id: client_id,
-            transactions: HashMap::new(),
-            is_locked: false,
-            available: Decimal::new(0, PRECISION),
-            held: Decimal::new(0, PRECISION),
-            total: Decimal::new(0, PRECISION),
+            config,
+            clients: IdMap::default(),
+            transaction_owners: IdOwners::default(),
+            id_filter: None,
+            rejects: None,
+            duplicates_ignored: 0,
+            adjustments_applied: 0,
+            disputes_auto_resolved: 0,
+            interest_postings: 0,
+            highest_transaction_id: None,
+            malformed_rows_skipped: 0,
+            records_processed: 0,
+            records_applied: 0,
+            rejections: BTreeMap::new(),
+            unknown_actions: BTreeMap::new(),
+            repeated_headers: 0,
+            halted_at: None,
+            records_after_halt: 0,
+            has_processed: false,
+            shards: None,
+            grouped: None,
+            accounts: None,
+            spill: None,
+            spilled: 0,
+            spill_reads: 0,
+            spill_bytes: 0,
+            resumed: None,
+            reservation: Reservation::default(),
+            since_memory_check: 0,
+            peak_memory: 0,
+            saved_state: None,
         }
     }
 
-    /// Takes transaction as input and will update it's status
-    /// This method will return Err if and only if itself is locked or account balance is not enough
-    /// For other unwanted situations such as transaction_id for dispute is missing,
-    /// it will continue while logging the incident
-    fn handle_transaction(&mut self, transaction: Transaction) -> Result<(), CustomError> {
-        //first check if this account is not locked,
-        //if locked, return error
-        if self.is_locked {
-            return Err(CustomError::LockedAccount);
+    /// Makes room for the clients and transactions config expects, before the engine applies any row
+    fn reserve(&mut self) {
+        let clients = self
+            .config
+            .expected_clients
+            .unwrap_or_default()
+            .min(MAX_CLIENTS);
+        //a resumed run already holds some of them
+        self.clients
+            .reserve(clients.saturating_sub(self.clients.len()));
+        self.reservation = Reservation::new(&self.config);
+    }
+
+    /// Drops the accounts and counters of the previous input, so the engine can process another one
+    /// The config is kept
+    pub fn reset(&mut self) {
+        *self = Self::new(self.config.clone());
+    }
+
+    /// Marks the engine as processing an input, refusing a second one until reset
+    fn take_input(&mut self) -> Result<(), CustomError> {
+        if self.has_processed {
+            return Err(CustomError::EngineReused);
         }
-        match transaction.get_action_type() {
-            Action::Deposit => {
-                //check if transaction number is unique,
-                if self.transactions.contains_key(&transaction.transaction_id) {
-                    return Err(CustomError::DuplicatedTransactionId);
-                }
-                self.available += transaction.decimal.unwrap();
-                self.total += transaction.decimal.unwrap();
-                self.transactions
-                    .insert(transaction.transaction_id, transaction);
-            }
-            Action::Withdrawal => {
-                //check if transaction number is unique,
-                if self.transactions.contains_key(&transaction.transaction_id) {
-                    return Err(CustomError::DuplicatedTransactionId);
-                }
-                //withdrawal should fail it total amount is not enough
-                if self.available < transaction.decimal.unwrap() {
-                    return Err(CustomError::AccountBalanceNotEnough);
-                }
-                self.available -= transaction.decimal.unwrap();
-                self.total -= transaction.decimal.unwrap();
-                self.transactions
-                    .insert(transaction.transaction_id, transaction);
+        self.has_processed = true;
+        Ok(())
+    }
+
+    /// Reads the whole input and writes the accounts
+    /// An engine processes a single input, call reset before processing another one
+    /// Several inputs written to the same writer share one output header
+    pub async fn process<R, W>(
+        &mut self,
+        reader: &mut Reader<R>,
+        writer: &mut Writer<W>,
+    ) -> Result<(), CustomError>
+    where
+        R: AsyncRead + Unpin + Send,
+        W: AsyncWrite + Unpin,
+    {
+        self.take_input()?;
+        let result = self.read_records(reader).await;
+        self.finish(result, writer).await
+    }
+
+    /// Processes only the rows appended since the last run, continuing from the state it saved in
+    /// config.state_dir, then saves the state for the next run once the accounts are written
+    /// An input that no longer holds the bytes the last run read, rotated or rewritten, is processed from the start
+    pub(crate) async fn process_incremental<R, W>(
+        &mut self,
+        reader: &mut Reader<R>,
+        writer: &mut Writer<W>,
+    ) -> Result<(), CustomError>
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send,
+        W: AsyncWrite + Unpin,
+    {
+        let dir = self.check_incremental()?;
+        self.continue_from(&dir, reader).await?;
+        self.process(reader, writer).await?;
+        if let Some(saved) = self.saved_state.take() {
+            let fingerprint = InputFingerprint::read(reader, saved.byte)
+                .await?
+                .expect("the input was read up to where its state was saved");
+            saved.write(&dir, fingerprint)?;
+        }
+        Ok(())
+    }
+
+    /// The directory of --incremental, once its options are checked
+    fn check_incremental(&self) -> Result<PathBuf, CustomError> {
+        let dir = self
+            .config
+            .state_dir
+            .clone()
+            .expect("--incremental requires --state-dir");
+        self.check_checkpoint_options()?;
+        //the reader is ahead of the rows applied, so no position of it matches the state
+        for (feature, is_used) in [
+            ("--pipeline", self.config.pipeline.is_some()),
+            ("--parse-threads", self.config.parse_threads > 1),
+        ] {
+            if is_used {
+                return Err(CustomError::UnsupportedWithCheckpoints { feature });
             }
-            Action::Dispute => {
-                let original_transaction = self.transactions.get_mut(&transaction.transaction_id);
-                match original_transaction {
-                    None => {
-                        //this dispute is erroneous
-                        return Err(CustomError::NonExistingTransactionId);
-                    }
-                    Some(original_transaction) => {
-                        //check if original_transaction is type deposit, if not disregard and return error
-                        match original_transaction.action_type {
-                            Action::Deposit => {
-                                //if deposit,
-                                self.available -= original_transaction.decimal.unwrap();
-                                self.held += original_transaction.decimal.unwrap();
-                                original_transaction.is_under_dispute = true;
-                            }
-                            _ => {
-                                return Err(CustomError::UndefinedBehaviour);
-                            }
-                        }
-                    }
-                }
+        }
+        Ok(dir)
+    }
+
+    /// Restores the state the last --incremental run saved in dir and moves the reader past the rows it covers,
+    /// unless there is none or the input changed since, which leave the engine to process it from the start
+    async fn continue_from<R>(
+        &mut self,
+        dir: &Path,
+        reader: &mut Reader<R>,
+    ) -> Result<(), CustomError>
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send,
+    {
+        let Some((path, fingerprint, state)) = last_state(dir)? else {
+            return Ok(());
+        };
+        let input = InputFingerprint::read(reader, fingerprint.byte).await?;
+        if let Some([byte, line, record]) = self.continue_with(&path, fingerprint, input, &state) {
+            reader.seek(byte, line, record).await?;
+        }
+        Ok(())
+    }
+
+    /// Restores the state saved in path when the input still holds the rows it covers, as input tells, returning
+    /// the position of the reader after them
+    fn continue_with(
+        &mut self,
+        path: &Path,
+        fingerprint: InputFingerprint,
+        input: Option<InputFingerprint>,
+        state: &[u8],
+    ) -> Option<[u64; 3]> {
+        if input != Some(fingerprint) {
+            warn!(
+                "The input was rotated or rewritten since the run that saved {}, it is processed from the start",
+                path.display()
+            );
+            return None;
+        }
+        let mut resumed = Engine::new(self.config.clone());
+        let position = match resumed.decode_state(state) {
+            Ok(position) => position,
+            Err(_) => {
+                warn!(
+                    "State {} is damaged, the input is processed from the start",
+                    path.display()
+                );
+                return None;
             }
+        };
+        *self = resumed;
+        info!(
+            "Continued from {} at record {}",
+            path.display(),
+            self.resumed.map_or(0, |progress| progress.record_index)
+        );
+        Some(position)
+    }
 
-            Action::Resolve => {
-                let original_transaction = self.transactions.get_mut(&transaction.transaction_id);
-                match original_transaction {
-                    None => {
-                        //this dispute is erroneous
-                        return Err(CustomError::NonExistingTransactionId);
-                    }
-                    Some(original_transaction) => {
-                        //check if original_transaction is type deposit, if not, print error
-                        match original_transaction.action_type {
-                            Action::Deposit => {
-                                //if deposit,
-                                if original_transaction.is_under_dispute {
-                                    self.available += original_transaction.decimal.unwrap();
-                                    self.held -= original_transaction.decimal.unwrap();
-                                    original_transaction.is_under_dispute = false;
-                                //no longer under dispute
-                                } else {
-                                    //not under dispute, return err
-                                    return Err(CustomError::NotUnderDispute);
-                                }
-                            }
-                            _ => {
-                                return Err(CustomError::UndefinedBehaviour);
-                            }
-                        }
-                    }
-                }
+    /// Reads the input like process, parsing rows on a task of its own while the engine applies the rows
+    /// before them
+    pub async fn process_pipelined<R, W>(
+        &mut self,
+        reader: Reader<R>,
+        writer: &mut Writer<W>,
+    ) -> Result<(), CustomError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin,
+    {
+        self.take_input()?;
+        let result = self.read_pipelined(reader).await;
+        self.finish(result, writer).await
+    }
+
+    /// Reads the input file like process, parsing parts of it on config.parse_threads threads at the same time
+    /// The rows are applied in input order, so the accounts are those process would write
+    pub(crate) async fn process_split<W>(
+        &mut self,
+        path: &Path,
+        writer: &mut Writer<W>,
+    ) -> Result<(), CustomError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.take_input()?;
+        let result = self.read_split(path, PARSE_PART).await;
+        self.finish(result, writer).await
+    }
+
+    /// Reads inputs that share no client at the same time, each on its own task with its own engine,
+    /// then writes the accounts of all of them
+    /// A client found in two inputs stops the run, as its rows would have been applied apart
+    pub(crate) async fn process_files<R, W>(
+        &mut self,
+        readers: Vec<Reader<R>>,
+        writer: &mut Writer<W>,
+    ) -> Result<(), CustomError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin,
+    {
+        self.take_input()?;
+        let result = self.read_files(readers).await;
+        self.finish(result, writer).await
+    }
+
+    /// Writes the accounts once the input is read, or what the policy asks for when reading failed
+    async fn finish<W>(
+        &mut self,
+        result: Result<(), CustomError>,
+        writer: &mut Writer<W>,
+    ) -> Result<(), CustomError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let err = match result {
+            Ok(()) => return self.write_output(writer, "").await.map(|_| ()),
+            Err(err) => err,
+        };
+        //the workers apply what they were sent, so their accounts are part of a partial output
+        //the first error is the one reported
+        let _ = self.join_shards().await;
+        if let Some(trailer) = self.partial_output(&err) {
+            self.write_output(writer, &trailer).await?;
+        }
+        Err(err)
+    }
+
+    /// What --partial-output writes after the accounts of a run that stopped on err, None when they are not written
+    fn partial_output(&mut self, err: &CustomError) -> Option<String> {
+        //rows held back by --group-by-client are part of a partial output too
+        let _ = self.apply_grouped();
+        //the run failed midway, the accounts only reflect the rows before the failure
+        match self.config.partial_output {
+            PartialOutput::Never => None,
+            PartialOutput::Write => Some(String::new()),
+            PartialOutput::Mark => Some(format!("# partial output, the run stopped: {}\n", err)),
+        }
+    }
+
+    /// Reads every input with an engine of its own, then takes their accounts and counters
+    /// Returns the first fatal error an input stopped on
+    async fn read_files<R>(&mut self, readers: Vec<Reader<R>>) -> Result<(), CustomError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        //these need rows of every input, which no engine sees together, or would write to the same file
+        for (feature, is_used) in [
+            ("--rejects", self.config.rejects_path.is_some()),
+            ("--enforce-chronology", self.config.enforce_chronology),
+            (
+                "--require-monotonic-tx-ids",
+                self.config.require_monotonic_tx_ids,
+            ),
+            ("--require-halt", self.config.require_halt),
+            (
+                "--auto-resolve-after",
+                self.config.auto_resolve_after.is_some(),
+            ),
+            ("--checkpoint-every", self.config.checkpoint_every.is_some()),
+            ("--resume-from", self.config.resume_from.is_some()),
+            ("--incremental", self.config.state_dir.is_some()),
+            ("--pipeline", self.config.pipeline.is_some()),
+            ("--id-index-dir", self.config.id_filter.is_some()),
+            ("--parse-threads", self.config.parse_threads > 1),
+        ] {
+            if is_used {
+                return Err(CustomError::UnsupportedWithParallelFiles { feature });
             }
-            Action::Chargeback => {
-                let original_transaction = self.transactions.get_mut(&transaction.transaction_id);
-                match original_transaction {
-                    None => {
-                        //this dispute is erroneous
-                        return Err(CustomError::NonExistingTransactionId);
-                    }
-                    Some(original_transaction) => {
-                        //check if original_transaction is type deposit, if not, print error
-                        match original_transaction.action_type {
-                            Action::Deposit => {
-                                //if deposit,
-                                if original_transaction.is_under_dispute {
-                                    self.held -= original_transaction.decimal.unwrap();
-                                    self.total -= original_transaction.decimal.unwrap();
-                                    original_transaction.is_under_dispute = false;
-                                    self.is_locked = true;
-                                //no longer under dispute
-                                } else {
-                                    //not under dispute, print o
-                                    return Err(CustomError::NotUnderDispute);
-                                }
-                            }
-                            _ => {
-                                return Err(CustomError::UndefinedBehaviour);
-                            }
-                        }
-                    }
+        }
+        if self.config.shared_accounts {
+            return self.read_shared(readers).await;
+        }
+        //each input gets an even share of the memory for stored transactions, and of what is expected
+        let mut config = self.config.clone();
+        config.max_memory /= readers.len().max(1) as u64;
+        config.expected_clients = config
+            .expected_clients
+            .map(|clients| clients.div_ceil(readers.len().max(1)));
+        let inputs: Vec<JoinHandle<(Engine, Result<(), CustomError>)>> = readers
+            .into_iter()
+            .map(|mut reader| {
+                let mut engine = Engine::new(config.clone());
+                tokio::spawn(async move {
+                    let result = engine.read_records(&mut reader).await;
+                    (engine, result)
+                })
+            })
+            .collect();
+        let mut result = Ok(());
+        //1-based input each client was read from
+        let mut owners: HashMap<ClientId, usize> = HashMap::new();
+        for (index, input) in inputs.into_iter().enumerate() {
+            let (engine, input_result) = input
+                .await
+                .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()));
+            result = result.and(input_result);
+            for &client in engine.clients.keys() {
+                if let Some(first) = owners.insert(client, index + 1) {
+                    result = result.and(Err(CustomError::OverlappingInputs {
+                        client,
+                        first,
+                        second: index + 1,
+                    }));
                 }
             }
+            self.merge_input(engine);
         }
-        //sanity check
-        // println!(
-        //     "client_id: {}, total: {}, available: {}, held: {}",
-        //     self._client_id, self.total, self.available, self.held
-        // );
-        assert_eq!(self.total, self.available + self.held);
-        Ok(())
+        result
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Reads every input at the same time, their rows going to workers shared by all of them, which apply each row
+    /// with the accounts it names locked
+    /// Returns the first fatal error a worker stopped on, or else the first one an input stopped on
+    async fn read_shared<R>(&mut self, readers: Vec<Reader<R>>) -> Result<(), CustomError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        for (feature, is_used) in [
+            //these walk every account, which no worker holds at once
+            ("--accrue-interest", self.config.accrue_interest.is_some()),
+            ("--spill-dir", self.config.spill_dir.is_some()),
+            ("--max-memory", self.config.max_memory > 0),
+            //records are counted by each input, so a window of them spans nothing shared
+            (
+                "--dispute-window-records",
+                matches!(self.config.dispute_window, Some(Window::Records(_))),
+            ),
+            //the sender is only known once the recipient is locked, too late to lock it in order
+            (
+                "--transfer-chargeback-refunds-sender",
+                self.config.transfer_chargeback_refunds_sender,
+            ),
+            ("--allow-tx-id-reuse", self.config.allow_tx_id_reuse),
+        ] {
+            if is_used {
+                return Err(CustomError::UnsupportedWithSharedAccounts { feature });
+            }
+        }
+        let accounts = SharedAccounts::new(ACCOUNT_SHARDS);
+        let shards = Shards::spawn(&self.config, &self.rejects, Some(&accounts))?;
+        let inputs: Vec<JoinHandle<(Engine, Result<(), CustomError>)>> = readers
+            .into_iter()
+            .map(|mut reader| {
+                let mut engine = Engine::new(self.config.clone());
+                engine.shards = Some(shards.handle());
+                tokio::spawn(async move {
+                    let result = engine.read_records(&mut reader).await;
+                    (engine, result)
+                })
+            })
+            .collect();
+        self.shards = Some(shards);
+        let mut result = Ok(());
+        for input in inputs {
+            let (engine, input_result) = input
+                .await
+                .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()));
+            result = result.and(input_result);
+            self.merge_input(engine);
+        }
+        //the workers stop once every input dropped its senders, an input stopped by a worker only says so
+        let joined = self.join_shards().await;
+        self.clients.extend(accounts.take());
+        //accounts only grow while rows are applied, so what they take at the end is about their peak
+        self.peak_memory = self.memory_estimate();
+        joined.and(result)
+    }
 
-    /// Test case for only deposit
-    #[test]
-    fn test_one_deposit() {
-        let client_id = 1;
-        let mut account = Account::new(client_id);
-        let transaction = Transaction::_new(
-            Action::Deposit,
-            client_id,
-            1,
-            Some(Decimal::new(1, PRECISION)),
-            false,
-        );
-        account.handle_transaction(transaction).unwrap();
+    /// Takes the counters of the engine that read an input, then its accounts
+    fn merge_input(&mut self, engine: Engine) {
+        self.records_processed += engine.records_processed;
+        self.malformed_rows_skipped += engine.malformed_rows_skipped;
+        self.repeated_headers += engine.repeated_headers;
+        //the record of a halt row is counted within its own input
+        self.halted_at = self.halted_at.or(engine.halted_at);
+        self.records_after_halt += engine.records_after_halt;
+        for (action, count) in &engine.unknown_actions {
+            *self.unknown_actions.entry(action.clone()).or_default() += count;
+        }
+        self.merge(engine);
+    }
 
-        assert_eq!(account.total, Decimal::new(1, PRECISION));
-        assert_eq!(account.available, Decimal::new(1, PRECISION));
-        assert_eq!(account.held, Decimal::new(0, PRECISION));
+    /// Reads and applies every record, then settles what is left at the end of the input
+    async fn read_records<R>(&mut self, reader: &mut Reader<R>) -> Result<(), CustomError>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let headers = reader.get_inner().headers().await?.clone();
+        let rows = self.start(headers)?;
+        let columns = rows.columns.clone();
+        let batch = VecDeque::with_capacity(self.config.batch_size.max(1));
+        self.read_rows(
+            Source::Direct {
+                reader,
+                rows,
+                batch,
+                batch_size: self.config.batch_size.max(1),
+                checkpoint_every: self.config.checkpoint_every,
+            },
+            &columns,
+        )
+        .await
     }
 
-    /// Test case for duplicated deposit
-    #[test]
-    fn test_duplicated_deposit() {
-        let client_id = 1;
-        let mut account = Account::new(client_id);
-        let transaction1 = Transaction::_new(
-            Action::Deposit,
-            client_id,
-            1,
+    /// Reads and parses every record on a task of its own while they are applied, at most
+    /// config.pipeline batches ahead, then settles what is left at the end of the input
+    async fn read_pipelined<R>(&mut self, mut reader: Reader<R>) -> Result<(), CustomError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        //the reader is ahead of the rows applied, so no position of it matches the state
+        if self.config.checkpoint_every.is_some() {
+            return Err(CustomError::UnsupportedWithCheckpoints {
+                feature: "--pipeline",
+            });
+        }
+        let headers = reader.get_inner().headers().await?.clone();
+        let rows = self.start(headers)?;
+        let columns = rows.columns.clone();
+        let (sender, receiver) = mpsc::channel(self.config.pipeline.unwrap_or(1).max(1));
+        let task = tokio::spawn(rows.send_all(reader, sender));
+        let result = self
+            .read_rows::<R>(
+                Source::Piped {
+                    receiver,
+                    batch: Vec::new().into_iter(),
+                },
+                &columns,
+            )
+            .await;
+        //rows still being read are not wanted once the engine stopped
+        if result.is_err() {
+            task.abort();
+        }
+        //a reader that panicked closed the channel early, so the rows that were applied are not all of them
+        if let Err(err) = task.await {
+            if err.is_panic() {
+                std::panic::resume_unwind(err.into_panic());
+            }
+        }
+        result
+    }
+
+    /// Reads the file in parts of about part bytes, parsed on config.parse_threads threads, and applies their rows
+    /// in input order, then settles what is left at the end of the input
+    async fn read_split(&mut self, path: &Path, part: u64) -> Result<(), CustomError> {
+        let (split, columns) = self.split(path, part)?;
+        let threads = self.config.parse_threads.max(1);
+        //each thread keeps a part it parsed waiting, so a single one waits for the engine
+        let (sender, receiver) = mpsc::channel(1);
+        let parser = std::thread::spawn(move || split.send_all(threads, sender));
+        let result = self
+            .read_rows::<&[u8]>(
+                Source::Piped {
+                    receiver,
+                    batch: Vec::new().into_iter(),
+                },
+                &columns,
+            )
+            .await;
+        result.and(Split::join(parser))
+    }
+
+    /// Reads the header of the file and where its parts of about part bytes start, for read_split
+    fn split(&mut self, path: &Path, part: u64) -> Result<(Split, Columns), CustomError> {
+        //the rows parsed are ahead of those applied, so no position of a reader matches the state
+        if self.config.checkpoint_every.is_some()
+            || self.config.resume_from.is_some()
+            || self.config.state_dir.is_some()
+        {
+            return Err(CustomError::UnsupportedWithCheckpoints {
+                feature: "--parse-threads",
+            });
+        }
+        if self.config.pipeline.is_some() {
+            return Err(CustomError::UnsupportedWithParseThreads {
+                feature: "--pipeline",
+            });
+        }
+        let mut file = std::fs::File::open(path)?;
+        //a pipe has no parts to seek to, and line breaks of a compressed input are not where its rows end
+        if !file.metadata()?.is_file() {
+            return Err(CustomError::UnsplittableInput {
+                input: String::from("an input it cannot seek in"),
+            });
+        }
+        let mut magic = Vec::new();
+        (&mut file).take(8).read_to_end(&mut magic)?;
+        if let Some(format) = compression(&magic) {
+            return Err(CustomError::UnsplittableInput {
+                input: format!("a {} compressed input", format),
+            });
+        }
+        let mut reader = BlockingReader::open(path)?;
+        let rows = self.start(reader.headers()?)?;
+        let columns = rows.columns.clone();
+        let start = reader.position();
+        let split = Split {
+            path: path.to_path_buf(),
+            points: split_points(&mut file, start.byte(), part)?,
+            slack: part,
+            start,
+            rows,
+        };
+        Ok((split, columns))
+    }
+
+    /// Readies the engine for the records after the header
+    fn start(&mut self, headers: StringRecord) -> Result<RowReader, CustomError> {
+        self.check_checkpoint_options()?;
+        let columns = Columns::from_headers(&headers);
+        if let Some(path) = &self.config.rejects_path {
+            self.rejects = Some(RejectsWriter::create(path, columns.memo.is_some())?);
+        }
+        if self.shards.is_some() {
+            //an input of --shared-accounts hands its rows to workers that are already running
+        } else if self.config.group_by_client {
+            for (feature, is_used) in [
+                ("--workers", self.config.workers > 1),
+                //these compare rows of different clients, which are applied apart
+                ("--enforce-chronology", self.config.enforce_chronology),
+                (
+                    "--require-monotonic-tx-ids",
+                    self.config.require_monotonic_tx_ids,
+                ),
+                ("--allow-tx-id-reuse", self.config.allow_tx_id_reuse),
+                //the whole input is held until it is applied, so nothing can be moved out or bounded
+                ("--spill-dir", self.config.spill_dir.is_some()),
+                ("--max-memory", self.config.max_memory > 0),
+                ("--id-index-dir", self.config.id_filter.is_some()),
+            ] {
+                if is_used {
+                    return Err(CustomError::UnsupportedWithGroupByClient { feature });
+                }
+            }
+            self.grouped = Some(Grouped::default());
+        } else if self.config.workers > 1 {
+            //these compare rows of different clients, which no worker sees together
+            for (feature, is_used) in [
+                ("--enforce-chronology", self.config.enforce_chronology),
+                (
+                    "--require-monotonic-tx-ids",
+                    self.config.require_monotonic_tx_ids,
+                ),
+                ("--allow-tx-id-reuse", self.config.allow_tx_id_reuse),
+                //the workers check ids against owners they share in memory
+                ("--id-index-dir", self.config.id_filter.is_some()),
+            ] {
+                if is_used {
+                    return Err(CustomError::UnsupportedWithWorkers { feature });
+                }
+            }
+            self.shards = Some(Shards::spawn(&self.config, &self.rejects, None)?);
+        } else {
+            self.spill = Spill::create(&self.config)?;
+            self.id_filter = self
+                .config
+                .id_filter
+                .as_ref()
+                .map(|config| FilteredOwners::create(config, ID_INDEX_FLUSH))
+                .transpose()?;
+            self.reserve();
+        }
+        Ok(RowReader {
+            headers,
+            columns,
+            record: ByteRecord::new(),
+            record_index: self.resumed.map_or(0, |progress| progress.record_index),
+            skip_malformed_rows: self.config.skip_malformed_rows,
+            allow_scientific: self.config.allow_scientific,
+        })
+    }
+
+    /// Applies the records of the source, then settles what is left at the end of the input
+    async fn read_rows<R>(
+        &mut self,
+        mut source: Source<'_, R>,
+        columns: &Columns,
+    ) -> Result<(), CustomError>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut read = self.rows_read();
+        loop {
+            self.checkpoint(&mut read, || source.position())?;
+            let row = match source.next().await {
+                Some(row) => row,
+                None => break,
+            };
+            match self.take_row(&mut read, row, columns, || source.position())? {
+                Taken::Next => {}
+                //the worker of the client opens its account
+                Taken::Dispatch(transaction) => self.dispatch(transaction).await?,
+                Taken::Stop => break,
+            }
+        }
+        self.end_rows(&mut read, || source.position())?;
+        self.join_shards().await?;
+        self.settle(read)
+    }
+
+    /// Where reading rows starts, after those of a run it resumes
+    fn rows_read(&mut self) -> RowsRead {
+        let progress = self.resumed.take().unwrap_or(Progress {
+            record_index: 0,
+            latest_timestamp: None,
+        });
+        RowsRead {
+            chronology: Chronology::new(self.config.reorder_buffer),
+            record_index: progress.record_index,
+            latest_timestamp: progress.latest_timestamp,
+            halted_at: None,
+            records_after_halt: 0,
+            checkpointed_at: progress.record_index,
+            left_at: None,
+        }
+    }
+
+    /// Saves a checkpoint when one is due, taken between two rows where the state and the position of the
+    /// reader agree
+    fn checkpoint(
+        &mut self,
+        read: &mut RowsRead,
+        position: impl FnOnce() -> Option<csv_async::Position>,
+    ) -> Result<(), CustomError> {
+        let (Some(every), Some(dir)) = (self.config.checkpoint_every, &self.config.checkpoint_dir)
+        else {
+            return Ok(());
+        };
+        let record_index = read.record_index;
+        if record_index > read.checkpointed_at
+            && record_index.is_multiple_of(every)
+            && read.halted_at.is_none()
+        {
+            let position = position()
+                .expect("checkpoints are refused with a pipelined reader and end a batch");
+            let progress = Progress {
+                record_index,
+                latest_timestamp: read.latest_timestamp,
+            };
+            let state = self.encode_state(
+                [position.byte(), position.line(), position.record()],
+                progress,
+            );
+            checkpoint::write(dir, record_index, &state, self.config.checkpoint_keep)?;
+            debug!("Checkpoint taken at record {}", record_index);
+            read.checkpointed_at = record_index;
+        }
+        Ok(())
+    }
+
+    /// Applies a row of the input, end is the position of the reader once it was taken
+    fn take_row(
+        &mut self,
+        read: &mut RowsRead,
+        row: ReadRow,
+        columns: &Columns,
+        end: impl FnOnce() -> Option<csv_async::Position>,
+    ) -> Result<Taken, CustomError> {
+        if read.halted_at.is_some() {
+            read.records_after_halt += 1;
+            return Ok(Taken::Next);
+        }
+        let ReadRecord {
+            line,
+            raw,
+            offset,
+            parsed,
+            is_truncated,
+        } = match row {
+            //files joined with cat repeat their header, which is not a record
+            ReadRow::Header { line } => {
+                debug!("Header repeated on line {} is skipped", line);
+                self.repeated_headers += 1;
+                return Ok(Taken::Next);
+            }
+            ReadRow::Record(row) => row,
+        };
+        if is_truncated && self.config.state_dir.is_some() {
+            //the last row ends its batch
+            let end = end().expect("--incremental is refused with a pipelined reader");
+            let mut start = csv_async::Position::new();
+            start
+                .set_byte(offset)
+                .set_line(line)
+                .set_record(end.record() - 1);
+            debug!("Row on line {} is left for the next run: {}", line, raw);
+            read.left_at = Some(start);
+            return Ok(Taken::Stop);
+        }
+        read.record_index += 1;
+        let record_index = read.record_index;
+        if self
+            .config
+            .progress_every
+            .is_some_and(|every| record_index.is_multiple_of(every))
+        {
+            let line = self.progress_line(record_index);
+            eprintln!("{}", line);
+        }
+        if let Some(Window::Records(window)) = self.config.dispute_window {
+            //transactions older than the window can never be disputed again, so drop them
+            if record_index.is_multiple_of(window) {
+                for account in self.clients.values_mut() {
+                    account.prune(record_index, window);
+                }
+            }
+        }
+        //a transfer that failed midway leaves the last row cut off, which can still parse as a bogus row
+        //files written without a final line break are common too, so only a last row that does not read
+        //as a whole one, with a field missing or cut short, is taken as cut off
+        if is_truncated && parsed.is_err() {
+            let err = CustomError::TruncatedInput {
+                offset,
+                partial: raw.clone(),
+            };
+            if !self.config.tolerate_truncated_tail {
+                return Err(err);
+            }
+            warn!("{} on line {}, the record is dropped", err, line);
+            *self.rejections.entry(err.reason()).or_default() += 1;
+            if let Some(rejects) = &mut self.rejects {
+                rejects.write(
+                    &["", "", "", "", &err.to_string(), &line.to_string(), &raw],
+                    "",
+                )?;
+            }
+            return Ok(Taken::Next);
+        }
+        let mut transaction = match parsed {
+            Ok(transaction) => transaction,
+            //a type newly introduced upstream, logged once per type so it cannot flood the log
+            Err(CustomError::UndefinedAction { action })
+                if self.config.unknown_action == UnknownAction::Skip =>
+            {
+                if !self.unknown_actions.contains_key(&action) {
+                    warn!(
+                        "Rows of unknown type {} are skipped, first seen on line {}",
+                        action, line
+                    );
+                }
+                *self.unknown_actions.entry(action).or_default() += 1;
+                return Ok(Taken::Next);
+            }
+            Err(err) => {
+                let err = CustomError::MalformedRow {
+                    line,
+                    source: Box::new(err),
+                };
+                if !self.config.skip_malformed_rows {
+                    return Err(err);
+                }
+                warn!("{}, it is skipped: {}", err, raw);
+                self.malformed_rows_skipped += 1;
+                *self.rejections.entry(err.reason()).or_default() += 1;
+                //nothing could be read from the row, so only the error, line and raw text are known
+                if let Some(rejects) = &mut self.rejects {
+                    rejects.write(
+                        &["", "", "", "", &err.to_string(), &line.to_string(), &raw],
+                        "",
+                    )?;
+                }
+                return Ok(Taken::Next);
+            }
+        };
+        if let Action::Halt = transaction.action_type {
+            read.halted_at = Some(record_index);
+            return Ok(Taken::Next);
+        }
+        transaction.record_index = record_index;
+        transaction.line = line;
+        transaction.raw = Some(raw);
+        read.latest_timestamp = read.latest_timestamp.max(transaction.timestamp);
+        if self.shards.is_some() {
+            return Ok(Taken::Dispatch(transaction));
+        }
+        if self.grouped.is_some() {
+            self.group(transaction)?;
+            return Ok(Taken::Next);
+        }
+        self.open_accounts(&transaction);
+        if self.config.enforce_chronology && columns.timestamp.is_some() {
+            if let Some(transaction) = read.chronology.push(transaction) {
+                self.release(transaction, &mut read.chronology)?;
+            }
+        } else {
+            self.apply(transaction)?;
+        }
+        self.spill_oldest()?;
+        self.check_memory()?;
+        Ok(Taken::Next)
+    }
+
+    /// Saves what the next --incremental run continues from, before the end of the input settles anything,
+    /// then applies the rows still waiting for their turn, position is that of the reader after the last row
+    fn end_rows(
+        &mut self,
+        read: &mut RowsRead,
+        position: impl FnOnce() -> Option<csv_async::Position>,
+    ) -> Result<(), CustomError> {
+        if self.config.state_dir.is_some() {
+            match read.halted_at {
+                Some(_) => warn!("The input halted, so its state is not saved for the next run"),
+                None => {
+                    let position = read.left_at.take().or_else(position).expect(
+                        "--incremental is refused with a pipelined reader and ends on an empty batch",
+                    );
+                    let state = self.encode_state(
+                        [position.byte(), position.line(), position.record()],
+                        Progress {
+                            record_index: read.record_index,
+                            latest_timestamp: read.latest_timestamp,
+                        },
+                    );
+                    self.saved_state = Some(SavedState {
+                        byte: position.byte(),
+                        record_index: read.record_index,
+                        state,
+                    });
+                }
+            }
+        }
+        //apply whatever is still waiting in the reorder buffer
+        while let Some(transaction) = read.chronology.pop() {
+            self.release(transaction, &mut read.chronology)?;
+        }
+        Ok(())
+    }
+
+    /// Settles what is left at the end of the input once every row is applied
+    fn settle(&mut self, read: RowsRead) -> Result<(), CustomError> {
+        self.apply_grouped()?;
+        //a day without transactions gives an empty or header-only file, which is not an error
+        self.records_processed = read.record_index;
+        self.halted_at = read.halted_at;
+        self.records_after_halt = read.records_after_halt;
+        info!("{} records were processed", self.records_processed);
+        match read.halted_at {
+            Some(halted_at) => info!("Halt row was seen at record {}", halted_at),
+            None if self.config.require_halt => return Err(CustomError::MissingHalt),
+            None => {}
+        }
+        if read.records_after_halt > 0 {
+            warn!(
+                "{} records after the halt row were not applied",
+                read.records_after_halt
+            );
+        }
+        if let Some(rate) = self.config.accrue_interest {
+            self.accrue_interest(rate, 0);
+        }
+        if self.interest_postings > 0 {
+            info!("Interest was posted {} times", self.interest_postings);
+        }
+        //disputes never followed by a resolve or chargeback would hold funds forever
+        if let Some(window) = &self.config.auto_resolve_after {
+            if let Some(end) = window.position(read.record_index, read.latest_timestamp) {
+                for (client_id, account) in &mut self.clients {
+                    if account.is_quarantined {
+                        continue;
+                    }
+                    for (transaction_id, disputed_at) in
+                        account.auto_resolve(window, end, &self.config)
+                    {
+                        self.disputes_auto_resolved += 1;
+                        info!(
+                            "Client id: {}, with transaction_id: {} was auto-resolved, disputed at record {}",
+                            client_id, transaction_id, disputed_at
+                        );
+                    }
+                }
+            }
+        }
+        if self.disputes_auto_resolved > 0 {
+            info!(
+                "{} stale disputes were auto-resolved",
+                self.disputes_auto_resolved
+            );
+        }
+        if read.chronology.out_of_order > 0 {
+            warn!(
+                "{} records were out of chronological order and were not applied",
+                read.chronology.out_of_order
+            );
+        }
+        if self.duplicates_ignored > 0 {
+            info!(
+                "{} duplicated dispute, resolve and chargeback rows were ignored",
+                self.duplicates_ignored
+            );
+        }
+        if self.malformed_rows_skipped > 0 {
+            warn!(
+                "{} rows could not be read and were skipped",
+                self.malformed_rows_skipped
+            );
+        }
+        for (action, count) in &self.unknown_actions {
+            warn!("{} rows of unknown type {} were skipped", count, action);
+        }
+        if self.adjustments_applied > 0 {
+            info!("{} adjustments were applied", self.adjustments_applied);
+        }
+        //a capped account lost rows, which must not go unnoticed
+        if let Some(cap) = self.config.max_transactions_per_account {
+            for (client_id, account) in &self.clients {
+                if account.rejected_over_cap > 0 {
+                    error!(
+                        "Client id: {} reached the cap of {} stored transactions, {} deposits and withdrawals were rejected",
+                        client_id, cap, account.rejected_over_cap
+                    );
+                }
+            }
+        }
+        //a quarantined account is missing from the output, its last balances are kept for investigation
+        for (client_id, account) in &self.clients {
+            if account.is_quarantined {
+                error!(
+                    "Client id: {} was quarantined and left out of the output, available {}, held {}, total {}",
+                    client_id, account.available, account.held, account.total
+                );
+            }
+        }
+        if let Some(rejects) = &mut self.rejects {
+            rejects.flush()?;
+        }
+        //chargebacks are reported for the audit trail, since the output only shows their count
+        for (client_id, account) in &self.clients {
+            for chargeback in &account.chargebacks {
+                info!(
+                    "Client id: {} had transaction_id: {} charged back for {} at record {}{}{}{}",
+                    client_id,
+                    chargeback.transaction_id,
+                    chargeback.amount,
+                    chargeback.record_index,
+                    chargeback
+                        .timestamp
+                        .map(|timestamp| format!(", timestamp {}", timestamp))
+                        .unwrap_or_default(),
+                    chargeback
+                        .reinstated_at
+                        .map(|record_index| format!(", reinstated at record {}", record_index))
+                        .unwrap_or_default(),
+                    memo_suffix(&chargeback.memo)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes every account to the output, then the report when configured
+    /// Returns the number of account rows written
+    /// The trailer, such as the marker of a partial output, follows the accounts and is not counted as a row
+    async fn write_output<W>(
+        &self,
+        writer: &mut Writer<W>,
+        trailer: &str,
+    ) -> Result<usize, CustomError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        //a full disk or a closed pipe stops the run, naming how much of the output got through
+        let mut rows_written = 0;
+        if !writer.has_header {
+            //the header is not an account row, so it is not counted
+            write_rows(writer.get_inner(), &self.output_header(), 0).await?;
+            writer.has_header = true;
+        }
+        //write out to stdout, rows are formatted into one buffer written once it fills up
+        let mut clients = self.clients.iter();
+        let mut output = String::with_capacity(OUTPUT_CHUNK + 256);
+        while self.next_chunk(&mut clients, &mut output) {
+            rows_written += write_rows(writer.get_inner(), &output, rows_written).await?;
+        }
+        write_rows(writer.get_inner(), trailer, rows_written).await?;
+        writer
+            .get_inner()
+            .flush()
+            .await
+            .map_err(|source| CustomError::OutputWrite {
+                rows_written,
+                source,
+            })?;
+        self.write_sidecars()?;
+        Ok(rows_written)
+    }
+
+    /// Formats the rows of the next accounts into output, until it holds about OUTPUT_CHUNK bytes to write at once
+    /// Returns false once no account is left to write
+    fn next_chunk<'a>(
+        &self,
+        clients: &mut impl Iterator<Item = (&'a ClientId, &'a Account)>,
+        output: &mut String,
+    ) -> bool {
+        output.clear();
+        for (client_id, account) in clients {
+            self.push_account(output, *client_id, account);
+            if output.len() >= OUTPUT_CHUNK {
+                break;
+            }
+        }
+        //accounts left out of the output add nothing, so an empty chunk means none is left
+        !output.is_empty()
+    }
+
+    /// Writes the report and audit files config asks for, once the accounts are written
+    fn write_sidecars(&self) -> Result<(), CustomError> {
+        if let Some(path) = &self.config.report_path {
+            self.report().write(path)?;
+        }
+        if let Some(path) = &self.config.audit_path {
+            audit::write(path, &self.audit())?;
+        }
+        Ok(())
+    }
+
+    /// Header of the output, ending with its line break
+    fn output_header(&self) -> String {
+        let mut header = String::from("client,available,held,total,locked");
+        if self.config.extra_columns {
+            header.push_str(
+                ",fees,closed,chargeback_count,frozen,disputes_opened,disputes_resolved,disputed_amount,tx_cap_exceeded",
+            );
+        }
+        header.push('\n');
+        header
+    }
+
+    /// Formats the output row of an account into output, nothing for an account left out of the output
+    fn push_account(&self, output: &mut String, client_id: ClientId, account: &Account) {
+        if (account.is_closed && self.config.omit_closed) || account.is_quarantined {
+            return;
+        }
+        //writing into a String cannot fail
+        let _ = write!(
+            output,
+            "{},{},{},{},{}",
+            client_id,
+            output_amount(account.available),
+            output_amount(account.held),
+            output_amount(account.total),
+            account.is_locked
+        );
+        if self.config.extra_columns {
+            let _ = write!(
+                output,
+                ",{},{},{},{},{},{},{},{}",
+                output_amount(account.fees),
+                account.is_closed,
+                account.chargebacks.len(),
+                account.is_frozen,
+                account.dispute_stats.opened,
+                account.dispute_stats.resolved,
+                output_amount(account.dispute_stats.disputed_amount),
+                account.rejected_over_cap > 0
+            );
+        }
+        output.push('\n');
+    }
+
+    /// Counts of the run, rejected rows broken down by reason
+    pub(crate) fn report(&self) -> RunReport {
+        RunReport {
+            records: self.records_processed,
+            applied: self.records_applied,
+            rejected: self.rejections.values().sum(),
+            ignored: self.duplicates_ignored,
+            adjustments_applied: self.adjustments_applied,
+            disputes_auto_resolved: self.disputes_auto_resolved,
+            rejected_by_reason: self.rejections.clone(),
+            unknown_actions: self.unknown_actions.clone(),
+            interest_postings: self.interest_postings,
+            accounts_quarantined: self
+                .clients
+                .values()
+                .filter(|account| account.is_quarantined)
+                .count() as u64,
+            accounts_over_cap: self
+                .clients
+                .values()
+                .filter(|account| account.rejected_over_cap > 0)
+                .count() as u64,
+            rejected_over_cap: self
+                .clients
+                .values()
+                .map(|account| account.rejected_over_cap)
+                .sum(),
+            repeated_headers: self.repeated_headers,
+            halted_at: self.halted_at,
+            records_after_halt: self.records_after_halt,
+            spilled: self.spilled,
+            spill_reads: self.spill_reads,
+            spill_bytes: self.spill_bytes,
+            peak_memory: self.peak_memory.max(self.memory_estimate()),
+            owner_bytes: if self.config.verbose {
+                self.transaction_owners.memory()
+            } else {
+                0
+            },
+            owner_map_bytes: if self.config.verbose {
+                table_bytes::<(TransactionId, ClientId)>(grown_capacity(
+                    self.transaction_owners.len(),
+                ))
+            } else {
+                0
+            },
+            id_filter: self
+                .id_filter
+                .as_ref()
+                .map(FilteredOwners::stats)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Audit trail of the run, the chargebacks and adjustments of every account by client and then by position
+    fn audit(&self) -> Vec<AuditEntry> {
+        let mut entries: Vec<AuditEntry> = self
+            .clients
+            .iter()
+            .flat_map(|(client_id, account)| {
+                let chargebacks = account.chargebacks.iter().map(|chargeback| AuditEntry {
+                    event: "chargeback",
+                    client: *client_id,
+                    tx: chargeback.transaction_id,
+                    action: chargeback.action_type.as_str(),
+                    amount: output_amount(chargeback.amount),
+                    record: chargeback.record_index,
+                    timestamp: chargeback.timestamp,
+                    reinstated_at: chargeback.reinstated_at,
+                    memo: chargeback.memo.clone(),
+                });
+                let adjustments = account.adjustments.iter().map(|adjustment| AuditEntry {
+                    event: "adjustment",
+                    client: *client_id,
+                    tx: adjustment.transaction_id,
+                    action: Action::Adjustment.as_str(),
+                    amount: output_amount(adjustment.amount),
+                    record: adjustment.record_index,
+                    timestamp: adjustment.timestamp,
+                    reinstated_at: None,
+                    memo: adjustment.memo.clone(),
+                });
+                chargebacks.chain(adjustments)
+            })
+            .collect();
+        entries.sort_by_key(|entry| (entry.client, entry.record));
+        entries
+    }
+
+    /// Approximate bytes held by the accounts, their stored transactions and the owners of ids
+    /// Tables are counted by capacity, as that is what they allocate
+    fn memory_estimate(&self) -> u64 {
+        table_bytes::<(ClientId, Account)>(self.clients.capacity())
+            + self.transaction_owners.memory()
+            + self.id_filter.as_ref().map_or(0, FilteredOwners::memory)
+            + self.clients.values().map(Account::memory).sum::<u64>()
+    }
+
+    /// How far the run got, printed to stderr every config.progress_every records
+    /// Workers apply the rows and hold the accounts, so with them only the records read are known here
+    fn progress_line(&mut self, record_index: u64) -> String {
+        if self.shards.is_some() {
+            return format!("progress: {} records read", record_index);
+        }
+        let estimate = self.memory_estimate();
+        self.peak_memory = self.peak_memory.max(estimate);
+        format!(
+            "progress: {} records, {} applied, {} rejected, memory {:.1} MB (estimated)",
+            record_index,
+            self.records_applied,
+            self.rejections.values().sum::<u64>(),
+            estimate as f64 / 1e6
+        )
+    }
+
+    /// Estimates memory every so many rows, keeping its peak
+    /// Once it passes --max-memory the run stops, unless the oldest transactions can spill to disk instead
+    fn check_memory(&mut self) -> Result<(), CustomError> {
+        self.since_memory_check += 1;
+        //never more often than there are accounts, so walking them costs a bounded share of each row
+        if self.since_memory_check < (SPILL_CHECK as usize).max(self.clients.len()) {
+            return Ok(());
+        }
+        self.since_memory_check = 0;
+        let estimate = self.memory_estimate();
+        self.peak_memory = self.peak_memory.max(estimate);
+        //workers share the limit evenly, like the spill files
+        let limit = self.config.max_memory / self.config.workers.max(1) as u64;
+        if limit > 0 && self.config.spill_dir.is_none() && estimate > limit {
+            return Err(CustomError::MemoryLimit {
+                estimate: estimate * self.config.workers.max(1) as u64,
+                limit: self.config.max_memory,
+            });
+        }
+        Ok(())
+    }
+
+    /// Applies a single transaction to its account
+    /// Returns Err only for errors that should stop the engine, other errors are logged and the transaction is skipped
+    fn apply(&mut self, mut transaction: Transaction) -> Result<(), CustomError> {
+        //amounts beyond the input precision are settled before anything stores them
+        if let Err(err) = transaction.round_to_precision(self.config.strict_precision) {
+            return self.reject(&transaction.row(), &err);
+        }
+        let row = transaction.row();
+        //looked up once, it may be read from the id index
+        let owner = self.owner(transaction.transaction_id)?;
+        self.restore(&transaction, owner)?;
+        //either party of a transfer may dispute it, the transfer is kept by the recipient
+        if let Action::Dispute | Action::Resolve | Action::Chargeback = transaction.action_type {
+            if let Some(recipient) = self.transfer_recipient(&transaction, owner) {
+                transaction.client_id = recipient;
+            }
+        }
+        let client_id = transaction.get_client_id();
+        let transaction_id = transaction.transaction_id;
+        let action_type = transaction.get_action_type();
+        if let Action::AccrueInterest = action_type {
+            let rate = transaction.decimal.unwrap();
+            if rate <= Decimal::ZERO {
+                return self.reject(&row, &CustomError::NonPositiveAmount);
+            }
+            self.accrue_interest(rate, transaction_id);
+            self.records_applied += 1;
+            return Ok(());
+        }
+        //upstream assigns ids in increasing order, going back hints at a corrupt or spliced file
+        //the other rows reference older ids, or are not numbered by upstream
+        if self.config.require_monotonic_tx_ids {
+            if let Action::Deposit | Action::Withdrawal | Action::WithdrawalPending = action_type {
+                if let Some(previous) = self
+                    .highest_transaction_id
+                    .filter(|previous| transaction_id <= *previous)
+                {
+                    let err = CustomError::NonMonotonicTransactionId {
+                        previous,
+                        found: transaction_id,
+                    };
+                    if self.config.strict {
+                        return Err(err);
+                    }
+                    return self.reject(&row, &err);
+                }
+                self.highest_transaction_id = Some(transaction_id);
+            }
+        }
+        //transaction ids are global, so they must not be reused or referenced by another client
+        if let Some(owner) = owner {
+            if let Err(err) = self.check_owner(&transaction, owner) {
+                warn!(
+                    "Client id: {}, with transaction_id: {} had following error: {}, original owner is client id: {}{}{}",
+                    client_id, transaction_id, err, owner, memo_suffix(&row.memo), row.location()
+                );
+                return self.write_reject(&row, &err);
+            }
+        }
+        //at-least-once delivery can repeat the last dispute, resolve or chargeback of a transaction
+        if self.is_replay(&transaction) {
+            self.duplicates_ignored += 1;
+            debug!(
+                "Client id: {}, with transaction_id: {} repeats the last {} and is ignored",
+                client_id,
+                transaction_id,
+                action_type.as_str()
+            );
+            return Ok(());
+        }
+        let quarantined = |client_id| {
+            self.clients
+                .get(&client_id)
+                .is_some_and(|account| account.is_quarantined)
+        };
+        if quarantined(client_id) || transaction.counterparty.is_some_and(quarantined) {
+            return self.reject(&row, &CustomError::AccountQuarantined);
+        }
+        if let Action::Transfer = action_type {
+            //the recipient keeps the transfer, so it owns the id
+            let recipient = transaction.counterparty;
+            if let Err(err) = self.transfer(transaction) {
+                return self.reject(&row, &err);
+            }
+            if let (Some(recipient), None) = (recipient, owner) {
+                self.claim(transaction_id, recipient)?;
+            }
+            self.records_applied += 1;
+            return Ok(());
+        }
+        let account = self
+            .clients
+            .get_mut(&client_id)
+            .expect("account is created when the row is read");
+        if let Err(err) = account.handle_transaction(transaction, &self.config) {
+            return match err.severity() {
+                //the row already changed the account, which can no longer be trusted
+                Severity::Fatal
+                    if matches!(
+                        err,
+                        CustomError::InvariantViolation { .. } | CustomError::NegativeHeld { .. }
+                    ) && self.config.quarantine_invariant_violations =>
+                {
+                    account.is_quarantined = true;
+                    error!("{}, the account is quarantined{}", err, row.location());
+                    self.write_reject(&row, &err)
+                }
+                Severity::Fatal => Err(err),
+                //simply log error and continue
+                Severity::Recoverable => self.reject(&row, &err),
+            };
+        }
+        if let Action::Chargeback = action_type {
+            if self.config.transfer_chargeback_refunds_sender {
+                let refund = self.clients[&client_id].transfer_refund(transaction_id);
+                if let Some((sender, amount)) = refund {
+                    let sender = self
+                        .clients
+                        .get_mut(&sender)
+                        .expect("account is created when the row is read");
+                    sender.available += amount;
+                    sender.total += amount;
+                }
+            }
+        }
+        if let Action::Adjustment = action_type {
+            self.adjustments_applied += 1;
+            warn!(
+                "Client id: {}, with transaction_id: {} was adjusted by {}{}",
+                client_id,
+                transaction_id,
+                row.amount.unwrap(),
+                memo_suffix(&row.memo)
+            );
+        }
+        //payouts are reported by the rail in two steps, each one is kept in the log for reconciliation
+        if let Action::WithdrawalPending | Action::WithdrawalSettle | Action::WithdrawalFail =
+            action_type
+        {
+            info!(
+                "Client id: {}, with transaction_id: {} applied {}",
+                client_id,
+                transaction_id,
+                action_type.as_str()
+            );
+        }
+        if let Action::Deposit
+        | Action::Withdrawal
+        | Action::WithdrawalPending
+        | Action::Escrow
+        | Action::Adjustment = action_type
+        {
+            if owner.is_none() {
+                self.claim(transaction_id, client_id)?;
+            }
+        }
+        self.records_applied += 1;
+        Ok(())
+    }
+
+    /// Client owning the id, from the id index with --id-index-dir
+    fn owner(&mut self, transaction_id: TransactionId) -> Result<Option<ClientId>, CustomError> {
+        match &mut self.id_filter {
+            Some(owners) => owners.get(transaction_id),
+            None => Ok(self.transaction_owners.get(transaction_id)),
+        }
+    }
+
+    /// Gives the id to the client, which apply found without an owner
+    fn claim(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+    ) -> Result<(), CustomError> {
+        match &mut self.id_filter {
+            Some(owners) => owners.claim(transaction_id, client_id),
+            None => {
+                self.transaction_owners.claim(transaction_id, client_id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Recipient keeping the transfer a dispute, resolve or chargeback row of its sender refers to
+    fn transfer_recipient(
+        &self,
+        transaction: &Transaction,
+        owner: Option<ClientId>,
+    ) -> Option<ClientId> {
+        let owner = owner?;
+        self.clients
+            .get(&owner)?
+            .transactions
+            .get(&transaction.transaction_id)
+            .filter(|stored| {
+                matches!(stored.action_type, Action::Transfer)
+                    && stored.counterparty() == Some(transaction.client_id)
+            })
+            .map(|_| owner)
+    }
+
+    /// Moves funds from the sender to the recipient of a transfer
+    /// Both accounts are checked before either is touched, so a rejected transfer changes nothing
+    fn transfer(&mut self, transaction: Transaction) -> Result<(), CustomError> {
+        let sender = transaction.client_id;
+        let recipient = match transaction.counterparty {
+            Some(recipient) if recipient != sender => recipient,
+            _ => return Err(CustomError::InvalidTransferRecipient),
+        };
+        let amount = transaction.decimal.unwrap();
+        if amount <= Decimal::ZERO {
+            return Err(CustomError::NonPositiveAmount);
+        }
+        check_amount_limit(amount, &self.config)?;
+        self.clients[&recipient].check_transfer_in(&transaction, &self.config)?;
+        self.clients
+            .get_mut(&sender)
+            .expect("account is created when the row is read")
+            .transfer_out(&transaction, &self.config)?;
+        self.clients
+            .get_mut(&recipient)
+            .expect("account is created when the row is read")
+            .transfer_in(transaction);
+        Ok(())
+    }
+
+    /// Posts interest at the given rate to every account that accrues it
+    fn accrue_interest(&mut self, rate: Decimal, transaction_id: TransactionId) {
+        for (client_id, account) in &mut self.clients {
+            if let Some(interest) = account.accrue_interest(rate, transaction_id, &self.config) {
+                self.interest_postings += 1;
+                debug!(
+                    "Client id: {}, with transaction_id: {} accrued interest of {}",
+                    client_id, transaction_id, interest
+                );
+            }
+        }
+    }
+
+    /// Every client id that is seen gets an account, even if none of its rows applies
+    /// Interest rows apply to every account and carry no real client id
+    fn open_accounts(&mut self, transaction: &Transaction) {
+        let record_index = transaction.record_index;
+        let reservation = &mut self.reservation;
+        if !matches!(transaction.action_type, Action::AccrueInterest) {
+            self.clients
+                .entry(transaction.client_id)
+                .or_insert_with(|| {
+                    Account::opened(transaction.client_id, record_index, reservation.take())
+                });
+        }
+        if let Some(counterparty) = transaction.counterparty {
+            self.clients
+                .entry(counterparty)
+                .or_insert_with(|| Account::opened(counterparty, record_index, reservation.take()));
+        }
+    }
+
+    /// Hands a row to the worker of its client, after the checks that span every client
+    /// Interest rows go to every worker
+    async fn dispatch(&mut self, mut transaction: Transaction) -> Result<(), CustomError> {
+        let mut shards = self
+            .shards
+            .take()
+            .expect("dispatch is only called with workers");
+        let is_delivered = match transaction.action_type {
+            //no worker holds every account
+            Action::AccrueInterest if self.config.shared_accounts => {
+                self.shards = Some(shards);
+                return Err(CustomError::UnsupportedWithSharedAccounts {
+                    feature: "interest rows",
+                });
+            }
+            Action::AccrueInterest => {
+                let rate = transaction.decimal.unwrap();
+                if rate <= Decimal::ZERO {
+                    self.shards = Some(shards);
+                    return self.reject(&transaction.row(), &CustomError::NonPositiveAmount);
+                }
+                self.records_applied += 1;
+                let mut is_delivered = true;
+                for shard in 0..shards.senders.len() {
+                    let job = Job::Interest {
+                        rate,
+                        transaction_id: transaction.transaction_id,
+                    };
+                    is_delivered &= shards.push(shard, job).await;
+                }
+                is_delivered
+            }
+            //both accounts of a transfer may be on different workers, which only shared accounts allow
+            Action::Transfer if !self.config.shared_accounts => {
+                self.shards = Some(shards);
+                return Err(CustomError::UnsupportedWithWorkers {
+                    feature: "transfer rows",
+                });
+            }
+            _ => {
+                //rounding comes first, a row rejected for its precision does not claim its id
+                let checked = transaction
+                    .round_to_precision(self.config.strict_precision)
+                    .and_then(|()| shards.claim(&transaction));
+                let shard = shards.shard(shards.route(&transaction));
+                let job = match checked {
+                    Ok(()) => Job::Apply(transaction),
+                    Err(err) => Job::Reject(transaction, err),
+                };
+                shards.push(shard, job).await
+            }
+        };
+        self.shards = Some(shards);
+        if !is_delivered {
+            //the worker stopped on a fatal error, which joining it returns
+            //an input of --shared-accounts has no workers to join, the run joins them once every input stopped
+            self.join_shards().await?;
+            return Err(CustomError::WorkerStopped);
+        }
+        Ok(())
+    }
+
+    /// Waits for the workers to apply what they were sent, and takes their accounts and counters back
+    /// Returns the first fatal error a worker stopped on
+    async fn join_shards(&mut self) -> Result<(), CustomError> {
+        let Some(mut shards) = self.shards.take() else {
+            return Ok(());
+        };
+        for (sender, batch) in shards.senders.drain(..).zip(shards.batches.drain(..)) {
+            //a worker that stopped does not take its last batch, its error is what matters
+            if !batch.is_empty() {
+                let _ = sender.send(batch).await;
+            }
+        }
+        let mut result = Ok(());
+        for worker in shards.workers {
+            let (shard, shard_result) = worker
+                .await
+                .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()));
+            self.merge(shard);
+            result = result.and(shard_result);
+        }
+        result
+    }
+
+    /// Adds a row to those of its client, after the checks that span every client
+    /// Interest rows are added to every client seen so far, which have an account by then
+    fn group(&mut self, mut transaction: Transaction) -> Result<(), CustomError> {
+        let client_id = transaction.client_id;
+        let grouped = self
+            .grouped
+            .as_mut()
+            .expect("group is only called with --group-by-client");
+        let job = match transaction.action_type {
+            Action::AccrueInterest => {
+                let rate = transaction.decimal.unwrap();
+                if rate <= Decimal::ZERO {
+                    return self.reject(&transaction.row(), &CustomError::NonPositiveAmount);
+                }
+                for jobs in grouped.clients.values_mut() {
+                    jobs.push(Job::Interest {
+                        rate,
+                        transaction_id: transaction.transaction_id,
+                    });
+                }
+                self.records_applied += 1;
+                return Ok(());
+            }
+            Action::Transfer => {
+                return Err(CustomError::UnsupportedWithGroupByClient {
+                    feature: "transfer rows",
+                })
+            }
+            _ => {
+                //rounding comes first, a row rejected for its precision does not claim its id
+                let checked = transaction
+                    .round_to_precision(self.config.strict_precision)
+                    .and_then(|()| grouped.claims.check(&transaction));
+                match checked {
+                    Ok(()) => Job::Apply(transaction),
+                    Err(err) => Job::Reject(transaction, err),
+                }
+            }
+        };
+        grouped.clients.entry(client_id).or_default().push(job);
+        Ok(())
+    }
+
+    /// Applies the rows of every client grouped so far, the clients spread over the threads of the rayon pool
+    /// Returns a fatal error one of them stopped on
+    fn apply_grouped(&mut self) -> Result<(), CustomError> {
+        let Some(grouped) = self.grouped.take() else {
+            return Ok(());
+        };
+        let config = self.config.clone();
+        let rejects = self.rejects.clone();
+        let applied: Vec<(Engine, Result<(), CustomError>)> = grouped
+            .clients
+            .into_par_iter()
+            .fold(
+                || {
+                    let mut engine = Engine::new(config.clone());
+                    engine.rejects = rejects.clone();
+                    (engine, Ok(()))
+                },
+                |(mut engine, result), (client_id, jobs)| {
+                    //once an engine stopped, the accounts it holds no longer matter
+                    let result = result.and_then(|()| engine.apply_client(client_id, jobs));
+                    (engine, result)
+                },
+            )
+            .collect();
+        let mut result = Ok(());
+        for (engine, engine_result) in applied {
+            self.merge(engine);
+            result = result.and(engine_result);
+        }
+        result
+    }
+
+    /// Applies the rows of one client in input order
+    /// Its account is pruned once its rows pass a multiple of the window, as every account is without grouping
+    fn apply_client(&mut self, client_id: ClientId, jobs: Vec<Job>) -> Result<(), CustomError> {
+        let mut pruned_at = 0;
+        for job in jobs {
+            match job {
+                Job::Apply(transaction) => {
+                    self.open_accounts(&transaction);
+                    if let Some(Window::Records(window)) = self.config.dispute_window {
+                        let boundary = transaction.record_index / window * window;
+                        if boundary > pruned_at {
+                            if let Some(account) = self.clients.get_mut(&client_id) {
+                                account.prune(boundary, window);
+                            }
+                            pruned_at = boundary;
+                        }
+                    }
+                    self.apply(transaction)?;
+                }
+                Job::Reject(transaction, err) => {
+                    self.open_accounts(&transaction);
+                    self.reject(&transaction.row(), &err)?;
+                }
+                Job::Interest {
+                    rate,
+                    transaction_id,
+                } => {
+                    let account = self
+                        .clients
+                        .get_mut(&client_id)
+                        .expect("interest is only grouped for clients with a row before it");
+                    if let Some(interest) =
+                        account.accrue_interest(rate, transaction_id, &self.config)
+                    {
+                        self.interest_postings += 1;
+                        debug!(
+                            "Client id: {}, with transaction_id: {} accrued interest of {}",
+                            client_id, transaction_id, interest
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the accounts and counters of a worker
+    fn merge(&mut self, shard: Engine) {
+        self.clients.extend(shard.clients);
+        for (transaction_id, client_id) in shard.transaction_owners.iter() {
+            self.transaction_owners.claim(transaction_id, client_id);
+        }
+        self.duplicates_ignored += shard.duplicates_ignored;
+        self.adjustments_applied += shard.adjustments_applied;
+        self.interest_postings += shard.interest_postings;
+        self.records_applied += shard.records_applied;
+        self.spilled += shard.spilled;
+        self.spill_reads += shard.spill_reads;
+        self.spill_bytes += shard.spill_bytes;
+        //the engines ran side by side, so their peaks add up
+        self.peak_memory += shard.peak_memory;
+        for (reason, count) in shard.rejections {
+            *self.rejections.entry(reason).or_default() += count;
+        }
+    }
+
+    /// Runs one job of a worker, pruned_at is the position of its last pruning
+    fn run_job(&mut self, job: Job, pruned_at: &mut u64) -> Result<(), CustomError> {
+        match job {
+            Job::Apply(transaction) if self.accounts.is_some() => {
+                self.lock_accounts(transaction, Self::apply)
+            }
+            Job::Reject(transaction, err) if self.accounts.is_some() => self
+                .lock_accounts(transaction, |engine, transaction| {
+                    engine.reject(&transaction.row(), &err)
+                }),
+            Job::Apply(transaction) => {
+                self.open_accounts(&transaction);
+                //the reader prunes at every multiple of the window, a worker once its rows go past one
+                if let Some(Window::Records(window)) = self.config.dispute_window {
+                    let record_index = transaction.record_index;
+                    let boundary = record_index / window * window;
+                    if boundary > *pruned_at {
+                        for account in self.clients.values_mut() {
+                            account.prune(boundary, window);
+                        }
+                        *pruned_at = boundary;
+                    }
+                }
+                self.apply(transaction)?;
+                self.spill_oldest()?;
+                self.check_memory()
+            }
+            Job::Reject(transaction, err) => {
+                self.open_accounts(&transaction);
+                self.reject(&transaction.row(), &err)
+            }
+            Job::Interest {
+                rate,
+                transaction_id,
+            } => {
+                self.accrue_interest(rate, transaction_id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Locks the shared accounts a row names, opening those that are missing, and runs apply with them moved
+    /// into clients, then moves them back
+    /// A dispute of a transfer by its sender names the recipient keeping it as well, which owns its id here
+    fn lock_accounts<T>(
+        &mut self,
+        transaction: Transaction,
+        apply: impl FnOnce(&mut Self, Transaction) -> T,
+    ) -> T {
+        let accounts = self
+            .accounts
+            .clone()
+            .expect("lock_accounts is only called with shared accounts");
+        let mut named = vec![transaction.client_id];
+        named.extend(transaction.counterparty);
+        named.extend(self.transaction_owners.get(transaction.transaction_id));
+        let record_index = transaction.record_index;
+        let reservation = &mut self.reservation;
+        let ordered = accounts.ordered(&named, |client_id| {
+            Account::opened(client_id, record_index, reservation.take())
+        });
+        //taking the locks in increasing order of client id is what keeps two workers from waiting on each other
+        let mut locked = lock_all(&ordered);
+        for (client_id, account) in &mut locked {
+            let account = std::mem::replace(&mut **account, Account::new(*client_id));
+            self.clients.insert(*client_id, account);
+        }
+        let applied = apply(self, transaction);
+        for (client_id, account) in &mut locked {
+            **account = self
+                .clients
+                .remove(client_id)
+                .expect("a locked account stays in clients while the row is applied");
+        }
+        applied
+    }
+
+    /// Checkpoints only hold the engine, so state kept elsewhere cannot be taken along
+    fn check_checkpoint_options(&self) -> Result<(), CustomError> {
+        if self.config.checkpoint_every.is_none()
+            && self.config.resume_from.is_none()
+            && self.config.state_dir.is_none()
+        {
+            return Ok(());
+        }
+        for (feature, is_used) in [
+            ("--workers", self.config.workers > 1),
+            ("--group-by-client", self.config.group_by_client),
+            ("--spill-dir", self.config.spill_dir.is_some()),
+            ("--enforce-chronology", self.config.enforce_chronology),
+            ("--rejects", self.config.rejects_path.is_some()),
+            //a checkpoint keeps the owners of ids, which the index keeps in files of its own
+            ("--id-index-dir", self.config.id_filter.is_some()),
+        ] {
+            if is_used {
+                return Err(CustomError::UnsupportedWithCheckpoints { feature });
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores the newest valid checkpoint of config.resume_from and moves the reader past the rows it covers
+    /// A damaged checkpoint is passed over for an older one
+    /// Returns the number of records the checkpoint covers, 0 when the run does not resume
+    pub(crate) async fn resume<R>(&mut self, reader: &mut Reader<R>) -> Result<u64, CustomError>
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send,
+    {
+        if let Some([byte, line, record]) = self.restore_checkpoint()? {
+            reader.seek(byte, line, record).await?;
+        }
+        Ok(self.resumed.map_or(0, |progress| progress.record_index))
+    }
+
+    /// Restores the newest valid checkpoint of config.resume_from, returning the position of the reader it was
+    /// taken at, None when the run does not resume
+    fn restore_checkpoint(&mut self) -> Result<Option<[u64; 3]>, CustomError> {
+        let dir = match &self.config.resume_from {
+            Some(dir) => dir.clone(),
+            None => return Ok(None),
+        };
+        self.check_checkpoint_options()?;
+        for path in checkpoint::list(&dir)? {
+            let mut resumed = Engine::new(self.config.clone());
+            let position = match checkpoint::read(&path)?.map(|state| resumed.decode_state(&state))
+            {
+                Some(Ok(position)) => position,
+                _ => {
+                    warn!(
+                        "Checkpoint {} is damaged, an older one is tried",
+                        path.display()
+                    );
+                    continue;
+                }
+            };
+            *self = resumed;
+            info!("Resumed from {} at record {}", path.display(), position[2]);
+            return Ok(Some(position));
+        }
+        Err(CustomError::NoCheckpoint {
+            dir: dir.display().to_string(),
+        })
+    }
+
+    /// First pass of --two-pass: reads the whole input for the ids a later row refers to, then moves the reader
+    /// back to the first row
+    /// Disputes, resolves, chargebacks and reversals refer to an earlier transaction, and so does a row reusing the
+    /// id of one, which is rejected naming it. Deposits with any other id are then only kept as ids
+    pub(crate) async fn scan_references<R>(
+        &mut self,
+        reader: &mut Reader<R>,
+    ) -> Result<(), CustomError>
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send,
+    {
+        //a pipe would be used up by the first pass
+        reader
+            .check_seekable()
+            .await
+            .map_err(|source| CustomError::UnseekableInput { source })?;
+        let headers = reader.get_inner().headers().await?.clone();
+        let start = reader.get_inner().position().clone();
+        let mut references = References::new(&headers);
+        let mut record = ByteRecord::new();
+        while reader.get_inner().read_byte_record(&mut record).await? {
+            references.add(&record);
+        }
+        references.finish(&mut self.config);
+        reader
+            .seek(start.byte(), start.line(), start.record())
+            .await
+    }
+
+    /// Accounts, counters and progress of the run, with the byte, line and record of the reader
+    fn encode_state(&self, position: [u64; 3], progress: Progress) -> Vec<u8> {
+        let mut state = CheckpointWriter(Vec::new());
+        for value in position {
+            state.u64(value);
+        }
+        state.u64(progress.record_index);
+        state.optional(progress.latest_timestamp);
+        for value in [
+            self.duplicates_ignored,
+            self.adjustments_applied,
+            self.disputes_auto_resolved,
+            self.interest_postings,
+            self.malformed_rows_skipped,
+            self.records_applied,
+            self.repeated_headers,
+        ] {
+            state.u64(value);
+        }
+        state.optional(self.highest_transaction_id.map(u64::from));
+        state.len(self.rejections.len());
+        for (reason, count) in &self.rejections {
+            state.text(reason);
+            state.u64(*count);
+        }
+        state.len(self.unknown_actions.len());
+        for (action, count) in &self.unknown_actions {
+            state.text(action);
+            state.u64(*count);
+        }
+        state.len(self.transaction_owners.len());
+        for (transaction_id, client_id) in self.transaction_owners.iter() {
+            state.u32(transaction_id);
+            state.u16(client_id);
+        }
+        state.len(self.clients.len());
+        for account in self.clients.values() {
+            account.encode(&mut state);
+        }
+        state.0
+    }
+
+    /// Restores what encode_state saved, returning the byte, line and record of the reader
+    fn decode_state(&mut self, state: &[u8]) -> Result<[u64; 3], CustomError> {
+        let mut state = CheckpointReader { state, position: 0 };
+        let position = [state.u64()?, state.u64()?, state.u64()?];
+        self.resumed = Some(Progress {
+            record_index: state.u64()?,
+            latest_timestamp: state.optional()?,
+        });
+        for counter in [
+            &mut self.duplicates_ignored,
+            &mut self.adjustments_applied,
+            &mut self.disputes_auto_resolved,
+            &mut self.interest_postings,
+            &mut self.malformed_rows_skipped,
+            &mut self.records_applied,
+            &mut self.repeated_headers,
+        ] {
+            *counter = state.u64()?;
+        }
+        self.highest_transaction_id = state
+            .optional()?
+            .map(|transaction_id| transaction_id as TransactionId);
+        for _ in 0..state.len()? {
+            let reason = state.reason()?;
+            self.rejections.insert(reason, state.u64()?);
+        }
+        for _ in 0..state.len()? {
+            let action = state.text()?;
+            self.unknown_actions.insert(action, state.u64()?);
+        }
+        for _ in 0..state.len()? {
+            let transaction_id = state.u32()?;
+            self.transaction_owners.claim(transaction_id, state.u16()?);
+        }
+        for _ in 0..state.len()? {
+            let account = Account::decode(&mut state)?;
+            self.clients.insert(account.client_id, account);
+        }
+        if state.position != state.state.len() {
+            return Err(state.damaged());
+        }
+        Ok(position)
+    }
+
+    /// Reads the transaction a row refers to back from the spill file, into every account that may keep it
+    fn restore(
+        &mut self,
+        transaction: &Transaction,
+        owner: Option<ClientId>,
+    ) -> Result<(), CustomError> {
+        let spill = match &mut self.spill {
+            Some(spill) => spill,
+            None => return Ok(()),
+        };
+        let transaction_id = transaction.transaction_id;
+        for client_id in [Some(transaction.client_id), owner, transaction.counterparty]
+            .into_iter()
+            .flatten()
+        {
+            let account = match self.clients.get_mut(&client_id) {
+                Some(account) => account,
+                None => continue,
+            };
+            if let Some(slot) = account.spilled.remove(&transaction_id) {
+                let mut record = [0; SPILL_RECORD];
+                spill.file.read(slot.offset, &mut record)?;
+                account
+                    .transactions
+                    .insert(transaction_id, DetachedTransaction::decode(&record));
+                self.spill_reads += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the oldest stored transactions to the spill file once the accounts keep more than its limit
+    /// Transactions under dispute or waiting for their payout stay, as they still hold funds
+    fn spill_oldest(&mut self) -> Result<(), CustomError> {
+        let spill = match &mut self.spill {
+            Some(spill) => spill,
+            None => return Ok(()),
+        };
+        spill.since_check += 1;
+        if spill.since_check < SPILL_CHECK {
+            return Ok(());
+        }
+        spill.since_check = 0;
+        let stored: usize = self
+            .clients
+            .values()
+            .map(|account| account.transactions.len())
+            .sum();
+        if stored <= spill.limit {
+            return Ok(());
+        }
+        //down to three quarters of the limit, so the next rows do not spill again at once
+        let count = stored - spill.limit / 4 * 3;
+        let mut oldest: Vec<(u64, ClientId, TransactionId)> = self
+            .clients
+            .values()
+            .flat_map(|account| {
+                account
+                    .transactions
+                    .iter()
+                    .filter(|(_, stored)| !stored.is_under_dispute() && !stored.is_payout_pending())
+                    .map(move |(id, stored)| (stored.record_index, account.client_id, *id))
+            })
+            .collect();
+        if count < oldest.len() {
+            oldest.select_nth_unstable(count);
+            oldest.truncate(count);
+        }
+        let base = spill.file.len();
+        let mut records = Vec::with_capacity(oldest.len() * SPILL_RECORD);
+        for (record_index, client_id, transaction_id) in oldest {
+            let account = self.clients.get_mut(&client_id).unwrap();
+            let stored = account.transactions.remove(&transaction_id).unwrap();
+            let offset = base + records.len() as u64;
+            records.extend_from_slice(&stored.encode());
+            account.spilled.insert(
+                transaction_id,
+                SpillSlot {
+                    offset,
+                    record_index,
+                },
+            );
+            self.spilled += 1;
+        }
+        spill.file.append(&records)?;
+        self.spill_bytes = spill.file.len();
+        Ok(())
+    }
+
+    /// Applies a transaction leaving the reorder buffer, unless it is older than one already applied
+    fn release(
+        &mut self,
+        transaction: Transaction,
+        chronology: &mut Chronology,
+    ) -> Result<(), CustomError> {
+        if let Err(err) = chronology.check(&transaction) {
+            return self.reject(&transaction.row(), &err);
+        }
+        self.apply(transaction)
+    }
+
+    /// Whether a dispute, resolve or chargeback row is identical to the last one applied to its transaction
+    fn is_replay(&self, transaction: &Transaction) -> bool {
+        if let Action::Dispute | Action::Resolve | Action::Chargeback = transaction.action_type {
+            self.clients
+                .get(&transaction.client_id)
+                .and_then(|account| account.transactions.get(&transaction.transaction_id))
+                .is_some_and(|original| original.last_lifecycle_row() == Some(transaction.row_hash))
+        } else {
+            false
+        }
+    }
+
+    /// Logs a row rejected with a recoverable error and records it in the rejects sidecar
+    fn reject(&mut self, row: &Row, err: &CustomError) -> Result<(), CustomError> {
+        warn!("{}", reject_message(row, err));
+        self.write_reject(row, err)
+    }
+
+    /// Records a rejected row in the rejects sidecar, if enabled
+    fn write_reject(&mut self, row: &Row, err: &CustomError) -> Result<(), CustomError> {
+        *self.rejections.entry(err.reason()).or_default() += 1;
+        if let Some(rejects) = &mut self.rejects {
+            let amount = row
+                .amount
+                .map(|amount| amount.to_string())
+                .unwrap_or_default();
+            rejects.write(
+                &[
+                    row.action_type.as_str(),
+                    &row.client_id.to_string(),
+                    &row.transaction_id.to_string(),
+                    &amount,
+                    &err.to_string(),
+                    &row.line.to_string(),
+                    &row.raw,
+                ],
+                row.memo.as_deref().unwrap_or_default(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Checks a row against the client that owns its transaction id
+    /// Deposit and withdrawal must not reuse an id, unless config allows another client to reuse it,
+    /// dispute, resolve and chargeback rows must carry the client id of the transaction they reference
+    fn check_owner(&self, transaction: &Transaction, owner: ClientId) -> Result<(), CustomError> {
+        match transaction.action_type {
+            Action::Deposit
+            | Action::Withdrawal
+            | Action::WithdrawalPending
+            | Action::Escrow
+            | Action::Adjustment
+            | Action::Transfer => {
+                if owner != transaction.client_id && self.config.allow_tx_id_reuse {
+                    Ok(())
+                } else {
+                    //the owner's account knows the original, unless it was pruned or settled since
+                    Err(match self.clients.get(&owner) {
+                        Some(account) => account.duplicate_id(transaction.transaction_id),
+                        None => CustomError::DuplicatedTransactionId { original: None },
+                    })
+                }
+            }
+            Action::Dispute | Action::Resolve | Action::Chargeback => {
+                let is_stored = self
+                    .clients
+                    .get(&transaction.client_id)
+                    .is_some_and(|account| account.has_transaction(transaction.transaction_id));
+                if owner == transaction.client_id {
+                    //the id was applied for this client, so if it is gone it was either settled,
+                    //which the account reports, or dropped by the dispute window
+                    let is_settled = self
+                        .clients
+                        .get(&transaction.client_id)
+                        .is_some_and(|account| account.is_settled(transaction.transaction_id));
+                    if !is_stored && !is_settled && self.config.dispute_window.is_some() {
+                        return Err(CustomError::DisputeWindowExpired);
+                    }
+                    return Ok(());
+                }
+                //when ids may be reused, the client can still reference its own copy of the id
+                if self.config.allow_tx_id_reuse && is_stored {
+                    return Ok(());
+                }
+                Err(CustomError::ClientMismatch {
+                    expected: owner,
+                    found: transaction.client_id,
+                })
+            }
+            //the account checks a reused id against its own transactions, chargebacks and escrows
+            Action::Reinstate
+            | Action::Reversal
+            | Action::EscrowRelease
+            | Action::WithdrawalSettle
+            | Action::WithdrawalFail => {
+                if owner == transaction.client_id || self.config.allow_tx_id_reuse {
+                    Ok(())
+                } else {
+                    Err(CustomError::ClientMismatch {
+                        expected: owner,
+                        found: transaction.client_id,
+                    })
+                }
+            }
+            //administrative rows do not reference another transaction
+            Action::Lock
+            | Action::Unlock
+            | Action::Close
+            | Action::Settle
+            | Action::Hold
+            | Action::Release
+            | Action::Freeze
+            | Action::Unfreeze
+            | Action::AccrueInterest
+            | Action::Halt => Ok(()),
+        }
+    }
+}
+
+/// Runs of --sync, read with the csv crate and written with std io on the calling thread, without a runtime
+/// Rows go through the same steps as in the runs on tokio, only the reads and writes block
+impl Engine {
+    /// Reads the whole input and writes the accounts like process, with blocking calls
+    /// The rows are all applied on this thread, so workers are refused
+    pub fn process_blocking<R, W>(
+        &mut self,
+        reader: &mut BlockingReader<R>,
+        writer: &mut Writer<W>,
+    ) -> Result<(), CustomError>
+    where
+        R: Read,
+        W: std::io::Write,
+    {
+        self.take_input()?;
+        let result = self.read_records_blocking(reader);
+        self.finish_blocking(result, writer)
+    }
+
+    /// Processes only the rows appended since the last run like process_incremental, with blocking calls
+    pub(crate) fn process_incremental_blocking<R, W>(
+        &mut self,
+        reader: &mut BlockingReader<R>,
+        writer: &mut Writer<W>,
+    ) -> Result<(), CustomError>
+    where
+        R: Read + Seek,
+        W: std::io::Write,
+    {
+        let dir = self.check_incremental()?;
+        if let Some((path, fingerprint, state)) = last_state(&dir)? {
+            let input = InputFingerprint::read_blocking(reader, fingerprint.byte)?;
+            if let Some([byte, line, record]) =
+                self.continue_with(&path, fingerprint, input, &state)
+            {
+                reader.seek(byte, line, record)?;
+            }
+        }
+        self.process_blocking(reader, writer)?;
+        if let Some(saved) = self.saved_state.take() {
+            let fingerprint = InputFingerprint::read_blocking(reader, saved.byte)?
+                .expect("the input was read up to where its state was saved");
+            saved.write(&dir, fingerprint)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the input file like process_split, applying the parsed rows on this thread
+    pub(crate) fn process_split_blocking<W>(
+        &mut self,
+        path: &Path,
+        writer: &mut Writer<W>,
+    ) -> Result<(), CustomError>
+    where
+        W: std::io::Write,
+    {
+        self.take_input()?;
+        let result = self.read_split_blocking(path, PARSE_PART);
+        self.finish_blocking(result, writer)
+    }
+
+    /// Restores the newest valid checkpoint like resume, with blocking calls
+    pub(crate) fn resume_blocking<R>(
+        &mut self,
+        reader: &mut BlockingReader<R>,
+    ) -> Result<u64, CustomError>
+    where
+        R: Read + Seek,
+    {
+        if let Some([byte, line, record]) = self.restore_checkpoint()? {
+            reader.seek(byte, line, record)?;
+        }
+        Ok(self.resumed.map_or(0, |progress| progress.record_index))
+    }
+
+    /// First pass of --two-pass like scan_references, with blocking calls
+    pub(crate) fn scan_references_blocking<R>(
+        &mut self,
+        reader: &mut BlockingReader<R>,
+    ) -> Result<(), CustomError>
+    where
+        R: Read + Seek,
+    {
+        reader
+            .check_seekable()
+            .map_err(|source| CustomError::UnseekableInput { source })?;
+        let headers = reader.headers()?;
+        let start = reader.position();
+        let mut references = References::new(&headers);
+        let mut record = ByteRecord::new();
+        while reader.read_byte_record(&mut record)? {
+            references.add(&record);
+        }
+        references.finish(&mut self.config);
+        reader.seek(start.byte(), start.line(), start.record())
+    }
+
+    /// Writes the accounts like finish, with blocking calls
+    fn finish_blocking<W>(
+        &mut self,
+        result: Result<(), CustomError>,
+        writer: &mut Writer<W>,
+    ) -> Result<(), CustomError>
+    where
+        W: std::io::Write,
+    {
+        let err = match result {
+            Ok(()) => return self.write_output_blocking(writer, "").map(|_| ()),
+            Err(err) => err,
+        };
+        if let Some(trailer) = self.partial_output(&err) {
+            self.write_output_blocking(writer, &trailer)?;
+        }
+        Err(err)
+    }
+
+    /// Writes every account to the output like write_output, with blocking calls
+    fn write_output_blocking<W>(
+        &self,
+        writer: &mut Writer<W>,
+        trailer: &str,
+    ) -> Result<usize, CustomError>
+    where
+        W: std::io::Write,
+    {
+        let mut rows_written = 0;
+        if !writer.has_header {
+            write_rows_blocking(writer.get_inner(), &self.output_header(), 0)?;
+            writer.has_header = true;
+        }
+        let mut clients = self.clients.iter();
+        let mut output = String::with_capacity(OUTPUT_CHUNK + 256);
+        while self.next_chunk(&mut clients, &mut output) {
+            rows_written += write_rows_blocking(writer.get_inner(), &output, rows_written)?;
+        }
+        write_rows_blocking(writer.get_inner(), trailer, rows_written)?;
+        writer
+            .get_inner()
+            .flush()
+            .map_err(|source| CustomError::OutputWrite {
+                rows_written,
+                source,
+            })?;
+        self.write_sidecars()?;
+        Ok(rows_written)
+    }
+
+    /// Reads and applies every record like read_records, with blocking calls
+    fn read_records_blocking<R: Read>(
+        &mut self,
+        reader: &mut BlockingReader<R>,
+    ) -> Result<(), CustomError> {
+        //workers are tasks of the runtime, which these runs do without
+        if self.config.workers > 1 {
+            return Err(CustomError::UnsupportedWithSync {
+                feature: "--workers",
+            });
+        }
+        let rows = self.start(reader.headers()?)?;
+        let columns = rows.columns.clone();
+        self.read_rows_blocking(BlockingSource::Direct { reader, rows }, &columns)
+    }
+
+    /// Reads the file in parts parsed on threads like read_split, applying their rows on this thread
+    fn read_split_blocking(&mut self, path: &Path, part: u64) -> Result<(), CustomError> {
+        let (split, columns) = self.split(path, part)?;
+        let threads = self.config.parse_threads.max(1);
+        let (sender, receiver) = mpsc::channel(1);
+        let parser = std::thread::spawn(move || split.send_all(threads, sender));
+        let result = self.read_rows_blocking::<&[u8]>(
+            BlockingSource::Piped {
+                receiver,
+                batch: Vec::new().into_iter(),
+            },
+            &columns,
+        );
+        result.and(Split::join(parser))
+    }
+
+    /// Applies the records of the source like read_rows, then settles what is left at the end of the input
+    fn read_rows_blocking<R: Read>(
+        &mut self,
+        mut source: BlockingSource<'_, R>,
+        columns: &Columns,
+    ) -> Result<(), CustomError> {
+        let mut read = self.rows_read();
+        loop {
+            self.checkpoint(&mut read, || source.position())?;
+            let Some(row) = source.next() else {
+                break;
+            };
+            match self.take_row(&mut read, row, columns, || source.position())? {
+                Taken::Next => {}
+                Taken::Dispatch(_) => unreachable!("rows are only dispatched to workers"),
+                Taken::Stop => break,
+            }
+        }
+        self.end_rows(&mut read, || source.position())?;
+        self.settle(read)
+    }
+}
+
+/// Ids of the first pass of --two-pass that a later row refers to
+struct References {
+    columns: Columns,
+    seen: IncreasingIds,
+    referenced: IdSet<TransactionId>,
+}
+
+impl References {
+    fn new(headers: &StringRecord) -> Self {
+        Self {
+            columns: Columns::from_headers(headers),
+            seen: IncreasingIds::default(),
+            referenced: IdSet::default(),
+        }
+    }
+
+    /// Takes note of the id of a record and whether it refers to an earlier one
+    fn add(&mut self, record: &ByteRecord) {
+        //rows that cannot be read are reported by the second pass
+        let transaction_id =
+            match std::str::from_utf8(record.get(self.columns.tx).unwrap_or_default())
+                .ok()
+                .and_then(|tx| tx.parse::<TransactionId>().ok())
+            {
+                Some(transaction_id) => transaction_id,
+                None => return,
+            };
+        let action = record.get(self.columns.action).unwrap_or_default();
+        let is_reference = [&b"dispute"[..], b"resolve", b"chargeback", b"reversal"]
+            .iter()
+            .any(|name| action.eq_ignore_ascii_case(name));
+        if is_reference || !self.seen.insert(transaction_id) {
+            self.referenced.insert(transaction_id);
+        }
+    }
+
+    /// Hands the ids referred to to the second pass
+    fn finish(self, config: &mut Config) {
+        info!(
+            "First pass found {} transaction ids referred to by a later row",
+            self.referenced.len()
+        );
+        config.referenced_ids = Some(std::sync::Arc::new(self.referenced));
+    }
+}
+
+/// Identifying fields of an input row, used to report rejections
+#[derive(Debug, Clone)]
+struct Row {
+    action_type: Action,
+    client_id: ClientId,
+    transaction_id: TransactionId,
+    amount: Option<Decimal>,
+    memo: Option<String>,
+    /// Line of the row in the input, counting the header
+    line: u64,
+    /// Text of the row, at most RAW_LIMIT bytes
+    raw: String,
+}
+
+impl Row {
+    /// Where the row is found in the input, appended to a log line
+    fn location(&self) -> String {
+        format!(", at line {}: {}", self.line, self.raw)
+    }
+}
+
+/// Log line of a row rejected with a recoverable error
+fn reject_message(row: &Row, err: &CustomError) -> String {
+    format!(
+        "Client id: {}, with transaction_id: {} had following error: {}{}{}",
+        row.client_id,
+        row.transaction_id,
+        err,
+        memo_suffix(&row.memo),
+        row.location()
+    )
+}
+
+/// Memo appended to a log line, empty when the row has none
+fn memo_suffix(memo: &Option<String>) -> String {
+    memo.as_ref()
+        .map(|memo| format!(", memo: {}", memo))
+        .unwrap_or_default()
+}
+
+/// Spill file of an engine, with how many stored transactions it lets the accounts keep in memory
+struct Spill {
+    file: SpillFile,
+    /// Stored transactions kept in memory, the oldest beyond it are moved to the file
+    limit: usize,
+    /// Rows applied since the stored transactions were last counted
+    since_check: u32,
+}
+
+impl Spill {
+    /// Spill of one engine when config names a spill directory, the memory is shared evenly by the workers
+    fn create(config: &Config) -> Result<Option<Self>, CustomError> {
+        let dir = match &config.spill_dir {
+            Some(dir) => dir,
+            None => return Ok(None),
+        };
+        let workers = config.workers.max(1) as u64;
+        Ok(Some(Self {
+            file: SpillFile::create(dir)?,
+            limit: (config.max_memory / workers / STORED_BYTES) as usize,
+            since_check: 0,
+        }))
+    }
+}
+
+/// Where a transaction moved to the spill file is, and the position it was applied at for the dispute window
+#[derive(Debug, Clone, Copy)]
+struct SpillSlot {
+    offset: u64,
+    record_index: u64,
+}
+
+/// Work the reader hands to the worker owning the client of a row
+enum Job {
+    Apply(Transaction),
+    /// Row rejected by a check that spans every client, its account is still opened
+    Reject(Transaction, CustomError),
+    /// Interest row, posted to every account of the worker
+    Interest {
+        rate: Decimal,
+        transaction_id: TransactionId,
+    },
+}
+
+/// Workers applying rows in parallel, each owning the accounts of its clients, or with --shared-accounts locking
+/// those a row names
+/// Every row of a client goes to the same worker, so the rows of a client are applied in input order
+/// A transfer goes to the worker of its sender, like the rows disputing it, so rows of its recipient on another
+/// worker may be applied before or after it
+struct Shards {
+    senders: Vec<mpsc::Sender<Vec<Job>>>,
+    /// Jobs waiting to be sent to each worker
+    batches: Vec<Vec<Job>>,
+    /// Empty for the inputs of --shared-accounts, whose workers the run joins
+    workers: Vec<JoinHandle<(Engine, Result<(), CustomError>)>>,
+    /// Ids checked by the readers, no worker sees the ids of every client
+    /// Shared by the inputs of --shared-accounts, which check them as they read
+    claims: Arc<Mutex<Claims>>,
+}
+
+/// Client that first used each id, and the sender of each transfer
+/// An id is claimed by its first row even when that row is then rejected, unlike without workers
+#[derive(Default)]
+struct Claims {
+    owners: IdOwners,
+    /// A transfer is owned by its recipient, but its sender may dispute it as well
+    transfer_senders: IdMap<TransactionId, ClientId>,
+}
+
+impl Shards {
+    /// Spawns config.workers workers, given accounts they lock instead of owning any
+    fn spawn(
+        config: &Config,
+        rejects: &Option<RejectsWriter>,
+        accounts: Option<&SharedAccounts<Account>>,
+    ) -> Result<Self, CustomError> {
+        let count = config.workers.max(1);
+        let mut senders = Vec::with_capacity(count);
+        let mut workers = Vec::with_capacity(count);
+        //each worker owns a share of the clients
+        let mut shared = config.clone();
+        shared.expected_clients = config
+            .expected_clients
+            .map(|clients| clients.div_ceil(count));
+        for _ in 0..count {
+            let (sender, receiver) = mpsc::channel(SHARD_QUEUE);
+            let mut engine = Engine::new(shared.clone());
+            engine.reserve();
+            engine.rejects = rejects.clone();
+            engine.spill = Spill::create(config)?;
+            engine.accounts = accounts.cloned();
+            senders.push(sender);
+            workers.push(tokio::spawn(work(engine, receiver)));
+        }
+        Ok(Self {
+            senders,
+            batches: (0..count)
+                .map(|_| Vec::with_capacity(SHARD_BATCH))
+                .collect(),
+            workers,
+            claims: Arc::default(),
+        })
+    }
+
+    /// Senders to the same workers, for another input of --shared-accounts
+    fn handle(&self) -> Self {
+        Self {
+            senders: self.senders.clone(),
+            batches: (0..self.senders.len())
+                .map(|_| Vec::with_capacity(SHARD_BATCH))
+                .collect(),
+            workers: Vec::new(),
+            claims: self.claims.clone(),
+        }
+    }
+
+    /// Worker applying the rows of the client
+    fn shard(&self, client_id: ClientId) -> usize {
+        client_id as usize % self.senders.len()
+    }
+
+    /// Queues a job for a worker and sends its batch once full
+    /// Returns false when the worker stopped on a fatal error
+    async fn push(&mut self, shard: usize, job: Job) -> bool {
+        self.batches[shard].push(job);
+        if self.batches[shard].len() < SHARD_BATCH {
+            return true;
+        }
+        let batch = std::mem::replace(&mut self.batches[shard], Vec::with_capacity(SHARD_BATCH));
+        self.senders[shard].send(batch).await.is_ok()
+    }
+
+    /// Client whose worker applies the row, the sender for the rows disputing a transfer so they follow it
+    fn route(&self, transaction: &Transaction) -> ClientId {
+        if let Action::Dispute | Action::Resolve | Action::Chargeback = transaction.action_type {
+            let claims = self.claims.lock().expect("claims lock is poisoned");
+            if let Some(&sender) = claims.transfer_senders.get(&transaction.transaction_id) {
+                return sender;
+            }
+        }
+        transaction.client_id
+    }
+
+    /// Checks the id of a row against the client that first used it
+    fn claim(&mut self, transaction: &Transaction) -> Result<(), CustomError> {
+        //a worker that panicked while holding the lock stops the run anyway
+        self.claims
+            .lock()
+            .expect("claims lock is poisoned")
+            .check(transaction)
+    }
+}
+
+impl Claims {
+    /// Checks the id of a row against the client that first used it, like the owner check without workers
+    fn check(&mut self, transaction: &Transaction) -> Result<(), CustomError> {
+        let client_id = transaction.client_id;
+        let transaction_id = transaction.transaction_id;
+        match transaction.action_type {
+            Action::Deposit
+            | Action::Withdrawal
+            | Action::WithdrawalPending
+            | Action::Escrow
+            | Action::Adjustment => match self.owners.get(transaction_id) {
+                Some(owner) if owner != client_id => {
+                    Err(CustomError::DuplicatedTransactionId { original: None })
+                }
+                Some(_) => Ok(()),
+                None => {
+                    self.owners.claim(transaction_id, client_id);
+                    Ok(())
+                }
+            },
+            //only reaches the workers with --shared-accounts, the recipient keeps the transfer so it owns the id
+            Action::Transfer => match (self.owners.get(transaction_id), transaction.counterparty) {
+                (Some(owner), _)
+                    if owner != client_id
+                        || self.transfer_senders.contains_key(&transaction_id) =>
+                {
+                    Err(CustomError::DuplicatedTransactionId { original: None })
+                }
+                //the worker finds the duplicate or the missing recipient
+                (Some(_), _) | (None, None) => Ok(()),
+                (None, Some(recipient)) => {
+                    self.owners.claim(transaction_id, recipient);
+                    self.transfer_senders.insert(transaction_id, client_id);
+                    Ok(())
+                }
+            },
+            Action::Dispute | Action::Resolve | Action::Chargeback => {
+                match self.owners.get(transaction_id) {
+                    Some(owner)
+                        if owner != client_id
+                            && self.transfer_senders.get(&transaction_id) != Some(&client_id) =>
+                    {
+                        Err(CustomError::ClientMismatch {
+                            expected: owner,
+                            found: client_id,
+                        })
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Action::Reinstate
+            | Action::Reversal
+            | Action::EscrowRelease
+            | Action::WithdrawalSettle
+            | Action::WithdrawalFail => match self.owners.get(transaction_id) {
+                Some(owner) if owner != client_id => Err(CustomError::ClientMismatch {
+                    expected: owner,
+                    found: client_id,
+                }),
+                _ => Ok(()),
+            },
+            Action::Lock
+            | Action::Unlock
+            | Action::Close
+            | Action::Settle
+            | Action::Hold
+            | Action::Release
+            | Action::Freeze
+            | Action::Unfreeze
+            | Action::AccrueInterest
+            | Action::Halt => Ok(()),
+        }
+    }
+}
+
+/// Rows of every client, read in full before any is applied with --group-by-client
+#[derive(Default)]
+struct Grouped {
+    /// Ids checked as the rows are read, no engine applying them sees every client
+    claims: Claims,
+    /// Rows of each client in input order
+    clients: IdMap<ClientId, Vec<Job>>,
+}
+
+/// Applies the jobs of one worker until the reader is done, then hands the engine back
+async fn work(
+    mut engine: Engine,
+    mut receiver: mpsc::Receiver<Vec<Job>>,
+) -> (Engine, Result<(), CustomError>) {
+    let mut pruned_at = 0;
+    while let Some(batch) = receiver.recv().await {
+        for job in batch {
+            if let Err(err) = engine.run_job(job, &mut pruned_at) {
+                return (engine, Err(err));
+            }
+        }
+    }
+    (engine, Ok(()))
+}
+
+/// Reorder buffer used when chronology is enforced
+/// Holds up to capacity transactions sorted by timestamp, so small inversions are applied in order
+struct Chronology {
+    capacity: usize,
+    pending: BinaryHeap<Reverse<Pending>>,
+    /// Latest timestamp pushed, given to rows that have no timestamp so they keep their place
+    latest_seen: Option<u64>,
+    /// Latest timestamp released
+    latest_released: Option<u64>,
+    /// Rows released after a later row, which are not applied
+    out_of_order: u64,
+}
+
+impl Chronology {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            pending: BinaryHeap::new(),
+            latest_seen: None,
+            latest_released: None,
+            out_of_order: 0,
+        }
+    }
+
+    /// Buffers a transaction, returning the oldest one once the buffer is over capacity
+    fn push(&mut self, transaction: Transaction) -> Option<Transaction> {
+        let timestamp = transaction.timestamp.or(self.latest_seen).unwrap_or(0);
+        self.latest_seen = Some(
+            self.latest_seen
+                .map_or(timestamp, |seen| seen.max(timestamp)),
+        );
+        self.pending.push(Reverse(Pending {
+            timestamp,
+            transaction,
+        }));
+        if self.pending.len() > self.capacity {
+            self.pop()
+        } else {
+            None
+        }
+    }
+
+    fn pop(&mut self) -> Option<Transaction> {
+        self.pending
+            .pop()
+            .map(|Reverse(pending)| pending.transaction)
+    }
+
+    /// Transactions must be released in non-decreasing timestamp order
+    fn check(&mut self, transaction: &Transaction) -> Result<(), CustomError> {
+        let timestamp = match transaction.timestamp {
+            None => return Ok(()),
+            Some(timestamp) => timestamp,
+        };
+        match self.latest_released {
+            Some(previous) if timestamp < previous => {
+                self.out_of_order += 1;
+                Err(CustomError::OutOfOrder {
+                    timestamp,
+                    previous,
+                })
+            }
+            _ => {
+                self.latest_released = Some(timestamp);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Transaction waiting in the reorder buffer, ordered by timestamp then by position in the input
+struct Pending {
+    timestamp: u64,
+    transaction: Transaction,
+}
+
+impl Pending {
+    fn key(&self) -> (u64, u64) {
+        (self.timestamp, self.transaction.record_index)
+    }
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// A row of the input as read and parsed, before the engine looks at it
+enum ReadRow {
+    /// The header repeated inside the input, on the given line
+    Header {
+        line: u64,
+    },
+    Record(ReadRecord),
+}
+
+/// A record of the input, parsed or not
+struct ReadRecord {
+    /// Line of the row in the file, counting the header
+    line: u64,
+    /// Fields joined back together, quoting is lost but the row can be found from it
+    raw: String,
+    /// Byte offset of the row in the file
+    offset: u64,
+    parsed: Result<Transaction, CustomError>,
+    /// Whether the row was cut off by the end of the input, before its line break
+    is_truncated: bool,
+}
+
+impl ReadRow {
+    /// Moves a row read from a part of the input, its records counted from 0, to where it is in the input
+    fn shift(&mut self, lines: u64, records: u64) {
+        match self {
+            ReadRow::Header { line } => *line += lines,
+            ReadRow::Record(row) => {
+                row.line += lines;
+                if let Err(CustomError::InvalidUtf8 { record, .. }) = &mut row.parsed {
+                    *record += records;
+                }
+            }
+        }
+    }
+}
+
+/// Reads and parses the records after the header, one at a time
+#[derive(Clone)]
+struct RowReader {
+    headers: StringRecord,
+    columns: Columns,
+    /// Rows are read as bytes so invalid UTF-8 can be told apart by field, into one record reused for every row
+    record: ByteRecord,
+    /// 1-based index of the last record read, not counting the header
+    record_index: u64,
+    skip_malformed_rows: bool,
+    allow_scientific: bool,
+}
+
+impl RowReader {
+    /// Next row of the input, None at its end
+    async fn next<R>(&mut self, reader: &mut Reader<R>) -> Option<ReadRow>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let read = match reader.get_inner().read_byte_record(&mut self.record).await {
+            Ok(true) => Ok(()),
+            Ok(false) => return None,
+            //csv reports the line unless reading failed before the record was found
+            Err(err) => Err((err.position().map(|position| position.line()), err.into())),
+        };
+        Some(self.take(read, reader.is_truncated()))
+    }
+
+    /// Next row of an input read with blocking calls, None at its end
+    fn next_blocking<R: Read>(&mut self, reader: &mut BlockingReader<R>) -> Option<ReadRow> {
+        let read = match reader.read_byte_record(&mut self.record) {
+            Ok(true) => Ok(()),
+            Ok(false) => return None,
+            Err(err) => Err((err.position().map(|position| position.line()), err.into())),
+        };
+        Some(self.take(read, reader.is_truncated()))
+    }
+
+    /// Parses the record just read, or reports why it could not be read with its line when known
+    fn take(
+        &mut self,
+        read: Result<(), (Option<u64>, CustomError)>,
+        is_truncated: bool,
+    ) -> ReadRow {
+        let (value, line) = match read {
+            Ok(()) => {
+                if is_header(&self.record, &self.headers) {
+                    let line = self.record.position().map_or(0, |position| position.line());
+                    return ReadRow::Header { line };
+                }
+                (
+                    Ok(&self.record),
+                    self.record.position().map(|position| position.line()),
+                )
+            }
+            Err((line, err)) => (Err(err), line),
+        };
+        self.record_index += 1;
+        let line = line.unwrap_or(self.record_index + 1);
+        let raw = value
+            .as_ref()
+            .map(|record| raw_text(record))
+            .unwrap_or_default();
+        let offset = value
+            .as_ref()
+            .ok()
+            .and_then(|record| record.position())
+            .map_or(0, |position| position.byte());
+        let parsed = value
+            .and_then(|record| {
+                decode(
+                    record,
+                    &self.columns,
+                    self.record_index,
+                    self.skip_malformed_rows,
+                )
+            })
+            .and_then(|fields| {
+                Transaction::from_record(&fields, &self.columns, self.allow_scientific)
+            });
+        ReadRow::Record(ReadRecord {
+            line,
+            raw,
+            offset,
+            parsed,
+            is_truncated,
+        })
+    }
+
+    /// Reads every row and sends them in batches, waiting whenever the engine is behind
+    /// Stops early once the engine no longer takes them
+    async fn send_all<R>(mut self, mut reader: Reader<R>, sender: mpsc::Sender<Vec<ReadRow>>)
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut batch = Vec::with_capacity(PIPELINE_BATCH);
+        while let Some(row) = self.next(&mut reader).await {
+            batch.push(row);
+            if batch.len() < PIPELINE_BATCH {
+                continue;
+            }
+            let full = std::mem::replace(&mut batch, Vec::with_capacity(PIPELINE_BATCH));
+            if sender.send(full).await.is_err() {
+                return;
+            }
+        }
+        if !batch.is_empty() {
+            let _ = sender.send(batch).await;
+        }
+    }
+}
+
+/// Parts of an input file parsed on threads of their own for --parse-threads
+struct Split {
+    path: PathBuf,
+    /// Where each part starts, then the end of the file
+    /// Only the first is sure to be the start of a row, the others are after the first line break from where they
+    /// were looked for, which may be inside quotes
+    points: Vec<u64>,
+    /// Bytes a part is read past its end at most
+    slack: u64,
+    /// Position after the header
+    start: csv_async::Position,
+    rows: RowReader,
+}
+
+/// Rows of a part of the input, their lines and records counted from its start
+struct Part {
+    rows: Vec<ReadRow>,
+    start: u64,
+    /// Line the reader counted from, that of the header when the part starts after it, 1 otherwise
+    line: u64,
+    /// Position after the last row
+    end: u64,
+    /// Line breaks read
+    lines: u64,
+    records: u64,
+    /// Whether the last row may run past where the part was read to
+    cut: bool,
+}
+
+impl Split {
+    /// Parses the parts, the nth on thread n modulo threads, and sends their rows in input order
+    /// A part that does not start where the rows of the one before end started inside quotes, or after a row
+    /// too long for its part: it is parsed again on this thread from there
+    /// Returns how many parts were parsed again, it stops early once the engine no longer takes rows
+    fn send_all(
+        &self,
+        threads: usize,
+        sender: mpsc::Sender<Vec<ReadRow>>,
+    ) -> Result<usize, CustomError> {
+        let parts = self.points.len().saturating_sub(1);
+        std::thread::scope(|scope| {
+            let receivers: Vec<_> = (0..threads.min(parts))
+                .map(|thread| {
+                    //a thread parses its next part while the one it parsed waits to be sent
+                    let (parsed, receiver) = std::sync::mpsc::sync_channel(1);
+                    scope.spawn(move || {
+                        for part in (thread..parts).step_by(threads) {
+                            let (start, end) = (self.points[part], self.points[part + 1]);
+                            let limit = end.saturating_add(self.slack);
+                            if parsed.send(self.parse(start, end, limit)).is_err() {
+                                return;
+                            }
+                        }
+                    });
+                    receiver
+                })
+                .collect();
+            let (mut at, mut line, mut records) = (self.start.byte(), self.start.line(), 0);
+            let mut parsed_again = 0;
+            for part in 0..parts {
+                //a thread that panicked is resumed once the scope ends
+                let Ok(parsed) = receivers[part % threads].recv() else {
+                    return Ok(parsed_again);
+                };
+                let mut parsed = parsed?;
+                if parsed.start != at || parsed.cut {
+                    parsed_again += 1;
+                    parsed = self.parse(at, self.points[part + 1], u64::MAX)?;
+                }
+                for row in &mut parsed.rows {
+                    row.shift(line - parsed.line, records);
+                }
+                at = parsed.end;
+                line += parsed.lines;
+                records += parsed.records;
+                if sender.blocking_send(parsed.rows).is_err() {
+                    break;
+                }
+            }
+            Ok(parsed_again)
+        })
+    }
+
+    /// Rows from start until one ends at or after end, reading the file no further than limit
+    fn parse(&self, start: u64, end: u64, limit: u64) -> Result<Part, CustomError> {
+        let mut reader = BlockingReader::open_bounded(&self.path, limit)?;
+        reader.seek(start, 1, 0)?;
+        //the reader does not move when it already is at start, after the header
+        let line = reader.position().line();
+        let mut rows = self.rows.clone();
+        rows.record_index = 0;
+        let mut parsed = Vec::new();
+        while reader.position().byte() < end {
+            match rows.next_blocking(&mut reader) {
+                Some(row) => parsed.push(row),
+                None => break,
+            }
+        }
+        let position = reader.position();
+        Ok(Part {
+            rows: parsed,
+            start,
+            line,
+            end: position.byte(),
+            lines: position.line() - line,
+            records: rows.record_index,
+            cut: position.byte() >= limit && limit < *self.points.last().unwrap(),
+        })
+    }
+
+    /// Waits for the parser of the parts once the engine stopped taking rows, its error is reported after
+    /// the engine's
+    fn join(
+        parser: std::thread::JoinHandle<Result<usize, CustomError>>,
+    ) -> Result<(), CustomError> {
+        let parsed = parser
+            .join()
+            .unwrap_or_else(|err| std::panic::resume_unwind(err));
+        match parsed {
+            Ok(parsed_again) if parsed_again > 0 => debug!(
+                "{} parts started inside quotes and were parsed again",
+                parsed_again
+            ),
+            _ => {}
+        }
+        parsed.map(|_| ())
+    }
+}
+
+/// Where the engine takes its rows from
+enum Source<'a, R> {
+    /// Rows read and parsed as the engine asks for them, up to batch_size at a time so the reader is not
+    /// awaited on every row
+    Direct {
+        reader: &'a mut Reader<R>,
+        rows: RowReader,
+        batch: VecDeque<ReadRow>,
+        batch_size: usize,
+        /// A batch ends on the record of a checkpoint, so the position of the reader is that of the state
+        checkpoint_every: Option<u64>,
+    },
+    /// Rows read and parsed ahead on a task of their own, in batches
+    Piped {
+        receiver: mpsc::Receiver<Vec<ReadRow>>,
+        batch: std::vec::IntoIter<ReadRow>,
+    },
+}
+
+impl<R: AsyncRead + Unpin + Send> Source<'_, R> {
+    async fn next(&mut self) -> Option<ReadRow> {
+        match self {
+            Source::Direct {
+                reader,
+                rows,
+                batch,
+                batch_size,
+                checkpoint_every,
+            } => {
+                if batch.is_empty() {
+                    while batch.len() < *batch_size {
+                        let Some(row) = rows.next(reader).await else {
+                            break;
+                        };
+                        let is_record = matches!(row, ReadRow::Record(_));
+                        batch.push_back(row);
+                        if is_record
+                            && checkpoint_every
+                                .is_some_and(|every| rows.record_index.is_multiple_of(every))
+                        {
+                            break;
+                        }
+                    }
+                }
+                batch.pop_front()
+            }
+            Source::Piped { receiver, batch } => loop {
+                if let Some(row) = batch.next() {
+                    return Some(row);
+                }
+                *batch = receiver.recv().await?.into_iter();
+            },
+        }
+    }
+
+    /// Position of the reader after the last row taken, which only a direct reader knows once its batch is
+    /// taken
+    fn position(&mut self) -> Option<csv_async::Position> {
+        match self {
+            Source::Direct { reader, batch, .. } if batch.is_empty() => {
+                Some(reader.get_inner().position().clone())
+            }
+            Source::Direct { .. } => None,
+            Source::Piped { .. } => None,
+        }
+    }
+}
+
+/// Where the engine takes its rows from with --sync
+enum BlockingSource<'a, R> {
+    /// Rows read and parsed one at a time as the engine asks for them, a blocking read costs no more per row
+    Direct {
+        reader: &'a mut BlockingReader<R>,
+        rows: RowReader,
+    },
+    /// Rows parsed ahead on threads of their own, in batches
+    Piped {
+        receiver: mpsc::Receiver<Vec<ReadRow>>,
+        batch: std::vec::IntoIter<ReadRow>,
+    },
+}
+
+impl<R: Read> BlockingSource<'_, R> {
+    fn next(&mut self) -> Option<ReadRow> {
+        match self {
+            BlockingSource::Direct { reader, rows } => rows.next_blocking(reader),
+            BlockingSource::Piped { receiver, batch } => loop {
+                if let Some(row) = batch.next() {
+                    return Some(row);
+                }
+                *batch = receiver.blocking_recv()?.into_iter();
+            },
+        }
+    }
+
+    /// Position of the reader after the last row taken, which only a direct reader knows
+    fn position(&mut self) -> Option<csv_async::Position> {
+        match self {
+            BlockingSource::Direct { reader, .. } => Some(reader.position()),
+            BlockingSource::Piped { .. } => None,
+        }
+    }
+}
+
+/// Positions of the columns, found by header name whatever its case and spacing
+/// The four core columns fall back to their usual place when the header does not name them
+#[derive(Debug, Clone)]
+struct Columns {
+    action: usize,
+    client: usize,
+    tx: usize,
+    /// Also found under the name decimal, which some partners use
+    amount: usize,
+    timestamp: Option<usize>,
+    fee: Option<usize>,
+    tx_end: Option<usize>,
+    memo: Option<usize>,
+    to: Option<usize>,
+    /// Header rows are read with, the canonical name of each column at its position
+    names: ByteRecord,
+}
+
+impl Columns {
+    fn from_headers(headers: &StringRecord) -> Self {
+        let position = |name: &str| {
+            headers
+                .iter()
+                .position(|header| header.trim().eq_ignore_ascii_case(name))
+        };
+        Self {
+            action: position("type").unwrap_or(0),
+            client: position("client").unwrap_or(1),
+            tx: position("tx").unwrap_or(2),
+            amount: position("amount")
+                .or_else(|| position("decimal"))
+                .unwrap_or(3),
+            timestamp: position("timestamp"),
+            fee: position("fee"),
+            tx_end: position("tx_end"),
+            memo: position("memo"),
+            to: position("to"),
+            names: ByteRecord::new(),
+        }
+        .named()
+    }
+
+    /// Names the columns at their positions, the rest keep an empty name and are not read
+    /// A position found for two columns reads as the first of them
+    fn named(mut self) -> Self {
+        let columns = [
+            ("type", Some(self.action)),
+            ("client", Some(self.client)),
+            ("tx", Some(self.tx)),
+            ("amount", Some(self.amount)),
+            ("timestamp", self.timestamp),
+            ("fee", self.fee),
+            ("tx_end", self.tx_end),
+            ("memo", self.memo),
+            ("to", self.to),
+        ];
+        let len = columns
+            .iter()
+            .filter_map(|(_, position)| *position)
+            .max()
+            .map_or(0, |position| position + 1);
+        let mut names = vec![""; len];
+        for (name, position) in columns.into_iter().rev() {
+            if let Some(position) = position {
+                names[position] = name;
+            }
+        }
+        self.names = ByteRecord::from(names);
+        self
+    }
+}
+
+/// Whether the record repeats the header of the input, or is the canonical header of a file with other columns
+/// Header names are matched whatever their case, like the columns are found
+fn is_header(record: &ByteRecord, headers: &StringRecord) -> bool {
+    let matches = |len: usize, names: &mut dyn Iterator<Item = &str>| {
+        record.len() == len
+            && record
+                .iter()
+                .zip(names)
+                .all(|(field, name)| field.eq_ignore_ascii_case(name.as_bytes()))
+    };
+    let canonical = ["type", "client", "tx", "amount"];
+    matches(headers.len(), &mut headers.iter())
+        || matches(canonical.len(), &mut canonical.into_iter())
+}
+
+/// Fields of a record read as bytes, without the whitespace around them
+/// Fields are borrowed from the record, only those with invalid UTF-8 that was replaced are copied
+struct Fields<'a> {
+    record: &'a ByteRecord,
+    /// Fields whose invalid bytes were replaced, by index
+    replaced: Vec<(usize, String)>,
+}
+
+impl Fields<'_> {
+    fn get(&self, index: usize) -> Option<&str> {
+        if let Some((_, field)) = self
+            .replaced
+            .iter()
+            .find(|(replaced, _)| *replaced == index)
+        {
+            return Some(field);
+        }
+        //decode checked every other field, padding inside quotes is dropped as well, whatever the reader trims
+        self.record
+            .get(index)
+            .map(|field| std::str::from_utf8(field).unwrap_or_default().trim())
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &str> {
+        (0..self.record.len()).filter_map(|index| self.get(index))
+    }
+}
+
+/// Decodes the fields of a record read as bytes, without the whitespace around them
+/// Invalid UTF-8 is an error naming the field and about where it is, unless lossy is set and the field is not
+/// one the balances depend on, then the invalid bytes are replaced
+fn decode<'a>(
+    record: &'a ByteRecord,
+    columns: &Columns,
+    record_index: u64,
+    lossy: bool,
+) -> Result<Fields<'a>, CustomError> {
+    let critical = [columns.action, columns.client, columns.tx, columns.amount];
+    let mut replaced = Vec::new();
+    //fields are trimmed and unquoted, so the offset is only close to the invalid byte
+    let mut offset = record.position().map_or(0, |position| position.byte());
+    for (index, field) in record.iter().enumerate() {
+        match std::str::from_utf8(field) {
+            Ok(_) => {}
+            Err(_) if lossy && !critical.contains(&index) => {
+                replaced.push((index, String::from_utf8_lossy(field).trim().to_string()))
+            }
+            Err(err) => {
+                return Err(CustomError::InvalidUtf8 {
+                    field: index + 1,
+                    record: record_index,
+                    offset: offset + err.valid_up_to() as u64,
+                })
+            }
+        }
+        offset += field.len() as u64 + 1;
+    }
+    Ok(Fields { record, replaced })
+}
+
+/// Fields of a record joined back together, at most RAW_LIMIT bytes
+/// Quoting is lost, and invalid UTF-8 is only converted in the fields that have it
+fn raw_text(record: &ByteRecord) -> String {
+    let mut raw = String::with_capacity(record.as_slice().len() + record.len());
+    for (index, field) in record.iter().enumerate() {
+        if index > 0 {
+            raw.push(',');
+        }
+        match std::str::from_utf8(field) {
+            Ok(field) => raw.push_str(field),
+            Err(_) => raw.push_str(&String::from_utf8_lossy(field)),
+        }
+        if raw.len() > RAW_LIMIT {
+            return truncate(&raw, RAW_LIMIT);
+        }
+    }
+    raw
+}
+
+impl Default for Columns {
+    /// The canonical `type,client,tx,amount` header
+    fn default() -> Self {
+        Self {
+            action: 0,
+            client: 1,
+            tx: 2,
+            amount: 3,
+            timestamp: None,
+            fee: None,
+            tx_end: None,
+            memo: None,
+            to: None,
+            names: ByteRecord::new(),
+        }
+        .named()
+    }
+}
+
+/// A row read by column name, before its values are validated
+#[derive(Debug, Default)]
+struct RawTransaction<'a> {
+    action: Option<&'a str>,
+    client: Option<&'a str>,
+    tx: Option<&'a str>,
+    amount: Option<&'a str>,
+    timestamp: Option<&'a str>,
+    fee: Option<&'a str>,
+    tx_end: Option<&'a str>,
+    memo: Option<&'a str>,
+    to: Option<&'a str>,
+}
+
+impl<'de> Deserialize<'de> for RawTransaction<'de> {
+    /// Fields are borrowed from the record, columns without a known name are skipped
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RawVisitor;
+
+        impl<'de> Visitor<'de> for RawVisitor {
+            type Value = RawTransaction<'de>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a transaction row")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut raw = RawTransaction::default();
+                while let Some(name) = map.next_key::<&[u8]>()? {
+                    let field = match name {
+                        b"type" => &mut raw.action,
+                        b"client" => &mut raw.client,
+                        b"tx" => &mut raw.tx,
+                        b"amount" => &mut raw.amount,
+                        b"timestamp" => &mut raw.timestamp,
+                        b"fee" => &mut raw.fee,
+                        b"tx_end" => &mut raw.tx_end,
+                        b"memo" => &mut raw.memo,
+                        b"to" => &mut raw.to,
+                        _ => {
+                            map.next_value::<IgnoredAny>()?;
+                            continue;
+                        }
+                    };
+                    //padding inside quotes is dropped as well, whatever the reader trims
+                    *field = map
+                        .next_value::<Option<&str>>()?
+                        .map(str::trim)
+                        .filter(|value| !value.is_empty());
+                }
+                Ok(raw)
+            }
+        }
+
+        deserializer.deserialize_map(RawVisitor)
+    }
+}
+
+#[derive(Debug)]
+struct Transaction {
+    action_type: Action,
+    client_id: ClientId,
+    transaction_id: TransactionId,
+    decimal: Option<Decimal>,
+    /// 1-based position of the row in the input, excluding the header
+    record_index: u64,
+    /// Unix timestamp in seconds, when the input has a timestamp column
+    timestamp: Option<u64>,
+    /// Fee charged on top of a deposit or withdrawal, zero when the input has no fee column
+    fee: Decimal,
+    /// Hash of all fields of a dispute, resolve or chargeback row, zero for other rows
+    row_hash: u64,
+    /// Last id of the range settled by a Settle row, whose tx column is the first id
+    settle_end: Option<TransactionId>,
+    /// Free-text reference from the memo column, at most MEMO_LIMIT bytes
+    /// Not stored with the transaction, so memos only cost memory while their row is processed
+    memo: Option<String>,
+    /// Line of the row in the input, counting the header, zero for transactions made by the engine
+    line: u64,
+    /// Text of the row for reporting, dropped once the row reaches its account like the memo
+    raw: Option<String>,
+    /// Other party of a transfer, the recipient on the row and the sender once kept by the recipient
+    counterparty: Option<ClientId>,
+}
+
+/// Where a two-phase withdrawal stands with the payout rail
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum PayoutState {
+    /// Reported as pending, the amount is held
+    Pending,
+    /// Paid out, the withdrawal is final and follows the withdrawal dispute rules
+    Settled,
+    /// Bounced, the amount went back to available so this state is final
+    Failed,
+}
+
+impl PayoutState {
+    /// Code of the state in the flags of a stored transaction and in the spill file, 0 for none
+    fn code(state: Option<Self>) -> u8 {
+        match state {
+            None => 0,
+            Some(PayoutState::Pending) => 1,
+            Some(PayoutState::Settled) => 2,
+            Some(PayoutState::Failed) => 3,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => None,
+            1 => Some(PayoutState::Pending),
+            2 => Some(PayoutState::Settled),
+            _ => Some(PayoutState::Failed),
+        }
+    }
+
+    /// State reached by a settle or fail row, or the error of an illegal transition
+    fn next(self, action: Action) -> Result<Self, CustomError> {
+        match (self, action) {
+            (PayoutState::Pending, Action::WithdrawalSettle) => Ok(PayoutState::Settled),
+            (PayoutState::Pending, Action::WithdrawalFail) => Ok(PayoutState::Failed),
+            (PayoutState::Settled, _) => Err(CustomError::WithdrawalAlreadySettled),
+            (PayoutState::Failed, _) => Err(CustomError::WithdrawalAlreadyFailed),
+            //other actions are not part of the payout lifecycle
+            _ => Err(CustomError::UndefinedBehaviour),
+        }
+    }
+}
+
+/// Where a stored transaction stands in the dispute lifecycle
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum DisputeState {
+    /// Never disputed
+    None,
+    /// Under dispute, the disputed amount is held
+    Disputed,
+    /// The last dispute was resolved, the transaction may be disputed again
+    Resolved,
+    /// The last dispute went stale and was resolved by the engine, otherwise the same as Resolved
+    AutoResolved,
+    /// Charged back, the money already left the account so this state is final
+    ChargedBack,
+    /// Deposit made in error and given back, this state is final
+    Reversed,
+}
+
+impl DisputeState {
+    /// Code of the state in the flags of a stored transaction and in the spill file
+    fn code(self) -> u8 {
+        match self {
+            DisputeState::None => 0,
+            DisputeState::Disputed => 1,
+            DisputeState::Resolved => 2,
+            DisputeState::AutoResolved => 3,
+            DisputeState::ChargedBack => 4,
+            DisputeState::Reversed => 5,
+        }
+    }
+
+    fn from_code(code: u8) -> Self {
+        match code {
+            0 => DisputeState::None,
+            1 => DisputeState::Disputed,
+            2 => DisputeState::Resolved,
+            3 => DisputeState::AutoResolved,
+            4 => DisputeState::ChargedBack,
+            _ => DisputeState::Reversed,
+        }
+    }
+
+    /// State reached by a dispute, resolve, chargeback or reversal, or the error of an illegal transition
+    /// Whether a resolved transaction may really be disputed again is left to the redispute policy
+    fn next(self, action: Action) -> Result<Self, CustomError> {
+        match (self, action) {
+            (DisputeState::ChargedBack, _) => Err(CustomError::AlreadyChargedBack),
+            (DisputeState::Reversed, _) => Err(CustomError::AlreadyReversed),
+            (DisputeState::Disputed, Action::Dispute | Action::Reversal) => {
+                Err(CustomError::AlreadyUnderDispute)
+            }
+            (
+                DisputeState::None | DisputeState::Resolved | DisputeState::AutoResolved,
+                Action::Reversal,
+            ) => Ok(DisputeState::Reversed),
+            (
+                DisputeState::None | DisputeState::Resolved | DisputeState::AutoResolved,
+                Action::Dispute,
+            ) => Ok(DisputeState::Disputed),
+            (DisputeState::Disputed, Action::Resolve) => Ok(DisputeState::Resolved),
+            (DisputeState::Disputed, Action::Chargeback) => Ok(DisputeState::ChargedBack),
+            (
+                DisputeState::None | DisputeState::Resolved | DisputeState::AutoResolved,
+                Action::Resolve | Action::Chargeback,
+            ) => Err(CustomError::NotUnderDispute),
+            //other actions are not part of the dispute lifecycle
+            _ => Err(CustomError::UndefinedBehaviour),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Action {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    /// Administrative freeze of the account, independent of any chargeback
+    Lock,
+    /// Releases an administrative lock
+    Unlock,
+    /// The client has exited, no more money can move in or out
+    Close,
+    /// Transactions in a range of ids are final and can no longer be disputed
+    Settle,
+    /// Administrative hold of an amount, independent of any dispute
+    Hold,
+    /// Gives back an amount held by a Hold action
+    Release,
+    /// Lifts the lock of a chargeback decided in the client's favor after review
+    Reinstate,
+    /// Gives back a deposit made in error, without locking the account
+    Reversal,
+    /// Keeps part of the balance aside until delivery, independent of any dispute
+    Escrow,
+    /// Ends an escrow, returning its funds to available or transferring them out
+    EscrowRelease,
+    /// Signed manual correction of the balance by an operator, which can never be disputed
+    Adjustment,
+    /// Blocks withdrawals while still accepting deposits and disputes, softer than Lock
+    Freeze,
+    /// Releases a freeze
+    Unfreeze,
+    /// Posts interest at the rate in the amount column to every account, its client id is ignored
+    /// Also the action of the interest transaction kept on each account
+    AccrueInterest,
+    /// First step of a two-phase withdrawal, the amount is held until the payout rail reports its outcome
+    WithdrawalPending,
+    /// The pending withdrawal was paid out, its held amount leaves the account
+    WithdrawalSettle,
+    /// The pending withdrawal bounced, its held amount is returned to available
+    WithdrawalFail,
+    /// Marks the clean end of the input, nothing after it is applied
+    Halt,
+    /// Moves funds from the client of the row to the client of the to column
+    /// Kept by the recipient, where it can be disputed like a deposit
+    Transfer,
+}
+
+impl Action {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Action::Deposit => "deposit",
+            Action::Withdrawal => "withdrawal",
+            Action::Dispute => "dispute",
+            Action::Resolve => "resolve",
+            Action::Chargeback => "chargeback",
+            Action::Lock => "lock",
+            Action::Unlock => "unlock",
+            Action::Close => "close",
+            Action::Settle => "settle",
+            Action::Hold => "hold",
+            Action::Release => "release",
+            Action::Reinstate => "reinstate",
+            Action::Reversal => "reversal",
+            Action::Escrow => "escrow",
+            Action::EscrowRelease => "escrow_release",
+            Action::Adjustment => "adjustment",
+            Action::Freeze => "freeze",
+            Action::Unfreeze => "unfreeze",
+            Action::AccrueInterest => "accrue_interest",
+            Action::WithdrawalPending => "withdrawal_pending",
+            Action::WithdrawalSettle => "withdrawal_settle",
+            Action::WithdrawalFail => "withdrawal_fail",
+            Action::Halt => "halt",
+            Action::Transfer => "transfer",
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deposit" => Ok(Action::Deposit),
+            "withdrawal" => Ok(Action::Withdrawal),
+            "dispute" => Ok(Action::Dispute),
+            "resolve" => Ok(Action::Resolve),
+            "chargeback" => Ok(Action::Chargeback),
+            "lock" => Ok(Action::Lock),
+            "unlock" => Ok(Action::Unlock),
+            "close" => Ok(Action::Close),
+            "settle" => Ok(Action::Settle),
+            "hold" => Ok(Action::Hold),
+            "release" => Ok(Action::Release),
+            "reinstate" => Ok(Action::Reinstate),
+            "reversal" => Ok(Action::Reversal),
+            "escrow" => Ok(Action::Escrow),
+            "escrow_release" => Ok(Action::EscrowRelease),
+            "adjustment" => Ok(Action::Adjustment),
+            "freeze" => Ok(Action::Freeze),
+            "unfreeze" => Ok(Action::Unfreeze),
+            "accrue_interest" => Ok(Action::AccrueInterest),
+            "withdrawal_pending" => Ok(Action::WithdrawalPending),
+            "withdrawal_settle" => Ok(Action::WithdrawalSettle),
+            "withdrawal_fail" => Ok(Action::WithdrawalFail),
+            "halt" => Ok(Action::Halt),
+            "transfer" => Ok(Action::Transfer),
+            _ => Err(CustomError::UndefinedAction {
+                action: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl Transaction {
+    /// Reads a row, scientific lets amounts and fees use exponent notation
+    fn from_record(
+        record: &Fields,
+        columns: &Columns,
+        scientific: bool,
+    ) -> Result<Self, CustomError> {
+        //decoded fields are valid UTF-8, only a row whose invalid bytes were replaced is read from a copy
+        let mut transaction = if record.replaced.is_empty() {
+            Self::from_raw(record.record.deserialize(Some(&columns.names))?, scientific)?
+        } else {
+            let copy: ByteRecord = record.iter().collect();
+            Self::from_raw(copy.deserialize(Some(&columns.names))?, scientific)?
+        };
+        if let Action::Dispute | Action::Resolve | Action::Chargeback = transaction.action_type {
+            transaction.row_hash = hash_fields(record.iter());
+        }
+        Ok(transaction)
+    }
+
+    /// Validates a row read by column name
+    fn from_raw<'a>(raw: RawTransaction<'a>, scientific: bool) -> Result<Self, CustomError> {
+        //an empty field is as missing as an absent one, and is told apart from a value that does not parse
+        let field = |value: Option<&'a str>, column: &'static str, action: Option<Action>| {
+            value.ok_or(CustomError::MissingField {
+                column,
+                action: action.map(|action| action.as_str()),
+            })
+        };
+        let action_type = Action::from_str(field(raw.action, "type", None)?)?;
+        let of_row = |err: CustomError| err.of_action(action_type.as_str());
+        let client_id =
+            parse_int(field(raw.client, "client", Some(action_type))?, "client").map_err(of_row)?;
+        let transaction_id =
+            parse_int(field(raw.tx, "tx", Some(action_type))?, "tx").map_err(of_row)?;
+        let timestamp = match raw.timestamp {
+            None => None,
+            Some(timestamp) => Some(parse_int(timestamp, "timestamp").map_err(of_row)?),
+        };
+        let fee = match raw.fee {
+            None => Decimal::new(0, PRECISION),
+            Some(fee) => parse_decimal(fee, "fee", scientific).map_err(of_row)?,
+        };
+        let settle_end = match raw.tx_end {
+            None => None,
+            Some(tx_end) => Some(parse_int(tx_end, "tx_end").map_err(of_row)?),
+        };
+        //a dispute may name the part of the transaction it disputes, and an escrow release the part it releases
+        //resolve and chargeback rows may echo the disputed amount, which is only checked when configured
+        let partial_amount = match (action_type, raw.amount) {
+            (
+                Action::Dispute | Action::Resolve | Action::Chargeback | Action::EscrowRelease,
+                Some(amount),
+            ) => Some(parse_decimal(amount, "amount", scientific).map_err(of_row)?),
+            _ => None,
+        };
+        let counterparty = match raw.to {
+            None => None,
+            Some(to) => Some(parse_int(to, "to").map_err(of_row)?),
+        };
+        let memo = raw
+            .memo
+            .map(|memo| truncate_memo(memo, client_id, transaction_id));
+        match action_type {
+            Action::Deposit
+            | Action::Withdrawal
+            | Action::Hold
+            | Action::Release
+            | Action::Escrow
+            | Action::Adjustment
+            | Action::AccrueInterest
+            | Action::WithdrawalPending
+            | Action::Transfer => {
+                //an empty amount would only give a parse error without context
+                let decimal = match raw.amount {
+                    None => {
+                        return Err(CustomError::MissingAmount {
+                            action: action_type.as_str(),
+                            client: client_id,
+                            tx: transaction_id,
+                        })
+                    }
+                    Some(amount) => parse_decimal(amount, "amount", scientific).map_err(of_row)?,
+                };
+                Ok(Transaction {
+                    action_type,
+                    client_id,
+                    transaction_id,
+                    decimal: Some(decimal),
+                    record_index: 0,
+                    timestamp,
+                    fee,
+                    row_hash: 0,
+                    settle_end,
+                    memo,
+                    line: 0,
+                    raw: None,
+                    counterparty,
+                })
+            }
+            Action::Dispute
+            | Action::Resolve
+            | Action::Chargeback
+            | Action::Lock
+            | Action::Unlock
+            | Action::Close
+            | Action::Settle
+            | Action::Reinstate
+            | Action::Reversal
+            | Action::EscrowRelease
+            | Action::Freeze
+            | Action::Unfreeze
+            | Action::WithdrawalSettle
+            | Action::WithdrawalFail
+            | Action::Halt => Ok(Transaction {
+                action_type,
+                client_id,
+                transaction_id,
+                decimal: partial_amount,
+                record_index: 0,
+                timestamp,
+                fee,
+                row_hash: 0,
+                settle_end,
+                memo,
+                line: 0,
+                raw: None,
+                counterparty,
+            }),
+        }
+    }
+
+    /// Saves an audit trail entry to a checkpoint
+    fn encode(&self, state: &mut CheckpointWriter) {
+        state.text(self.action_type.as_str());
+        state.u16(self.client_id);
+        state.u32(self.transaction_id);
+        state.optional_decimal(self.decimal);
+        state.u64(self.record_index);
+        state.optional(self.timestamp);
+        state.decimal(self.fee);
+        state.u64(self.row_hash);
+        state.optional(self.settle_end.map(u64::from));
+        state.optional_text(self.memo.as_deref());
+        state.u64(self.line);
+        state.optional_text(self.raw.as_deref());
+        state.optional(self.counterparty.map(u64::from));
+    }
+
+    /// Restores an audit trail entry saved by encode
+    fn decode(state: &mut CheckpointReader) -> Result<Self, CustomError> {
+        Ok(Self {
+            action_type: state.action()?,
+            client_id: state.u16()?,
+            transaction_id: state.u32()?,
+            decimal: state.optional_decimal()?,
+            record_index: state.u64()?,
+            timestamp: state.optional()?,
+            fee: state.decimal()?,
+            row_hash: state.u64()?,
+            settle_end: state.optional()?.map(|end| end as TransactionId),
+            memo: state.optional_text()?,
+            line: state.u64()?,
+            raw: state.optional_text()?,
+            counterparty: state.optional()?.map(|client| client as ClientId),
+        })
+    }
+
+    /// Identifying fields kept for reporting once the transaction has been consumed
+    fn row(&self) -> Row {
+        Row {
+            action_type: self.action_type,
+            client_id: self.client_id,
+            transaction_id: self.transaction_id,
+            amount: self.decimal,
+            memo: self.memo.clone(),
+            line: self.line,
+            raw: self.raw.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Rounds the amount and the fee half up to PRECISION decimal places, or refuses them when strict
+    /// Trailing zeros beyond the precision are not excess, interest rates are not amounts
+    fn round_to_precision(&mut self, strict: bool) -> Result<(), CustomError> {
+        if let Action::AccrueInterest = self.action_type {
+            return Ok(());
+        }
+        for (column, value) in [
+            ("amount", self.decimal.as_mut()),
+            ("fee", Some(&mut self.fee)),
+        ] {
+            let value = match value {
+                Some(value) if value.scale() > PRECISION => value,
+                _ => continue,
+            };
+            let rounded =
+                value.round_dp_with_strategy(PRECISION, RoundingStrategy::MidpointAwayFromZero);
+            if rounded == *value {
+                continue;
+            }
+            if strict {
+                return Err(CustomError::ExcessPrecision {
+                    column,
+                    value: *value,
+                    precision: PRECISION,
+                });
+            }
+            debug!(
+                "Client id: {}, with transaction_id: {} had {} {} rounded to {}",
+                self.client_id, self.transaction_id, column, value, rounded
+            );
+            *value = rounded;
+        }
+        Ok(())
+    }
+
+    /// Details of this transaction for a later row that reuses its id
+    fn original(&self) -> OriginalTransaction {
+        OriginalTransaction {
+            action: self.action_type.as_str(),
+            client: self.client_id,
+            amount: self.decimal,
+            record_index: self.record_index,
+        }
+    }
+
+    /// What an account keeps of this deposit, withdrawal, adjustment or received transfer
+    fn stored(&self) -> DetachedTransaction {
+        let mut stored = DetachedTransaction {
+            record: StoredTransaction::new(self.record_index, self.action_type),
+            details: None,
+        };
+        stored.set_amounts(self.decimal.unwrap(), self.fee);
+        stored.set_counterparty(self.counterparty);
+        stored.set_timestamp(self.timestamp);
+        stored
+    }
+
+    fn get_action_type(&self) -> Action {
+        self.action_type
+    }
+
+    fn get_client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    /// Transaction made by the engine rather than read from the input, such as an interest posting
+    fn synthetic(
+        action_type: Action,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        decimal: Decimal,
+    ) -> Self {
+        Self {
+            action_type,
+            client_id,
+            transaction_id,
+            decimal: Some(decimal),
+            record_index: 0,
+            timestamp: None,
+            fee: Decimal::new(0, PRECISION),
+            row_hash: 0,
+            settle_end: None,
+            memo: None,
+            line: 0,
+            raw: None,
+            counterparty: None,
+        }
+    }
+
+    /// Only for testing and debugging purpose
+    fn _new(
+        action_type: Action,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        decimal: Option<Decimal>,
+    ) -> Self {
+        Self {
+            action_type,
+            client_id,
+            transaction_id,
+            decimal,
+            record_index: 0,
+            timestamp: None,
+            fee: Decimal::new(0, PRECISION),
+            row_hash: hash_fields(
+                [
+                    action_type.as_str(),
+                    &client_id.to_string(),
+                    &transaction_id.to_string(),
+                ]
+                .into_iter(),
+            ),
+            settle_end: None,
+            memo: None,
+            line: 0,
+            raw: None,
+            counterparty: None,
+        }
+    }
+}
+
+/// What an account keeps of a deposit, withdrawal, adjustment or received transfer
+/// The client and the id are known from the account and its map, memo and raw text were reported with the row
+/// Packed into 48 bytes: the states and which optional fields are set share one byte, amounts are kept as the
+/// integer of their scale, and what most transactions never need is kept in the slab of the account
+#[derive(Debug)]
+struct StoredTransaction {
+    /// 1-based position of the row in the input, excluding the header
+    record_index: u64,
+    /// Only set when the flags say so
+    timestamp: u64,
+    /// Mantissa of the amount at amount_scale, unless the amount is too large and kept in details
+    amount: i64,
+    /// Mantissa of the fee at fee_scale, unless the fee is too large and kept in details
+    fee: i64,
+    /// Slot of the last dispute and amounts too large for an i64 in the slab of the account, NO_DETAILS until
+    /// they are needed as most transactions never are
+    details: SlabIndex,
+    /// Sender of a received transfer, only set when the flags say so
+    counterparty: ClientId,
+    action_type: Action,
+    /// Dispute state in the low 3 bits, then payout state in 2 bits, whether it is settled, and whether the
+    /// counterparty and the timestamp are set
+    flags: u8,
+    /// Scale of the amount, LARGE_SCALE when it is in details
+    amount_scale: u8,
+    fee_scale: u8,
+}
+
+/// Scale of an amount kept whole in the details of its stored transaction
+const LARGE_SCALE: u8 = u8::MAX;
+/// Index of details in the slab of their account, kept to 4 bytes so records stay small
+type SlabIndex = u32;
+
+/// Slot of a stored transaction without details
+const NO_DETAILS: SlabIndex = SlabIndex::MAX;
+const DISPUTE_STATE_BITS: u8 = 0b111;
+const PAYOUT_STATE_SHIFT: u8 = 3;
+const PAYOUT_STATE_BITS: u8 = 0b11 << PAYOUT_STATE_SHIFT;
+const SETTLED_FLAG: u8 = 1 << 5;
+const COUNTERPARTY_FLAG: u8 = 1 << 6;
+const TIMESTAMP_FLAG: u8 = 1 << 7;
+
+/// Parts of a stored transaction most never need
+#[derive(Debug, Default)]
+struct StoredDetails {
+    /// Last dispute of the transaction, once it is disputed
+    dispute: Option<DisputeRecord>,
+    /// Amount whose mantissa does not fit an i64
+    amount: Option<Decimal>,
+    /// Fee whose mantissa does not fit an i64
+    fee: Option<Decimal>,
+}
+
+/// Mantissa and scale of the decimal, None when the mantissa does not fit an i64
+fn compact(value: Decimal) -> Option<(i64, u8)> {
+    Some((i64::try_from(value.mantissa()).ok()?, value.scale() as u8))
+}
+
+/// Last dispute opened on a stored transaction
+#[derive(Debug)]
+struct DisputeRecord {
+    /// Number of times the transaction has been disputed
+    /// A resolved transaction can be disputed again, which increments this counter
+    count: u32,
+    /// Position of the row that opened the dispute, used to find stale disputes
+    record_index: u64,
+    /// Timestamp of the row that opened the dispute
+    timestamp: Option<u64>,
+    /// Part of the amount held by the dispute, the whole amount unless the dispute row named a smaller one
+    amount: Decimal,
+    /// row_hash of the last dispute, resolve or chargeback applied to the transaction
+    /// A row identical to it is a replay and is ignored
+    last_row: Option<u64>,
+}
+
+impl StoredTransaction {
+    /// Record without amounts, details or optional fields, which the caller sets
+    fn new(record_index: u64, action_type: Action) -> Self {
+        Self {
+            record_index,
+            timestamp: 0,
+            amount: 0,
+            fee: 0,
+            details: NO_DETAILS,
+            counterparty: 0,
+            action_type,
+            flags: 0,
+            amount_scale: 0,
+            fee_scale: 0,
+        }
+    }
+
+    fn dispute_state(&self) -> DisputeState {
+        DisputeState::from_code(self.flags & DISPUTE_STATE_BITS)
+    }
+
+    fn set_dispute_state(&mut self, state: DisputeState) {
+        self.flags = self.flags & !DISPUTE_STATE_BITS | state.code();
+    }
+
+    /// Where a two-phase withdrawal stands with the payout rail, None for every other transaction
+    fn payout_state(&self) -> Option<PayoutState> {
+        PayoutState::from_code((self.flags & PAYOUT_STATE_BITS) >> PAYOUT_STATE_SHIFT)
+    }
+
+    fn set_payout_state(&mut self, state: Option<PayoutState>) {
+        self.flags =
+            self.flags & !PAYOUT_STATE_BITS | PayoutState::code(state) << PAYOUT_STATE_SHIFT;
+    }
+
+    /// Settled transactions can no longer be disputed
+    fn is_settled(&self) -> bool {
+        self.flags & SETTLED_FLAG != 0
+    }
+
+    fn settle(&mut self) {
+        self.flags |= SETTLED_FLAG;
+    }
+
+    /// Sender of a received transfer
+    fn counterparty(&self) -> Option<ClientId> {
+        (self.flags & COUNTERPARTY_FLAG != 0).then_some(self.counterparty)
+    }
+
+    fn set_counterparty(&mut self, counterparty: Option<ClientId>) {
+        self.counterparty = counterparty.unwrap_or_default();
+        self.flags = self.flags & !COUNTERPARTY_FLAG
+            | if counterparty.is_some() {
+                COUNTERPARTY_FLAG
+            } else {
+                0
+            };
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        (self.flags & TIMESTAMP_FLAG != 0).then_some(self.timestamp)
+    }
+
+    fn set_timestamp(&mut self, timestamp: Option<u64>) {
+        self.timestamp = timestamp.unwrap_or_default();
+        self.flags = self.flags & !TIMESTAMP_FLAG
+            | if timestamp.is_some() {
+                TIMESTAMP_FLAG
+            } else {
+                0
+            };
+    }
+
+    /// Kind of a deposit, withdrawal or received transfer, which the dispute policy may let be disputed
+    /// A two-phase withdrawal only once it is settled, before that no money has left
+    fn dispute_kind(&self) -> Option<DisputedKind> {
+        match self.action_type {
+            Action::Deposit => Some(DisputedKind::Deposit),
+            Action::Transfer => Some(DisputedKind::Transfer),
+            Action::Withdrawal
+                if matches!(self.payout_state(), None | Some(PayoutState::Settled)) =>
+            {
+                Some(DisputedKind::Withdrawal)
+            }
+            _ => None,
+        }
+    }
+
+    /// What the dispute policy sees of this transaction, disputed for the given amount
+    fn dispute_view(&self, disputed_amount: Decimal) -> Option<TransactionView> {
+        Some(TransactionView {
+            kind: self.dispute_kind()?,
+            disputed_amount,
+        })
+    }
+
+    fn is_under_dispute(&self) -> bool {
+        self.dispute_state() == DisputeState::Disputed
+    }
+
+    fn is_payout_pending(&self) -> bool {
+        self.payout_state() == Some(PayoutState::Pending)
+    }
+
+    /// Whether the dispute comes too late for this transaction
+    /// A dispute exactly at the edge of the window is still accepted
+    fn is_outside_window(&self, window: &Window, dispute: &Transaction) -> bool {
+        match (
+            window.position(self.record_index, self.timestamp()),
+            window.position(dispute.record_index, dispute.timestamp),
+        ) {
+            (Some(original), Some(dispute)) => dispute.saturating_sub(original) > window.length(),
+            _ => false,
+        }
+    }
+}
+
+/// Reads of a stored transaction that may need its details, for the forms it is handed out in
+trait Detailed: Deref<Target = StoredTransaction> {
+    fn details(&self) -> Option<&StoredDetails>;
+
+    fn amount(&self) -> Decimal {
+        match self.amount_scale {
+            LARGE_SCALE => self.details().and_then(|details| details.amount).unwrap(),
+            scale => Decimal::new(self.amount, u32::from(scale)),
+        }
+    }
+
+    fn fee(&self) -> Decimal {
+        match self.fee_scale {
+            LARGE_SCALE => self.details().and_then(|details| details.fee).unwrap(),
+            scale => Decimal::new(self.fee, u32::from(scale)),
+        }
+    }
+
+    /// Last dispute of the transaction, None until it is disputed
+    fn dispute(&self) -> Option<&DisputeRecord> {
+        self.details()?.dispute.as_ref()
+    }
+
+    fn dispute_count(&self) -> u32 {
+        self.dispute().map_or(0, |dispute| dispute.count)
+    }
+
+    /// Part of the amount held by the last dispute, zero if it was never disputed
+    fn disputed_amount(&self) -> Decimal {
+        self.dispute()
+            .map_or(Decimal::new(0, PRECISION), |dispute| dispute.amount)
+    }
+
+    fn last_lifecycle_row(&self) -> Option<u64> {
+        self.dispute().and_then(|dispute| dispute.last_row)
+    }
+
+    /// Details of this transaction for a later row that reuses its id
+    fn original(&self, client: ClientId) -> OriginalTransaction {
+        OriginalTransaction {
+            action: self.action_type.as_str(),
+            client,
+            amount: Some(self.amount()),
+            record_index: self.record_index,
+        }
+    }
+
+    /// Record of the spill file, SPILL_RECORD bytes whatever the optional fields hold
+    fn encode(&self) -> Vec<u8> {
+        let mut record = Vec::with_capacity(SPILL_RECORD);
+        let optional = |record: &mut Vec<u8>, value: Option<u64>| {
+            record.push(value.is_some() as u8);
+            record.extend_from_slice(&value.unwrap_or_default().to_le_bytes());
+        };
+        record.push(match self.action_type {
+            Action::Deposit => 0,
+            Action::Withdrawal => 1,
+            Action::Adjustment => 2,
+            Action::Transfer => 3,
+            action => unreachable!("{} is never stored", action.as_str()),
+        });
+        record.push(self.dispute_state().code());
+        record.push(PayoutState::code(self.payout_state()));
+        record.push(self.is_settled() as u8);
+        record.push(self.counterparty().is_some() as u8);
+        record.extend_from_slice(&self.counterparty.to_le_bytes());
+        record.extend_from_slice(&self.amount().serialize());
+        record.extend_from_slice(&self.fee().serialize());
+        record.extend_from_slice(&self.record_index.to_le_bytes());
+        optional(&mut record, self.timestamp());
+        record.push(self.dispute().is_some() as u8);
+        let empty = DisputeRecord {
+            count: 0,
+            record_index: 0,
+            timestamp: None,
+            amount: Decimal::ZERO,
+            last_row: None,
+        };
+        let dispute = self.dispute().unwrap_or(&empty);
+        record.extend_from_slice(&dispute.count.to_le_bytes());
+        record.extend_from_slice(&dispute.record_index.to_le_bytes());
+        optional(&mut record, dispute.timestamp);
+        record.extend_from_slice(&dispute.amount.serialize());
+        optional(&mut record, dispute.last_row);
+        debug_assert_eq!(record.len(), SPILL_RECORD);
+        record
+    }
+}
+
+/// Stored transaction outside of any account, holding its own details
+/// Made from a row, or read back from the spill file or a checkpoint, before an account takes it
+#[derive(Debug)]
+struct DetachedTransaction {
+    record: StoredTransaction,
+    details: Option<StoredDetails>,
+}
+
+impl Deref for DetachedTransaction {
+    type Target = StoredTransaction;
+
+    fn deref(&self) -> &StoredTransaction {
+        &self.record
+    }
+}
+
+impl DerefMut for DetachedTransaction {
+    fn deref_mut(&mut self) -> &mut StoredTransaction {
+        &mut self.record
+    }
+}
+
+impl Detailed for DetachedTransaction {
+    fn details(&self) -> Option<&StoredDetails> {
+        self.details.as_ref()
+    }
+}
+
+impl DetachedTransaction {
+    /// Keeps the amount and fee as mantissas, or whole in the details when they do not fit
+    fn set_amounts(&mut self, amount: Decimal, fee: Decimal) {
+        (self.record.amount, self.record.amount_scale) = compact(amount).unwrap_or_else(|| {
+            self.details.get_or_insert_default().amount = Some(amount);
+            (0, LARGE_SCALE)
+        });
+        (self.record.fee, self.record.fee_scale) = compact(fee).unwrap_or_else(|| {
+            self.details.get_or_insert_default().fee = Some(fee);
+            (0, LARGE_SCALE)
+        });
+    }
+
+    /// Reads back a record written by encode
+    fn decode(record: &[u8; SPILL_RECORD]) -> Self {
+        let mut record = SpillRecord {
+            record,
+            position: 0,
+        };
+        let action_type = match record.byte() {
+            0 => Action::Deposit,
+            1 => Action::Withdrawal,
+            2 => Action::Adjustment,
+            _ => Action::Transfer,
+        };
+        let dispute_state = DisputeState::from_code(record.byte());
+        let payout_state = PayoutState::from_code(record.byte());
+        let is_settled = record.byte() == 1;
+        let has_counterparty = record.byte() == 1;
+        let counterparty = ClientId::from_le_bytes(record.take());
+        let amount = Decimal::deserialize(record.take());
+        let fee = Decimal::deserialize(record.take());
+        let record_index = u64::from_le_bytes(record.take());
+        let timestamp = record.optional();
+        let has_dispute = record.byte() == 1;
+        let dispute = DisputeRecord {
+            count: u32::from_le_bytes(record.take()),
+            record_index: u64::from_le_bytes(record.take()),
+            timestamp: record.optional(),
+            amount: Decimal::deserialize(record.take()),
+            last_row: record.optional(),
+        };
+        let mut stored = Self {
+            record: StoredTransaction::new(record_index, action_type),
+            details: None,
+        };
+        stored.set_amounts(amount, fee);
+        stored.set_dispute_state(dispute_state);
+        stored.set_payout_state(payout_state);
+        if is_settled {
+            stored.settle();
+        }
+        stored.set_counterparty(has_counterparty.then_some(counterparty));
+        stored.set_timestamp(timestamp);
+        if has_dispute {
+            stored.details.get_or_insert_default().dispute = Some(dispute);
+        }
+        stored
+    }
+}
+
+/// Stored transactions of an account, with the details of those that need any in a slab beside the table
+/// A record names its details by their index in the slab, so no record holds an allocation of its own: storing
+/// one is a write into the table, and dropping the account frees the table and the slab without visiting a record
+#[derive(Debug, Default)]
+struct StoredTransactions {
+    records: IdMap<TransactionId, StoredTransaction>,
+    details: Slab<StoredDetails>,
+}
+
+impl StoredTransactions {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            records: IdMap::with_capacity_and_hasher(capacity, Default::default()),
+            details: Slab::default(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    #[cfg(test)]
+    fn capacity(&self) -> usize {
+        self.records.capacity()
+    }
+
+    fn contains_key(&self, transaction_id: &TransactionId) -> bool {
+        self.records.contains_key(transaction_id)
+    }
+
+    fn get(&self, transaction_id: &TransactionId) -> Option<Stored<'_>> {
+        let record = self.records.get(transaction_id)?;
+        Some(Stored {
+            record,
+            details: (record.details != NO_DETAILS).then(|| &self.details[record.details as usize]),
+        })
+    }
+
+    fn get_mut(&mut self, transaction_id: &TransactionId) -> Option<StoredMut<'_>> {
+        Some(StoredMut {
+            record: self.records.get_mut(transaction_id)?,
+            slab: &mut self.details,
+        })
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&TransactionId, Stored<'_>)> {
+        self.records.iter().map(|(transaction_id, record)| {
+            let details =
+                (record.details != NO_DETAILS).then(|| &self.details[record.details as usize]);
+            (transaction_id, Stored { record, details })
+        })
+    }
+
+    /// Takes the transaction in, moving its details to the slab
+    fn insert(&mut self, transaction_id: TransactionId, transaction: DetachedTransaction) {
+        let DetachedTransaction {
+            mut record,
+            details,
+        } = transaction;
+        if let Some(details) = details {
+            record.details = self.details.insert(details) as SlabIndex;
+        }
+        if let Some(replaced) = self.records.insert(transaction_id, record) {
+            if replaced.details != NO_DETAILS {
+                self.details.remove(replaced.details as usize);
+            }
+        }
+    }
+
+    /// Hands the transaction out with its details, freeing its slot in the slab
+    fn remove(&mut self, transaction_id: &TransactionId) -> Option<DetachedTransaction> {
+        let mut record = self.records.remove(transaction_id)?;
+        let details =
+            (record.details != NO_DETAILS).then(|| self.details.remove(record.details as usize));
+        record.details = NO_DETAILS;
+        Some(DetachedTransaction { record, details })
+    }
+
+    /// Keeps the transactions keep returns true for, the details of the others are freed
+    fn retain(&mut self, mut keep: impl FnMut(&TransactionId, &mut StoredTransaction) -> bool) {
+        let details = &mut self.details;
+        self.records.retain(|transaction_id, record| {
+            let is_kept = keep(transaction_id, record);
+            if !is_kept && record.details != NO_DETAILS {
+                details.remove(record.details as usize);
+            }
+            is_kept
+        });
+    }
+
+    /// Bytes reserved by the table and the slab
+    fn memory(&self) -> u64 {
+        table_bytes::<(TransactionId, StoredTransaction)>(self.records.capacity())
+            + (self.details.capacity() * std::mem::size_of::<StoredDetails>()) as u64
+    }
+}
+
+/// Records without their details, for checks that only need the states and flags
+impl Index<&TransactionId> for StoredTransactions {
+    type Output = StoredTransaction;
+
+    fn index(&self, transaction_id: &TransactionId) -> &StoredTransaction {
+        &self.records[transaction_id]
+    }
+}
+
+/// Stored transaction kept by an account, with its details
+#[derive(Debug, Clone, Copy)]
+struct Stored<'a> {
+    record: &'a StoredTransaction,
+    details: Option<&'a StoredDetails>,
+}
+
+impl Deref for Stored<'_> {
+    type Target = StoredTransaction;
+
+    fn deref(&self) -> &StoredTransaction {
+        self.record
+    }
+}
+
+impl Detailed for Stored<'_> {
+    fn details(&self) -> Option<&StoredDetails> {
+        self.details
+    }
+}
+
+/// Stored transaction kept by an account, with the slab its details are kept in
+#[derive(Debug)]
+struct StoredMut<'a> {
+    record: &'a mut StoredTransaction,
+    slab: &'a mut Slab<StoredDetails>,
+}
+
+impl Deref for StoredMut<'_> {
+    type Target = StoredTransaction;
+
+    fn deref(&self) -> &StoredTransaction {
+        self.record
+    }
+}
+
+impl DerefMut for StoredMut<'_> {
+    fn deref_mut(&mut self) -> &mut StoredTransaction {
+        self.record
+    }
+}
+
+impl Detailed for StoredMut<'_> {
+    fn details(&self) -> Option<&StoredDetails> {
+        (self.record.details != NO_DETAILS).then(|| &self.slab[self.record.details as usize])
+    }
+}
+
+impl StoredMut<'_> {
+    /// Details of the transaction, taking a slot of the slab the first time
+    fn details_mut(&mut self) -> &mut StoredDetails {
+        if self.record.details == NO_DETAILS {
+            self.record.details = self.slab.insert(StoredDetails::default()) as SlabIndex;
+        }
+        &mut self.slab[self.record.details as usize]
+    }
+
+    /// Records a dispute row holding the given amount
+    fn open_dispute(&mut self, amount: Decimal, dispute: &Transaction) {
+        let count = self.dispute_count() + 1;
+        self.details_mut().dispute = Some(DisputeRecord {
+            count,
+            record_index: dispute.record_index,
+            timestamp: dispute.timestamp,
+            amount,
+            last_row: Some(dispute.row_hash),
+        });
+    }
+
+    /// Records the resolve or chargeback row ending the dispute
+    fn close_dispute(&mut self, row_hash: u64) {
+        if self.record.details == NO_DETAILS {
+            return;
+        }
+        if let Some(dispute) = self.details_mut().dispute.as_mut() {
+            dispute.last_row = Some(row_hash);
+        }
+    }
+}
+
+/// Cursor over a record of the spill file
+struct SpillRecord<'a> {
+    record: &'a [u8; SPILL_RECORD],
+    position: usize,
+}
+
+impl SpillRecord<'_> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        self.position += N;
+        self.record[self.position - N..self.position]
+            .try_into()
+            .unwrap()
+    }
+
+    fn byte(&mut self) -> u8 {
+        self.take::<1>()[0]
+    }
+
+    /// Flag byte followed by the value, which is zero when the flag is not set
+    fn optional(&mut self) -> Option<u64> {
+        let is_some = self.byte() == 1;
+        let value = u64::from_le_bytes(self.take());
+        is_some.then_some(value)
+    }
+}
+
+/// State of a run being saved to a checkpoint, numbers are little endian
+struct CheckpointWriter(Vec<u8>);
+
+impl CheckpointWriter {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn flag(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u64(len as u64);
+    }
+
+    fn decimal(&mut self, value: Decimal) {
+        self.0.extend_from_slice(&value.serialize());
+    }
+
+    /// Flag byte followed by the value, which is zero when the flag is not set
+    fn optional(&mut self, value: Option<u64>) {
+        self.flag(value.is_some());
+        self.u64(value.unwrap_or_default());
+    }
+
+    fn optional_decimal(&mut self, value: Option<Decimal>) {
+        self.flag(value.is_some());
+        self.decimal(value.unwrap_or_default());
+    }
+
+    fn text(&mut self, value: &str) {
+        self.len(value.len());
+        self.0.extend_from_slice(value.as_bytes());
+    }
+
+    fn optional_text(&mut self, value: Option<&str>) {
+        self.flag(value.is_some());
+        self.text(value.unwrap_or_default());
+    }
+}
+
+/// Cursor over the state saved in a checkpoint, running past its end means the checkpoint is damaged
+struct CheckpointReader<'a> {
+    state: &'a [u8],
+    position: usize,
+}
+
+impl CheckpointReader<'_> {
+    fn damaged(&self) -> CustomError {
+        CustomError::Checkpoint {
+            source: std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("state does not read back at byte {}", self.position),
+            ),
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&[u8], CustomError> {
+        if self.state.len() - self.position < len {
+            return Err(self.damaged());
+        }
+        self.position += len;
+        Ok(&self.state[self.position - len..self.position])
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], CustomError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, CustomError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, CustomError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, CustomError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, CustomError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn flag(&mut self) -> Result<bool, CustomError> {
+        Ok(self.u8()? == 1)
+    }
+
+    /// Length of what follows, which cannot be longer than what is left
+    fn len(&mut self) -> Result<usize, CustomError> {
+        let len = self.u64()?;
+        if len > (self.state.len() - self.position) as u64 {
+            return Err(self.damaged());
+        }
+        Ok(len as usize)
+    }
+
+    fn decimal(&mut self) -> Result<Decimal, CustomError> {
+        Ok(Decimal::deserialize(self.take()?))
+    }
+
+    fn optional(&mut self) -> Result<Option<u64>, CustomError> {
+        let is_some = self.flag()?;
+        let value = self.u64()?;
+        Ok(is_some.then_some(value))
+    }
+
+    fn optional_decimal(&mut self) -> Result<Option<Decimal>, CustomError> {
+        let is_some = self.flag()?;
+        let value = self.decimal()?;
+        Ok(is_some.then_some(value))
+    }
+
+    fn text(&mut self) -> Result<String, CustomError> {
+        let len = self.len()?;
+        let bytes = self.bytes(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| self.damaged())
+    }
+
+    fn optional_text(&mut self) -> Result<Option<String>, CustomError> {
+        let is_some = self.flag()?;
+        let value = self.text()?;
+        Ok(is_some.then_some(value))
+    }
+
+    fn action(&mut self) -> Result<Action, CustomError> {
+        Action::from_str(&self.text()?).map_err(|_| self.damaged())
+    }
+
+    /// A rejection reason, one of the labels CustomError::reason gives
+    fn reason(&mut self) -> Result<&'static str, CustomError> {
+        CustomError::reason_named(&self.text()?).ok_or_else(|| self.damaged())
+    }
+}
+
+/// Writes output rows to the sink, each ending with a line break
+/// Returns the number of rows written, or the error naming how many rows got through whole before the sink failed
+async fn write_rows<W: AsyncWrite + Unpin>(
+    sink: &mut W,
+    rows: &str,
+    rows_written: usize,
+) -> Result<usize, CustomError> {
+    let mut rows = rows.as_bytes();
+    let mut written = 0;
+    while !rows.is_empty() {
+        let len = match sink.write(rows).await {
+            Ok(0) => Err(std::io::Error::from(std::io::ErrorKind::WriteZero)),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            result => result,
+        }
+        .map_err(|source| CustomError::OutputWrite {
+            rows_written: rows_written + written,
+            source,
+        })?;
+        written += rows[..len].iter().filter(|byte| **byte == b'\n').count();
+        rows = &rows[len..];
+    }
+    Ok(written)
+}
+
+/// Writes output rows to the sink like write_rows, with blocking calls
+fn write_rows_blocking<W: std::io::Write>(
+    sink: &mut W,
+    rows: &str,
+    rows_written: usize,
+) -> Result<usize, CustomError> {
+    let mut rows = rows.as_bytes();
+    let mut written = 0;
+    while !rows.is_empty() {
+        let len = match sink.write(rows) {
+            Ok(0) => Err(std::io::Error::from(std::io::ErrorKind::WriteZero)),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            result => result,
+        }
+        .map_err(|source| CustomError::OutputWrite {
+            rows_written: rows_written + written,
+            source,
+        })?;
+        written += rows[..len].iter().filter(|byte| **byte == b'\n').count();
+        rows = &rows[len..];
+    }
+    Ok(written)
+}
+
+/// An output amount with exactly PRECISION decimal places,
+/// so equal balances print the same whatever the scale of the rows that made them
+fn output_amount(amount: Decimal) -> Decimal {
+    let mut amount = if amount.is_zero() {
+        //a zero may carry a sign, which would print as -0
+        Decimal::ZERO
+    } else {
+        amount
+    };
+    amount.rescale(PRECISION);
+    amount
+}
+
+/// Parses a decimal field, naming the column and the raw value when it fails
+/// Exporters differ, so an optional sign, digits and at most one point are accepted, with digits on
+/// either side of the point or both: `.5`, `1.` and `+1.0` are fine
+/// Exponent notation such as `1e2` is refused with its own error unless scientific is set, and digit separators
+/// such as `1_000` are refused too, though rust_decimal would take them
+fn parse_decimal(
+    value: &str,
+    column: &'static str,
+    scientific: bool,
+) -> Result<Decimal, CustomError> {
+    if let Some(decimal) = parse_plain_decimal(value.as_bytes()) {
+        return Ok(decimal);
+    }
+    let digits = value.strip_prefix(['+', '-']).unwrap_or(value);
+    let invalid = |source| CustomError::InvalidDecimal {
+        column,
+        value: value.to_string(),
+        action: None,
+        source,
+    };
+    if let Some((mantissa, exponent)) = digits.split_once(['e', 'E']) {
+        if !scientific {
+            return Err(CustomError::ExponentNotation {
+                column,
+                value: value.to_string(),
+                action: None,
+            });
+        }
+        //the mantissa follows the rules of a plain amount, the exponent is a signed integer
+        let exponent = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+        if !mantissa.chars().all(|c| c.is_ascii_digit() || c == '.')
+            || exponent.is_empty()
+            || !exponent.chars().all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid(rust_decimal::Error::ErrorString(String::from(
+                "Invalid decimal: unknown character",
+            ))));
+        }
+        return Decimal::from_scientific(value).map_err(invalid);
+    }
+    if !digits.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return Err(invalid(rust_decimal::Error::ErrorString(String::from(
+            "Invalid decimal: unknown character",
+        ))));
+    }
+    Decimal::from_str(value).map_err(invalid)
+}
+
+/// Largest mantissa a Decimal holds, 96 bits
+const MAX_MANTISSA: u128 = (1 << 96) - 1;
+
+/// Reads a plain amount straight from the bytes: an optional sign, digits and at most one point, with digits
+/// on either side of the point or both, keeping the scale as written and dropping the sign of zero like
+/// Decimal::from_str does
+/// Digits past 96 bits or past a scale of 28, which from_str rounds or refuses, and anything that is not a plain
+/// amount are None and left to the full parse
+fn parse_plain_decimal(value: &[u8]) -> Option<Decimal> {
+    let (negative, value) = match value.split_first()? {
+        (b'-', rest) => (true, rest),
+        (b'+', rest) => (false, rest),
+        _ => (false, value),
+    };
+    //no point is a point past the end, so the scale comes out as zero
+    let mut point = value.len();
+    //19 digits always fit a u64, most amounts are read without the cost of u128 arithmetic or overflow checks
+    let mantissa = if value.len() <= 19 {
+        let mut mantissa = 0u64;
+        for (index, byte) in value.iter().enumerate() {
+            let digit = byte.wrapping_sub(b'0');
+            if digit < 10 {
+                mantissa = mantissa * 10 + u64::from(digit);
+            } else if *byte == b'.' && point == value.len() {
+                point = index;
+            } else {
+                return None;
+            }
+        }
+        u128::from(mantissa)
+    } else {
+        let mut mantissa = 0u128;
+        for (index, byte) in value.iter().enumerate() {
+            let digit = byte.wrapping_sub(b'0');
+            if digit < 10 {
+                mantissa = mantissa * 10 + u128::from(digit);
+                if mantissa > MAX_MANTISSA {
+                    return None;
+                }
+            } else if *byte == b'.' && point == value.len() {
+                point = index;
+            } else {
+                return None;
+            }
+        }
+        mantissa
+    };
+    let scale = value.len().saturating_sub(point + 1);
+    if value.len() == usize::from(point < value.len()) || scale > 28 {
+        return None;
+    }
+    Some(Decimal::from_parts(
+        mantissa as u32,
+        (mantissa >> 32) as u32,
+        (mantissa >> 64) as u32,
+        negative && mantissa != 0,
+        scale as u32,
+    ))
+}
+
+/// Unsigned integer types of the id and timestamp columns, with the largest value they hold
+trait IntColumn: FromStr<Err = ParseIntError> + TryFrom<u64> {
+    const MAX: u64;
+}
+
+impl IntColumn for u16 {
+    const MAX: u64 = u16::MAX as u64;
+}
+
+impl IntColumn for u32 {
+    const MAX: u64 = u32::MAX as u64;
+}
+
+impl IntColumn for u64 {
+    const MAX: u64 = u64::MAX;
+}
+
+/// Parses an integer field, naming the column and the raw value when it fails
+/// and the allowed range when the value does not fit, zero is a valid id
+fn parse_int<T: IntColumn>(value: &str, column: &'static str) -> Result<T, CustomError> {
+    //plain digits are read straight from the bytes, anything else is left to from_str for its error
+    if let Some(value) = parse_digits(value.as_bytes()).and_then(|value| T::try_from(value).ok()) {
+        return Ok(value);
+    }
+    T::from_str(value).map_err(|source| match source.kind() {
+        IntErrorKind::PosOverflow => CustomError::IntOutOfRange {
+            column,
+            value: value.to_string(),
+            action: None,
+            max: T::MAX,
+        },
+        _ => CustomError::InvalidInt {
+            column,
+            value: value.to_string(),
+            action: None,
+            source,
+        },
+    })
+}
+
+/// Reads ASCII digits as an integer, None when there are none, anything else or too many
+fn parse_digits(value: &[u8]) -> Option<u64> {
+    if value.is_empty() {
+        return None;
+    }
+    value.iter().try_fold(0u64, |parsed, byte| {
+        let digit = byte.checked_sub(b'0').filter(|digit| *digit < 10)?;
+        parsed.checked_mul(10)?.checked_add(u64::from(digit))
+    })
+}
+
+/// Cuts a memo down to MEMO_LIMIT bytes, on a character boundary
+fn truncate_memo(memo: &str, client_id: ClientId, transaction_id: TransactionId) -> String {
+    if memo.len() > MEMO_LIMIT {
+        warn!(
+            "Client id: {}, with transaction_id: {} has a memo longer than {} bytes, it is truncated",
+            client_id, transaction_id, MEMO_LIMIT
+        );
+    }
+    truncate(memo, MEMO_LIMIT)
+}
+
+/// Cuts text down to limit bytes, on a character boundary
+fn truncate(text: &str, limit: usize) -> String {
+    if text.len() <= limit {
+        return text.to_string();
+    }
+    let end = (0..=limit)
+        .rev()
+        .find(|index| text.is_char_boundary(*index))
+        .unwrap_or_default();
+    text[..end].to_string()
+}
+
+/// Hashes the fields of a row, so identical rows can be recognized without keeping them
+fn hash_fields<'a>(fields: impl Iterator<Item = &'a str>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for field in fields {
+        field.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Completed chargeback, kept so auditors can tell which transaction locked the account and when
+#[derive(Debug)]
+struct Chargeback {
+    transaction_id: TransactionId,
+    /// Action of the charged back transaction
+    action_type: Action,
+    /// Gross amount of the charged back transaction
+    amount: Decimal,
+    /// Position of the chargeback row in the input
+    record_index: u64,
+    /// Timestamp of the chargeback row, when the input has a timestamp column
+    timestamp: Option<u64>,
+    /// Memo of the chargeback row, such as a dispute case number
+    memo: Option<String>,
+    /// Position of the Reinstate row that overturned this chargeback
+    /// Reinstated chargebacks are kept so the audit trail stays complete
+    reinstated_at: Option<u64>,
+}
+
+/// Applied adjustment, kept with its memo for the audit trail since the stored transaction has none
+#[derive(Debug)]
+struct Adjustment {
+    transaction_id: TransactionId,
+    /// Signed amount added to available and total
+    amount: Decimal,
+    /// Position of the adjustment row in the input
+    record_index: u64,
+    /// Timestamp of the adjustment row, when the input has a timestamp column
+    timestamp: Option<u64>,
+    /// Memo of the adjustment row, such as a ticket number
+    memo: Option<String>,
+}
+
+/// Running dispute counters of an account, fed to downstream risk scoring
+/// Charged back disputes are counted by the chargeback history
+#[derive(Debug)]
+struct DisputeStats {
+    /// Disputes applied, counting every re-dispute
+    opened: u32,
+    /// Disputes resolved by a resolve row or auto-resolved
+    resolved: u32,
+    /// Sum of the amounts held by all disputes, partial disputes count their portion
+    disputed_amount: Decimal,
+}
+
+impl DisputeStats {
+    fn new() -> Self {
+        Self {
+            opened: 0,
+            resolved: 0,
+            disputed_amount: Decimal::new(0, PRECISION),
+        }
+    }
+}
+
+/// Account represents status of individual client
+#[derive(Debug)]
+struct Account {
+    client_id: ClientId,
+    /// Deposits, adjustments and received transfers, with their dispute state, and withdrawals while the dispute
+    /// policy can dispute them or their payout is pending, stored as a withdrawal with its payout state
+    /// Disputes, resolves and chargebacks only change the state of the transaction they name, escrows are kept in
+    /// escrows and the other rows in admin_actions
+    /// Withdrawals that can never be disputed are only kept in kept_ids, and settled transactions are dropped
+    /// Transaction number is unique
+    transactions: StoredTransactions,
+    /// Ids of transactions kept only so they cannot be reused: withdrawals when the dispute policy never disputes
+    /// them, and with --two-pass deposits no later row refers to
+    /// A dispute, resolve or chargeback naming one is rejected without knowing its amount
+    kept_ids: IncreasingIds,
+    /// Transactions moved to the spill file, read back into transactions when a row refers to them
+    /// Only transactions that hold no funds are moved, so they are never under dispute or pending
+    spilled: IdMap<TransactionId, SpillSlot>,
+    /// Administrative rows (lock, unlock, freeze, unfreeze, close, settle, hold, release, reinstate), reversals, escrows,
+    /// interest postings, the outcomes of pending withdrawals and sent transfers
+    /// in the order they were applied, kept for the audit trail
+    /// They are kept apart from transactions so that they can never be disputed
+    admin_actions: Vec<Transaction>,
+    /// is_locked is set to true if chargeback takes place or by a Lock action
+    /// A locked account rejects deposit and withdrawal, but still processes dispute, resolve and chargeback
+    is_locked: bool,
+    /// Set when the lock originated from a chargeback, which an Unlock action cannot clear by default
+    is_locked_by_chargeback: bool,
+    /// Set by a Lock action and cleared by Unlock only, so reinstating chargebacks leaves it in place
+    is_locked_by_admin: bool,
+    /// is_frozen is set by a Freeze action and cleared by Unfreeze, independently of is_locked
+    /// A frozen account rejects withdrawal only
+    is_frozen: bool,
+    /// is_closed is set by a Close action and cannot be undone
+    /// A closed account rejects deposit and withdrawal, but still processes dispute, resolve and chargeback
+    is_closed: bool,
+    /// Set when a Close action waits for held funds to be settled before closing the account
+    is_close_pending: bool,
+    /// Set when the balances stopped adding up, the account then takes no more rows and is left out of the output
+    is_quarantined: bool,
+    /// The total funds that are available for trading, staking, withdrawal, etc. This should be equal to the (total - held)
+    available: Decimal,
+    /// The total funds that are held for dispute. This should be equal to (total - available)
+    held: Decimal,
+    /// The total funds that are available or held. This should be equal to (available - held)
+    total: Decimal,
+    /// Fees charged on deposits and withdrawals, net of refunded ones
+    fees: Decimal,
+    /// Part of held put there by Hold actions, kept apart so dispute holds are unaffected
+    admin_held: Decimal,
+    /// Funds still kept by each Escrow action, also part of held
+    /// Fully released escrows stay at zero so releasing them again is rejected
+    escrows: IdMap<TransactionId, Decimal>,
+    /// Chargebacks applied to this account, oldest first
+    chargebacks: Vec<Chargeback>,
+    /// Adjustments applied to this account, oldest first
+    adjustments: Vec<Adjustment>,
+    /// Dispute counters, shown in the extra columns
+    dispute_stats: DisputeStats,
+    /// Ranges of transaction ids settled by Settle rows, the transactions themselves are dropped
+    settled_ranges: Vec<(TransactionId, TransactionId)>,
+    /// Deposits and withdrawals rejected because the account reached the transaction cap
+    rejected_over_cap: u64,
+    /// Positions of the latest withdrawals inside the velocity window, oldest first
+    /// Holds at most the velocity limit and stays unallocated for clients that never withdraw
+    recent_withdrawals: VecDeque<u64>,
+    /// Position of the row that first named this client, to tell rows of a client never seen before
+    /// None for accounts made outside a run
+    opened_at: Option<u64>,
+}
+
+impl Account {
+    /// Saves the account to a checkpoint, it keeps nothing in the spill file as checkpoints refuse one
+    fn encode(&self, state: &mut CheckpointWriter) {
+        state.u16(self.client_id);
+        state.len(self.transactions.len());
+        for (transaction_id, transaction) in self.transactions.iter() {
+            state.u32(*transaction_id);
+            state.0.extend_from_slice(&transaction.encode());
+        }
+        state.len(self.kept_ids.len());
+        for transaction_id in self.kept_ids.iter() {
+            state.u32(transaction_id);
+        }
+        state.len(self.admin_actions.len());
+        for transaction in &self.admin_actions {
+            transaction.encode(state);
+        }
+        for flag in [
+            self.is_locked,
+            self.is_locked_by_chargeback,
+            self.is_locked_by_admin,
+            self.is_frozen,
+            self.is_closed,
+            self.is_close_pending,
+            self.is_quarantined,
+        ] {
+            state.flag(flag);
+        }
+        for amount in [
+            self.available,
+            self.held,
+            self.total,
+            self.fees,
+            self.admin_held,
+        ] {
+            state.decimal(amount);
+        }
+        state.len(self.escrows.len());
+        for (transaction_id, amount) in &self.escrows {
+            state.u32(*transaction_id);
+            state.decimal(*amount);
+        }
+        state.len(self.chargebacks.len());
+        for chargeback in &self.chargebacks {
+            state.u32(chargeback.transaction_id);
+            state.text(chargeback.action_type.as_str());
+            state.decimal(chargeback.amount);
+            state.u64(chargeback.record_index);
+            state.optional(chargeback.timestamp);
+            state.optional_text(chargeback.memo.as_deref());
+            state.optional(chargeback.reinstated_at);
+        }
+        state.u32(self.dispute_stats.opened);
+        state.u32(self.dispute_stats.resolved);
+        state.decimal(self.dispute_stats.disputed_amount);
+        state.len(self.settled_ranges.len());
+        for (first, last) in &self.settled_ranges {
+            state.u32(*first);
+            state.u32(*last);
+        }
+        state.u64(self.rejected_over_cap);
+        state.len(self.recent_withdrawals.len());
+        for record_index in &self.recent_withdrawals {
+            state.u64(*record_index);
+        }
+        state.optional(self.opened_at);
+        state.len(self.adjustments.len());
+        for adjustment in &self.adjustments {
+            state.u32(adjustment.transaction_id);
+            state.decimal(adjustment.amount);
+            state.u64(adjustment.record_index);
+            state.optional(adjustment.timestamp);
+            state.optional_text(adjustment.memo.as_deref());
+        }
+    }
+
+    /// Approximate bytes held by the account beyond its entry in the table of accounts
+    fn memory(&self) -> u64 {
+        self.transactions.memory()
+            + self.kept_ids.memory()
+            + table_bytes::<(TransactionId, SpillSlot)>(self.spilled.capacity())
+            + table_bytes::<(TransactionId, Decimal)>(self.escrows.capacity())
+            + (self.admin_actions.capacity() * std::mem::size_of::<Transaction>()
+                + self.chargebacks.capacity() * std::mem::size_of::<Chargeback>()
+                + self.adjustments.capacity() * std::mem::size_of::<Adjustment>()
+                + self.settled_ranges.capacity()
+                    * std::mem::size_of::<(TransactionId, TransactionId)>()
+                + self.recent_withdrawals.capacity() * std::mem::size_of::<u64>())
+                as u64
+    }
+
+    /// Restores an account saved by encode
+    fn decode(state: &mut CheckpointReader) -> Result<Self, CustomError> {
+        let mut account = Account::new(state.u16()?);
+        for _ in 0..state.len()? {
+            let transaction_id = state.u32()?;
+            let transaction = DetachedTransaction::decode(&state.take()?);
+            account.transactions.insert(transaction_id, transaction);
+        }
+        for _ in 0..state.len()? {
+            account.kept_ids.insert(state.u32()?);
+        }
+        for _ in 0..state.len()? {
+            account.admin_actions.push(Transaction::decode(state)?);
+        }
+        for flag in [
+            &mut account.is_locked,
+            &mut account.is_locked_by_chargeback,
+            &mut account.is_locked_by_admin,
+            &mut account.is_frozen,
+            &mut account.is_closed,
+            &mut account.is_close_pending,
+            &mut account.is_quarantined,
+        ] {
+            *flag = state.flag()?;
+        }
+        for amount in [
+            &mut account.available,
+            &mut account.held,
+            &mut account.total,
+            &mut account.fees,
+            &mut account.admin_held,
+        ] {
+            *amount = state.decimal()?;
+        }
+        for _ in 0..state.len()? {
+            let transaction_id = state.u32()?;
+            account.escrows.insert(transaction_id, state.decimal()?);
+        }
+        for _ in 0..state.len()? {
+            account.chargebacks.push(Chargeback {
+                transaction_id: state.u32()?,
+                action_type: state.action()?,
+                amount: state.decimal()?,
+                record_index: state.u64()?,
+                timestamp: state.optional()?,
+                memo: state.optional_text()?,
+                reinstated_at: state.optional()?,
+            });
+        }
+        account.dispute_stats = DisputeStats {
+            opened: state.u32()?,
+            resolved: state.u32()?,
+            disputed_amount: state.decimal()?,
+        };
+        for _ in 0..state.len()? {
+            account.settled_ranges.push((state.u32()?, state.u32()?));
+        }
+        account.rejected_over_cap = state.u64()?;
+        for _ in 0..state.len()? {
+            account.recent_withdrawals.push_back(state.u64()?);
+        }
+        account.opened_at = state.optional()?;
+        for _ in 0..state.len()? {
+            account.adjustments.push(Adjustment {
+                transaction_id: state.u32()?,
+                amount: state.decimal()?,
+                record_index: state.u64()?,
+                timestamp: state.optional()?,
+                memo: state.optional_text()?,
+            });
+        }
+        Ok(account)
+    }
+
+    /// Intitializes a new account
+    fn new(client_id: ClientId) -> Self {
+        Self {
+            client_id,
+            transactions: StoredTransactions::default(),
+            kept_ids: IncreasingIds::default(),
+            spilled: IdMap::default(),
+            admin_actions: Vec::new(),
+            is_locked: false,
+            is_locked_by_chargeback: false,
+            is_locked_by_admin: false,
+            is_frozen: false,
+            is_closed: false,
+            is_close_pending: false,
+            is_quarantined: false,
+            available: Decimal::new(0, PRECISION),
+            held: Decimal::new(0, PRECISION),
+            total: Decimal::new(0, PRECISION),
+            fees: Decimal::new(0, PRECISION),
+            admin_held: Decimal::new(0, PRECISION),
+            escrows: IdMap::default(),
+            chargebacks: Vec::new(),
+            adjustments: Vec::new(),
+            dispute_stats: DisputeStats::new(),
+            settled_ranges: Vec::new(),
+            rejected_over_cap: 0,
+            recent_withdrawals: VecDeque::new(),
+            opened_at: None,
+        }
+    }
+
+    /// Initializes the account of a client first named by the row at the given position
+    /// with room for capacity stored transactions
+    fn opened(client_id: ClientId, record_index: u64, capacity: usize) -> Self {
+        Self {
+            opened_at: Some(record_index),
+            transactions: StoredTransactions::with_capacity(capacity),
+            ..Self::new(client_id)
+        }
+    }
+
+    /// Whether the transaction id falls in a range settled for this account
+    fn is_settled(&self, transaction_id: TransactionId) -> bool {
+        self.settled_ranges
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&transaction_id))
+    }
+
+    /// Whether the id is one of a deposit, withdrawal, adjustment or received transfer still known to this account
+    fn has_transaction(&self, transaction_id: TransactionId) -> bool {
+        self.transactions.contains_key(&transaction_id)
+            || self.kept_ids.contains(transaction_id)
+            || self.spilled.contains_key(&transaction_id)
+    }
+
+    /// Rejects a row referencing a transaction this account never applied
+    fn unknown_tx(&self, transaction: &Transaction) -> CustomError {
+        CustomError::NonExistingTransactionId {
+            action: transaction.action_type.as_str(),
+            tx: transaction.transaction_id,
+            new_client: self.opened_at == Some(transaction.record_index),
+        }
+    }
+
+    /// Rejects a row reusing the id of a transaction applied to this account, naming the original when it is still kept
+    /// Escrows and sent transfers are only kept in the audit trail, so they are looked up there
+    fn duplicate_id(&self, transaction_id: TransactionId) -> CustomError {
+        let original = match self.transactions.get(&transaction_id) {
+            Some(stored) => Some(stored.original(self.client_id)),
+            None => self
+                .admin_actions
+                .iter()
+                .find(|action| {
+                    action.transaction_id == transaction_id
+                        && matches!(action.action_type, Action::Escrow | Action::Transfer)
+                })
+                .map(Transaction::original),
+        };
+        CustomError::DuplicatedTransactionId { original }
+    }
+
+    /// Drops transactions that fell out of a dispute window of the given number of records
+    /// Transactions under dispute or waiting for their payout are kept, since they still hold funds
+    fn prune(&mut self, record_index: u64, window: u64) {
+        self.transactions.retain(|_, transaction| {
+            transaction.is_under_dispute()
+                || transaction.is_payout_pending()
+                || record_index - transaction.record_index <= window
+        });
+        self.spilled
+            .retain(|_, slot| record_index - slot.record_index <= window);
+    }
+
+    /// Checks that a transfer can be credited to this account, under the rules of a deposit without fee
+    fn check_transfer_in(
+        &self,
+        transaction: &Transaction,
+        config: &Config,
+    ) -> Result<(), CustomError> {
+        if self.is_locked {
+            return Err(CustomError::LockedAccount);
+        }
+        if self.is_closed || self.is_close_pending {
+            return Err(CustomError::AccountClosed);
+        }
+        if self.has_transaction(transaction.transaction_id) {
+            return Err(self.duplicate_id(transaction.transaction_id));
+        }
+        let amount = transaction.decimal.unwrap();
+        if let Some(cap) = config.tier_limits(self.client_id).max_total {
+            if self.total + amount > cap {
+                return Err(CustomError::DepositWouldExceedTierCap { cap });
+            }
+        }
+        Ok(())
+    }
+
+    /// Debits the sender of a transfer, under the rules of a withdrawal without fee or credit
+    /// The transfer is kept in the audit trail, only the recipient keeps it as a transaction
+    fn transfer_out(
+        &mut self,
+        transaction: &Transaction,
+        config: &Config,
+    ) -> Result<(), CustomError> {
+        if self.is_locked {
+            return Err(CustomError::LockedAccount);
+        }
+        if self.is_closed || self.is_close_pending {
+            return Err(CustomError::AccountClosed);
+        }
+        if self.is_frozen {
+            return Err(CustomError::AccountFrozen);
+        }
+        let amount = transaction.decimal.unwrap();
+        if self.available < amount {
+            return Err(CustomError::AccountBalanceNotEnough);
+        }
+        if let Some(floor) = config.min_balance(self.client_id) {
+            if self.available - amount < floor {
+                return Err(CustomError::BelowMinimumBalance { floor });
+            }
+        }
+        self.available -= amount;
+        self.total -= amount;
+        let mut sent = Transaction::synthetic(
+            Action::Transfer,
+            self.client_id,
+            transaction.transaction_id,
+            amount,
+        );
+        sent.record_index = transaction.record_index;
+        sent.counterparty = transaction.counterparty;
+        self.admin_actions.push(sent);
+        Ok(())
+    }
+
+    /// Credits the recipient of a transfer and keeps it so it can be disputed like a deposit
+    fn transfer_in(&mut self, transaction: Transaction) {
+        let amount = transaction.decimal.unwrap();
+        self.available += amount;
+        self.total += amount;
+        let mut stored = transaction.stored();
+        stored.set_counterparty(Some(transaction.client_id));
+        self.transactions.insert(transaction.transaction_id, stored);
+    }
+
+    /// Sender and amount to credit back for a transfer that was just charged back on this account
+    fn transfer_refund(&self, transaction_id: TransactionId) -> Option<(ClientId, Decimal)> {
+        let chargeback = self
+            .chargebacks
+            .last()
+            .filter(|chargeback| chargeback.transaction_id == transaction_id)?;
+        if !matches!(chargeback.action_type, Action::Transfer) {
+            return None;
+        }
+        let sender = self.transactions.get(&transaction_id)?.counterparty()?;
+        Some((sender, chargeback.amount))
+    }
+
+    /// What the dispute policy sees of this account
+    fn view(&self) -> AccountView {
+        AccountView {
+            available: self.available,
+        }
+    }
+
+    /// Applies the balance changes decided by the dispute policy
+    /// Balances a step leaves alone are not touched, adding zero could change their scale in the output
+    fn apply_dispute_effect(&mut self, effect: DisputeEffect) {
+        for (balance, change) in [
+            (&mut self.available, effect.available),
+            (&mut self.held, effect.held),
+            (&mut self.total, effect.total),
+        ] {
+            if !change.is_zero() {
+                *balance += change;
+            }
+        }
+        if effect.lock {
+            self.is_locked = true;
+            self.is_locked_by_chargeback = true;
+        }
+    }
+
+    /// Adds interest on available funds at the given rate, rounded half to even to the precision
+    /// Closed accounts, and unless configured locked or frozen ones, accrue nothing, nor do balances
+    /// that are not positive
+    /// The posting is kept as an interest transaction in the audit trail
+    fn accrue_interest(
+        &mut self,
+        rate: Decimal,
+        transaction_id: TransactionId,
+        config: &Config,
+    ) -> Option<Decimal> {
+        if self.is_closed
+            || self.is_quarantined
+            || (self.is_locked && !config.accrue_interest_on_locked)
+            || (self.is_frozen && !config.accrue_interest_on_frozen)
+            || self.available <= Decimal::ZERO
+        {
+            return None;
+        }
+        let interest = (self.available * rate)
+            .round_dp_with_strategy(PRECISION, RoundingStrategy::MidpointNearestEven);
+        if interest.is_zero() {
+            return None;
+        }
+        self.available += interest;
+        self.total += interest;
+        self.admin_actions.push(Transaction::synthetic(
+            Action::AccrueInterest,
+            self.client_id,
+            transaction_id,
+            interest,
+        ));
+        Some(interest)
+    }
+
+    /// Resolves disputes opened more than the window before end, the position of the end of the input
+    /// Returns the ids of the resolved transactions with the record that opened their dispute
+    /// A dispute the policy refuses to resolve stays open
+    fn auto_resolve(
+        &mut self,
+        window: &Window,
+        end: u64,
+        config: &Config,
+    ) -> Vec<(TransactionId, u64)> {
+        let account = self.view();
+        let mut resolved = Vec::new();
+        let mut effects = Vec::new();
+        //held once the effects collected so far are applied
+        let mut held = self.held;
+        for (transaction_id, transaction) in self.transactions.iter() {
+            let dispute = match transaction.dispute() {
+                Some(dispute) if transaction.is_under_dispute() => dispute,
+                _ => continue,
+            };
+            let (disputed_at, disputed_amount) = (dispute.record_index, dispute.amount);
+            let is_stale = window
+                .position(dispute.record_index, dispute.timestamp)
+                .is_some_and(|position| end.saturating_sub(position) > window.length());
+            if !is_stale {
+                continue;
+            }
+            //same as a resolve row
+            let effect = transaction.dispute_view(disputed_amount).and_then(|view| {
+                config
+                    .dispute_policy
+                    .decide(&account, &view, DisputeStep::Resolve, config)
+                    .ok()
+            });
+            let effect = match effect {
+                None => continue,
+                Some(effect) => effect,
+            };
+            if held + effect.held < Decimal::ZERO {
+                error!(
+                    "Client id: {}, with transaction_id: {} was not auto-resolved, it would take {} out of held {}",
+                    self.client_id, transaction_id, -effect.held, held
+                );
+                continue;
+            }
+            held += effect.held;
+            effects.push(effect);
+            self.dispute_stats.resolved += 1;
+            resolved.push((*transaction_id, disputed_at));
+        }
+        for (transaction_id, _) in &resolved {
+            if let Some(mut transaction) = self.transactions.get_mut(transaction_id) {
+                transaction.set_dispute_state(DisputeState::AutoResolved);
+            }
+        }
+        for effect in effects {
+            self.apply_dispute_effect(effect);
+        }
+        //a deferred closure may have been waiting for these funds
+        if self.is_close_pending && self.held.is_zero() {
+            self.is_close_pending = false;
+            self.is_closed = true;
+        }
+        resolved.sort();
+        resolved
+    }
+
+    /// Takes transaction as input and will update it's status
+    /// This method will return Err if a deposit or withdrawal hits a locked account or account balance is not enough
+    /// For other unwanted situations such as transaction_id for dispute is missing,
+    /// it will continue while logging the incident
+    fn handle_transaction(
+        &mut self,
+        mut transaction: Transaction,
+        config: &Config,
+    ) -> Result<(), CustomError> {
+        let transaction_id = transaction.transaction_id;
+        let action_type = transaction.get_action_type();
+        //the audit trail can be long, the raw text has already been reported with the row
+        transaction.raw = None;
+        //first check if this account is not locked,
+        //if locked, reject new money movement but keep processing disputes on existing transactions
+        //so that funds held by other open disputes can still be resolved or charged back
+        //releasing an escrow moves money too, unless config allows it on a locked account
+        if self.is_locked {
+            match transaction.get_action_type() {
+                Action::Deposit
+                | Action::Withdrawal
+                | Action::WithdrawalPending
+                | Action::Escrow
+                | Action::Adjustment => {
+                    return Err(CustomError::LockedAccount);
+                }
+                Action::EscrowRelease if !config.allow_locked_escrow_release => {
+                    return Err(CustomError::LockedAccount);
+                }
+                _ => {}
+            }
+        }
+        //a closed account works the same way, and a pending closure already counts as closed
+        //releasing a hold or an escrow is still allowed, it may be what a pending closure waits for
+        if self.is_closed || self.is_close_pending {
+            if let Action::Deposit
+            | Action::Withdrawal
+            | Action::WithdrawalPending
+            | Action::Close
+            | Action::Hold
+            | Action::Escrow
+            | Action::Adjustment = transaction.get_action_type()
+            {
+                return Err(CustomError::AccountClosed);
+            }
+        }
+        //a runaway upstream must not grow one account without bound
+        //rows referencing stored transactions are not new ones, so they are unaffected
+        if let Some(cap) = config.max_transactions_per_account {
+            if let Action::Deposit | Action::Withdrawal | Action::WithdrawalPending =
+                transaction.get_action_type()
+            {
+                if self.transactions.len() + self.kept_ids.len() + self.spilled.len() >= cap {
+                    self.rejected_over_cap += 1;
+                    return Err(CustomError::AccountTransactionCapExceeded { cap });
+                }
+            }
+        }
+        //the referenced transaction must belong to the same client as this row
+        if let Action::Dispute | Action::Resolve | Action::Chargeback =
+            transaction.get_action_type()
+        {
+            if self.has_transaction(transaction.transaction_id)
+                && self.client_id != transaction.client_id
+            {
+                return Err(CustomError::ClientMismatch {
+                    expected: self.client_id,
+                    found: transaction.client_id,
+                });
+            }
+        }
+        match transaction.get_action_type() {
+            Action::Deposit => {
+                //check if transaction number is unique,
+                if self.has_transaction(transaction.transaction_id) {
+                    return Err(self.duplicate_id(transaction.transaction_id));
+                }
+                //a negative deposit would be a withdrawal that skips the balance check
+                let amount = transaction.decimal.unwrap();
+                if amount <= Decimal::ZERO {
+                    return Err(CustomError::NonPositiveAmount);
+                }
+                check_amount_limit(amount, config)?;
+                //the fee is taken out of the deposited amount, so it must be smaller
+                if transaction.fee.is_sign_negative() || transaction.fee >= amount {
+                    return Err(CustomError::InvalidFee);
+                }
+                //the client's verification tier caps what the account may hold
+                let tier_limits = config.tier_limits(self.client_id);
+                if let Some(cap) = tier_limits.max_total {
+                    if self.total + amount - transaction.fee > cap {
+                        return Err(CustomError::DepositWouldExceedTierCap { cap });
+                    }
+                }
+                self.available += amount - transaction.fee;
+                self.total += amount - transaction.fee;
+                self.fees += transaction.fee;
+                //a deposit no later row refers to only needs its id
+                let is_referenced = config
+                    .referenced_ids
+                    .as_ref()
+                    .is_none_or(|ids| ids.contains(&transaction.transaction_id));
+                if is_referenced {
+                    self.transactions
+                        .insert(transaction.transaction_id, transaction.stored());
+                } else {
+                    self.kept_ids.insert(transaction.transaction_id);
+                }
+            }
+            //a pending withdrawal goes through the same checks, but its amount is only held
+            Action::Withdrawal | Action::WithdrawalPending => {
+                //check if transaction number is unique,
+                if self.has_transaction(transaction.transaction_id) {
+                    return Err(self.duplicate_id(transaction.transaction_id));
+                }
+                if self.is_frozen {
+                    return Err(CustomError::AccountFrozen);
+                }
+                //a negative amount always passes the balance check below and would credit the account
+                if transaction.decimal.unwrap() <= Decimal::ZERO {
+                    return Err(CustomError::NonPositiveAmount);
+                }
+                check_amount_limit(transaction.decimal.unwrap(), config)?;
+                let tier_limits = config.tier_limits(self.client_id);
+                if let Some(limit) = tier_limits.max_withdrawal {
+                    if transaction.decimal.unwrap() > limit {
+                        return Err(CustomError::WithdrawalOverTierLimit { limit });
+                    }
+                }
+                if transaction.fee.is_sign_negative() {
+                    return Err(CustomError::InvalidFee);
+                }
+                //too many recent withdrawals hint at structuring
+                let velocity_position = config.velocity_limit.and_then(|velocity| {
+                    velocity
+                        .window
+                        .position(transaction.record_index, transaction.timestamp)
+                        .map(|position| (velocity, position))
+                });
+                if let Some((velocity, position)) = velocity_position {
+                    //forget withdrawals that left the window
+                    while let Some(&oldest) = self.recent_withdrawals.front() {
+                        if position.saturating_sub(oldest) < velocity.window.length() {
+                            break;
+                        }
+                        self.recent_withdrawals.pop_front();
+                    }
+                    if self.recent_withdrawals.len() >= velocity.max_withdrawals {
+                        return Err(CustomError::VelocityLimitExceeded {
+                            max_withdrawals: velocity.max_withdrawals,
+                        });
+                    }
+                }
+                //withdrawal should fail it total amount is not enough, including the fee
+                //and any credit the client is allowed to draw on
+                let amount = transaction.decimal.unwrap() + transaction.fee;
+                let credit_limit = config
+                    .credit_limits
+                    .get(&self.client_id)
+                    .copied()
+                    .unwrap_or_default();
+                if self.available + credit_limit < amount {
+                    return Err(CustomError::AccountBalanceNotEnough);
+                }
+                //some accounts must keep a minimum balance, only withdrawals are bound by it
+                if let Some(floor) = config.min_balance(self.client_id) {
+                    if self.available - amount < floor {
+                        return Err(CustomError::BelowMinimumBalance { floor });
+                    }
+                }
+                self.available -= amount;
+                let mut stored = transaction.stored();
+                if let Action::WithdrawalPending = transaction.action_type {
+                    //the fee is only charged once the payout settles
+                    self.held += amount;
+                    //stored as a withdrawal so a settled payout is disputed like any other
+                    stored.action_type = Action::Withdrawal;
+                    stored.set_payout_state(Some(PayoutState::Pending));
+                } else {
+                    self.total -= amount;
+                    self.fees += transaction.fee;
+                }
+                if let Some((_, position)) = velocity_position {
+                    self.recent_withdrawals.push_back(position);
+                }
+                //a withdrawal that can never be disputed is only kept as an id, unless its payout is still pending
+                if stored.is_payout_pending() || config.dispute_policy.disputes_withdrawals(config)
+                {
+                    self.transactions.insert(transaction.transaction_id, stored);
+                } else {
+                    self.kept_ids.insert(transaction.transaction_id);
+                }
+            }
+            Action::Adjustment => {
+                //adjustments are kept with the other transactions so their id cannot be reused,
+                //their own action type keeps them from being disputed
+                if self.has_transaction(transaction.transaction_id) {
+                    return Err(self.duplicate_id(transaction.transaction_id));
+                }
+                //a negative adjustment can only take what is available, credit does not count
+                let amount = transaction.decimal.unwrap();
+                if amount.is_sign_negative() && self.available < -amount {
+                    return Err(CustomError::AccountBalanceNotEnough);
+                }
+                self.available += amount;
+                self.total += amount;
+                self.adjustments.push(Adjustment {
+                    transaction_id: transaction.transaction_id,
+                    amount,
+                    record_index: transaction.record_index,
+                    timestamp: transaction.timestamp,
+                    memo: transaction.memo.clone(),
+                });
+                self.transactions
+                    .insert(transaction.transaction_id, transaction.stored());
+            }
+            Action::Dispute => {
+                let is_settled = self.is_settled(transaction.transaction_id);
+                let account = self.view();
+                let original_transaction = self.transactions.get_mut(&transaction.transaction_id);
+                let (effect, amount) = match original_transaction {
+                    None if is_settled => {
+                        //settled and dropped from memory
+                        return Err(CustomError::TransactionSettled);
+                    }
+                    //a withdrawal kept as an id only, which the policy would refuse
+                    None if self.kept_ids.contains(transaction.transaction_id) => {
+                        return Err(CustomError::UndefinedBehaviour);
+                    }
+                    None => {
+                        //this dispute is erroneous
+                        return Err(self.unknown_tx(&transaction));
+                    }
+                    Some(mut original_transaction) => {
+                        //check if original_transaction can be disputed, if not disregard and return error
+                        if original_transaction.dispute_kind().is_none() {
+                            return Err(CustomError::UndefinedBehaviour);
+                        }
+                        if original_transaction.is_settled() {
+                            return Err(CustomError::TransactionSettled);
+                        }
+                        //disputing twice would hold the same funds twice, and charged back funds already left
+                        let dispute_state =
+                            original_transaction.dispute_state().next(Action::Dispute)?;
+                        if let Some(window) = &config.dispute_window {
+                            if original_transaction.is_outside_window(window, &transaction) {
+                                return Err(CustomError::DisputeWindowExpired);
+                            }
+                        }
+                        //a resolved transaction may be disputed again, up to the configured limit
+                        if let Some(max_redisputes) = config.max_redisputes {
+                            if original_transaction.dispute_count() > max_redisputes {
+                                return Err(CustomError::RedisputeLimitExceeded { max_redisputes });
+                            }
+                        }
+                        //without an amount the dispute covers the whole transaction
+                        let original_amount = original_transaction.amount();
+                        //when amounts are echoes, a dispute always covers the whole transaction
+                        if config.validate_dispute_amounts {
+                            check_echoed_amount(original_amount, transaction.decimal)?;
+                        }
+                        let amount = transaction.decimal.unwrap_or(original_amount);
+                        if amount > original_amount {
+                            return Err(CustomError::DisputeExceedsAmount {
+                                amount: original_amount,
+                            });
+                        }
+                        if amount <= Decimal::ZERO {
+                            return Err(CustomError::NonPositiveAmount);
+                        }
+                        let effect = config.dispute_policy.decide(
+                            &account,
+                            &original_transaction.dispute_view(amount).unwrap(),
+                            DisputeStep::Dispute,
+                            config,
+                        )?;
+                        check_held_release(self.client_id, self.held, -effect.held, &transaction)?;
+                        original_transaction.set_dispute_state(dispute_state);
+                        original_transaction.open_dispute(amount, &transaction);
+                        (effect, amount)
+                    }
+                };
+                self.apply_dispute_effect(effect);
+                self.dispute_stats.opened += 1;
+                self.dispute_stats.disputed_amount += amount;
+            }
+
+            Action::Resolve => {
+                let account = self.view();
+                let original_transaction = self.transactions.get_mut(&transaction.transaction_id);
+                let effect = match original_transaction {
+                    //a withdrawal kept as an id only, which could not have been disputed
+                    None if self.kept_ids.contains(transaction.transaction_id) => {
+                        return Err(CustomError::NotUnderDispute);
+                    }
+                    None => {
+                        //this dispute is erroneous
+                        return Err(self.unknown_tx(&transaction));
+                    }
+                    Some(mut original_transaction) => {
+                        //check if original_transaction can be disputed, if not, print error
+                        let view = original_transaction
+                            .dispute_view(original_transaction.disputed_amount())
+                            .ok_or(CustomError::UndefinedBehaviour)?;
+                        let dispute_state =
+                            original_transaction.dispute_state().next(Action::Resolve)?;
+                        if config.validate_dispute_amounts {
+                            check_echoed_amount(
+                                original_transaction.disputed_amount(),
+                                transaction.decimal,
+                            )?;
+                        }
+                        let effect = config.dispute_policy.decide(
+                            &account,
+                            &view,
+                            DisputeStep::Resolve,
+                            config,
+                        )?;
+                        check_held_release(self.client_id, self.held, -effect.held, &transaction)?;
+                        original_transaction.set_dispute_state(dispute_state);
+                        original_transaction.close_dispute(transaction.row_hash);
+                        effect
+                    }
+                };
+                self.apply_dispute_effect(effect);
+                self.dispute_stats.resolved += 1;
+            }
+            Action::Chargeback => {
+                let account = self.view();
+                let original_transaction = self.transactions.get_mut(&transaction.transaction_id);
+                let effect = match original_transaction {
+                    //a withdrawal kept as an id only, which could not have been disputed
+                    None if self.kept_ids.contains(transaction.transaction_id) => {
+                        return Err(CustomError::NotUnderDispute);
+                    }
+                    None => {
+                        //this dispute is erroneous
+                        return Err(self.unknown_tx(&transaction));
+                    }
+                    Some(mut original_transaction) => {
+                        //check if original_transaction can be disputed, if not, print error
+                        let amount = original_transaction.disputed_amount();
+                        let view = original_transaction
+                            .dispute_view(amount)
+                            .ok_or(CustomError::UndefinedBehaviour)?;
+                        let dispute_state = original_transaction
+                            .dispute_state()
+                            .next(Action::Chargeback)?;
+                        if config.validate_dispute_amounts {
+                            check_echoed_amount(amount, transaction.decimal)?;
+                        }
+                        let effect = config.dispute_policy.decide(
+                            &account,
+                            &view,
+                            DisputeStep::Chargeback,
+                            config,
+                        )?;
+                        check_held_release(self.client_id, self.held, -effect.held, &transaction)?;
+                        //disputes work on the gross amount, the fee is only given back if configured
+                        //and when the whole amount was charged back
+                        if config.refund_fee_on_chargeback
+                            && amount == original_transaction.amount()
+                        {
+                            self.available += original_transaction.fee();
+                            self.total += original_transaction.fee();
+                            self.fees -= original_transaction.fee();
+                        }
+                        original_transaction.set_dispute_state(dispute_state);
+                        original_transaction.close_dispute(transaction.row_hash);
+                        self.chargebacks.push(Chargeback {
+                            transaction_id: transaction.transaction_id,
+                            action_type: original_transaction.action_type,
+                            amount,
+                            record_index: transaction.record_index,
+                            timestamp: transaction.timestamp,
+                            memo: transaction.memo.clone(),
+                            reinstated_at: None,
+                        });
+                        effect
+                    }
+                };
+                self.apply_dispute_effect(effect);
+            }
+            Action::Reversal => {
+                let is_settled = self.is_settled(transaction.transaction_id);
+                let original_transaction = self.transactions.get_mut(&transaction.transaction_id);
+                let mut original_transaction = match original_transaction {
+                    None if is_settled => return Err(CustomError::TransactionSettled),
+                    //only deposits can be reversed
+                    None if self.kept_ids.contains(transaction.transaction_id) => {
+                        return Err(CustomError::UndefinedBehaviour)
+                    }
+                    None => return Err(self.unknown_tx(&transaction)),
+                    Some(original_transaction) => original_transaction,
+                };
+                //only deposits can be reversed
+                if !matches!(original_transaction.action_type, Action::Deposit) {
+                    return Err(CustomError::UndefinedBehaviour);
+                }
+                if original_transaction.is_settled() {
+                    return Err(CustomError::TransactionSettled);
+                }
+                let dispute_state = original_transaction
+                    .dispute_state()
+                    .next(Action::Reversal)?;
+                //the deposit is undone as credited, and its fee is given back
+                let amount = original_transaction.amount() - original_transaction.fee();
+                if self.available < amount {
+                    return Err(CustomError::AccountBalanceNotEnough);
+                }
+                self.available -= amount;
+                self.total -= amount;
+                self.fees -= original_transaction.fee();
+                original_transaction.set_dispute_state(dispute_state);
+                self.admin_actions.push(transaction);
+            }
+            Action::Escrow => {
+                if self.escrows.contains_key(&transaction.transaction_id) {
+                    return Err(self.duplicate_id(transaction.transaction_id));
+                }
+                //only available funds can be escrowed, credit does not count
+                let amount = transaction.decimal.unwrap();
+                if amount <= Decimal::ZERO {
+                    return Err(CustomError::NonPositiveAmount);
+                }
+                if self.available < amount {
+                    return Err(CustomError::AccountBalanceNotEnough);
+                }
+                self.available -= amount;
+                self.held += amount;
+                self.escrows.insert(transaction.transaction_id, amount);
+                self.admin_actions.push(transaction);
+            }
+            Action::EscrowRelease => {
+                let escrowed = match self.escrows.get_mut(&transaction.transaction_id) {
+                    None => return Err(self.unknown_tx(&transaction)),
+                    Some(escrowed) if escrowed.is_zero() => {
+                        return Err(CustomError::EscrowAlreadyReleased)
+                    }
+                    Some(escrowed) => escrowed,
+                };
+                //without an amount the release covers what is left in escrow
+                let amount = transaction.decimal.unwrap_or(*escrowed);
+                if amount <= Decimal::ZERO {
+                    return Err(CustomError::NonPositiveAmount);
+                }
+                if amount > *escrowed {
+                    return Err(CustomError::ReleaseExceedsEscrow {
+                        escrowed: *escrowed,
+                    });
+                }
+                check_held_release(self.client_id, self.held, amount, &transaction)?;
+                *escrowed -= amount;
+                self.held -= amount;
+                if config.escrow_release_transfers {
+                    //delivered, the funds go to the seller
+                    self.total -= amount;
+                } else {
+                    self.available += amount;
+                }
+                self.admin_actions.push(transaction);
+            }
+            Action::Lock => {
+                if self.is_locked {
+                    //locking twice is harmless
+                    warn!(
+                        "Client id: {}, with transaction_id: {} is already locked",
+                        self.client_id, transaction.transaction_id
+                    );
+                } else {
+                    self.is_locked = true;
+                }
+                self.is_locked_by_admin = true;
+                self.admin_actions.push(transaction);
+            }
+            Action::Unlock => {
+                if self.is_locked_by_chargeback && !config.allow_unlock_chargeback {
+                    return Err(CustomError::LockedByChargeback);
+                }
+                if !self.is_locked {
+                    warn!(
+                        "Client id: {}, with transaction_id: {} is not locked",
+                        self.client_id, transaction.transaction_id
+                    );
+                }
+                self.is_locked = false;
+                self.is_locked_by_chargeback = false;
+                self.is_locked_by_admin = false;
+                self.admin_actions.push(transaction);
+            }
+            Action::Freeze => {
+                if self.is_frozen {
+                    //freezing twice is harmless
+                    warn!(
+                        "Client id: {}, with transaction_id: {} is already frozen",
+                        self.client_id, transaction.transaction_id
+                    );
+                }
+                self.is_frozen = true;
+                self.admin_actions.push(transaction);
+            }
+            Action::Unfreeze => {
+                if !self.is_frozen {
+                    warn!(
+                        "Client id: {}, with transaction_id: {} is not frozen",
+                        self.client_id, transaction.transaction_id
+                    );
+                }
+                self.is_frozen = false;
+                self.admin_actions.push(transaction);
+            }
+            Action::WithdrawalSettle | Action::WithdrawalFail => {
+                //a withdrawal kept as an id only was never pending
+                if self.kept_ids.contains(transaction.transaction_id) {
+                    return Err(CustomError::WithdrawalNotPending);
+                }
+                let unknown_tx = self.unknown_tx(&transaction);
+                let mut original_transaction = self
+                    .transactions
+                    .get_mut(&transaction.transaction_id)
+                    .ok_or(unknown_tx)?;
+                let payout_state = original_transaction
+                    .payout_state()
+                    .ok_or(CustomError::WithdrawalNotPending)?
+                    .next(transaction.action_type)?;
+                let amount = original_transaction.amount() + original_transaction.fee();
+                check_held_release(self.client_id, self.held, amount, &transaction)?;
+                self.held -= amount;
+                if let Action::WithdrawalSettle = transaction.action_type {
+                    self.total -= amount;
+                    self.fees += original_transaction.fee();
+                } else {
+                    self.available += amount;
+                }
+                original_transaction.set_payout_state(Some(payout_state));
+                self.admin_actions.push(transaction);
+            }
+            //posted to every account by the engine, never routed to a single account
+            Action::AccrueInterest => unreachable!("interest is accrued engine-wide"),
+            //ends the input before any account sees it
+            Action::Halt => unreachable!("halt rows are never applied"),
+            //touches two accounts, so the engine applies it
+            Action::Transfer => unreachable!("transfers are applied by the engine"),
+            Action::Close => {
+                //funds held by open disputes must be settled before closing
+                if !self.held.is_zero() && !config.defer_close {
+                    return Err(CustomError::HeldFundsOnClose);
+                }
+                self.is_close_pending = true;
+                self.admin_actions.push(transaction);
+            }
+            Action::Settle => {
+                let start = transaction.transaction_id;
+                let end = transaction.settle_end.unwrap_or(start);
+                if start > end {
+                    return Err(CustomError::InvalidSettleRange { start, end });
+                }
+                //settled transactions are dropped, except open disputes which must still be
+                //resolved or charged back and pending payouts which must still be settled or failed,
+                //they are only marked so they cannot be disputed again
+                self.transactions.retain(|id, stored| {
+                    if !(start..=end).contains(id) {
+                        return true;
+                    }
+                    stored.settle();
+                    stored.is_under_dispute() || stored.is_payout_pending()
+                });
+                self.kept_ids.retain(|id| !(start..=end).contains(&id));
+                self.spilled.retain(|id, _| !(start..=end).contains(id));
+                self.settled_ranges.push((start, end));
+                self.admin_actions.push(transaction);
+            }
+            Action::Reinstate => {
+                if !self.is_locked {
+                    return Err(CustomError::AccountNotLocked);
+                }
+                let chargeback = self.chargebacks.iter_mut().find(|chargeback| {
+                    chargeback.transaction_id == transaction.transaction_id
+                        && chargeback.reinstated_at.is_none()
+                });
+                let chargeback = match chargeback {
+                    None => return Err(CustomError::NotChargedBack),
+                    Some(chargeback) => chargeback,
+                };
+                chargeback.reinstated_at = Some(transaction.record_index);
+                //a charged back withdrawal already gave the money back to the client
+                if config.reinstate_refunds {
+                    if let Action::Deposit = chargeback.action_type {
+                        self.available += chargeback.amount;
+                        self.total += chargeback.amount;
+                    }
+                }
+                //the account stays locked while another chargeback still stands, or when a Lock row locked it
+                if self
+                    .chargebacks
+                    .iter()
+                    .all(|chargeback| chargeback.reinstated_at.is_some())
+                {
+                    self.is_locked_by_chargeback = false;
+                    self.is_locked = self.is_locked_by_admin;
+                }
+                self.admin_actions.push(transaction);
+            }
+            Action::Hold => {
+                //only available funds can be held, credit does not count
+                let amount = transaction.decimal.unwrap();
+                if amount <= Decimal::ZERO {
+                    return Err(CustomError::NonPositiveAmount);
+                }
+                if self.available < amount {
+                    return Err(CustomError::AccountBalanceNotEnough);
+                }
+                self.available -= amount;
+                self.held += amount;
+                self.admin_held += amount;
+                self.admin_actions.push(transaction);
+            }
+            Action::Release => {
+                //funds held by disputes can only be freed by resolve or chargeback
+                let amount = transaction.decimal.unwrap();
+                if amount <= Decimal::ZERO {
+                    return Err(CustomError::NonPositiveAmount);
+                }
+                if amount > self.admin_held {
+                    return Err(CustomError::ReleaseExceedsHold {
+                        held: self.admin_held,
+                    });
+                }
+                check_held_release(self.client_id, self.held, amount, &transaction)?;
+                self.available += amount;
+                self.held -= amount;
+                self.admin_held -= amount;
+                self.admin_actions.push(transaction);
+            }
+        }
+        //a deferred closure completes once the last dispute is settled
+        if self.is_close_pending && self.held.is_zero() {
+            self.is_close_pending = false;
+            self.is_closed = true;
+        }
+        self.check_invariant(transaction_id, action_type)
+    }
+
+    /// Sanity check that available and held add up to total after a transaction
+    fn check_invariant(
+        &self,
+        transaction_id: TransactionId,
+        action_type: Action,
+    ) -> Result<(), CustomError> {
+        //development builds stop right at the bug, tests go on to exercise the error path
+        #[cfg(not(test))]
+        debug_assert_eq!(self.total, self.available + self.held);
+        if self.total != self.available + self.held {
+            return Err(CustomError::InvariantViolation {
+                client: self.client_id,
+                available: self.available,
+                held: self.held,
+                total: self.total,
+                action: action_type.as_str(),
+                tx: transaction_id,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// An amount echoed on a dispute, resolve or chargeback row must be the amount it refers to, scale aside
+fn check_echoed_amount(expected: Decimal, echoed: Option<Decimal>) -> Result<(), CustomError> {
+    match echoed {
+        Some(found) if found != expected => {
+            Err(CustomError::DisputeAmountMismatch { expected, found })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Held can never go negative, taking more out of it than it has only comes from a bug or a faulty policy
+fn check_held_release(
+    client: ClientId,
+    held: Decimal,
+    release: Decimal,
+    transaction: &Transaction,
+) -> Result<(), CustomError> {
+    if release > held {
+        return Err(CustomError::NegativeHeld {
+            client,
+            held,
+            release,
+            action: transaction.action_type.as_str(),
+            tx: transaction.transaction_id,
+        });
+    }
+    Ok(())
+}
+
+/// Deposits and withdrawals above the configured ceiling are rejected, an amount exactly at it passes
+fn check_amount_limit(amount: Decimal, config: &Config) -> Result<(), CustomError> {
+    match config.max_transaction_amount {
+        Some(limit) if amount > limit => Err(CustomError::AmountLimitExceeded { limit }),
+        _ => Ok(()),
+    }
+}
+
+/// Parts of a run on their own, for the benches
+#[doc(hidden)]
+pub mod bench {
+    use super::*;
+    use crate::io::id_index::IdIndex;
+
+    /// Rows of an input read without being applied
+    pub struct Parsed(Vec<Transaction>);
+
+    /// Reads every row of an input with the canonical header, as process does before applying them
+    pub async fn parse(input: &[u8]) -> Parsed {
+        let columns = Columns::default();
+        let mut reader = Reader::from_reader(input);
+        let mut record = ByteRecord::new();
+        let mut transactions = Vec::new();
+        while reader
+            .get_inner()
+            .read_byte_record(&mut record)
+            .await
+            .unwrap()
+        {
+            let fields = decode(&record, &columns, 0, false).unwrap();
+            let mut transaction = Transaction::from_record(&fields, &columns, false).unwrap();
+            transaction.record_index = transactions.len() as u64 + 1;
+            transactions.push(transaction);
+        }
+        Parsed(transactions)
+    }
+
+    /// Applies parsed rows to a new engine in their order, returning the engine holding the accounts
+    pub fn apply(config: Config, parsed: Parsed) -> Engine {
+        let mut engine = Engine::new(config);
+        for transaction in parsed.0 {
+            engine.open_accounts(&transaction);
+            engine.apply(transaction).unwrap();
+        }
+        engine
+    }
+
+    /// Reads an amount straight from the bytes, None for the amounts left to Decimal::from_str
+    pub fn parse_amount(amount: &[u8]) -> Option<Decimal> {
+        parse_plain_decimal(amount)
+    }
+
+    /// Owners of transaction ids as the engine keeps them
+    #[derive(Default)]
+    pub struct Owners(IdOwners);
+
+    impl Owners {
+        pub fn claim(&mut self, transaction_id: TransactionId, owner: ClientId) {
+            self.0.claim(transaction_id, owner);
+        }
+
+        pub fn get(&self, transaction_id: TransactionId) -> Option<ClientId> {
+            self.0.get(transaction_id)
+        }
+
+        /// Bytes the owners take
+        pub fn memory(&self) -> u64 {
+            self.0.memory()
+        }
+
+        /// Bytes a hash map of the same owners would take once grown to hold them
+        pub fn map_memory(&self) -> u64 {
+            table_bytes::<(TransactionId, ClientId)>(grown_capacity(self.0.len()))
+        }
+    }
+
+    /// Owners of transaction ids kept in files of a directory, as --id-index-dir keeps them behind its filter
+    pub struct IndexedOwners(IdIndex);
+
+    impl IndexedOwners {
+        /// Owners in files of the directory, written as the engine writes them
+        pub fn create(dir: &Path) -> Self {
+            Self(IdIndex::create(dir, ID_INDEX_FLUSH).unwrap())
+        }
+
+        pub fn claim(&mut self, transaction_id: TransactionId, owner: ClientId) {
+            self.0.insert(transaction_id, owner).unwrap();
+        }
+
+        pub fn get(&self, transaction_id: TransactionId) -> Option<ClientId> {
+            self.0.get(transaction_id).unwrap()
+        }
+
+        /// Most bytes the files took on disk
+        pub fn bytes(&self) -> u64 {
+            self.0.bytes()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{IdFilter, Tiers, VelocityLimit},
+        generate::{workload, Rng, Scenario},
+        owners::FilterStats,
+        policy::{ConservativePolicy, DisputePolicy, SharedPolicy, StandardPolicy},
+    };
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+    };
+
+    thread_local! {
+        /// Allocations made by the current thread
+        static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+        /// Bytes allocated by the current thread and not freed by it yet
+        static LIVE_BYTES: Cell<i64> = const { Cell::new(0) };
+    }
+
+    /// System allocator counting allocations and live bytes per thread, so a test can tell how many
+    /// a piece of code makes and how much memory it holds
+    struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            //a thread being torn down has no counter left
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            let _ = LIVE_BYTES.try_with(|bytes| bytes.set(bytes.get() + layout.size() as i64));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let _ = LIVE_BYTES.try_with(|bytes| bytes.set(bytes.get() - layout.size() as i64));
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Reads a row given as its fields, the way the reader does
+    fn parse_row(
+        fields: Vec<&str>,
+        columns: &Columns,
+        scientific: bool,
+    ) -> Result<Transaction, CustomError> {
+        let record = ByteRecord::from(fields);
+        Transaction::from_record(&decode(&record, columns, 1, false)?, columns, scientific)
+    }
+
+    /// Runs the engine over an in-memory csv and returns the output rows sorted by client id
+    /// A copy of the engine first runs with --sync, which must write the same rows
+    async fn run(engine: &mut Engine, input: &str) -> Vec<String> {
+        let sync = run_sync(sync_copy(engine), input.as_bytes()).unwrap();
+        let mut reader = Reader::from_reader(input.as_bytes());
+        let mut writer = Writer::from_inner(Vec::new());
+        engine.process(&mut reader, &mut writer).await.unwrap();
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        let mut lines: Vec<String> = output.lines().skip(1).map(String::from).collect();
+        lines.sort();
+        assert_eq!(sync, lines, "the run with --sync differs");
+        lines
+    }
+
+    /// Runs the engine over an input that stops the run and returns the error, which a copy of the engine run
+    /// with --sync must stop on too
+    async fn run_err(engine: &mut Engine, input: impl AsRef<[u8]>) -> CustomError {
+        let sync = run_sync(sync_copy(engine), input.as_ref()).unwrap_err();
+        let mut reader = Reader::from_reader(input.as_ref());
+        let mut writer = Writer::from_inner(Vec::new());
+        let err = engine.process(&mut reader, &mut writer).await.unwrap_err();
+        assert_eq!(
+            sync.to_string(),
+            err.to_string(),
+            "the run with --sync differs"
+        );
+        err
+    }
+
+    /// An engine of the same config and accounts, with a single worker, as --sync runs it
+    fn sync_copy(engine: &Engine) -> Engine {
+        let mut copy = Engine::new(Config {
+            workers: 1,
+            ..engine.config.clone()
+        });
+        //accounts a test filled in before the run are taken through the state of a checkpoint
+        if !engine.clients.is_empty() {
+            let progress = Progress {
+                record_index: 0,
+                latest_timestamp: None,
+            };
+            copy.decode_state(&engine.encode_state([0; 3], progress))
+                .unwrap();
+            copy.resumed = None;
+        }
+        copy
+    }
+
+    /// An empty directory of its own under the temp dir, so concurrent and repeated runs of a test never share one
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        static DIRS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "{}_{}_{}",
+            name,
+            std::process::id(),
+            DIRS.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Runs the engine with --sync on a thread without the tokio runtime, returning its sorted output
+    fn run_sync(mut engine: Engine, input: &[u8]) -> Result<Vec<String>, CustomError> {
+        let input = input.to_vec();
+        std::thread::spawn(move || {
+            let mut reader = BlockingReader::from_reader(input.as_slice());
+            let mut writer = Writer::from_inner(Vec::new());
+            engine.process_blocking(&mut reader, &mut writer)?;
+            let output = String::from_utf8(writer.into_inner()).unwrap();
+            let mut lines: Vec<String> = output.lines().skip(1).map(String::from).collect();
+            lines.sort();
+            Ok(lines)
+        })
+        .join()
+        .unwrap()
+    }
+
+    /// The summary or report csv without its peak memory, an estimate that follows the layout of the tables
+    fn without_peak_memory(report: &str) -> String {
+        report
+            .lines()
+            .filter(|line| !line.starts_with("peak memory"))
+            .map(|line| format!("{}\n", line))
+            .collect()
+    }
+
+    /// Report of a run without its peak memory, which depends on how the accounts grew
+    fn counts(report: RunReport) -> RunReport {
+        RunReport {
+            peak_memory: 0,
+            ..report
+        }
+    }
+
+    /// Sink taking a fixed number of bytes, then failing like a full disk
+    struct FailingSink {
+        remaining: usize,
+    }
+
+    impl AsyncWrite for FailingSink {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            if self.remaining == 0 {
+                return std::task::Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::StorageFull,
+                    "no space left on device",
+                )));
+            }
+            let written = buf.len().min(self.remaining);
+            self.remaining -= written;
+            std::task::Poll::Ready(Ok(written))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Test case for only deposit
+    #[test]
+    fn test_one_deposit() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let transaction = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        account
+            .handle_transaction(transaction, &Config::default())
+            .unwrap();
+
+        assert_eq!(account.total, Decimal::new(1, PRECISION));
+        assert_eq!(account.available, Decimal::new(1, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+    }
+
+    /// Test case for duplicated deposit
+    #[test]
+    fn test_duplicated_deposit() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let transaction1 = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        let transaction2 = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(2, PRECISION)),
+        );
+        account
+            .handle_transaction(transaction1, &Config::default())
+            .unwrap();
+        if let Ok(()) = account.handle_transaction(transaction2, &Config::default()) {
+            //this should fail
+            panic!()
+        }
+    }
+
+    /// Test case for multiple deposit
+    #[test]
+    fn test_multiple_deposit() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        for i in 1..11 {
+            let transaction = Transaction::_new(
+                Action::Deposit,
+                client_id,
+                i,
+                Some(Decimal::new(i.into(), PRECISION)),
+            );
+            account
+                .handle_transaction(transaction, &Config::default())
+                .unwrap();
+        }
+
+        assert_eq!(account.total, Decimal::new(55, PRECISION));
+        assert_eq!(account.available, Decimal::new(55, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+    }
+
+    #[test]
+    fn test_simple_withdrawal() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+
+        let withdrawal = Transaction::_new(
+            Action::Withdrawal,
+            client_id,
+            2,
+            Some(Decimal::new(1, PRECISION)),
+        );
+
+        account
+            .handle_transaction(withdrawal, &Config::default())
+            .unwrap();
+
+        assert_eq!(account.total, Decimal::new(0, PRECISION));
+        assert_eq!(account.available, Decimal::new(0, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+    }
+
+    #[test]
+    fn test_faulty_withdrawal() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+
+        let withdrawal = Transaction::_new(
+            Action::Withdrawal,
+            client_id,
+            2,
+            Some(Decimal::new(2, PRECISION)),
+        );
+
+        if let Ok(()) = account.handle_transaction(withdrawal, &Config::default()) {
+            //value should not change
+            assert_eq!(account.total, Decimal::new(1, PRECISION));
+            assert_eq!(account.available, Decimal::new(1, PRECISION));
+            assert_eq!(account.held, Decimal::new(0, PRECISION));
+            panic!()
+        }
+    }
+
+    #[test]
+    fn test_dispute() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+        assert_eq!(account.total, Decimal::new(1, PRECISION));
+        assert_eq!(account.available, Decimal::new(0, PRECISION));
+        assert_eq!(account.held, Decimal::new(1, PRECISION));
+        assert!(account.transactions.get(&1).unwrap().is_under_dispute())
+    }
+
+    #[test]
+    fn test_faulty_dispute() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+
+        let dispute = Transaction::_new(Action::Dispute, client_id, 2, None);
+
+        if let Ok(()) = account.handle_transaction(dispute, &Config::default()) {
+            panic!()
+        }
+        assert_eq!(account.total, Decimal::new(1, PRECISION));
+        assert_eq!(account.available, Decimal::new(1, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+
+        let resolve = Transaction::_new(Action::Resolve, client_id, 1, None);
+
+        account
+            .handle_transaction(resolve, &Config::default())
+            .unwrap();
+
+        assert_eq!(account.total, Decimal::new(1, PRECISION));
+        assert_eq!(account.available, Decimal::new(1, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+        assert!(!account.transactions.get(&1).unwrap().is_under_dispute())
+    }
+
+    #[test]
+    fn test_chargeback() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+
+        let chargeback = Transaction::_new(Action::Chargeback, client_id, 1, None);
+
+        account
+            .handle_transaction(chargeback, &Config::default())
+            .unwrap();
+
+        assert_eq!(account.total, Decimal::new(0, PRECISION));
+        assert_eq!(account.available, Decimal::new(0, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+        assert!(!account.transactions.get(&1).unwrap().is_under_dispute());
+        assert!(account.is_locked);
+    }
+
+    #[test]
+    fn test_locked_account() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+
+        let chargeback = Transaction::_new(Action::Chargeback, client_id, 1, None);
+
+        account
+            .handle_transaction(chargeback, &Config::default())
+            .unwrap();
+
+        let deposit2 = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            2,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        if let Ok(()) = account.handle_transaction(deposit2, &Config::default()) {
+            panic!()
+        }
+        assert_eq!(account.total, Decimal::new(0, PRECISION));
+        assert_eq!(account.available, Decimal::new(0, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+        assert!(!account.transactions.get(&1).unwrap().is_under_dispute());
+        assert!(account.is_locked);
+    }
+
+    #[test]
+    fn test_client_mismatch() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+
+        let dispute = Transaction::_new(Action::Dispute, 2, 1, None);
+
+        match account.handle_transaction(dispute, &Config::default()) {
+            Err(CustomError::ClientMismatch { expected, found }) => {
+                assert_eq!(expected, 1);
+                assert_eq!(found, 2);
+            }
+            _ => panic!(),
+        }
+        assert_eq!(account.available, Decimal::new(1, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+    }
+
+    /// Another client's dispute, resolve or chargeback must not touch the owner's account
+    #[tokio::test]
+    async fn test_cross_client_dispute() {
+        let mut engine = Engine::new(Config::default());
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,2,2,2.0\n\
+                     dispute,2,1,\n\
+                     chargeback,2,1,\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,1.0000,0.0000,1.0000,false",
+                "2,2.0000,0.0000,2.0000,false"
+            ]
+        );
+        let owner = engine.transaction_owners.get(1).unwrap();
+        assert!(engine
+            .check_owner(&Transaction::_new(Action::Dispute, 2, 1, None), owner)
+            .is_err());
+        assert!(engine
+            .check_owner(&Transaction::_new(Action::Dispute, 1, 1, None), owner)
+            .is_ok());
+    }
+
+    /// Transaction ids are globally unique, so another client cannot reuse one
+    #[tokio::test]
+    async fn test_cross_client_duplicated_transaction_id() {
+        let mut engine = Engine::new(Config::default());
+        let input = "type,client,tx,amount\n\
+                     deposit,1,7,5.0\n\
+                     deposit,2,7,9.0\n\
+                     withdrawal,2,7,1.0\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,5.0000,0.0000,5.0000,false",
+                "2,0.0000,0.0000,0.0000,false"
+            ]
+        );
+    }
+
+    /// Ids reused by another client are rejected whether ids come in sequence, filling bitmaps of owners, or
+    /// spread over the whole range, each alone in its list, and the owners take less than a hash map of them
+    /// only when dense
+    #[tokio::test]
+    async fn test_duplicate_ids_dense_and_sparse() {
+        for (pattern, spread) in [("dense", 1u32), ("sparse", 2_654_435_761)] {
+            let mut input = String::from("type,client,tx,amount\n");
+            let mut reused = 0;
+            for index in 1..=60_000u32 {
+                let tx = index.wrapping_mul(spread);
+                input.push_str(&format!("deposit,{},{},1.0\n", index % 50, tx));
+                //an earlier id, by the next client
+                if index % 97 == 0 {
+                    let earlier = (index / 2).wrapping_mul(spread);
+                    input.push_str(&format!("deposit,{},{},1.0\n", index / 2 % 50 + 1, earlier));
+                    input.push_str(&format!("dispute,{},{},\n", index / 2 % 50 + 1, earlier));
+                    reused += 1;
+                }
+            }
+            input.push_str(&format!("withdrawal,7,{},1.0\n", u32::MAX));
+            input.push_str(&format!("deposit,8,{},1.0\n", u32::MAX));
+            let mut engine = Engine::new(Config {
+                verbose: true,
+                ..Config::default()
+            });
+            run(&mut engine, &input).await;
+            let report = engine.report();
+            assert_eq!(
+                report.rejected_by_reason["duplicate tx"],
+                reused + 1,
+                "{}",
+                pattern
+            );
+            assert_eq!(
+                report.rejected_by_reason["client mismatch"], reused,
+                "{}",
+                pattern
+            );
+            assert_eq!(engine.transaction_owners.get(u32::MAX), Some(7));
+            match pattern {
+                "dense" => assert!(report.owner_bytes * 3 < report.owner_map_bytes),
+                _ => assert!(report.owner_bytes > report.owner_map_bytes),
+            }
+        }
+    }
+
+    /// Shuffling the transaction ids of a workload changes nothing but the order ids arrive in
+    #[tokio::test]
+    async fn test_shuffled_ids() {
+        for scenario in [Scenario::Disputes, Scenario::FewClients] {
+            let input = String::from_utf8(workload(scenario, 10_000, 5)).unwrap();
+            let shuffled: String = input
+                .lines()
+                .enumerate()
+                .map(|(index, row)| {
+                    let mut fields: Vec<String> = row.split(',').map(String::from).collect();
+                    if index > 0 {
+                        let id: TransactionId = fields[2].parse().unwrap();
+                        fields[2] = id.wrapping_mul(2_654_435_761).to_string();
+                    }
+                    fields.join(",") + "\n"
+                })
+                .collect();
+            let mut engine = Engine::new(Config::default());
+            let expected = run(&mut engine, &input).await;
+            let mut shuffled_engine = Engine::new(Config::default());
+            assert_eq!(
+                run(&mut shuffled_engine, &shuffled).await,
+                expected,
+                "{}",
+                scenario.as_str()
+            );
+            let kept = |engine: &Engine| {
+                engine
+                    .clients
+                    .values()
+                    .map(|account| account.kept_ids.len())
+                    .sum::<usize>()
+            };
+            assert!(kept(&engine) > 0);
+            assert_eq!(kept(&shuffled_engine), kept(&engine));
+            assert_eq!(engine.report().rejected, shuffled_engine.report().rejected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_allow_tx_id_reuse() {
+        let mut engine = Engine::new(Config {
+            allow_tx_id_reuse: true,
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,7,5.0\n\
+                     deposit,2,7,9.0\n\
+                     dispute,2,7,\n";
+        let output = run(&mut engine, input).await;
+
+        //each client disputes its own copy of the reused id
+        assert_eq!(
+            output,
+            vec![
+                "1,5.0000,0.0000,5.0000,false",
+                "2,0.0000,9.0000,9.0000,false"
+            ]
+        );
+    }
+
+    /// Resolving another open dispute must still work after the account is locked
+    #[test]
+    fn test_resolve_on_locked_account() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        for i in 1..3 {
+            let deposit = Transaction::_new(
+                Action::Deposit,
+                client_id,
+                i,
+                Some(Decimal::new(1, PRECISION)),
+            );
+            account
+                .handle_transaction(deposit, &Config::default())
+                .unwrap();
+            let dispute = Transaction::_new(Action::Dispute, client_id, i, None);
+            account
+                .handle_transaction(dispute, &Config::default())
+                .unwrap();
+        }
+
+        let chargeback = Transaction::_new(Action::Chargeback, client_id, 1, None);
+        account
+            .handle_transaction(chargeback, &Config::default())
+            .unwrap();
+        assert!(account.is_locked);
+
+        let resolve = Transaction::_new(Action::Resolve, client_id, 2, None);
+        account
+            .handle_transaction(resolve, &Config::default())
+            .unwrap();
+
+        assert_eq!(account.total, Decimal::new(1, PRECISION));
+        assert_eq!(account.available, Decimal::new(1, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+        assert!(account.is_locked);
+    }
+
+    /// An older deposit can still be disputed and charged back after the account is locked
+    #[test]
+    fn test_chargeback_on_locked_account() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        for i in 1..3 {
+            let deposit = Transaction::_new(
+                Action::Deposit,
+                client_id,
+                i,
+                Some(Decimal::new(i.into(), PRECISION)),
+            );
+            account
+                .handle_transaction(deposit, &Config::default())
+                .unwrap();
+        }
+        let dispute = Transaction::_new(Action::Dispute, client_id, 2, None);
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+        let chargeback = Transaction::_new(Action::Chargeback, client_id, 2, None);
+        account
+            .handle_transaction(chargeback, &Config::default())
+            .unwrap();
+        assert!(account.is_locked);
+
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+        assert_eq!(account.available, Decimal::new(0, PRECISION));
+        assert_eq!(account.held, Decimal::new(1, PRECISION));
+
+        let chargeback = Transaction::_new(Action::Chargeback, client_id, 1, None);
+        account
+            .handle_transaction(chargeback, &Config::default())
+            .unwrap();
+
+        assert_eq!(account.total, Decimal::new(0, PRECISION));
+        assert_eq!(account.available, Decimal::new(0, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+        assert!(account.is_locked);
+    }
+
+    #[test]
+    fn test_lock_and_unlock() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let lock = Transaction::_new(Action::Lock, client_id, 1, None);
+        account
+            .handle_transaction(lock, &Config::default())
+            .unwrap();
+        assert!(account.is_locked);
+
+        //locking again is a no-op
+        let lock = Transaction::_new(Action::Lock, client_id, 2, None);
+        account
+            .handle_transaction(lock, &Config::default())
+            .unwrap();
+        assert!(account.is_locked);
+
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            3,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        if let Ok(()) = account.handle_transaction(deposit, &Config::default()) {
+            panic!()
+        }
+
+        let unlock = Transaction::_new(Action::Unlock, client_id, 4, None);
+        account
+            .handle_transaction(unlock, &Config::default())
+            .unwrap();
+        assert!(!account.is_locked);
+
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            3,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+        assert_eq!(account.total, Decimal::new(1, PRECISION));
+        assert_eq!(account.admin_actions.len(), 3);
+
+        //administrative rows can never be disputed
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+        if let Ok(()) = account.handle_transaction(dispute, &Config::default()) {
+            panic!()
+        }
+    }
+
+    #[test]
+    fn test_unlock_chargeback_lock() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+        let chargeback = Transaction::_new(Action::Chargeback, client_id, 1, None);
+        account
+            .handle_transaction(chargeback, &Config::default())
+            .unwrap();
+
+        let unlock = Transaction::_new(Action::Unlock, client_id, 2, None);
+        match account.handle_transaction(unlock, &Config::default()) {
+            Err(CustomError::LockedByChargeback) => {}
+            _ => panic!(),
+        }
+        assert!(account.is_locked);
+
+        let config = Config {
+            allow_unlock_chargeback: true,
+            ..Config::default()
+        };
+        let unlock = Transaction::_new(Action::Unlock, client_id, 3, None);
+        account.handle_transaction(unlock, &config).unwrap();
+        assert!(!account.is_locked);
+    }
+
+    /// A resolved transaction can be disputed again, and a chargeback after that still locks
+    #[test]
+    fn test_redispute() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+        let resolve = Transaction::_new(Action::Resolve, client_id, 1, None);
+        account
+            .handle_transaction(resolve, &Config::default())
+            .unwrap();
+
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+        assert_eq!(account.total, Decimal::new(1, PRECISION));
+        assert_eq!(account.available, Decimal::new(0, PRECISION));
+        assert_eq!(account.held, Decimal::new(1, PRECISION));
+        assert_eq!(account.transactions.get(&1).unwrap().dispute_count(), 2);
+
+        let chargeback = Transaction::_new(Action::Chargeback, client_id, 1, None);
+        account
+            .handle_transaction(chargeback, &Config::default())
+            .unwrap();
+        assert_eq!(account.total, Decimal::new(0, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+        assert!(account.is_locked);
+    }
+
+    #[test]
+    fn test_max_redisputes() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let config = Config {
+            max_redisputes: Some(1),
+            ..Config::default()
+        };
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        account.handle_transaction(deposit, &config).unwrap();
+
+        //first dispute plus one redispute are allowed
+        for _ in 0..2 {
+            let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+            account.handle_transaction(dispute, &config).unwrap();
+            let resolve = Transaction::_new(Action::Resolve, client_id, 1, None);
+            account.handle_transaction(resolve, &config).unwrap();
+        }
+
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+        match account.handle_transaction(dispute, &config) {
+            Err(CustomError::RedisputeLimitExceeded { max_redisputes: 1 }) => {}
+            _ => panic!(),
+        }
+        assert_eq!(account.available, Decimal::new(1, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+        assert_eq!(account.transactions.get(&1).unwrap().dispute_count(), 2);
+    }
+
+    /// Disputing the same deposit twice used to hold its amount twice, driving available negative
+    #[test]
+    fn test_double_dispute() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+        match account.handle_transaction(dispute, &Config::default()) {
+            Err(CustomError::AlreadyUnderDispute) => {}
+            _ => panic!(),
+        }
+        assert_eq!(account.total, Decimal::new(1, PRECISION));
+        assert_eq!(account.available, Decimal::new(0, PRECISION));
+        assert_eq!(account.held, Decimal::new(1, PRECISION));
+        assert_eq!(account.transactions.get(&1).unwrap().dispute_count(), 1);
+
+        let resolve = Transaction::_new(Action::Resolve, client_id, 1, None);
+        account
+            .handle_transaction(resolve, &Config::default())
+            .unwrap();
+        assert_eq!(account.total, Decimal::new(1, PRECISION));
+        assert_eq!(account.available, Decimal::new(1, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_disabled() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(5, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+        let withdrawal = Transaction::_new(
+            Action::Withdrawal,
+            client_id,
+            2,
+            Some(Decimal::new(3, PRECISION)),
+        );
+        account
+            .handle_transaction(withdrawal, &Config::default())
+            .unwrap();
+
+        let dispute = Transaction::_new(Action::Dispute, client_id, 2, None);
+        match account.handle_transaction(dispute, &Config::default()) {
+            Err(CustomError::UndefinedBehaviour) => {}
+            _ => panic!(),
+        }
+        assert_eq!(account.total, Decimal::new(2, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+    }
+
+    #[test]
+    fn test_withdrawal_resolve() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let config = Config {
+            allow_withdrawal_disputes: true,
+            ..Config::default()
+        };
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(5, PRECISION)),
+        );
+        account.handle_transaction(deposit, &config).unwrap();
+        let withdrawal = Transaction::_new(
+            Action::Withdrawal,
+            client_id,
+            2,
+            Some(Decimal::new(3, PRECISION)),
+        );
+        account.handle_transaction(withdrawal, &config).unwrap();
+
+        let dispute = Transaction::_new(Action::Dispute, client_id, 2, None);
+        account.handle_transaction(dispute, &config).unwrap();
+        assert_eq!(account.total, Decimal::new(5, PRECISION));
+        assert_eq!(account.available, Decimal::new(2, PRECISION));
+        assert_eq!(account.held, Decimal::new(3, PRECISION));
+
+        let resolve = Transaction::_new(Action::Resolve, client_id, 2, None);
+        account.handle_transaction(resolve, &config).unwrap();
+        assert_eq!(account.total, Decimal::new(2, PRECISION));
+        assert_eq!(account.available, Decimal::new(2, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+        assert!(!account.is_locked);
+    }
+
+    /// Chargeback of a withdrawal returns the funds even when the account was emptied since
+    #[test]
+    fn test_withdrawal_chargeback_on_emptied_account() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let config = Config {
+            allow_withdrawal_disputes: true,
+            ..Config::default()
+        };
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(5, PRECISION)),
+        );
+        account.handle_transaction(deposit, &config).unwrap();
+        for (i, amount) in [(2, 3), (3, 2)] {
+            let withdrawal = Transaction::_new(
+                Action::Withdrawal,
+                client_id,
+                i,
+                Some(Decimal::new(amount, PRECISION)),
+            );
+            account.handle_transaction(withdrawal, &config).unwrap();
+        }
+        assert_eq!(account.total, Decimal::new(0, PRECISION));
+
+        let dispute = Transaction::_new(Action::Dispute, client_id, 2, None);
+        account.handle_transaction(dispute, &config).unwrap();
+        //cannot dispute it twice either
+        let dispute = Transaction::_new(Action::Dispute, client_id, 2, None);
+        if let Ok(()) = account.handle_transaction(dispute, &config) {
+            panic!()
+        }
+
+        let chargeback = Transaction::_new(Action::Chargeback, client_id, 2, None);
+        account.handle_transaction(chargeback, &config).unwrap();
+        assert_eq!(account.total, Decimal::new(3, PRECISION));
+        assert_eq!(account.available, Decimal::new(3, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+        assert!(account.is_locked);
+    }
+
+    /// A dispute exactly N records after the deposit is accepted, one record later it is not
+    #[test]
+    fn test_dispute_window_records() {
+        let client_id = 1;
+        let config = Config {
+            dispute_window: Some(Window::Records(3)),
+            ..Config::default()
+        };
+        for (dispute_index, expired) in [(4, false), (5, true)] {
+            let mut account = Account::new(client_id);
+            let mut deposit = Transaction::_new(
+                Action::Deposit,
+                client_id,
+                1,
+                Some(Decimal::new(1, PRECISION)),
+            );
+            deposit.record_index = 1;
+            account.handle_transaction(deposit, &config).unwrap();
+
+            let mut dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+            dispute.record_index = dispute_index;
+            match account.handle_transaction(dispute, &config) {
+                Ok(()) => assert!(!expired),
+                Err(CustomError::DisputeWindowExpired) => assert!(expired),
+                Err(_) => panic!(),
+            }
+        }
+    }
+
+    /// A dispute exactly 60 days after the deposit is accepted, one second later it is not
+    #[test]
+    fn test_dispute_window_duration() {
+        let client_id = 1;
+        let window = crate::config::parse_duration("60d").unwrap();
+        let config = Config {
+            dispute_window: Some(Window::Duration(window)),
+            ..Config::default()
+        };
+        for (dispute_timestamp, expired) in [
+            (Some(window), false),
+            (Some(window + 1), true),
+            (None, false),
+        ] {
+            let mut account = Account::new(client_id);
+            let mut deposit = Transaction::_new(
+                Action::Deposit,
+                client_id,
+                1,
+                Some(Decimal::new(1, PRECISION)),
+            );
+            deposit.timestamp = Some(0);
+            account.handle_transaction(deposit, &config).unwrap();
+
+            let mut dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+            dispute.timestamp = dispute_timestamp;
+            match account.handle_transaction(dispute, &config) {
+                Ok(()) => assert!(!expired),
+                Err(CustomError::DisputeWindowExpired) => assert!(expired),
+                Err(_) => panic!(),
+            }
+        }
+    }
+
+    /// Transactions outside the window are dropped, but disputes against them still report the window
+    #[tokio::test]
+    async fn test_dispute_window_prune() {
+        let mut engine = Engine::new(Config {
+            dispute_window: Some(Window::Records(2)),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,1,2,2.0\n\
+                     dispute,1,2,\n\
+                     deposit,1,3,3.0\n\
+                     dispute,1,1,\n\
+                     deposit,1,1,1.0\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(output, vec!["1,4.0000,2.0000,6.0000,false"]);
+        let account = engine.clients.get(&1).unwrap();
+        assert!(!account.transactions.contains_key(&1));
+        //still under dispute, so kept
+        assert!(account.transactions.contains_key(&2));
+        let dispute = Transaction::_new(Action::Dispute, 1, 1, None);
+        match engine.check_owner(&dispute, 1) {
+            Err(CustomError::DisputeWindowExpired) => {}
+            _ => panic!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timestamp_column() {
+        let mut engine = Engine::new(Config {
+            dispute_window: Some(Window::Duration(100)),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount,timestamp\n\
+                     deposit,1,1,1.0,1000\n\
+                     deposit,1,2,2.0,1050\n\
+                     dispute,1,1,,1101\n\
+                     dispute,1,2,,1150\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(output, vec!["1,1.0000,2.0000,3.0000,false"]);
+    }
+
+    /// Rows out of timestamp order are rejected when chronology is enforced without a buffer
+    #[tokio::test]
+    async fn test_enforce_chronology() {
+        let mut engine = Engine::new(Config {
+            enforce_chronology: true,
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount,timestamp\n\
+                     deposit,1,1,1.0,10\n\
+                     deposit,1,2,2.0,30\n\
+                     deposit,1,3,4.0,20\n\
+                     deposit,1,4,8.0,30\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(output, vec!["1,11.0000,0.0000,11.0000,false"]);
+    }
+
+    /// Small inversions are sorted by the buffer, larger ones are still rejected
+    #[tokio::test]
+    async fn test_reorder_buffer() {
+        let mut engine = Engine::new(Config {
+            enforce_chronology: true,
+            reorder_buffer: 1,
+            ..Config::default()
+        });
+        //the dispute arrives before its deposit, then a row far too late
+        let input = "type,client,tx,amount,timestamp\n\
+                     dispute,1,1,,20\n\
+                     deposit,1,1,1.0,10\n\
+                     deposit,1,2,2.0,30\n\
+                     deposit,1,3,4.0,40\n\
+                     deposit,1,4,8.0,15\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(output, vec!["1,6.0000,1.0000,7.0000,false"]);
+    }
+
+    /// Without a timestamp column the chronology check does nothing
+    #[tokio::test]
+    async fn test_enforce_chronology_without_timestamps() {
+        let mut engine = Engine::new(Config {
+            enforce_chronology: true,
+            reorder_buffer: 4,
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     withdrawal,1,2,1.0\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(output, vec!["1,0.0000,0.0000,0.0000,false"]);
+    }
+
+    /// Fees are taken from the net amount, while disputes hold the gross amount
+    #[tokio::test]
+    async fn test_fee_column() {
+        let mut engine = Engine::new(Config {
+            extra_columns: true,
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount,fee\n\
+                     deposit,1,1,10.0,1.0\n\
+                     withdrawal,1,2,4.0,0.5\n\
+                     withdrawal,1,3,4.0,0.6\n\
+                     deposit,1,4,1.0,1.0\n\
+                     deposit,1,5,1.0,-0.1\n\
+                     deposit,2,6,2.0,\n";
+        let mut reader = Reader::from_reader(input.as_bytes());
+        let mut writer = Writer::from_inner(Vec::new());
+        engine.process(&mut reader, &mut writer).await.unwrap();
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        let mut lines: Vec<&str> = output.lines().collect();
+        lines.sort();
+
+        assert_eq!(
+            lines,
+            vec![
+                "1,4.5000,0.0000,4.5000,false,1.5000,false,0,false,0,0,0.0000,false",
+                "2,2.0000,0.0000,2.0000,false,0.0000,false,0,false,0,0,0.0000,false",
+                "client,available,held,total,locked,fees,closed,chargeback_count,frozen,disputes_opened,disputes_resolved,disputed_amount,tx_cap_exceeded"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fee_chargeback() {
+        let client_id = 1;
+        for refund_fee_on_chargeback in [false, true] {
+            let config = Config {
+                refund_fee_on_chargeback,
+                ..Config::default()
+            };
+            let mut account = Account::new(client_id);
+            let mut deposit = Transaction::_new(
+                Action::Deposit,
+                client_id,
+                1,
+                Some(Decimal::new(10, PRECISION)),
+            );
+            deposit.fee = Decimal::new(1, PRECISION);
+            account.handle_transaction(deposit, &config).unwrap();
+            let deposit = Transaction::_new(
+                Action::Deposit,
+                client_id,
+                2,
+                Some(Decimal::new(5, PRECISION)),
+            );
+            account.handle_transaction(deposit, &config).unwrap();
+
+            let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+            account.handle_transaction(dispute, &config).unwrap();
+            assert_eq!(account.available, Decimal::new(4, PRECISION));
+            assert_eq!(account.held, Decimal::new(10, PRECISION));
+
+            let chargeback = Transaction::_new(Action::Chargeback, client_id, 1, None);
+            account.handle_transaction(chargeback, &config).unwrap();
+            assert_eq!(account.held, Decimal::new(0, PRECISION));
+            if refund_fee_on_chargeback {
+                assert_eq!(account.total, Decimal::new(5, PRECISION));
+                assert_eq!(account.fees, Decimal::new(0, PRECISION));
+            } else {
+                assert_eq!(account.total, Decimal::new(4, PRECISION));
+                assert_eq!(account.fees, Decimal::new(1, PRECISION));
+            }
+        }
+    }
+
+    /// Withdrawing exactly up to the credit limit works, one ten-thousandth more does not
+    #[test]
+    fn test_credit_limit() {
+        let client_id = 1;
+        let config = Config {
+            credit_limits: HashMap::from([(client_id, Decimal::new(50000, PRECISION))]),
+            ..Config::default()
+        };
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(10000, PRECISION)),
+        );
+        account.handle_transaction(deposit, &config).unwrap();
+
+        let withdrawal = Transaction::_new(
+            Action::Withdrawal,
+            client_id,
+            2,
+            Some(Decimal::new(60001, PRECISION)),
+        );
+        match account.handle_transaction(withdrawal, &config) {
+            Err(CustomError::AccountBalanceNotEnough) => {}
+            _ => panic!(),
+        }
+        assert_eq!(account.available, Decimal::new(10000, PRECISION));
+
+        let withdrawal = Transaction::_new(
+            Action::Withdrawal,
+            client_id,
+            3,
+            Some(Decimal::new(60000, PRECISION)),
+        );
+        account.handle_transaction(withdrawal, &config).unwrap();
+        assert_eq!(account.available, Decimal::new(-50000, PRECISION));
+        assert_eq!(account.total, Decimal::new(-50000, PRECISION));
+
+        //other clients have no credit
+        let mut other = Account::new(2);
+        let withdrawal =
+            Transaction::_new(Action::Withdrawal, 2, 4, Some(Decimal::new(1, PRECISION)));
+        if let Ok(()) = other.handle_transaction(withdrawal, &config) {
+            panic!()
+        }
+    }
+
+    /// Disputes and chargebacks are not bound by the credit limit
+    #[test]
+    fn test_chargeback_below_credit_limit() {
+        let client_id = 1;
+        let config = Config {
+            credit_limits: HashMap::from([(client_id, Decimal::new(5, PRECISION))]),
+            ..Config::default()
+        };
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(10, PRECISION)),
+        );
+        account.handle_transaction(deposit, &config).unwrap();
+        let withdrawal = Transaction::_new(
+            Action::Withdrawal,
+            client_id,
+            2,
+            Some(Decimal::new(12, PRECISION)),
+        );
+        account.handle_transaction(withdrawal, &config).unwrap();
+
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+        account.handle_transaction(dispute, &config).unwrap();
+        assert_eq!(account.available, Decimal::new(-12, PRECISION));
+        assert_eq!(account.held, Decimal::new(10, PRECISION));
+
+        let chargeback = Transaction::_new(Action::Chargeback, client_id, 1, None);
+        account.handle_transaction(chargeback, &config).unwrap();
+        assert_eq!(account.available, Decimal::new(-12, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+        assert_eq!(account.total, Decimal::new(-12, PRECISION));
+        assert!(account.is_locked);
+    }
+
+    #[test]
+    fn test_close() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+        let close = Transaction::_new(Action::Close, client_id, 2, None);
+        account
+            .handle_transaction(close, &Config::default())
+            .unwrap();
+        assert!(account.is_closed);
+
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            3,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        match account.handle_transaction(deposit, &Config::default()) {
+            Err(CustomError::AccountClosed) => {}
+            _ => panic!(),
+        }
+        //closing again is not allowed either
+        let close = Transaction::_new(Action::Close, client_id, 4, None);
+        match account.handle_transaction(close, &Config::default()) {
+            Err(CustomError::AccountClosed) => {}
+            _ => panic!(),
+        }
+
+        //disputes still go through
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+        let chargeback = Transaction::_new(Action::Chargeback, client_id, 1, None);
+        account
+            .handle_transaction(chargeback, &Config::default())
+            .unwrap();
+        assert_eq!(account.total, Decimal::new(0, PRECISION));
+        assert!(account.is_closed);
+        assert!(account.is_locked);
+    }
+
+    #[test]
+    fn test_close_with_held_funds() {
+        let client_id = 1;
+        for defer_close in [false, true] {
+            let config = Config {
+                defer_close,
+                ..Config::default()
+            };
+            let mut account = Account::new(client_id);
+            let deposit = Transaction::_new(
+                Action::Deposit,
+                client_id,
+                1,
+                Some(Decimal::new(1, PRECISION)),
+            );
+            account.handle_transaction(deposit, &config).unwrap();
+            let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+            account.handle_transaction(dispute, &config).unwrap();
+
+            let close = Transaction::_new(Action::Close, client_id, 2, None);
+            match account.handle_transaction(close, &config) {
+                Ok(()) => assert!(defer_close),
+                Err(CustomError::HeldFundsOnClose) => assert!(!defer_close),
+                Err(_) => panic!(),
+            }
+            assert!(!account.is_closed);
+            assert_eq!(account.is_close_pending, defer_close);
+
+            let resolve = Transaction::_new(Action::Resolve, client_id, 1, None);
+            account.handle_transaction(resolve, &config).unwrap();
+            assert_eq!(account.is_closed, defer_close);
+            assert!(!account.is_close_pending);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_omit_closed() {
+        let mut engine = Engine::new(Config {
+            omit_closed: true,
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,2,2,2.0\n\
+                     close,2,3,\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(output, vec!["1,1.0000,0.0000,1.0000,false"]);
+    }
+
+    /// Amounts exactly at the limit pass, above it they are rejected and never stored
+    #[tokio::test]
+    async fn test_max_transaction_amount() {
+        let rejects_path = std::env::temp_dir().join("test_max_transaction_amount_rejects.csv");
+        let mut engine = Engine::new(Config {
+            max_transaction_amount: Some(Decimal::new(100000000, PRECISION)),
+            rejects_path: Some(rejects_path.clone()),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10000.0000\n\
+                     deposit,1,2,10000.0001\n\
+                     withdrawal,1,3,10000.0001\n\
+                     dispute,1,2,\n\
+                     withdrawal,1,4,10000.0000\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(output, vec!["1,0.0000,0.0000,0.0000,false"]);
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "type,client,tx,amount,error,line,raw\n\
+             deposit,1,2,10000.0001,Amount exceeds the limit of 10000.0000,3,\"deposit,1,2,10000.0001\"\n\
+             withdrawal,1,3,10000.0001,Amount exceeds the limit of 10000.0000,4,\"withdrawal,1,3,10000.0001\"\n\
+             dispute,1,2,,Non existing transaction id 2 for dispute,5,\"dispute,1,2,\"\n"
+        );
+    }
+
+    /// Withdraws one ten-thousandth at the given record index
+    fn withdraw_at(
+        account: &mut Account,
+        transaction_id: TransactionId,
+        record_index: u64,
+        config: &Config,
+    ) -> Result<(), CustomError> {
+        let mut withdrawal = Transaction::_new(
+            Action::Withdrawal,
+            account.client_id,
+            transaction_id,
+            Some(Decimal::new(1, PRECISION)),
+        );
+        withdrawal.record_index = record_index;
+        account.handle_transaction(withdrawal, config)
+    }
+
+    #[test]
+    fn test_velocity_limit() {
+        let client_id = 1;
+        let config = Config {
+            velocity_limit: Some(VelocityLimit {
+                max_withdrawals: 3,
+                window: Window::Records(10),
+            }),
+            ..Config::default()
+        };
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(100, PRECISION)),
+        );
+        account.handle_transaction(deposit, &config).unwrap();
+
+        //a burst exactly at the limit passes
+        for i in 2..5 {
+            withdraw_at(&mut account, i, i.into(), &config).unwrap();
+        }
+        //one over the limit is rejected
+        match withdraw_at(&mut account, 5, 5, &config) {
+            Err(CustomError::VelocityLimitExceeded { max_withdrawals: 3 }) => {}
+            _ => panic!(),
+        }
+        //deposits still work
+        let mut deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            6,
             Some(Decimal::new(1, PRECISION)),
+        );
+        deposit.record_index = 6;
+        account.handle_transaction(deposit, &config).unwrap();
+        //the first withdrawal at record 2 is still inside the window at record 11
+        match withdraw_at(&mut account, 7, 11, &config) {
+            Err(CustomError::VelocityLimitExceeded { .. }) => {}
+            _ => panic!(),
+        }
+        //and has left it at record 12
+        withdraw_at(&mut account, 8, 12, &config).unwrap();
+        assert_eq!(account.available, Decimal::new(97, PRECISION));
+        assert_eq!(account.recent_withdrawals.len(), 3);
+    }
+
+    #[test]
+    fn test_velocity_limit_duration() {
+        let client_id = 1;
+        let config = Config {
+            velocity_limit: Some(VelocityLimit {
+                max_withdrawals: 1,
+                window: Window::Duration(60),
+            }),
+            ..Config::default()
+        };
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(100, PRECISION)),
+        );
+        account.handle_transaction(deposit, &config).unwrap();
+        for (transaction_id, timestamp, allowed) in [(2, 0, true), (3, 59, false), (4, 60, true)] {
+            let mut withdrawal = Transaction::_new(
+                Action::Withdrawal,
+                client_id,
+                transaction_id,
+                Some(Decimal::new(1, PRECISION)),
+            );
+            withdrawal.timestamp = Some(timestamp);
+            assert_eq!(
+                account.handle_transaction(withdrawal, &config).is_ok(),
+                allowed
+            );
+        }
+    }
+
+    /// Repeated lifecycle rows are ignored without being rejected, genuine redisputes still apply
+    #[tokio::test]
+    async fn test_replayed_lifecycle_rows() {
+        let rejects_path = std::env::temp_dir().join("test_replayed_lifecycle_rows_rejects.csv");
+        let mut engine = Engine::new(Config {
+            rejects_path: Some(rejects_path.clone()),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,1,2,2.0\n\
+                     dispute,1,1,\n\
+                     dispute,1,1,\n\
+                     resolve,1,1,\n\
+                     resolve,1,1,\n\
+                     dispute,1,1,\n\
+                     dispute,1,2,\n\
+                     chargeback,1,2,\n\
+                     chargeback,1,2,\n\
+                     resolve,1,2,\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(output, vec!["1,0.0000,1.0000,1.0000,true"]);
+        assert_eq!(engine.duplicates_ignored, 3);
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        //only the conflicting resolve after the chargeback is an error
+        assert_eq!(
+            rejects,
+            "type,client,tx,amount,error,line,raw\n\
+             resolve,1,2,,Transaction was already charged back,12,\"resolve,1,2,\"\n"
+        );
+    }
+
+    #[test]
+    fn test_settle() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        for i in 1..5 {
+            let deposit = Transaction::_new(
+                Action::Deposit,
+                client_id,
+                i,
+                Some(Decimal::new(1, PRECISION)),
+            );
+            account
+                .handle_transaction(deposit, &Config::default())
+                .unwrap();
+        }
+        let dispute = Transaction::_new(Action::Dispute, client_id, 2, None);
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+
+        let mut settle = Transaction::_new(Action::Settle, client_id, 5, None);
+        settle.settle_end = Some(1);
+        match account.handle_transaction(settle, &Config::default()) {
+            Err(CustomError::InvalidSettleRange { start: 5, end: 1 }) => {}
+            _ => panic!(),
+        }
+
+        let mut settle = Transaction::_new(Action::Settle, client_id, 1, None);
+        settle.settle_end = Some(3);
+        account
+            .handle_transaction(settle, &Config::default())
+            .unwrap();
+        //the open dispute is kept, the rest of the range is dropped
+        assert!(!account.transactions.contains_key(&1));
+        assert!(account.transactions.contains_key(&2));
+        assert!(!account.transactions.contains_key(&3));
+        assert!(account.transactions.contains_key(&4));
+
+        for i in [1, 3] {
+            let dispute = Transaction::_new(Action::Dispute, client_id, i, None);
+            match account.handle_transaction(dispute, &Config::default()) {
+                Err(CustomError::TransactionSettled) => {}
+                _ => panic!(),
+            }
+        }
+        //the open dispute can still be resolved, but not disputed again
+        let resolve = Transaction::_new(Action::Resolve, client_id, 2, None);
+        account
+            .handle_transaction(resolve, &Config::default())
+            .unwrap();
+        let dispute = Transaction::_new(Action::Dispute, client_id, 2, None);
+        match account.handle_transaction(dispute, &Config::default()) {
+            Err(CustomError::TransactionSettled) => {}
+            _ => panic!(),
+        }
+        let dispute = Transaction::_new(Action::Dispute, client_id, 4, None);
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+        assert_eq!(account.available, Decimal::new(3, PRECISION));
+        assert_eq!(account.held, Decimal::new(1, PRECISION));
+    }
+
+    /// Settled transactions report as settled even with a dispute window, and other clients are untouched
+    #[tokio::test]
+    async fn test_settle_end_to_end() {
+        let mut engine = Engine::new(Config {
+            dispute_window: Some(Window::Records(100)),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount,tx_end\n\
+                     deposit,1,1,1.0,\n\
+                     deposit,2,2,2.0,\n\
+                     deposit,1,3,3.0,\n\
+                     settle,1,1,,3\n\
+                     dispute,1,1,,\n\
+                     dispute,2,2,,\n\
+                     deposit,1,3,3.0,\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,4.0000,0.0000,4.0000,false",
+                "2,0.0000,2.0000,2.0000,false"
+            ]
+        );
+        let dispute = Transaction::_new(Action::Dispute, 1, 1, None);
+        assert!(engine.check_owner(&dispute, 1).is_ok());
+    }
+
+    /// Administrative holds are kept apart from dispute holds
+    #[test]
+    fn test_hold_and_release() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(10, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+        let hold = Transaction::_new(
+            Action::Hold,
+            client_id,
+            2,
+            Some(Decimal::new(11, PRECISION)),
+        );
+        match account.handle_transaction(hold, &Config::default()) {
+            Err(CustomError::AccountBalanceNotEnough) => {}
+            _ => panic!(),
+        }
+        let hold = Transaction::_new(Action::Hold, client_id, 2, Some(Decimal::new(4, PRECISION)));
+        account
+            .handle_transaction(hold, &Config::default())
+            .unwrap();
+        assert_eq!(account.available, Decimal::new(6, PRECISION));
+        assert_eq!(account.held, Decimal::new(4, PRECISION));
+
+        //the deposit can no longer be fully held by a dispute, available goes negative
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+        assert_eq!(account.available, Decimal::new(-4, PRECISION));
+        assert_eq!(account.held, Decimal::new(14, PRECISION));
+        //a release cannot free funds held by the dispute
+        let release = Transaction::_new(
+            Action::Release,
+            client_id,
+            3,
+            Some(Decimal::new(5, PRECISION)),
+        );
+        match account.handle_transaction(release, &Config::default()) {
+            Err(CustomError::ReleaseExceedsHold { held }) => {
+                assert_eq!(held, Decimal::new(4, PRECISION))
+            }
+            _ => panic!(),
+        }
+        let chargeback = Transaction::_new(Action::Chargeback, client_id, 1, None);
+        account
+            .handle_transaction(chargeback, &Config::default())
+            .unwrap();
+        assert_eq!(account.held, Decimal::new(4, PRECISION));
+        assert_eq!(account.total, Decimal::new(0, PRECISION));
+
+        //the account is locked by the chargeback, but the hold can still be released
+        let release = Transaction::_new(
+            Action::Release,
+            client_id,
+            3,
+            Some(Decimal::new(4, PRECISION)),
+        );
+        account
+            .handle_transaction(release, &Config::default())
+            .unwrap();
+        assert_eq!(account.available, Decimal::new(0, PRECISION));
+        assert_eq!(account.held, Decimal::new(0, PRECISION));
+    }
+
+    /// A hold blocks closing the account, and releasing it completes a deferred closure
+    #[tokio::test]
+    async fn test_hold_end_to_end() {
+        let mut engine = Engine::new(Config {
+            defer_close: true,
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,5.0\n\
+                     hold,1,2,-1.0\n\
+                     hold,1,2,2.0\n\
+                     close,1,3,\n\
+                     hold,1,4,1.0\n\
+                     release,1,5,3.0\n\
+                     release,1,5,2.0\n\
+                     deposit,2,6,1.0\n\
+                     hold,2,7,1.0\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,5.0000,0.0000,5.0000,false",
+                "2,0.0000,1.0000,1.0000,false"
+            ]
+        );
+        assert!(engine.clients[&1].is_closed);
+    }
+
+    /// Every chargeback is recorded in order, including those on an already locked account
+    #[tokio::test]
+    async fn test_chargeback_history() {
+        let mut engine = Engine::new(Config {
+            extra_columns: true,
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount,timestamp\n\
+                     deposit,1,1,1.0,100\n\
+                     deposit,1,2,2.0,101\n\
+                     deposit,2,3,3.0,102\n\
+                     dispute,1,1,,103\n\
+                     dispute,1,2,,104\n\
+                     chargeback,1,2,,105\n\
+                     chargeback,1,1,,106\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,0.0000,0.0000,0.0000,true,0.0000,false,2,false,2,0,3.0000,false",
+                "2,3.0000,0.0000,3.0000,false,0.0000,false,0,false,0,0,0.0000,false"
+            ]
+        );
+        let chargebacks: Vec<_> = engine.clients[&1]
+            .chargebacks
+            .iter()
+            .map(|chargeback| {
+                (
+                    chargeback.transaction_id,
+                    chargeback.amount,
+                    chargeback.record_index,
+                    chargeback.timestamp,
+                )
+            })
+            .collect();
+        assert_eq!(
+            chargebacks,
+            vec![
+                (2, Decimal::new(20, 1), 6, Some(105)),
+                (1, Decimal::new(10, 1), 7, Some(106))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reinstate() {
+        let client_id = 1;
+        for reinstate_refunds in [false, true] {
+            let config = Config {
+                reinstate_refunds,
+                ..Config::default()
+            };
+            let mut account = Account::new(client_id);
+            for i in 1..4 {
+                let deposit = Transaction::_new(
+                    Action::Deposit,
+                    client_id,
+                    i,
+                    Some(Decimal::new(i as i64, PRECISION)),
+                );
+                account.handle_transaction(deposit, &config).unwrap();
+            }
+            let reinstate = Transaction::_new(Action::Reinstate, client_id, 1, None);
+            match account.handle_transaction(reinstate, &config) {
+                Err(CustomError::AccountNotLocked) => {}
+                _ => panic!(),
+            }
+            for i in [1, 2] {
+                let dispute = Transaction::_new(Action::Dispute, client_id, i, None);
+                account.handle_transaction(dispute, &config).unwrap();
+                let chargeback = Transaction::_new(Action::Chargeback, client_id, i, None);
+                account.handle_transaction(chargeback, &config).unwrap();
+            }
+            //the deposit was never charged back
+            let reinstate = Transaction::_new(Action::Reinstate, client_id, 3, None);
+            match account.handle_transaction(reinstate, &config) {
+                Err(CustomError::NotChargedBack) => {}
+                _ => panic!(),
+            }
+            //the second chargeback still stands, so the account stays locked
+            let reinstate = Transaction::_new(Action::Reinstate, client_id, 1, None);
+            account.handle_transaction(reinstate, &config).unwrap();
+            assert!(account.is_locked);
+            let reinstate = Transaction::_new(Action::Reinstate, client_id, 1, None);
+            match account.handle_transaction(reinstate, &config) {
+                Err(CustomError::NotChargedBack) => {}
+                _ => panic!(),
+            }
+            let reinstate = Transaction::_new(Action::Reinstate, client_id, 2, None);
+            account.handle_transaction(reinstate, &config).unwrap();
+            assert!(!account.is_locked);
+            assert!(!account.is_locked_by_chargeback);
+            //reinstated chargebacks stay in the history
+            assert_eq!(account.chargebacks.len(), 2);
+            assert!(account
+                .chargebacks
+                .iter()
+                .all(|chargeback| chargeback.reinstated_at.is_some()));
+            let expected = if reinstate_refunds {
+                Decimal::new(6, PRECISION)
+            } else {
+                Decimal::new(3, PRECISION)
+            };
+            assert_eq!(account.available, expected);
+            assert_eq!(account.total, expected);
+        }
+
+        //an account locked by a Lock row before its chargeback stays locked once the chargeback is reinstated
+        let mut account = Account::new(client_id);
+        let config = Config::default();
+        let deposit = Transaction::_new(Action::Deposit, client_id, 1, Some(Decimal::ONE));
+        account.handle_transaction(deposit, &config).unwrap();
+        for action in [Action::Lock, Action::Dispute, Action::Chargeback] {
+            let transaction = Transaction::_new(action, client_id, 1, None);
+            account.handle_transaction(transaction, &config).unwrap();
+        }
+        let reinstate = Transaction::_new(Action::Reinstate, client_id, 1, None);
+        account.handle_transaction(reinstate, &config).unwrap();
+        assert!(account.is_locked);
+        assert!(account.is_locked_by_admin);
+        assert!(!account.is_locked_by_chargeback);
+        let unlock = Transaction::_new(Action::Unlock, client_id, 2, None);
+        account.handle_transaction(unlock, &config).unwrap();
+        assert!(!account.is_locked);
+    }
+
+    /// Partial disputes hold only their portion
+    #[test]
+    fn test_partial_dispute() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(10, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+        let dispute = Transaction::_new(
+            Action::Dispute,
+            client_id,
+            1,
+            Some(Decimal::new(11, PRECISION)),
+        );
+        match account.handle_transaction(dispute, &Config::default()) {
+            Err(CustomError::DisputeExceedsAmount { .. }) => {}
+            _ => panic!(),
+        }
+        let dispute = Transaction::_new(
+            Action::Dispute,
+            client_id,
+            1,
+            Some(Decimal::new(3, PRECISION)),
+        );
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+        assert_eq!(account.available, Decimal::new(7, PRECISION));
+        assert_eq!(account.held, Decimal::new(3, PRECISION));
+        let resolve = Transaction::_new(Action::Resolve, client_id, 1, None);
+        account
+            .handle_transaction(resolve, &Config::default())
+            .unwrap();
+        assert_eq!(account.available, Decimal::new(10, PRECISION));
+
+        let dispute = Transaction::_new(
+            Action::Dispute,
+            client_id,
+            1,
+            Some(Decimal::new(4, PRECISION)),
+        );
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+        let chargeback = Transaction::_new(Action::Chargeback, client_id, 1, None);
+        account
+            .handle_transaction(chargeback, &Config::default())
+            .unwrap();
+        assert!(account.is_locked);
+        assert_eq!(account.available, Decimal::new(6, PRECISION));
+        assert_eq!(account.total, Decimal::new(6, PRECISION));
+        assert_eq!(account.chargebacks[0].amount, Decimal::new(4, PRECISION));
+
+        //a partial chargeback is still final, the rest of the transaction cannot be disputed
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+        match account.handle_transaction(dispute, &Config::default()) {
+            Err(CustomError::AlreadyChargedBack) => {}
+            _ => panic!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partial_dispute_column() {
+        let mut engine = Engine::new(Config::default());
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,3.0\n\
+                     dispute,1,1,1.0\n\
+                     deposit,2,2,3.0\n\
+                     dispute,2,2,\n\
+                     deposit,3,3,3.0\n\
+                     dispute,3,3,-1.0\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,2.0000,1.0000,3.0000,false",
+                "2,0.0000,3.0000,3.0000,false",
+                "3,3.0000,0.0000,3.0000,false"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dispute_state_transitions() {
+        use DisputeState::*;
+        let actions = [
+            Action::Dispute,
+            Action::Resolve,
+            Action::Chargeback,
+            Action::Reversal,
+        ];
+        let expected = [
+            (
+                None,
+                [
+                    Ok(Disputed),
+                    Err("Not under dispute"),
+                    Err("Not under dispute"),
+                    Ok(Reversed),
+                ],
+            ),
+            (
+                Disputed,
+                [
+                    Err("Already under dispute"),
+                    Ok(Resolved),
+                    Ok(ChargedBack),
+                    Err("Already under dispute"),
+                ],
+            ),
+            (
+                Resolved,
+                [
+                    Ok(Disputed),
+                    Err("Not under dispute"),
+                    Err("Not under dispute"),
+                    Ok(Reversed),
+                ],
+            ),
+            (
+                AutoResolved,
+                [
+                    Ok(Disputed),
+                    Err("Not under dispute"),
+                    Err("Not under dispute"),
+                    Ok(Reversed),
+                ],
+            ),
+            (
+                ChargedBack,
+                [Err("Transaction was already charged back"); 4],
+            ),
+            (Reversed, [Err("Transaction was already reversed"); 4]),
+        ];
+        for (state, results) in expected {
+            for (action, result) in actions.iter().zip(results) {
+                let next = state.next(*action).map_err(|err| err.to_string());
+                assert_eq!(
+                    next,
+                    result.map_err(String::from),
+                    "{:?} {:?}",
+                    state,
+                    action
+                );
+            }
+        }
+    }
+
+    /// A charged back transaction cannot be disputed, resolved or charged back again
+    /// whether or not the account was unlocked since, and the balances are left untouched
+    #[tokio::test]
+    async fn test_dispute_after_chargeback() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,3.0\n\
+                     deposit,1,2,2.0\n\
+                     dispute,1,1,\n\
+                     chargeback,1,1,\n\
+                     unlock,1,3,\n\
+                     dispute,1,1,\n\
+                     resolve,1,1,\n\
+                     chargeback,1,1,\n";
+        for (allow_unlock_chargeback, locked) in [(false, true), (true, false)] {
+            let mut engine = Engine::new(Config {
+                allow_unlock_chargeback,
+                ..Config::default()
+            });
+            let output = run(&mut engine, input).await;
+
+            assert_eq!(output, vec![format!("1,2.0000,0.0000,2.0000,{}", locked)]);
+            assert_eq!(engine.clients[&1].chargebacks.len(), 1);
+            //the last chargeback repeats the applied one, so it is ignored as a replay
+            let report = engine.report();
+            assert_eq!(report.rejected_by_reason["already charged back"], 2);
+            assert_eq!(report.ignored, 1);
+        }
+    }
+
+    /// A reversal gives a deposit back without locking the account
+    #[test]
+    fn test_reversal() {
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        let mut deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(10, PRECISION)),
+        );
+        deposit.fee = Decimal::new(1, PRECISION);
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+        let withdrawal = Transaction::_new(
+            Action::Withdrawal,
+            client_id,
+            2,
+            Some(Decimal::new(5, PRECISION)),
+        );
+        account
+            .handle_transaction(withdrawal, &Config::default())
+            .unwrap();
+        for (transaction_id, expected) in [
+            (2, "Undefined Behaviour"),
+            (3, "Non existing transaction id 3 for reversal"),
+        ] {
+            let reversal = Transaction::_new(Action::Reversal, client_id, transaction_id, None);
+            let err = account
+                .handle_transaction(reversal, &Config::default())
+                .unwrap_err();
+            assert_eq!(err.to_string(), expected);
+        }
+        //only 4 of the 9 credited are left
+        let reversal = Transaction::_new(Action::Reversal, client_id, 1, None);
+        match account.handle_transaction(reversal, &Config::default()) {
+            Err(CustomError::AccountBalanceNotEnough) => {}
+            _ => panic!(),
+        }
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            3,
+            Some(Decimal::new(5, PRECISION)),
+        );
+        account
+            .handle_transaction(deposit, &Config::default())
+            .unwrap();
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+        account
+            .handle_transaction(dispute, &Config::default())
+            .unwrap();
+        let reversal = Transaction::_new(Action::Reversal, client_id, 1, None);
+        match account.handle_transaction(reversal, &Config::default()) {
+            Err(CustomError::AlreadyUnderDispute) => {}
+            _ => panic!(),
+        }
+        let resolve = Transaction::_new(Action::Resolve, client_id, 1, None);
+        account
+            .handle_transaction(resolve, &Config::default())
+            .unwrap();
+
+        let reversal = Transaction::_new(Action::Reversal, client_id, 1, None);
+        account
+            .handle_transaction(reversal, &Config::default())
+            .unwrap();
+        assert!(!account.is_locked);
+        assert_eq!(account.available, Decimal::new(0, PRECISION));
+        assert_eq!(account.total, Decimal::new(0, PRECISION));
+        assert_eq!(account.fees, Decimal::new(0, PRECISION));
+        for action in [Action::Reversal, Action::Dispute] {
+            let transaction = Transaction::_new(action, client_id, 1, None);
+            match account.handle_transaction(transaction, &Config::default()) {
+                Err(CustomError::AlreadyReversed) => {}
+                _ => panic!(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_escrow() {
+        let client_id = 1;
+        for escrow_release_transfers in [false, true] {
+            let config = Config {
+                escrow_release_transfers,
+                ..Config::default()
+            };
+            let mut account = Account::new(client_id);
+            let deposit = Transaction::_new(
+                Action::Deposit,
+                client_id,
+                1,
+                Some(Decimal::new(10, PRECISION)),
+            );
+            account.handle_transaction(deposit, &config).unwrap();
+            let escrow = Transaction::_new(
+                Action::Escrow,
+                client_id,
+                2,
+                Some(Decimal::new(6, PRECISION)),
+            );
+            account.handle_transaction(escrow, &config).unwrap();
+            assert_eq!(account.available, Decimal::new(4, PRECISION));
+            assert_eq!(account.held, Decimal::new(6, PRECISION));
+            //escrowed funds cannot be withdrawn
+            let withdrawal = Transaction::_new(
+                Action::Withdrawal,
+                client_id,
+                3,
+                Some(Decimal::new(5, PRECISION)),
+            );
+            match account.handle_transaction(withdrawal, &config) {
+                Err(CustomError::AccountBalanceNotEnough) => {}
+                _ => panic!(),
+            }
+
+            let release = Transaction::_new(
+                Action::EscrowRelease,
+                client_id,
+                2,
+                Some(Decimal::new(7, PRECISION)),
+            );
+            match account.handle_transaction(release, &config) {
+                Err(CustomError::ReleaseExceedsEscrow { escrowed }) => {
+                    assert_eq!(escrowed, Decimal::new(6, PRECISION))
+                }
+                _ => panic!(),
+            }
+            let release = Transaction::_new(
+                Action::EscrowRelease,
+                client_id,
+                2,
+                Some(Decimal::new(2, PRECISION)),
+            );
+            account.handle_transaction(release, &config).unwrap();
+            let release = Transaction::_new(Action::EscrowRelease, client_id, 2, None);
+            account.handle_transaction(release, &config).unwrap();
+            let release = Transaction::_new(Action::EscrowRelease, client_id, 2, None);
+            match account.handle_transaction(release, &config) {
+                Err(CustomError::EscrowAlreadyReleased) => {}
+                _ => panic!(),
+            }
+            assert_eq!(account.held, Decimal::new(0, PRECISION));
+            if escrow_release_transfers {
+                assert_eq!(account.available, Decimal::new(4, PRECISION));
+                assert_eq!(account.total, Decimal::new(4, PRECISION));
+            } else {
+                assert_eq!(account.available, Decimal::new(10, PRECISION));
+                assert_eq!(account.total, Decimal::new(10, PRECISION));
+            }
+        }
+    }
+
+    /// Escrows are apart from disputes, and a locked account releases them only when allowed
+    #[tokio::test]
+    async fn test_escrow_on_locked_account() {
+        for allow_locked_escrow_release in [false, true] {
+            let mut engine = Engine::new(Config {
+                allow_locked_escrow_release,
+                ..Config::default()
+            });
+            let input = "type,client,tx,amount\n\
+                         deposit,1,1,2.0\n\
+                         deposit,1,2,5.0\n\
+                         escrow,1,3,4.0\n\
+                         escrow,1,1,1.0\n\
+                         dispute,1,1,\n\
+                         chargeback,1,1,\n\
+                         escrow,1,4,1.0\n\
+                         escrow_release,1,3,\n";
+            let output = run(&mut engine, input).await;
+
+            let expected = if allow_locked_escrow_release {
+                "1,5.0000,0.0000,5.0000,true"
+            } else {
+                "1,1.0000,4.0000,5.0000,true"
+            };
+            assert_eq!(output, vec![expected]);
+        }
+    }
+
+    /// A client is in the output even when none of its rows applies
+    #[tokio::test]
+    async fn test_account_with_only_rejected_rows() {
+        let mut engine = Engine::new(Config {
+            enforce_chronology: true,
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount,timestamp\n\
+                     withdrawal,1,1,1.0,10\n\
+                     deposit,2,2,1.0,10\n\
+                     dispute,3,9,,11\n\
+                     deposit,4,3,1.0,5\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,0.0000,0.0000,0.0000,false",
+                "2,1.0000,0.0000,1.0000,false",
+                "3,0.0000,0.0000,0.0000,false",
+                "4,0.0000,0.0000,0.0000,false"
+            ]
+        );
+    }
+
+    /// Adjustments move the balance both ways and can never be disputed
+    #[tokio::test]
+    async fn test_adjustment() {
+        let report_path = std::env::temp_dir().join("test_adjustment_report.csv");
+        let mut engine = Engine::new(Config {
+            report_path: Some(report_path.clone()),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,5.0\n\
+                     adjustment,1,2,+12.3456\n\
+                     adjustment,1,3,-17.3457\n\
+                     adjustment,1,3,-17.3456\n\
+                     dispute,1,2,\n\
+                     reversal,1,2,\n\
+                     adjustment,2,2,1.0\n\
+                     deposit,3,4,1.0\n\
+                     dispute,3,4,\n\
+                     chargeback,3,4,\n\
+                     adjustment,3,5,1.0\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,0.0000,0.0000,0.0000,false",
+                "2,0.0000,0.0000,0.0000,false",
+                "3,0.0000,0.0000,0.0000,true"
+            ]
+        );
+        assert_eq!(engine.adjustments_applied, 2);
+        let report = engine.report();
+        assert_eq!(report.adjustments_applied, 2);
+        assert!(report.to_string().contains("adjustments applied: 2\n"));
+        let written = std::fs::read_to_string(&report_path).unwrap();
+        std::fs::remove_file(&report_path).unwrap();
+        assert!(written.contains("adjustments applied,,2\n"));
+        assert!(matches!(
+            engine.clients[&1].transactions[&2].action_type,
+            Action::Adjustment
+        ));
+    }
+
+    /// Memos reach the rejects sidecar quoted, and are not kept on stored transactions
+    #[tokio::test]
+    async fn test_memo_column() {
+        let rejects_path = std::env::temp_dir().join("test_memo_column_rejects.csv");
+        let mut engine = Engine::new(Config {
+            rejects_path: Some(rejects_path.clone()),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount,memo\n\
+                     deposit,1,1,1.0,INV-2931\n\
+                     withdrawal,1,2,5.0,\"INV-2932, \"\"urgent\"\"\"\n\
+                     dispute,1,3,,\n\
+                     dispute,1,1,,CASE-7\n\
+                     chargeback,1,1,,CASE-7\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(output, vec!["1,0.0000,0.0000,0.0000,true"]);
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "type,client,tx,amount,error,line,raw,memo\n\
+             withdrawal,1,2,5.0,Not enough account balance,3,\"withdrawal,1,2,5.0,INV-2932, \"\"urgent\"\"\",\"INV-2932, \"\"urgent\"\"\"\n\
+             dispute,1,3,,Non existing transaction id 3 for dispute,4,\"dispute,1,3,,\",\n"
+        );
+        let account = &engine.clients[&1];
+        //stored transactions have no memo, only the chargeback keeps its own
+        assert!(account.transactions.contains_key(&1));
+        assert_eq!(account.chargebacks[0].memo.as_deref(), Some("CASE-7"));
+    }
+
+    #[test]
+    fn test_truncate_memo() {
+        assert_eq!(truncate_memo("INV-2931", 1, 1), "INV-2931");
+        let memo = "a".repeat(MEMO_LIMIT + 1);
+        assert_eq!(truncate_memo(&memo, 1, 1).len(), MEMO_LIMIT);
+        //a multi-byte character straddling the limit is dropped whole
+        let memo = format!("{}é", "a".repeat(MEMO_LIMIT - 1));
+        assert_eq!(truncate_memo(&memo, 1, 1), "a".repeat(MEMO_LIMIT - 1));
+    }
+
+    /// A frozen account only rejects withdrawals, and is independent of chargeback locking
+    #[tokio::test]
+    async fn test_freeze() {
+        let rejects_path = std::env::temp_dir().join("test_freeze_rejects.csv");
+        let mut engine = Engine::new(Config {
+            extra_columns: true,
+            rejects_path: Some(rejects_path.clone()),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,5.0\n\
+                     freeze,1,2,\n\
+                     deposit,1,3,1.0\n\
+                     withdrawal,1,4,1.0\n\
+                     dispute,1,3,\n\
+                     chargeback,1,3,\n\
+                     unfreeze,1,5,\n\
+                     deposit,2,6,5.0\n\
+                     lock,2,7,\n\
+                     freeze,2,8,\n\
+                     unlock,2,9,\n\
+                     withdrawal,2,10,1.0\n\
+                     unfreeze,2,11,\n\
+                     withdrawal,2,12,1.0\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,5.0000,0.0000,5.0000,true,0.0000,false,1,false,1,0,1.0000,false",
+                "2,4.0000,0.0000,4.0000,false,0.0000,false,0,false,0,0,0.0000,false"
+            ]
+        );
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "type,client,tx,amount,error,line,raw\n\
+             withdrawal,1,4,1.0,Account is frozen,5,\"withdrawal,1,4,1.0\"\n\
+             withdrawal,2,10,1.0,Account is frozen,13,\"withdrawal,2,10,1.0\"\n"
+        );
+    }
+
+    /// Tier caps bind deposits and withdrawals only, disputes and adjustments are exempt
+    #[tokio::test]
+    async fn test_tier_limits() {
+        let rejects_path = std::env::temp_dir().join("test_tier_limits_rejects.csv");
+        let mut engine = Engine::new(Config {
+            tiers: Some(Tiers {
+                clients: HashMap::from([(2, 2)]),
+                default_tier: 0,
+            }),
+            rejects_path: Some(rejects_path.clone()),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,400.0\n\
+                     deposit,1,2,100.0\n\
+                     deposit,1,3,0.0001\n\
+                     withdrawal,1,4,100.0001\n\
+                     withdrawal,1,5,100.0\n\
+                     adjustment,1,6,200.0\n\
+                     dispute,1,2,\n\
+                     resolve,1,2,\n\
+                     deposit,2,7,1000.0\n\
+                     withdrawal,2,8,1000.0\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,600.0000,0.0000,600.0000,false",
+                "2,0.0000,0.0000,0.0000,false"
+            ]
+        );
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "type,client,tx,amount,error,line,raw\n\
+             deposit,1,3,0.0001,Deposit would take the account over its tier cap of 500,4,\"deposit,1,3,0.0001\"\n\
+             withdrawal,1,4,100.0001,Withdrawal exceeds the tier limit of 100,5,\"withdrawal,1,4,100.0001\"\n"
+        );
+    }
+
+    /// Withdrawals may take available exactly to the floor, not below it
+    #[test]
+    fn test_min_balance() {
+        let client_id = 1;
+        let config = Config {
+            min_balance: Some(Decimal::new(250000, PRECISION)),
+            min_balances: HashMap::from([(2, Decimal::new(0, PRECISION))]),
+            ..Config::default()
+        };
+        let mut account = Account::new(client_id);
+        let deposit = Transaction::_new(
+            Action::Deposit,
+            client_id,
+            1,
+            Some(Decimal::new(1000000, PRECISION)),
+        );
+        account.handle_transaction(deposit, &config).unwrap();
+        let withdrawal = Transaction::_new(
+            Action::Withdrawal,
+            client_id,
+            2,
+            Some(Decimal::new(750001, PRECISION)),
+        );
+        match account.handle_transaction(withdrawal, &config) {
+            Err(CustomError::BelowMinimumBalance { floor }) => {
+                assert_eq!(floor, Decimal::new(250000, PRECISION))
+            }
+            _ => panic!(),
+        }
+        let withdrawal = Transaction::_new(
+            Action::Withdrawal,
+            client_id,
+            3,
+            Some(Decimal::new(750000, PRECISION)),
+        );
+        account.handle_transaction(withdrawal, &config).unwrap();
+        assert_eq!(account.available, Decimal::new(250000, PRECISION));
+        //a dispute may breach the floor
+        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None);
+        account.handle_transaction(dispute, &config).unwrap();
+        assert_eq!(account.available, Decimal::new(-750000, PRECISION));
+
+        //the client's own floor overrides the global one
+        let mut account = Account::new(2);
+        let deposit = Transaction::_new(Action::Deposit, 2, 4, Some(Decimal::new(10, PRECISION)));
+        account.handle_transaction(deposit, &config).unwrap();
+        let withdrawal =
+            Transaction::_new(Action::Withdrawal, 2, 5, Some(Decimal::new(10, PRECISION)));
+        account.handle_transaction(withdrawal, &config).unwrap();
+    }
+
+    /// Disputes older than the threshold at the end of the run are resolved, newer ones stay open
+    #[tokio::test]
+    async fn test_auto_resolve() {
+        let report_path = std::env::temp_dir().join("test_auto_resolve_report.csv");
+        let mut engine = Engine::new(Config {
+            auto_resolve_after: Some(Window::Records(3)),
+            defer_close: true,
+            report_path: Some(report_path.clone()),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,2,2,2.0\n\
+                     dispute,1,1,\n\
+                     dispute,2,2,\n\
+                     close,1,3,\n\
+                     deposit,3,4,3.0\n\
+                     dispute,3,4,\n";
+        let output = run(&mut engine, input).await;
+
+        //the dispute of client 2 is exactly at the edge of the window, so it stays open
+        assert_eq!(
+            output,
+            vec![
+                "1,1.0000,0.0000,1.0000,false",
+                "2,0.0000,2.0000,2.0000,false",
+                "3,0.0000,3.0000,3.0000,false"
+            ]
+        );
+        assert_eq!(engine.disputes_auto_resolved, 1);
+        let report = engine.report();
+        assert_eq!(report.disputes_auto_resolved, 1);
+        assert!(report
+            .to_string()
+            .contains("ignored: 0\ndisputes auto-resolved: 1\n"));
+        let written = std::fs::read_to_string(&report_path).unwrap();
+        std::fs::remove_file(&report_path).unwrap();
+        assert!(written.contains("ignored,,0\ndisputes auto-resolved,,1\n"));
+        let account = &engine.clients[&1];
+        assert!(account.is_closed);
+        assert_eq!(
+            account.transactions[&1].dispute_state(),
+            DisputeState::AutoResolved
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auto_resolve_duration() {
+        let mut engine = Engine::new(Config {
+            auto_resolve_after: Some(Window::Duration(100)),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount,timestamp\n\
+                     deposit,1,1,1.0,0\n\
+                     deposit,2,2,2.0,0\n\
+                     dispute,1,1,,10\n\
+                     dispute,2,2,,\n\
+                     deposit,3,3,1.0,111\n";
+        let output = run(&mut engine, input).await;
+
+        //a dispute without timestamp is never stale
+        assert_eq!(
+            output,
+            vec![
+                "1,1.0000,0.0000,1.0000,false",
+                "2,0.0000,2.0000,2.0000,false",
+                "3,1.0000,0.0000,1.0000,false"
+            ]
+        );
+    }
+
+    /// Interest is rounded half to even, accounts that are locked, frozen or not positive accrue nothing
+    #[tokio::test]
+    async fn test_accrue_interest() {
+        let rejects_path = std::env::temp_dir().join("test_accrue_interest_rejects.csv");
+        let mut engine = Engine::new(Config {
+            rejects_path: Some(rejects_path.clone()),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,2,2,0.0050\n\
+                     deposit,3,3,0.0150\n\
+                     deposit,4,4,0.0250\n\
+                     deposit,5,5,2.0\n\
+                     lock,5,6,\n\
+                     deposit,6,7,1.0\n\
+                     freeze,6,8,\n\
+                     deposit,7,9,1.0\n\
+                     withdrawal,7,10,1.0\n\
+                     dispute,7,9,\n\
+                     accrue_interest,0,11,0.01\n\
+                     accrue_interest,0,12,0\n";
+        let output = run(&mut engine, input).await;
+
+        //1.0 * 0.01 = 0.01, 0.005 * 0.01 = 0.00005 rounds to 0
+        //0.015 * 0.01 = 0.00015 rounds up to 0.0002, 0.025 * 0.01 = 0.00025 rounds down to 0.0002
+        assert_eq!(
+            output,
+            vec![
+                "1,1.0100,0.0000,1.0100,false",
+                "2,0.0050,0.0000,0.0050,false",
+                "3,0.0152,0.0000,0.0152,false",
+                "4,0.0252,0.0000,0.0252,false",
+                "5,2.0000,0.0000,2.0000,true",
+                "6,1.0000,0.0000,1.0000,false",
+                "7,-1.0000,1.0000,0.0000,false"
+            ]
+        );
+        assert_eq!(engine.interest_postings, 3);
+        assert!(engine
+            .report()
+            .to_string()
+            .contains("interest postings: 3\n"));
+        let posting = &engine.clients[&1].admin_actions[0];
+        assert!(matches!(posting.get_action_type(), Action::AccrueInterest));
+        assert_eq!(posting.transaction_id, 11);
+        assert_eq!(posting.decimal, Some(Decimal::new(100, PRECISION)));
+        assert!(engine.clients[&2].admin_actions.is_empty());
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "type,client,tx,amount,error,line,raw\n\
+             accrue_interest,0,12,0,Amount must be positive,14,\"accrue_interest,0,12,0\"\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_accrue_interest_at_end() {
+        let mut engine = Engine::new(Config {
+            accrue_interest: Some(Decimal::new(5, 1)),
+            accrue_interest_on_locked: true,
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,2.0\n\
+                     lock,1,2,\n\
+                     deposit,2,3,4.0\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,3.0000,0.0000,3.0000,true",
+                "2,6.0000,0.0000,6.0000,false"
+            ]
+        );
+    }
+
+    /// A pending withdrawal holds its amount until it is settled or failed, and is only disputable once settled
+    #[tokio::test]
+    async fn test_two_phase_withdrawal() {
+        let rejects_path = std::env::temp_dir().join("test_two_phase_withdrawal_rejects.csv");
+        let mut engine = Engine::new(Config {
+            allow_withdrawal_disputes: true,
+            rejects_path: Some(rejects_path.clone()),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     withdrawal_pending,1,2,4.0\n\
+                     dispute,1,2,\n\
+                     withdrawal_settle,1,2,\n\
+                     withdrawal_fail,1,2,\n\
+                     dispute,1,2,\n\
+                     resolve,1,2,\n\
+                     withdrawal_pending,1,3,5.0\n\
+                     withdrawal_pending,1,4,2.0\n\
+                     withdrawal_fail,1,3,\n\
+                     withdrawal_settle,1,3,\n\
+                     withdrawal_settle,1,1,\n\
+                     withdrawal_settle,1,9,\n\
+                     deposit,2,5,1.0\n\
+                     withdrawal_pending,2,6,1.0\n\
+                     lock,2,7,\n\
+                     withdrawal_settle,2,6,\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,6.0000,0.0000,6.0000,false",
+                "2,0.0000,0.0000,0.0000,true"
+            ]
+        );
+        let account = &engine.clients[&1];
+        assert_eq!(
+            account.transactions[&2].payout_state(),
+            Some(PayoutState::Settled)
+        );
+        assert_eq!(
+            account.transactions[&3].payout_state(),
+            Some(PayoutState::Failed)
+        );
+        //settle and fail rows are kept in the audit trail
+        assert_eq!(account.admin_actions.len(), 2);
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "type,client,tx,amount,error,line,raw\n\
+             dispute,1,2,,Undefined Behaviour,4,\"dispute,1,2,\"\n\
+             withdrawal_fail,1,2,,Withdrawal was already settled,6,\"withdrawal_fail,1,2,\"\n\
+             withdrawal_pending,1,4,2.0,Not enough account balance,10,\"withdrawal_pending,1,4,2.0\"\n\
+             withdrawal_settle,1,3,,Withdrawal already failed,12,\"withdrawal_settle,1,3,\"\n\
+             withdrawal_settle,1,1,,Withdrawal was never pending,13,\"withdrawal_settle,1,1,\"\n\
+             withdrawal_settle,1,9,,Non existing transaction id 9 for withdrawal_settle,14,\"withdrawal_settle,1,9,\"\n"
+        );
+    }
+
+    /// Only deposits and withdrawals must increase, disputes reference older ids
+    #[tokio::test]
+    async fn test_monotonic_transaction_ids() {
+        let rejects_path = std::env::temp_dir().join("test_monotonic_transaction_ids_rejects.csv");
+        let mut engine = Engine::new(Config {
+            require_monotonic_tx_ids: true,
+            rejects_path: Some(rejects_path.clone()),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,2,1.0\n\
+                     deposit,2,5,2.0\n\
+                     dispute,1,2,\n\
+                     withdrawal,2,4,1.0\n\
+                     deposit,1,5,1.0\n\
+                     resolve,1,2,\n\
+                     withdrawal,2,6,1.0\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,1.0000,0.0000,1.0000,false",
+                "2,1.0000,0.0000,1.0000,false"
+            ]
+        );
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "type,client,tx,amount,error,line,raw\n\
+             withdrawal,2,4,1.0,Transaction id 4 is not above the previous id 5,5,\"withdrawal,2,4,1.0\"\n\
+             deposit,1,5,1.0,Transaction id 5 is not above the previous id 5,6,\"deposit,1,5,1.0\"\n"
+        );
+
+        let mut engine = Engine::new(Config {
+            require_monotonic_tx_ids: true,
+            strict: true,
+            ..Config::default()
+        });
+        let err = run_err(&mut engine, &input).await;
+        assert!(matches!(
+            err,
+            CustomError::NonMonotonicTransactionId {
+                previous: 5,
+                found: 4
+            }
+        ));
+    }
+
+    /// Only stored transactions count towards the cap, and disputes on them still apply
+    #[tokio::test]
+    async fn test_transaction_cap() {
+        let rejects_path = std::env::temp_dir().join("test_transaction_cap_rejects.csv");
+        let report_path = std::env::temp_dir().join("test_transaction_cap_report.csv");
+        let mut engine = Engine::new(Config {
+            max_transactions_per_account: Some(2),
+            extra_columns: true,
+            rejects_path: Some(rejects_path.clone()),
+            report_path: Some(report_path.clone()),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     withdrawal,1,2,5.0\n\
+                     deposit,1,3,2.0\n\
+                     deposit,1,4,3.0\n\
+                     withdrawal,1,5,1.0\n\
+                     dispute,1,1,\n\
+                     resolve,1,1,\n\
+                     deposit,2,6,1.0\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,3.0000,0.0000,3.0000,false,0.0000,false,0,false,1,1,1.0000,true",
+                "2,1.0000,0.0000,1.0000,false,0.0000,false,0,false,0,0,0.0000,false"
+            ]
+        );
+        assert_eq!(engine.clients[&1].rejected_over_cap, 2);
+        let report = engine.report();
+        assert_eq!((report.accounts_over_cap, report.rejected_over_cap), (1, 2));
+        assert!(report
+            .to_string()
+            .contains("accounts over cap: 1, 2 rows rejected\n"));
+        let written = std::fs::read_to_string(&report_path).unwrap();
+        std::fs::remove_file(&report_path).unwrap();
+        assert!(written.contains("accounts over cap,,1\nrejected over cap,,2\n"));
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "type,client,tx,amount,error,line,raw\n\
+             withdrawal,1,2,5.0,Not enough account balance,3,\"withdrawal,1,2,5.0\"\n\
+             deposit,1,4,3.0,Account reached the cap of 2 stored transactions,5,\"deposit,1,4,3.0\"\n\
+             withdrawal,1,5,1.0,Account reached the cap of 2 stored transactions,6,\"withdrawal,1,5,1.0\"\n"
+        );
+    }
+
+    /// Rows after the halt row are counted but never applied
+    #[tokio::test]
+    async fn test_halt() {
+        let report_path = std::env::temp_dir().join("test_halt_report.csv");
+        let mut engine = Engine::new(Config {
+            require_halt: true,
+            report_path: Some(report_path.clone()),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     halt,0,0,\n\
+                     deposit,1,2,1.0\n\
+                     deposit,2,3,1.0\n\
+                     not a row\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(output, vec!["1,1.0000,0.0000,1.0000,false"]);
+        assert!(!engine.clients.contains_key(&0));
+        let report = engine.report();
+        assert_eq!(report.halted_at, Some(2));
+        assert_eq!(report.records_after_halt, 3);
+        assert!(without_peak_memory(&report.to_string())
+            .ends_with("halted at record: 2\nrecords after halt: 3\n"));
+        let written = std::fs::read_to_string(&report_path).unwrap();
+        std::fs::remove_file(&report_path).unwrap();
+        assert!(without_peak_memory(&written).ends_with("halted at,,2\nrecords after halt,,3\n"));
+
+        let mut engine = Engine::new(Config {
+            require_halt: true,
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n";
+        let err = run_err(&mut engine, &input).await;
+        assert!(matches!(err, CustomError::MissingHalt));
+    }
+
+    /// Either party may dispute a transfer, which is held and charged back on the recipient like a deposit
+    #[tokio::test]
+    async fn test_transfer_dispute() {
+        let input = "type,client,tx,amount,to\n\
+                     deposit,1,1,10.0,\n\
+                     transfer,1,2,4.0,2\n\
+                     dispute,1,2,,\n\
+                     resolve,2,2,,\n\
+                     withdrawal,2,3,3.0,\n\
+                     dispute,2,2,,\n\
+                     chargeback,1,2,,\n\
+                     transfer,1,4,1.0,2\n\
+                     transfer,1,5,20.0,3\n\
+                     transfer,1,6,1.0,1\n\
+                     transfer,1,7,1.0,\n\
+                     dispute,3,2,,\n\
+                     deposit,3,8,1.0,\n";
+        for transfer_chargeback_refunds_sender in [false, true] {
+            let rejects_path = std::env::temp_dir().join(format!(
+                "test_transfer_dispute_rejects_{}.csv",
+                transfer_chargeback_refunds_sender
+            ));
+            let mut engine = Engine::new(Config {
+                transfer_chargeback_refunds_sender,
+                rejects_path: Some(rejects_path.clone()),
+                ..Config::default()
+            });
+            let output = run(&mut engine, input).await;
+
+            //the recipient already spent part of the transfer, so its chargeback drives available negative
+            //only the recipient is locked by it
+            let sender = if transfer_chargeback_refunds_sender {
+                "1,10.0000,0.0000,10.0000,false"
+            } else {
+                "1,6.0000,0.0000,6.0000,false"
+            };
+            assert_eq!(
+                output,
+                vec![
+                    sender,
+                    "2,-3.0000,0.0000,-3.0000,true",
+                    "3,1.0000,0.0000,1.0000,false"
+                ]
+            );
+            let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+            std::fs::remove_file(&rejects_path).unwrap();
+            assert_eq!(
+                rejects,
+                "type,client,tx,amount,error,line,raw\n\
+                 transfer,1,4,1.0,Account is Locked,9,\"transfer,1,4,1.0,2\"\n\
+                 transfer,1,5,20.0,Not enough account balance,10,\"transfer,1,5,20.0,3\"\n\
+                 transfer,1,6,1.0,Transfer needs a recipient other than the sender,11,\"transfer,1,6,1.0,1\"\n\
+                 transfer,1,7,1.0,Transfer needs a recipient other than the sender,12,\"transfer,1,7,1.0,\"\n\
+                 dispute,3,2,,\"Transaction belongs to client 2, not client 3\",13,\"dispute,3,2,,\"\n"
+            );
+        }
+    }
+
+    /// The same input under the two built-in dispute policies
+    #[tokio::test]
+    async fn test_dispute_policies() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,5.0\n\
+                     withdrawal,1,2,3.0\n\
+                     dispute,1,1,\n\
+                     dispute,1,2,\n\
+                     deposit,2,3,1.0\n\
+                     dispute,2,3,\n";
+        let mut engine = Engine::new(Config {
+            allow_withdrawal_disputes: true,
+            ..Config::default()
+        });
+        let output = run(&mut engine, input).await;
+        assert_eq!(
+            output,
+            vec![
+                "1,-3.0000,8.0000,5.0000,false",
+                "2,0.0000,1.0000,1.0000,false"
+            ]
+        );
+
+        //the deposit dispute would take available negative and withdrawals are never disputed
+        let mut engine = Engine::new(Config {
+            allow_withdrawal_disputes: true,
+            dispute_policy: SharedPolicy::new(ConservativePolicy),
+            ..Config::default()
+        });
+        let output = run(&mut engine, input).await;
+        assert_eq!(
+            output,
+            vec![
+                "1,2.0000,0.0000,2.0000,false",
+                "2,0.0000,1.0000,1.0000,false"
+            ]
+        );
+    }
+
+    /// A row that cannot be read stops the run with its line, or is skipped when configured
+    #[tokio::test]
+    async fn test_malformed_rows() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,1,2,\n\
+                     deposit,1,x,1.0\n\
+                     withdrawal,1,6\n\
+                     deposit,1,7,1.2.3\n\
+                     deposit,1,3,1.0\n";
+        let mut engine = Engine::new(Config::default());
+        let err = run_err(&mut engine, &input).await;
+        assert_eq!(
+            err.to_string(),
+            "row on line 3 could not be read: deposit of client 1 with tx 2 has no amount"
+        );
+        //an unparsable amount names its column and raw value
+        let mut engine = Engine::new(Config::default());
+        let err = run_err(&mut engine, "type,client,tx,amount\ndeposit,1,7,1.2.3\n").await;
+        assert_eq!(
+            err.to_string(),
+            "row on line 2 could not be read: amount `1.2.3` of deposit row could not be parsed into decimal: Invalid decimal: two decimal points"
+        );
+
+        let rejects_path = std::env::temp_dir().join("test_malformed_rows_rejects.csv");
+        let mut engine = Engine::new(Config {
+            skip_malformed_rows: true,
+            rejects_path: Some(rejects_path.clone()),
+            ..Config::default()
+        });
+        let output = run(&mut engine, input).await;
+        assert_eq!(output, vec!["1,2.0000,0.0000,2.0000,false"]);
+        assert_eq!(engine.malformed_rows_skipped, 4);
+        //skipped rows are found by their line and raw text
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "type,client,tx,amount,error,line,raw\n\
+             ,,,,row on line 3 could not be read: deposit of client 1 with tx 2 has no amount,3,\"deposit,1,2,\"\n\
+             ,,,,row on line 4 could not be read: tx `x` of deposit row could not be parsed into int: invalid digit found in string,4,\"deposit,1,x,1.0\"\n\
+             ,,,,row on line 5 could not be read: withdrawal of client 1 with tx 6 has no amount,5,\"withdrawal,1,6\"\n\
+             ,,,,row on line 6 could not be read: amount `1.2.3` of deposit row could not be parsed into decimal: Invalid decimal: two decimal points,6,\"deposit,1,7,1.2.3\"\n"
+        );
+    }
+
+    /// Short rows name the missing column, only rows moving an amount need the fourth one
+    #[test]
+    fn test_record_field_count() {
+        let amount_actions = [
+            "deposit",
+            "withdrawal",
+            "hold",
+            "release",
+            "escrow",
+            "adjustment",
+            "accrue_interest",
+            "withdrawal_pending",
+            "transfer",
+        ];
+        let other_actions = [
+            "dispute",
+            "resolve",
+            "chargeback",
+            "lock",
+            "unlock",
+            "close",
+            "settle",
+            "reinstate",
+            "reversal",
+            "escrow_release",
+            "freeze",
+            "unfreeze",
+            "withdrawal_settle",
+            "withdrawal_fail",
+            "halt",
+        ];
+        let parse = |fields: &[&str]| parse_row(fields.to_vec(), &Columns::default(), false);
+        for action in amount_actions.into_iter().chain(other_actions) {
+            assert!(matches!(
+                parse(&[action]),
+                Err(CustomError::MissingField {
+                    column: "client",
+                    ..
+                })
+            ));
+            assert!(matches!(
+                parse(&[action, "1"]),
+                Err(CustomError::MissingField { column: "tx", .. })
+            ));
+            let three_columns = parse(&[action, "1", "2"]);
+            let empty_amount = parse(&[action, "1", "2", ""]);
+            if amount_actions.contains(&action) {
+                for result in [three_columns, empty_amount] {
+                    assert!(matches!(
+                        result,
+                        Err(CustomError::MissingAmount {
+                            client: 1,
+                            tx: 2,
+                            ..
+                        })
+                    ));
+                }
+            } else {
+                assert!(three_columns.is_ok());
+                assert!(empty_amount.is_ok());
+            }
+            let transaction = parse(&[action, "1", "2", "1.0", "extra"]).unwrap();
+            assert_eq!(transaction.client_id, 1);
+            assert_eq!(transaction.transaction_id, 2);
+        }
+        assert!(matches!(
+            parse(&[]),
+            Err(CustomError::MissingField {
+                column: "type",
+                action: None
+            })
+        ));
+    }
+
+    /// Empty client and tx fields name the column and the row type, instead of failing as unparsable ints
+    #[tokio::test]
+    async fn test_empty_fields() {
+        for (row, err) in [
+            ("deposit,,5,1.0", "missing client on deposit row"),
+            ("withdrawal,1,,1.0", "missing tx on withdrawal row"),
+            ("dispute,,,", "missing client on dispute row"),
+            (",1,5,1.0", "missing type column"),
+        ] {
+            let input = format!("type,client,tx,amount\ndeposit,1,1,10.0\n{}\n", row);
+            let mut engine = Engine::new(Config::default());
+            let mut reader = Reader::from_reader(input.as_bytes());
+            let mut writer = Writer::from_inner(Vec::new());
+            let result = engine.process(&mut reader, &mut writer).await;
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                format!("row on line 3 could not be read: {}", err)
+            );
+
+            let mut engine = Engine::new(Config {
+                skip_malformed_rows: true,
+                ..Config::default()
+            });
+            let output = run(&mut engine, &input).await;
+            assert_eq!(output, vec!["1,10.0000,0.0000,10.0000,false"]);
+            assert_eq!(engine.report().rejected_by_reason["malformed row"], 1);
+        }
+    }
+
+    /// Quoted fields padded with spaces read like clean ones, in every column
+    #[tokio::test]
+    async fn test_quoted_padding() {
+        let clean = "type,client,tx,amount,fee,memo,timestamp\n\
+                     deposit,1,1,10.5,0.5,INV-1,100\n\
+                     withdrawal,1,2,1.5,0,INV-2,200\n\
+                     dispute,1,1,,,,300\n";
+        let padded =
+            "\" type\",\"client \",\" tx \",\" amount\",\" fee \",\"memo \",\" timestamp\"\n\
+                      \" deposit\",\" 1 \",\"1 \",\" 10.5 \",\" 0.5\",\" INV-1 \",\" 100 \"\n\
+                      \"withdrawal \",\"\t1\",\" 2\",\" 1.5 \",\" 0 \",\" INV-2\",\"200 \"\n\
+                      \" dispute \",\" 1\",\" 1 \",\" \",\"  \",\" \",\" 300\"\n";
+        let mut engine = Engine::new(Config::default());
+        let expected = run(&mut engine, clean).await;
+        assert_eq!(expected, vec!["1,-2.0000,10.5000,8.5000,false"]);
+        let mut engine = Engine::new(Config::default());
+        assert_eq!(run(&mut engine, padded).await, expected);
+
+        //the parsing layer trims on its own, whatever the reader is set to
+        let record = ByteRecord::from(vec![" deposit", "\t1 ", " 1 ", "2.0 "]);
+        let decoded = decode(&record, &Columns::default(), 1, false).unwrap();
+        assert_eq!(
+            decoded.iter().collect::<Vec<_>>(),
+            vec!["deposit", "1", "1", "2.0"]
+        );
+    }
+
+    /// An engine processes one input, reset starts it over and outputs sharing a writer share its header
+    #[tokio::test]
+    async fn test_engine_reuse() {
+        let first_day = "type,client,tx,amount\n\
+                         deposit,1,1,10.0\n";
+        let second_day = "type,client,tx,amount\n\
+                          deposit,2,2,5.0\n";
+        let mut engine = Engine::new(Config::default());
+        let mut writer = Writer::from_inner(Vec::new());
+        let mut reader = Reader::from_reader(first_day.as_bytes());
+        engine.process(&mut reader, &mut writer).await.unwrap();
+        let mut reader = Reader::from_reader(second_day.as_bytes());
+        let err = engine.process(&mut reader, &mut writer).await.unwrap_err();
+        assert_eq!(err.severity(), Severity::Fatal);
+        assert_eq!(
+            err.to_string(),
+            "the engine already processed an input, reset it before processing another one"
+        );
+
+        //two files into one writer
+        engine.reset();
+        let mut reader = Reader::from_reader(second_day.as_bytes());
+        engine.process(&mut reader, &mut writer).await.unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "client,available,held,total,locked\n\
+             1,10.0000,0.0000,10.0000,false\n\
+             2,5.0000,0.0000,5.0000,false\n"
+        );
+
+        //two files into two writers
+        let mut engine = Engine::new(Config::default());
+        let mut outputs = Vec::new();
+        for input in [first_day, second_day] {
+            engine.reset();
+            let mut reader = Reader::from_reader(input.as_bytes());
+            let mut writer = Writer::from_inner(Vec::new());
+            engine.process(&mut reader, &mut writer).await.unwrap();
+            outputs.push(String::from_utf8(writer.into_inner()).unwrap());
+        }
+        assert_eq!(
+            outputs,
+            vec![
+                "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n",
+                "client,available,held,total,locked\n2,5.0000,0.0000,5.0000,false\n"
+            ]
+        );
+        assert_eq!(engine.report().records, 1);
+    }
+
+    /// Rows are read by the column names found in the header, whatever their order, case and padding
+    #[test]
+    fn test_raw_transaction() {
+        let columns = Columns::from_headers(&StringRecord::from(vec![
+            " Memo", "tx", "note", "TYPE", "client", "decimal",
+        ]));
+        assert_eq!(
+            columns.names,
+            ByteRecord::from(vec!["memo", "tx", "", "type", "client", "amount"])
+        );
+        let record = ByteRecord::from(vec!["rent", " 7", "ignored", "deposit", "3", " 1.5 "]);
+        let raw: RawTransaction = record.deserialize(Some(&columns.names)).unwrap();
+        assert_eq!(raw.action, Some("deposit"));
+        assert_eq!(
+            (raw.client, raw.tx, raw.amount),
+            (Some("3"), Some("7"), Some("1.5"))
+        );
+        assert_eq!(
+            (raw.memo, raw.fee, raw.timestamp),
+            (Some("rent"), None, None)
+        );
+
+        //without a header naming them the core columns are read from their usual place
+        let columns = Columns::from_headers(&StringRecord::from(vec!["a", "b", "c", "d", "fee"]));
+        let record = ByteRecord::from(vec!["withdrawal", "1", "2", "3.0", " "]);
+        let raw: RawTransaction = record.deserialize(Some(&columns.names)).unwrap();
+        assert_eq!(
+            (raw.action, raw.client, raw.tx, raw.amount, raw.fee),
+            (Some("withdrawal"), Some("1"), Some("2"), Some("3.0"), None)
+        );
+    }
+
+    /// Parse errors name the column, the raw value and the type of the row
+    #[test]
+    fn test_parse_error_context() {
+        let columns = Columns::from_headers(&StringRecord::from(vec![
+            "type",
+            "client",
+            "tx",
+            "amount",
+            "fee",
+            "timestamp",
+            "to",
+        ]));
+        let parse = |fields: Vec<&str>| {
+            parse_row(fields, &columns, false)
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        };
+        for (fields, err) in [
+            (
+                vec!["withdrawal", "abc", "1", "1.0"],
+                "client `abc` of withdrawal row could not be parsed into int: invalid digit found in string",
+            ),
+            (
+                vec!["dispute", "1", "-3", ""],
+                "tx `-3` of dispute row could not be parsed into int: invalid digit found in string",
+            ),
+            (
+                vec!["dispute", "1", "3", "2,5"],
+                "amount `2,5` of dispute row could not be parsed into decimal: Invalid decimal: unknown character",
+            ),
+            (
+                vec!["deposit", "1", "1", "1.0", "x"],
+                "fee `x` of deposit row could not be parsed into decimal: Invalid decimal: unknown character",
+            ),
+            (
+                vec!["deposit", "1", "1", "1.0", "", "soon"],
+                "timestamp `soon` of deposit row could not be parsed into int: invalid digit found in string",
+            ),
+            (
+                vec!["transfer", "1", "1", "1.0", "", "", "70000"],
+                "to `70000` of transfer row is out of range, it must be between 0 and 65535",
+            ),
+            (vec!["deposit", "1", "1", "1e3"], "amount `1e3` of deposit row uses exponent notation, which is not accepted"),
+        ] {
+            assert_eq!(parse(fields.clone()), Err(String::from(err)), "{:?}", fields);
+        }
+    }
+
+    /// Empty and header-only files only produce the output header
+    #[tokio::test]
+    async fn test_empty_input() {
+        for input in ["", "type,client,tx,amount\n"] {
+            let mut engine = Engine::new(Config::default());
+            let mut reader = Reader::from_reader(input.as_bytes());
+            let mut writer = Writer::from_inner(Vec::new());
+            engine.process(&mut reader, &mut writer).await.unwrap();
+            let output = String::from_utf8(writer.into_inner()).unwrap();
+            assert_eq!(output, "client,available,held,total,locked\n");
+            assert_eq!(engine.records_processed, 0);
+        }
+    }
+
+    /// Warnings name the line of the row and its text
+    #[test]
+    fn test_reject_message() {
+        let mut transaction = parse_row(
+            vec!["withdrawal", "1", "4", "1.0"],
+            &Columns::default(),
             false,
+        )
+        .unwrap();
+        transaction.line = 7;
+        transaction.raw = Some(String::from("withdrawal,1,4,1.0"));
+        assert_eq!(
+            reject_message(&transaction.row(), &CustomError::AccountFrozen),
+            "Client id: 1, with transaction_id: 4 had following error: Account is frozen, at line 7: withdrawal,1,4,1.0"
+        );
+        let long = "x".repeat(RAW_LIMIT + 1);
+        assert_eq!(truncate(&long, RAW_LIMIT).len(), RAW_LIMIT);
+    }
+
+    /// Every variant is classified by a match without wildcard, so only the split itself is checked here
+    #[test]
+    fn test_error_severity() {
+        let fatal = [
+            CustomError::UndefinedAction {
+                action: String::from("fee_adjustment"),
+            },
+            CustomError::MissingField {
+                column: "tx",
+                action: None,
+            },
+            CustomError::MissingHalt,
+            CustomError::MalformedRow {
+                line: 2,
+                source: Box::new(CustomError::MissingField {
+                    column: "tx",
+                    action: None,
+                }),
+            },
+        ];
+        let recoverable = [
+            CustomError::AccountBalanceNotEnough,
+            CustomError::LockedAccount,
+            CustomError::NonMonotonicTransactionId {
+                previous: 2,
+                found: 1,
+            },
+            CustomError::InvalidTransferRecipient,
+        ];
+        assert!(fatal.iter().all(|err| err.severity() == Severity::Fatal));
+        assert!(recoverable
+            .iter()
+            .all(|err| err.severity() == Severity::Recoverable));
+    }
+
+    /// Every row is counted once, as applied, rejected under the reason of its error, or ignored
+    #[tokio::test]
+    async fn test_run_report() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     withdrawal,1,2,20.0\n\
+                     withdrawal,1,3,30.0\n\
+                     dispute,1,99,\n\
+                     deposit,1,1,5.0\n\
+                     deposit,2,1,5.0\n\
+                     dispute,1,1,\n\
+                     dispute,1,1,\n\
+                     chargeback,1,1,\n\
+                     deposit,1,4,5.0\n\
+                     deposit,2,x,5.0\n\
+                     deposit,2,5,1.0\n";
+        let report_path = std::env::temp_dir().join("test_run_report.csv");
+        let mut engine = Engine::new(Config {
+            skip_malformed_rows: true,
+            report_path: Some(report_path.clone()),
+            ..Config::default()
+        });
+        run(&mut engine, input).await;
+        let report = engine.report();
+        assert_eq!(
+            without_peak_memory(&report.to_string()),
+            "records: 12\n\
+             applied: 4\n\
+             rejected: 7\n  \
+             duplicate tx: 2\n  \
+             insufficient funds: 2\n  \
+             locked account: 1\n  \
+             malformed row: 1\n  \
+             unknown tx on dispute: 1\n\
+             ignored: 1\n"
+        );
+        assert_eq!(
+            report.records,
+            report.applied + report.rejected + report.ignored
+        );
+        //the report file holds the same numbers
+        let written = std::fs::read_to_string(&report_path).unwrap();
+        std::fs::remove_file(&report_path).unwrap();
+        assert_eq!(
+            without_peak_memory(&written),
+            "metric,reason,count\n\
+             records,,12\n\
+             applied,,4\n\
+             rejected,,7\n\
+             rejected,duplicate tx,2\n\
+             rejected,insufficient funds,2\n\
+             rejected,locked account,1\n\
+             rejected,malformed row,1\n\
+             rejected,unknown tx on dispute,1\n\
+             ignored,,1\n"
         );
-        let transaction2 = Transaction::_new(
+    }
+
+    /// Counts kept apart from the rows are in the report too, in both its forms
+    #[tokio::test]
+    async fn test_run_report_counts() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     adjustment,1,2,+1.0\n\
+                     deposit,1,3,1.0\n\
+                     deposit,2,4,5.0\n\
+                     dispute,2,4,\n\
+                     accrue_interest,0,5,0.01\n\
+                     deposit,3,6,1.0\n\
+                     halt,0,0,\n\
+                     deposit,3,7,1.0\n\
+                     deposit,3,8,1.0\n";
+        let report_path = std::env::temp_dir().join("test_run_report_counts.csv");
+        let mut engine = Engine::new(Config {
+            max_transactions_per_account: Some(2),
+            auto_resolve_after: Some(Window::Records(2)),
+            report_path: Some(report_path.clone()),
+            ..Config::default()
+        });
+        run(&mut engine, input).await;
+        assert_eq!(
+            without_peak_memory(&engine.report().to_string()),
+            "records: 8\n\
+             applied: 6\n\
+             rejected: 1\n  \
+             account tx cap: 1\n\
+             ignored: 0\n\
+             adjustments applied: 1\n\
+             disputes auto-resolved: 1\n\
+             interest postings: 1\n\
+             accounts over cap: 1, 1 rows rejected\n\
+             halted at record: 8\n\
+             records after halt: 2\n"
+        );
+        let written = std::fs::read_to_string(&report_path).unwrap();
+        std::fs::remove_file(&report_path).unwrap();
+        assert_eq!(
+            without_peak_memory(&written),
+            "metric,reason,count\n\
+             records,,8\n\
+             applied,,6\n\
+             rejected,,1\n\
+             rejected,account tx cap,1\n\
+             ignored,,0\n\
+             adjustments applied,,1\n\
+             disputes auto-resolved,,1\n\
+             interest postings,,1\n\
+             accounts over cap,,1\n\
+             rejected over cap,,1\n\
+             halted at,,8\n\
+             records after halt,,2\n"
+        );
+    }
+
+    /// The audit csv lists every chargeback with its memo and the reinstate row that overturned it, and every
+    /// adjustment with its memo
+    #[tokio::test]
+    async fn test_audit() {
+        let input = "type,client,tx,amount,timestamp,memo\n\
+                     deposit,2,1,10.0,100,\n\
+                     deposit,1,2,5.0,101,\n\
+                     dispute,2,1,,102,\n\
+                     chargeback,2,1,,103,\"CASE-7, fraud\"\n\
+                     dispute,1,2,,104,\n\
+                     chargeback,1,2,,105,\n\
+                     reinstate,2,1,,106,reviewed\n\
+                     adjustment,3,3,1.5,107,TICKET-12\n";
+        let audit_path = std::env::temp_dir().join("test_audit.csv");
+        let mut engine = Engine::new(Config {
+            audit_path: Some(audit_path.clone()),
+            ..Config::default()
+        });
+        run(&mut engine, input).await;
+        let written = std::fs::read_to_string(&audit_path).unwrap();
+        std::fs::remove_file(&audit_path).unwrap();
+        assert_eq!(
+            written,
+            "event,client,tx,type,amount,record,timestamp,reinstated_at,memo\n\
+             chargeback,1,2,deposit,5.0000,6,105,,\n\
+             chargeback,2,1,deposit,10.0000,4,103,7,\"CASE-7, fraud\"\n\
+             adjustment,3,3,adjustment,1.5000,8,107,,TICKET-12\n"
+        );
+    }
+
+    /// Writing the output allocates about the same whatever the number of accounts
+    #[tokio::test]
+    async fn test_output_allocations() {
+        let mut engine = Engine::new(Config {
+            extra_columns: true,
+            ..Default::default()
+        });
+        for client_id in 0..10_000 {
+            let mut account = Account::new(client_id);
+            account.available = Decimal::new(i64::from(client_id) * 125, 2);
+            account.total = account.available;
+            engine.clients.insert(client_id, account);
+        }
+        let mut writer = Writer::from_inner(Vec::with_capacity(4 << 20));
+        let before = ALLOCATIONS.with(Cell::get);
+        let rows = engine.write_output(&mut writer, "").await.unwrap();
+        let allocations = ALLOCATIONS.with(Cell::get) - before;
+        assert_eq!(rows, 10_000);
+        assert!(allocations < 16, "{} allocations", allocations);
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        assert_eq!(output.lines().count(), 10_001);
+        assert!(output
+            .contains("\n8,10.0000,0.0000,10.0000,false,0.0000,false,0,false,0,0,0.0000,false\n"));
+    }
+
+    /// A failing output stops the run with the number of account rows written, it never panics
+    #[tokio::test]
+    async fn test_output_write_error() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,2,2,1.0\n\
+                     deposit,3,3,1.0\n";
+        let header = "client,available,held,total,locked\n";
+        let row = "1,1.0000,0.0000,1.0000,false\n";
+        for (remaining, rows_written) in [
+            (0, 0),
+            (header.len() - 1, 0),
+            (header.len() + row.len() + 2, 1),
+            (header.len() + 2 * row.len(), 2),
+        ] {
+            let mut engine = Engine::new(Config::default());
+            let mut reader = Reader::from_reader(input.as_bytes());
+            let mut writer = Writer::from_inner(FailingSink { remaining });
+            let err = engine.process(&mut reader, &mut writer).await.unwrap_err();
+            assert_eq!(err.severity(), Severity::Fatal);
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "output could not be written after {} account rows: no space left on device",
+                    rows_written
+                )
+            );
+        }
+    }
+
+    /// Balances that stop adding up stop the run, or only set the account aside when quarantining
+    #[tokio::test]
+    async fn test_invariant_violation() {
+        let later_input = "type,client,tx,amount\n\
+                           deposit,1,3,1.0\n\
+                           withdrawal,1,4,1.0\n\
+                           deposit,2,5,1.0\n";
+        //an engine holding a deposit for clients 1 and 2, with the balances of client 1 corrupted by a bug
+        let corrupted = |config: Config| {
+            let mut engine = Engine::new(config);
+            for (client_id, transaction_id) in [(1, 1), (2, 2)] {
+                let mut account = Account::new(client_id);
+                let deposit = Transaction::_new(
+                    Action::Deposit,
+                    client_id,
+                    transaction_id,
+                    Some(Decimal::new(10, 1)),
+                );
+                account.handle_transaction(deposit, &engine.config).unwrap();
+                engine.clients.insert(client_id, account);
+            }
+            engine.clients.get_mut(&1).unwrap().total += Decimal::ONE;
+            engine
+        };
+        let mut engine = corrupted(Config::default());
+        let err = run_err(&mut engine, &later_input).await;
+        assert_eq!(err.severity(), Severity::Fatal);
+        assert_eq!(
+            err.to_string(),
+            "Client 1 has total 3.0 but available 2.0 and held 0.0000 after deposit tx 3"
+        );
+
+        let mut engine = corrupted(Config {
+            quarantine_invariant_violations: true,
+            ..Config::default()
+        });
+        let output = run(&mut engine, later_input).await;
+        assert_eq!(output, vec!["2,2.0000,0.0000,2.0000,false"]);
+        //the row that broke the account applied, later rows of the account are rejected
+        let account = &engine.clients[&1];
+        assert!(account.is_quarantined);
+        assert_eq!(account.available, Decimal::new(20, 1));
+        let report = engine.report();
+        assert_eq!(report.rejected_by_reason["invariant violation"], 1);
+        assert_eq!(report.rejected_by_reason["quarantined account"], 1);
+        assert_eq!(report.accounts_quarantined, 1);
+        assert!(report.to_string().contains("accounts quarantined: 1\n"));
+    }
+
+    /// Rows of unknown types stop the run by default, or are skipped and counted by type
+    #[tokio::test]
+    async fn test_unknown_action() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,5.0\n\
+                     fee_adjustment,1,2,1.0\n\
+                     withdrawal,1,3,2.0\n\
+                     fee_adjustment,1,4,1.0\n\
+                     loyalty_bonus,2,5,3.0\n\
+                     deposit,2,6,1.0\n";
+        let mut engine = Engine::new(Config::default());
+        let err = run_err(&mut engine, &input).await;
+        assert_eq!(
+            err.to_string(),
+            "row on line 3 could not be read: Undefined Action fee_adjustment"
+        );
+
+        let mut engine = Engine::new(Config {
+            unknown_action: UnknownAction::Skip,
+            ..Config::default()
+        });
+        let output = run(&mut engine, input).await;
+        assert_eq!(
+            output,
+            vec![
+                "1,3.0000,0.0000,3.0000,false",
+                "2,1.0000,0.0000,1.0000,false"
+            ]
+        );
+        assert_eq!(
+            without_peak_memory(&engine.report().to_string()),
+            "records: 6\n\
+             applied: 3\n\
+             rejected: 0\n\
+             ignored: 0\n\
+             unknown: 3\n  \
+             fee_adjustment: 2\n  \
+             loyalty_bonus: 1\n"
+        );
+    }
+
+    /// Releases held more generously than it was taken
+    #[derive(Debug)]
+    struct GreedyPolicy;
+
+    impl DisputePolicy for GreedyPolicy {
+        fn decide(
+            &self,
+            account: &AccountView,
+            transaction: &TransactionView,
+            step: DisputeStep,
+            config: &Config,
+        ) -> Result<DisputeEffect, CustomError> {
+            let mut effect = StandardPolicy.decide(account, transaction, step, config)?;
+            if step == DisputeStep::Resolve {
+                effect.available *= Decimal::TWO;
+                effect.held *= Decimal::TWO;
+            }
+            Ok(effect)
+        }
+    }
+
+    /// Taking more out of held than it has is an error, and the account is left as it was
+    #[test]
+    fn test_negative_held() {
+        let config = Config {
+            dispute_policy: SharedPolicy::new(GreedyPolicy),
+            ..Config::default()
+        };
+        let client_id = 1;
+        let mut account = Account::new(client_id);
+        for transaction in [
+            Transaction::_new(Action::Deposit, client_id, 1, Some(Decimal::new(30, 1))),
+            Transaction::_new(Action::Dispute, client_id, 1, None),
+        ] {
+            account.handle_transaction(transaction, &config).unwrap();
+        }
+        let resolve = Transaction::_new(Action::Resolve, client_id, 1, None);
+        let err = account.handle_transaction(resolve, &config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Client 1 would take 6.0 out of held 3.0 on resolve tx 1"
+        );
+        assert_eq!(err.severity(), Severity::Fatal);
+        assert_eq!(account.held, Decimal::new(30, 1));
+        assert_eq!(account.available, Decimal::ZERO);
+        assert!(account.transactions[&1].is_under_dispute());
+    }
+
+    /// Random lifecycles of valid rows never take held below zero nor break the balance invariant
+    #[test]
+    fn test_held_never_negative() {
+        let actions = [
             Action::Deposit,
-            client_id,
-            1,
-            Some(Decimal::new(2, PRECISION)),
-            false,
+            Action::Withdrawal,
+            Action::WithdrawalPending,
+            Action::WithdrawalSettle,
+            Action::WithdrawalFail,
+            Action::Dispute,
+            Action::Resolve,
+            Action::Chargeback,
+            Action::Reinstate,
+            Action::Hold,
+            Action::Release,
+            Action::Escrow,
+            Action::EscrowRelease,
+            Action::Reversal,
+            Action::Unlock,
+        ];
+        let config = Config {
+            allow_withdrawal_disputes: true,
+            allow_unlock_chargeback: true,
+            ..Config::default()
+        };
+        for seed in 0..200u64 {
+            //xorshift, so every failure can be replayed from its seed
+            let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+            let mut next = |bound: u64| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state % bound
+            };
+            let mut account = Account::new(1);
+            for step in 0..80 {
+                let action = actions[next(actions.len() as u64) as usize];
+                //new rows take a fresh id, the others reference one of the first ids
+                let transaction_id = match action {
+                    Action::Deposit
+                    | Action::Withdrawal
+                    | Action::WithdrawalPending
+                    | Action::Hold
+                    | Action::Escrow => 100 + step,
+                    _ => 100 + next(step.max(1) as u64) as TransactionId,
+                };
+                let amount = match action {
+                    Action::Dispute | Action::EscrowRelease if next(2) == 0 => None,
+                    Action::WithdrawalSettle
+                    | Action::WithdrawalFail
+                    | Action::Resolve
+                    | Action::Chargeback
+                    | Action::Reinstate
+                    | Action::Reversal
+                    | Action::Unlock => None,
+                    _ => Some(Decimal::new(next(1000) as i64 + 1, 1)),
+                };
+                let transaction = Transaction::_new(action, 1, transaction_id, amount);
+                if let Err(err) = account.handle_transaction(transaction, &config) {
+                    assert_eq!(
+                        err.severity(),
+                        Severity::Recoverable,
+                        "seed {} step {}: {}",
+                        seed,
+                        step,
+                        err
+                    );
+                }
+                assert!(account.held >= Decimal::ZERO, "seed {} step {}", seed, step);
+                assert_eq!(account.total, account.available + account.held);
+            }
+        }
+    }
+
+    /// Equal balances print the same whatever the scale of the rows that made them
+    #[tokio::test]
+    async fn test_output_scale() {
+        let mut engine = Engine::new(Config::default());
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.5\n\
+                     deposit,2,2,1.50\n\
+                     deposit,3,3,1.5000\n\
+                     deposit,4,4,3\n\
+                     withdrawal,4,5,1.50\n\
+                     deposit,5,6,1\n\
+                     deposit,5,7,0.5\n";
+        let output = run(&mut engine, input).await;
+        let fields: Vec<&str> = output
+            .iter()
+            .map(|line| line.split_once(',').unwrap().1)
+            .collect();
+        assert_eq!(fields, vec!["1.5000,0.0000,1.5000,false"; 5]);
+    }
+
+    /// Amount strings from various exporters, the rules are documented on parse_decimal
+    #[test]
+    fn test_amount_formats() {
+        let parse = |amount: &str| {
+            parse_row(
+                vec!["deposit", "1", "1", amount],
+                &Columns::default(),
+                false,
+            )
+            .map(|transaction| transaction.decimal.unwrap())
+            .map_err(|err| err.to_string())
+        };
+        for (amount, expected) in [
+            ("1.5", Decimal::new(15, 1)),
+            (".5", Decimal::new(5, 1)),
+            ("1.", Decimal::ONE),
+            ("+3.2", Decimal::new(32, 1)),
+            ("+.5", Decimal::new(5, 1)),
+            ("-.5", Decimal::new(-5, 1)),
+            ("007.10", Decimal::new(71, 1)),
+        ] {
+            assert_eq!(parse(amount), Ok(expected), "{}", amount);
+        }
+        for (amount, err) in [
+            ("1e2", "amount `1e2` of deposit row uses exponent notation, which is not accepted"),
+            ("1.5E-2", "amount `1.5E-2` of deposit row uses exponent notation, which is not accepted"),
+            ("1_000", "amount `1_000` of deposit row could not be parsed into decimal: Invalid decimal: unknown character"),
+            ("1.2.3", "amount `1.2.3` of deposit row could not be parsed into decimal: Invalid decimal: two decimal points"),
+            (".", "amount `.` of deposit row could not be parsed into decimal: Invalid decimal: no digits found"),
+            ("+", "amount `+` of deposit row could not be parsed into decimal: Invalid decimal: no digits found"),
+            ("++1", "amount `++1` of deposit row could not be parsed into decimal: Invalid decimal: unknown character"),
+            ("0x10", "amount `0x10` of deposit row could not be parsed into decimal: Invalid decimal: unknown character"),
+        ] {
+            assert_eq!(parse(amount), Err(String::from(err)), "{}", amount);
+        }
+    }
+
+    /// Values read straight from the bytes match from_str, scale included, and the rest is left to it
+    #[test]
+    fn test_byte_parsing() {
+        for amount in [
+            "0",
+            "1.5",
+            "-1.5",
+            "+1.50",
+            "007.10",
+            "0.0000",
+            "-0.25",
+            "123456789.123456789",
+            "-9223372036854775807",
+            "0.0000000000000000000000000001",
+            ".5",
+            "+.5",
+            "1.",
+            "-0.0",
+            "-0",
+            "18446744073709551615",
+            "18446744073709551616",
+            "79228162514264337593543950335",
+            "-7922816251426433759354395033.5",
+            "00000000000000000000000000000000001",
+            "1.0000000000000000000000000000",
+        ] {
+            let parsed = parse_plain_decimal(amount.as_bytes()).unwrap();
+            assert_eq!(
+                parsed.serialize(),
+                Decimal::from_str(amount).unwrap().serialize(),
+                "{}",
+                amount
+            );
+        }
+        for amount in [
+            "",
+            "-",
+            ".",
+            "+.",
+            "1.2.3",
+            "1_0",
+            "1e2",
+            " 1",
+            "--1",
+            "79228162514264337593543950336",
+            "7922816251426433759354395033.56",
+            "0.00000000000000000000000000001",
+        ] {
+            assert_eq!(parse_plain_decimal(amount.as_bytes()), None, "{}", amount);
+        }
+        for id in ["0", "7", "0042", "65535", "4294967295"] {
+            assert_eq!(
+                parse_digits(id.as_bytes()),
+                Some(u64::from_str(id).unwrap())
+            );
+        }
+        for id in ["", "+1", "-1", " 1", "1a", "18446744073709551616"] {
+            assert_eq!(parse_digits(id.as_bytes()), None, "{}", id);
+        }
+        assert_eq!(parse_int::<u16>("65535", "client").unwrap(), u16::MAX);
+        assert_eq!(parse_int::<u32>("+7", "tx").unwrap(), 7);
+        assert!(matches!(
+            parse_int::<u16>("65536", "client"),
+            Err(CustomError::IntOutOfRange { max: 65535, .. })
+        ));
+    }
+
+    /// Exponent notation stops the run by default, naming the line, and reads as a plain amount when allowed
+    #[tokio::test]
+    async fn test_scientific_amounts() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     deposit,1,5,1e4\n\
+                     withdrawal,1,6,2.5E-1\n";
+        let mut engine = Engine::new(Config::default());
+        let err = run_err(&mut engine, &input).await;
+        assert_eq!(
+            err.to_string(),
+            "row on line 3 could not be read: amount `1e4` of deposit row uses exponent notation, which is not accepted"
         );
-        account.handle_transaction(transaction1).unwrap();
-        if let Ok(()) = account.handle_transaction(transaction2) {
-            //this should fail
-            panic!()
+
+        let mut engine = Engine::new(Config {
+            allow_scientific: true,
+            ..Config::default()
+        });
+        let output = run(&mut engine, input).await;
+        assert_eq!(output, vec!["1,10009.7500,0.0000,10009.7500,false"]);
+
+        let parse = |amount: &str| {
+            parse_row(vec!["deposit", "1", "1", amount], &Columns::default(), true)
+                .map(|transaction| transaction.decimal.unwrap())
+                .map_err(|err| err.to_string())
+        };
+        for (amount, expected) in [
+            ("1e4", Decimal::new(10000, 0)),
+            ("+1.5E+2", Decimal::new(150, 0)),
+            ("25e-2", Decimal::new(25, 2)),
+            ("1.5", Decimal::new(15, 1)),
+        ] {
+            assert_eq!(parse(amount), Ok(expected), "{}", amount);
+        }
+        for amount in ["1e", "e4", "1e4.5", "1e_4", "1e4e4"] {
+            assert!(parse(amount).is_err(), "{}", amount);
+        }
+    }
+
+    /// A negative withdrawal passes any balance check, so it must be rejected before it credits the account
+    #[tokio::test]
+    async fn test_negative_amounts() {
+        let rejects_path = std::env::temp_dir().join("test_negative_amounts_rejects.csv");
+        let mut engine = Engine::new(Config {
+            rejects_path: Some(rejects_path.clone()),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,7,1,10.0\n\
+                     withdrawal,7,99,-500.0\n\
+                     withdrawal_pending,7,100,-1.0\n\
+                     withdrawal,7,101,0.0\n\
+                     deposit,7,102,-5.0\n\
+                     dispute,7,1,-2.0\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(output, vec!["7,10.0000,0.0000,10.0000,false"]);
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "type,client,tx,amount,error,line,raw\n\
+             withdrawal,7,99,-500.0,Amount must be positive,3,\"withdrawal,7,99,-500.0\"\n\
+             withdrawal_pending,7,100,-1.0,Amount must be positive,4,\"withdrawal_pending,7,100,-1.0\"\n\
+             withdrawal,7,101,0.0,Amount must be positive,5,\"withdrawal,7,101,0.0\"\n\
+             deposit,7,102,-5.0,Amount must be positive,6,\"deposit,7,102,-5.0\"\n\
+             dispute,7,1,-2.0,Amount must be positive,7,\"dispute,7,1,-2.0\"\n"
+        );
+    }
+
+    /// Header variants received from partners read the same as the canonical header
+    #[tokio::test]
+    async fn test_header_variants() {
+        let rows = "deposit,1,1,2.0\n\
+                    withdrawal,1,2,0.5\n\
+                    deposit,2,3,1.0\n\
+                    dispute,2,3\n";
+        let expected = vec![
+            "1,1.5000,0.0000,1.5000,false",
+            "2,0.0000,1.0000,1.0000,false",
+        ];
+        for header in [
+            "type,client,tx,amount",
+            "Type, Client, TX, Amount",
+            "type,client,tx,amount ",
+            " TYPE ,CLIENT,Tx,DECIMAL",
+            "type,client,tx,decimal",
+        ] {
+            let mut engine = Engine::new(Config::default());
+            let output = run(&mut engine, &format!("{}\n{}", header, rows)).await;
+            assert_eq!(output, expected, "{}", header);
+        }
+        //columns are found by name, so their order does not matter
+        let mut engine = Engine::new(Config::default());
+        let input = "Client,Amount,Type,Tx\n\
+                     1,2.0,deposit,1\n\
+                     1,0.5,withdrawal,2\n\
+                     2,1.0,deposit,3\n\
+                     2,,dispute,3\n";
+        assert_eq!(run(&mut engine, input).await, expected);
+    }
+
+    /// Amounts echoed on resolve and chargeback rows are read and ignored by default,
+    /// and must match the transaction when validated
+    #[tokio::test]
+    async fn test_echoed_dispute_amounts() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,3.0\n\
+                     dispute,1,1,3.0000\n\
+                     resolve,1,1,3.0000\n\
+                     deposit,2,2,3.0\n\
+                     dispute,2,2,2.0\n\
+                     chargeback,2,2,9.0\n";
+        let mut engine = Engine::new(Config::default());
+        let output = run(&mut engine, input).await;
+        //client 2 disputed part of the deposit, the chargeback takes only that part
+        assert_eq!(
+            output,
+            vec![
+                "1,3.0000,0.0000,3.0000,false",
+                "2,1.0000,0.0000,1.0000,true"
+            ]
+        );
+
+        let rejects_path = std::env::temp_dir().join("test_echoed_dispute_amounts_rejects.csv");
+        let mut engine = Engine::new(Config {
+            validate_dispute_amounts: true,
+            rejects_path: Some(rejects_path.clone()),
+            ..Config::default()
+        });
+        let output = run(&mut engine, input).await;
+        //the dispute of client 2 disagrees with its deposit, so nothing was disputed to charge back
+        assert_eq!(
+            output,
+            vec![
+                "1,3.0000,0.0000,3.0000,false",
+                "2,3.0000,0.0000,3.0000,false"
+            ]
+        );
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "type,client,tx,amount,error,line,raw\n\
+             dispute,2,2,2.0,\"Row echoes an amount of 2.0, but the transaction amount is 3.0\",6,\"dispute,2,2,2.0\"\n\
+             chargeback,2,2,9.0,Not under dispute,7,\"chargeback,2,2,9.0\"\n"
+        );
+
+        //a resolve echoing another amount than the one disputed is rejected, the dispute stays open
+        let mut engine = Engine::new(Config {
+            validate_dispute_amounts: true,
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,3.0\n\
+                     dispute,1,1,\n\
+                     resolve,1,1,1.0\n";
+        let output = run(&mut engine, input).await;
+        assert_eq!(output, vec!["1,0.0000,3.0000,3.0000,false"]);
+        assert_eq!(
+            engine.report().rejected_by_reason["dispute amount mismatch"],
+            1
+        );
+    }
+
+    /// Columns after the amount that the engine does not know are ignored, named in the header or not
+    #[tokio::test]
+    async fn test_extra_columns_ignored() {
+        let expected = run(
+            &mut Engine::new(Config::default()),
+            "type,client,tx,amount\n\
+             deposit,1,1,2.0\n\
+             withdrawal,1,2,0.5\n\
+             dispute,1,1,\n\
+             deposit,2,3,1.0\n",
+        )
+        .await;
+        for input in [
+            "type,client,tx,amount,batch_id\n\
+             deposit,1,1,2.0,b1\n\
+             withdrawal,1,2,0.5,b1\n\
+             dispute,1,1,,b2\n\
+             deposit,2,3,1.0,b2\n",
+            "type,client,tx,amount,batch_id,note\n\
+             deposit,1,1,2.0,b1,first\n\
+             withdrawal,1,2,0.5,b1,\n\
+             dispute,1,1,,b2,\"late, disputed\"\n\
+             deposit,2,3,1.0,b2,last\n",
+            //rows longer than the header
+            "type,client,tx,amount\n\
+             deposit,1,1,2.0,b1\n\
+             withdrawal,1,2,0.5,b1,x\n\
+             dispute,1,1,,b2\n\
+             deposit,2,3,1.0\n",
+        ] {
+            let mut engine = Engine::new(Config::default());
+            assert_eq!(run(&mut engine, input).await, expected, "{}", input);
+        }
+    }
+
+    /// Amounts beyond four decimal places are rounded half up before they are stored, or rejected when strict
+    #[tokio::test]
+    async fn test_excess_precision() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.00005\n\
+                     dispute,1,1,\n\
+                     deposit,2,2,2.00004\n\
+                     withdrawal,2,3,0.50000\n\
+                     deposit,3,4,3.0\n\
+                     dispute,3,4,1.00005\n\
+                     resolve,3,4,\n";
+        let mut engine = Engine::new(Config::default());
+        let output = run(&mut engine, input).await;
+        //the dispute held exactly what was credited
+        assert_eq!(engine.clients[&1].held, Decimal::new(10001, PRECISION));
+        assert_eq!(
+            engine.clients[&3]
+                .transactions
+                .get(&4)
+                .unwrap()
+                .disputed_amount(),
+            Decimal::new(10001, PRECISION)
+        );
+        assert_eq!(
+            output,
+            vec![
+                "1,0.0000,1.0001,1.0001,false",
+                "2,1.5000,0.0000,1.5000,false",
+                "3,3.0000,0.0000,3.0000,false"
+            ]
+        );
+        let mut engine = Engine::new(Config::default());
+        let input_resolved = format!("{}resolve,1,1,\n", input);
+        run(&mut engine, &input_resolved).await;
+        assert_eq!(engine.clients[&1].available, Decimal::new(10001, PRECISION));
+        assert_eq!(engine.clients[&1].held, Decimal::ZERO);
+
+        let mut engine = Engine::new(Config {
+            strict_precision: true,
+            ..Config::default()
+        });
+        let output = run(&mut engine, input).await;
+        assert_eq!(
+            output,
+            vec![
+                "1,0.0000,0.0000,0.0000,false",
+                "2,0.0000,0.0000,0.0000,false",
+                "3,3.0000,0.0000,3.0000,false"
+            ]
+        );
+        let report = engine.report();
+        assert_eq!(report.rejected_by_reason["excess precision"], 3);
+        assert_eq!(
+            CustomError::ExcessPrecision {
+                column: "amount",
+                value: Decimal::from_str("1.00005").unwrap(),
+                precision: PRECISION
+            }
+            .to_string(),
+            "amount 1.00005 has more than 4 decimal places"
+        );
+    }
+
+    /// Ids too large for their type name the column, the value and the range, zero ids are valid
+    #[tokio::test]
+    async fn test_id_range() {
+        let parse = |client: &str, tx: &str| {
+            parse_row(
+                vec!["deposit", client, tx, "1.0"],
+                &Columns::default(),
+                false,
+            )
+            .map(|transaction| (transaction.client_id, transaction.transaction_id))
+            .map_err(|err| err.to_string())
+        };
+        assert_eq!(parse("0", "0"), Ok((0, 0)));
+        assert_eq!(parse("65535", "4294967295"), Ok((65535, 4294967295)));
+        assert_eq!(
+            parse("70000", "1"),
+            Err(String::from(
+                "client `70000` of deposit row is out of range, it must be between 0 and 65535"
+            ))
+        );
+        assert_eq!(
+            parse("1", "5000000000"),
+            Err(String::from(
+                "tx `5000000000` of deposit row is out of range, it must be between 0 and 4294967295"
+            ))
+        );
+        assert_eq!(
+            parse("-1", "1"),
+            Err(String::from(
+                "client `-1` of deposit row could not be parsed into int: invalid digit found in string"
+            ))
+        );
+
+        let input = "type,client,tx,amount\n\
+                     deposit,0,0,1.0\n\
+                     deposit,70000,1,1.0\n\
+                     deposit,1,5000000000,1.0\n\
+                     deposit,1,2,1.0\n";
+        let mut engine = Engine::new(Config::default());
+        let err = run_err(&mut engine, &input).await;
+        assert_eq!(
+            err.to_string(),
+            "row on line 3 could not be read: client `70000` of deposit row is out of range, it must be between 0 and 65535"
+        );
+        //the lenient policy skips the rows
+        let mut engine = Engine::new(Config {
+            skip_malformed_rows: true,
+            ..Config::default()
+        });
+        let output = run(&mut engine, input).await;
+        assert_eq!(
+            output,
+            vec![
+                "0,1.0000,0.0000,1.0000,false",
+                "1,1.0000,0.0000,1.0000,false"
+            ]
+        );
+        assert_eq!(engine.malformed_rows_skipped, 2);
+    }
+
+    /// A last record without its line break was cut off, it stops the run or is dropped when tolerated
+    #[tokio::test]
+    async fn test_truncated_tail() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     withdrawal,1,2,";
+        let mut engine = Engine::new(Config::default());
+        let err = run_err(&mut engine, &input).await;
+        assert_eq!(
+            err.to_string(),
+            "input ends mid-record at byte 39: withdrawal,1,2,"
+        );
+        assert_eq!(err.severity(), Severity::Fatal);
+
+        let mut engine = Engine::new(Config {
+            tolerate_truncated_tail: true,
+            ..Config::default()
+        });
+        let output = run(&mut engine, input).await;
+        assert_eq!(output, vec!["1,10.0000,0.0000,10.0000,false"]);
+        assert_eq!(engine.report().rejected_by_reason["truncated input"], 1);
+
+        //a cut before the amount or inside the type leaves a row that does not read at all
+        for partial in ["withdrawal,1,2", "withdr"] {
+            let input = format!("type,client,tx,amount\ndeposit,1,1,10.0\n{}", partial);
+            let mut engine = Engine::new(Config::default());
+            let err = run_err(&mut engine, &input).await;
+            assert_eq!(
+                err.to_string(),
+                format!("input ends mid-record at byte 39: {}", partial)
+            );
+        }
+
+        //an amount ending in a point is a whole amount, not a cut
+        let mut engine = Engine::new(Config::default());
+        let output = run(
+            &mut engine,
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,3,1.",
+        )
+        .await;
+        assert_eq!(output, vec!["1,11.0000,0.0000,11.0000,false"]);
+        assert_eq!(engine.report().rejected, 0);
+
+        //a line break of either kind ends the last record
+        for input in [
+            "type,client,tx,amount\ndeposit,1,1,10.0\n",
+            "type,client,tx,amount\r\ndeposit,1,1,10.0\r\n",
+            "type,client,tx,amount\n",
+            "type,client,tx,amount",
+        ] {
+            let mut engine = Engine::new(Config::default());
+            run(&mut engine, input).await;
+        }
+    }
+
+    /// Windows line endings, mixed ones and a missing final line break read like the clean file
+    #[tokio::test]
+    async fn test_line_endings() {
+        let clean = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     deposit,2,2,\"3.5\"\n\
+                     withdrawal,1,3,4.25\n\
+                     dispute,2,2,\n\
+                     deposit,1,4,\"1.0\"\n";
+        let mut engine = Engine::new(Config::default());
+        let expected = run(&mut engine, clean).await;
+        assert_eq!(
+            expected,
+            vec![
+                "1,6.7500,0.0000,6.7500,false",
+                "2,0.0000,3.5000,3.5000,false"
+            ]
+        );
+
+        let crlf = clean.replace('\n', "\r\n");
+        let mixed = clean
+            .split_inclusive('\n')
+            .enumerate()
+            .map(|(index, line)| match index % 3 {
+                0 => line.replace('\n', "\r\n"),
+                1 => line.replace('\n', "\r"),
+                _ => line.to_string(),
+            })
+            .collect::<String>();
+        for input in [
+            crlf.clone(),
+            mixed,
+            clean.trim_end().to_string(),
+            crlf.trim_end().to_string(),
+            clean.trim_end().to_string() + "\r",
+            //a last row with its amount left out, no trailing comma and no line break
+            clean.replace("dispute,2,2,\n", "").trim_end().to_string() + "\r\ndispute,2,2",
+        ] {
+            let mut engine = Engine::new(Config::default());
+            assert_eq!(run(&mut engine, &input).await, expected, "{:?}", input);
+        }
+    }
+
+    /// Invalid UTF-8 is reported with its place, and only replaced in fields the balances do not depend on
+    #[tokio::test]
+    async fn test_invalid_utf8() {
+        //latin-1 bytes in the memo of the first and last row, and in the client of the third
+        let input: &[u8] = b"type,client,tx,amount,memo\n\
+                             deposit,1,1,10.0,caf\xe9\n\
+                             deposit,1,2,5.0,ok\n\
+                             deposit,1\xff,3,1.0,\n\
+                             withdrawal,1,4,100.0,r\xe9f\n";
+        let mut engine = Engine::new(Config::default());
+        let err = run_err(&mut engine, input).await;
+        assert_eq!(
+            err.to_string(),
+            "row on line 2 could not be read: invalid UTF-8 in field 5 of record 1, near byte 47"
+        );
+
+        let rejects_path = std::env::temp_dir().join("test_invalid_utf8_rejects.csv");
+        let mut engine = Engine::new(Config {
+            skip_malformed_rows: true,
+            rejects_path: Some(rejects_path.clone()),
+            ..Config::default()
+        });
+        let mut reader = Reader::from_reader(input);
+        let mut writer = Writer::from_inner(Vec::new());
+        engine.process(&mut reader, &mut writer).await.unwrap();
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        assert_eq!(
+            output.lines().nth(1),
+            Some("1,15.0000,0.0000,15.0000,false")
+        );
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "type,client,tx,amount,error,line,raw,memo\n\
+             ,,,,\"row on line 4 could not be read: invalid UTF-8 in field 2 of record 3, near byte 77\",4,\"deposit,1\u{fffd},3,1.0,\",\n\
+             withdrawal,1,4,100.0,Not enough account balance,5,\"withdrawal,1,4,100.0,r\u{fffd}f\",r\u{fffd}f\n"
+        );
+    }
+
+    /// Duplicated ids name the transaction they were first applied as
+    #[tokio::test]
+    async fn test_duplicate_details() {
+        let rejects_path = std::env::temp_dir().join("test_duplicate_details_rejects.csv");
+        let mut engine = Engine::new(Config {
+            rejects_path: Some(rejects_path.clone()),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     deposit,1,2,5.0\n\
+                     deposit,1,1,10.0\n\
+                     withdrawal,2,2,3.0\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,15.0000,0.0000,15.0000,false",
+                "2,0.0000,0.0000,0.0000,false"
+            ]
+        );
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "type,client,tx,amount,error,line,raw\n\
+             deposit,1,1,10.0,\"Duplicated transcation id, first applied as deposit of 10.0 by client 1 at record 1\",4,\"deposit,1,1,10.0\"\n\
+             withdrawal,2,2,3.0,\"Duplicated transcation id, first applied as deposit of 5.0 by client 1 at record 2\",5,\"withdrawal,2,2,3.0\"\n"
+        );
+
+        //an account rejecting the id on its own reports the same details
+        let config = Config::default();
+        let mut account = Account::new(1);
+        let mut deposit = Transaction::_new(Action::Deposit, 1, 7, Some(Decimal::new(20, 0)));
+        deposit.record_index = 3;
+        account.handle_transaction(deposit, &config).unwrap();
+        let withdrawal = Transaction::_new(Action::Withdrawal, 1, 7, Some(Decimal::new(4, 0)));
+        match account.handle_transaction(withdrawal, &config) {
+            Err(CustomError::DuplicatedTransactionId {
+                original: Some(original),
+            }) => assert_eq!(
+                original,
+                OriginalTransaction {
+                    action: "deposit",
+                    client: 1,
+                    amount: Some(Decimal::new(20, 0)),
+                    record_index: 3,
+                }
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    /// Rows referencing a missing transaction say which lifecycle step was lost, and whether the client was new
+    #[tokio::test]
+    async fn test_unknown_tx_context() {
+        let rejects_path = std::env::temp_dir().join("test_unknown_tx_context_rejects.csv");
+        let mut engine = Engine::new(Config {
+            rejects_path: Some(rejects_path.clone()),
+            ..Config::default()
+        });
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     dispute,1,2,\n\
+                     resolve,2,3,\n\
+                     resolve,2,4,\n\
+                     chargeback,1,5,\n";
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,10.0000,0.0000,10.0000,false",
+                "2,0.0000,0.0000,0.0000,false"
+            ]
+        );
+        let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+        std::fs::remove_file(&rejects_path).unwrap();
+        assert_eq!(
+            rejects,
+            "type,client,tx,amount,error,line,raw\n\
+             dispute,1,2,,Non existing transaction id 2 for dispute,3,\"dispute,1,2,\"\n\
+             resolve,2,3,,\"Non existing transaction id 3 for resolve, the client had no earlier rows\",4,\"resolve,2,3,\"\n\
+             resolve,2,4,,Non existing transaction id 4 for resolve,5,\"resolve,2,4,\"\n\
+             chargeback,1,5,,Non existing transaction id 5 for chargeback,6,\"chargeback,1,5,\"\n"
+        );
+        assert_eq!(
+            without_peak_memory(&engine.report().to_string()),
+            "records: 5\n\
+             applied: 1\n\
+             rejected: 4\n  \
+             unknown tx on chargeback: 1\n  \
+             unknown tx on dispute: 1\n  \
+             unknown tx on resolve: 2\n\
+             ignored: 0\n"
+        );
+    }
+
+    /// Daily files joined with cat repeat their header, which is skipped instead of stopping the run
+    #[tokio::test]
+    async fn test_concatenated_files() {
+        let first_day = "type,client,tx,amount\n\
+                         deposit,1,1,10.0\n\
+                         withdrawal,1,2,4.0\n";
+        let second_day = "type,client,tx,amount\n\
+                          deposit,2,3,7.0\n\
+                          dispute,1,1,\n";
+        let mut engine = Engine::new(Config::default());
+        let output = run(&mut engine, &[first_day, second_day, second_day].concat()).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,-4.0000,10.0000,6.0000,false",
+                "2,7.0000,0.0000,7.0000,false"
+            ]
+        );
+        let report = engine.report();
+        assert_eq!(report.repeated_headers, 2);
+        assert_eq!(report.records, 6);
+        assert!(without_peak_memory(&report.to_string()).ends_with("repeated headers: 2\n"));
+
+        //a file with a memo column joined with one without it, in another case
+        let mut engine = Engine::new(Config::default());
+        let input = [
+            "type,client,tx,amount,memo\n\
+             deposit,1,1,10.0,INV-1\n",
+            "Type,Client,TX,Amount\n\
+             deposit,1,2,3.0\n",
+        ]
+        .concat();
+        let output = run(&mut engine, &input).await;
+
+        assert_eq!(output, vec!["1,13.0000,0.0000,13.0000,false"]);
+        assert_eq!(engine.report().repeated_headers, 1);
+    }
+
+    /// Dispute heavy input over several clients, with ids used by two clients and interest rows
+    /// Ids are only reused or referenced by another client from deposits that always apply,
+    /// as the first use of an id claims it when rows are checked before they are applied, applied or not
+    fn interleaved_clients() -> String {
+        let mut input = String::from("type,client,tx,amount\n");
+        let mut deposits: Vec<(u64, u64)> = Vec::new();
+        let mut charged: Vec<(u64, u64)> = Vec::new();
+        let mut seed: u64 = 7;
+        for tx in 1..=6000u64 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let client = (seed >> 33) % 23;
+            let pick = |pool: &Vec<(u64, u64)>| match pool.len() {
+                0 => (tx, client),
+                len => pool[(seed >> 17) as usize % len],
+            };
+            //deposits of charged back clients are rejected once they are locked
+            let (always, earlier) = (
+                pick(&deposits),
+                pick(if client.is_multiple_of(5) {
+                    &charged
+                } else {
+                    &deposits
+                }),
+            );
+            let row = match (seed >> 40) % 10 {
+                0..=3 => {
+                    if client.is_multiple_of(5) {
+                        charged.push((tx, client));
+                    } else {
+                        deposits.push((tx, client));
+                    }
+                    format!("deposit,{},{},{}.5", client, tx, (seed >> 20) % 100)
+                }
+                4 => format!("withdrawal,{},{},{}.25", client, tx, (seed >> 20) % 50),
+                //mostly the client's own deposit, sometimes one of another client
+                5 | 6 if (seed >> 50).is_multiple_of(4) => {
+                    format!("dispute,{},{},", client, always.0)
+                }
+                5 | 6 => format!("dispute,{},{},", earlier.1, earlier.0),
+                7 => format!("resolve,{},{},", earlier.1, earlier.0),
+                8 if client.is_multiple_of(5) => format!("chargeback,{},{},", earlier.1, earlier.0),
+                8 => format!("resolve,{},{},", client, earlier.0),
+                _ => format!("deposit,{},{},1.0", client, always.0),
+            };
+            input.push_str(&row);
+            input.push('\n');
+            if tx.is_multiple_of(1500) {
+                input.push_str(&format!("accrue_interest,0,{},0.01\n", 100_000 + tx));
+            }
+        }
+        input
+    }
+
+    /// Rows sharded over workers give the same accounts and counts as one worker
+    #[tokio::test]
+    async fn test_workers() {
+        let input = interleaved_clients();
+        let config = Config {
+            extra_columns: true,
+            dispute_window: Some(Window::Records(500)),
+            ..Config::default()
+        };
+        let mut single = Engine::new(config.clone());
+        let expected = run(&mut single, &input).await;
+        for workers in [2, 3, 8] {
+            let mut engine = Engine::new(Config {
+                workers,
+                ..config.clone()
+            });
+            assert_eq!(
+                run(&mut engine, &input).await,
+                expected,
+                "{} workers",
+                workers
+            );
+            assert_eq!(
+                counts(engine.report()),
+                counts(single.report()),
+                "{} workers",
+                workers
+            );
+        }
+    }
+
+    /// Rows applied client by client once the whole input is read give the same accounts and counts as in input order
+    #[tokio::test]
+    async fn test_group_by_client() {
+        let mut inputs = vec![(String::from("interleaved"), interleaved_clients())];
+        for scenario in Scenario::ALL {
+            let input = String::from_utf8(workload(scenario, 2_000, 5)).unwrap();
+            inputs.push((scenario.as_str().to_string(), input));
+        }
+        let windowed = Config {
+            extra_columns: true,
+            dispute_window: Some(Window::Records(500)),
+            ..Config::default()
+        };
+        for config in [Config::default(), windowed] {
+            for (name, input) in &inputs {
+                let mut single = Engine::new(config.clone());
+                let expected = run(&mut single, input).await;
+                let mut grouped = Engine::new(Config {
+                    group_by_client: true,
+                    ..config.clone()
+                });
+                assert_eq!(run(&mut grouped, input).await, expected, "{}", name);
+                assert_eq!(
+                    counts(grouped.report()),
+                    counts(single.report()),
+                    "{}",
+                    name
+                );
+            }
+        }
+
+        let input = "type,client,tx,amount,to\n\
+                     deposit,1,1,10.0,\n\
+                     transfer,1,2,1.0,2\n";
+        let mut engine = Engine::new(Config {
+            group_by_client: true,
+            ..Config::default()
+        });
+        let mut reader = Reader::from_reader(input.as_bytes());
+        let mut writer = Writer::from_inner(Vec::new());
+        assert!(matches!(
+            engine.process(&mut reader, &mut writer).await,
+            Err(CustomError::UnsupportedWithGroupByClient {
+                feature: "transfer rows"
+            })
+        ));
+        for (config, feature) in [
+            (
+                Config {
+                    workers: 2,
+                    ..Config::default()
+                },
+                "--workers",
+            ),
+            (
+                Config {
+                    enforce_chronology: true,
+                    ..Config::default()
+                },
+                "--enforce-chronology",
+            ),
+        ] {
+            let mut engine = Engine::new(Config {
+                group_by_client: true,
+                ..config
+            });
+            let mut reader = Reader::from_reader(input.as_bytes());
+            let mut writer = Writer::from_inner(Vec::new());
+            assert_eq!(
+                engine
+                    .process(&mut reader, &mut writer)
+                    .await
+                    .unwrap_err()
+                    .to_string(),
+                format!("{} cannot be used with --group-by-client", feature)
+            );
+        }
+    }
+
+    /// Options comparing rows of different clients are refused with workers
+    #[tokio::test]
+    async fn test_workers_unsupported() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n";
+        let configs = [
+            Config {
+                enforce_chronology: true,
+                ..Config::default()
+            },
+            Config {
+                require_monotonic_tx_ids: true,
+                ..Config::default()
+            },
+            Config {
+                allow_tx_id_reuse: true,
+                ..Config::default()
+            },
+        ];
+        for config in configs {
+            let mut engine = Engine::new(Config {
+                workers: 2,
+                ..config
+            });
+            let mut reader = Reader::from_reader(input.as_bytes());
+            let mut writer = Writer::from_inner(Vec::new());
+            let err = engine.process(&mut reader, &mut writer).await.unwrap_err();
+            assert_eq!(err.severity(), Severity::Fatal);
+            assert!(err
+                .to_string()
+                .ends_with("cannot be used with more than one worker"));
+        }
+    }
+
+    /// Withdrawals the policy can never dispute are only kept as ids, which still guard against reuse
+    #[tokio::test]
+    async fn test_withdrawal_retention() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     withdrawal,1,2,4.0\n\
+                     withdrawal,1,2,1.0\n\
+                     deposit,1,2,1.0\n\
+                     dispute,1,2,\n\
+                     resolve,1,2,\n\
+                     chargeback,1,2,\n\
+                     reversal,1,2,\n\
+                     withdrawal_settle,1,2,\n\
+                     dispute,2,2,\n";
+        let mut engine = Engine::new(Config::default());
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,6.0000,0.0000,6.0000,false",
+                "2,0.0000,0.0000,0.0000,false"
+            ]
+        );
+        let account = &engine.clients[&1];
+        assert!(!account.transactions.contains_key(&2));
+        assert!(account.kept_ids.contains(2));
+        assert_eq!(
+            engine.report().rejected_by_reason,
+            BTreeMap::from([
+                ("client mismatch", 1),
+                ("duplicate tx", 2),
+                ("not under dispute", 2),
+                ("undefined behaviour", 2),
+                ("withdrawal not pending", 1),
+            ])
+        );
+
+        //withdrawals are kept whole again once they may be disputed
+        let mut engine = Engine::new(Config {
+            allow_withdrawal_disputes: true,
+            ..Config::default()
+        });
+        let output = run(&mut engine, input).await;
+
+        assert_eq!(
+            output,
+            vec![
+                "1,6.0000,0.0000,6.0000,false",
+                "2,0.0000,0.0000,0.0000,false"
+            ]
+        );
+        let account = &engine.clients[&1];
+        assert!(account.transactions.contains_key(&2));
+        assert_eq!(account.kept_ids.len(), 0);
+    }
+
+    const _: () = assert!(std::mem::size_of::<StoredTransaction>() == 48);
+
+    /// Amounts too large for the compact form of a stored transaction are kept whole
+    #[tokio::test]
+    async fn test_large_stored_amounts() {
+        let input = "type,client,tx,amount
+deposit,1,1,2000000000000000.0001
+deposit,1,2,1.5
+dispute,1,1,
+resolve,1,1,
+dispute,1,1,
+";
+        let mut engine = Engine::new(Config::default());
+        let output = run(&mut engine, input).await;
+        assert_eq!(
+            output,
+            vec!["1,1.5000,2000000000000000.0001,2000000000000001.5001,false"]
+        );
+        let account = &engine.clients[&1];
+        let large = account.transactions.get(&1).unwrap();
+        assert_eq!(large.amount_scale, LARGE_SCALE);
+        assert_eq!(large.amount(), "2000000000000000.0001".parse().unwrap());
+        assert_eq!(large.dispute_count(), 2);
+        let small = account.transactions.get(&2).unwrap();
+        assert!(small.details.is_none());
+        assert_eq!(small.amount().to_string(), "1.5");
+
+        //the spill record keeps the whole amount
+        let decoded = DetachedTransaction::decode(&large.encode().try_into().unwrap());
+        assert_eq!(decoded.amount(), large.amount());
+        assert_eq!(decoded.dispute_state(), DisputeState::Disputed);
+    }
+
+    /// Details of dropped transactions leave their slot in the slab to the next dispute
+    #[tokio::test]
+    async fn test_details_slots_reused() {
+        let input = "type,client,tx,amount,tx_end
+deposit,1,1,1.0,
+deposit,1,2,1.0,
+dispute,1,1,,
+resolve,1,1,,
+dispute,1,2,,
+resolve,1,2,,
+settle,1,1,,2
+deposit,1,3,1.0,
+dispute,1,3,,
+";
+        let mut engine = Engine::new(Config::default());
+        let output = run(&mut engine, input).await;
+        assert_eq!(output, vec!["1,2.0000,1.0000,3.0000,false"]);
+        let account = &engine.clients[&1];
+        assert_eq!(account.transactions.len(), 1);
+        assert!(account.transactions[&3].details < 2);
+        assert_eq!(account.transactions.get(&3).unwrap().dispute_count(), 1);
+    }
+
+    /// Runs the engine over the input twice as --two-pass does, returning its sorted output
+    /// A copy of the engine first does the same with --sync, which must write the same rows
+    async fn run_two_pass(engine: &mut Engine, input: &str) -> Vec<String> {
+        let mut sync = sync_copy(engine);
+        let bytes = input.as_bytes().to_vec();
+        let expected = std::thread::spawn(move || {
+            let mut reader = BlockingReader::from_reader(std::io::Cursor::new(bytes));
+            sync.scan_references_blocking(&mut reader).unwrap();
+            let mut writer = Writer::from_inner(Vec::new());
+            sync.process_blocking(&mut reader, &mut writer).unwrap();
+            let output = String::from_utf8(writer.into_inner()).unwrap();
+            let mut lines: Vec<String> = output.lines().skip(1).map(String::from).collect();
+            lines.sort();
+            lines
+        })
+        .join()
+        .unwrap();
+        let mut reader = Reader::from_reader(std::io::Cursor::new(input.as_bytes().to_vec()));
+        engine.scan_references(&mut reader).await.unwrap();
+        let mut writer = Writer::from_inner(Vec::new());
+        engine.process(&mut reader, &mut writer).await.unwrap();
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        let mut lines: Vec<String> = output.lines().skip(1).map(String::from).collect();
+        lines.sort();
+        assert_eq!(expected, lines, "the run with --sync differs");
+        lines
+    }
+
+    /// Keeping only the deposits a later row refers to writes the same accounts and rejects the same rows, naming
+    /// the same originals, on every workload and a file with each kind of row that refers to an earlier one
+    #[tokio::test]
+    async fn test_two_pass() {
+        let fixture = "type,client,tx,amount,to,tx_end
+deposit,1,1,10.0
+deposit,1,2,5.0
+deposit,2,3,7.0
+DISPUTE,1,1,
+resolve,1,1,
+deposit,1,2,1.0
+withdrawal,2,3,1.0
+deposit,2,4,3.0
+chargeback,2,4,
+dispute,2,4,
+chargeback,2,4,
+deposit,3,5,4.0
+reversal,3,5,
+deposit,3,6,2.0
+transfer,3,7,1.0,1
+dispute,1,7,
+settle,1,1,,,2
+dispute,1,2,
+deposit,4,8,x
+deposit,4,9,2.0
+";
+        let mut inputs = vec![("fixture", fixture.to_owned())];
+        for scenario in Scenario::ALL {
+            let input = String::from_utf8(workload(scenario, 2_000, 9)).unwrap();
+            inputs.push((scenario.as_str(), input));
+        }
+        for (name, input) in inputs {
+            let rejects_path = std::env::temp_dir().join(format!("test_two_pass_{}.csv", name));
+            let config = Config {
+                skip_malformed_rows: true,
+                rejects_path: Some(rejects_path.clone()),
+                ..Config::default()
+            };
+            let mut engine = Engine::new(config.clone());
+            let expected = run(&mut engine, &input).await;
+            let expected_rejects = std::fs::read_to_string(&rejects_path).unwrap();
+
+            let mut two_pass = Engine::new(config);
+            assert_eq!(
+                run_two_pass(&mut two_pass, &input).await,
+                expected,
+                "{}",
+                name
+            );
+            let rejects = std::fs::read_to_string(&rejects_path).unwrap();
+            std::fs::remove_file(&rejects_path).unwrap();
+            assert_eq!(rejects, expected_rejects, "{}", name);
+            let (report, expected_report) = (two_pass.report(), engine.report());
+            assert_eq!(
+                report.rejected_by_reason, expected_report.rejected_by_reason,
+                "{}",
+                name
+            );
+            assert_eq!(report.applied, expected_report.applied, "{}", name);
+
+            let stored = |engine: &Engine| {
+                engine
+                    .clients
+                    .values()
+                    .map(|account| account.transactions.len())
+                    .sum::<usize>()
+            };
+            assert!(stored(&two_pass) < stored(&engine), "{}", name);
         }
     }
 
-    /// Test case for multiple deposit
-    #[test]
-    fn test_multiple_deposit() {
-        let client_id = 1;
-        let mut account = Account::new(client_id);
-        for i in 1..11 {
-            let transaction = Transaction::_new(
-                Action::Deposit,
-                client_id,
-                i,
-                Some(Decimal::new(i.into(), PRECISION)),
-                false,
+    /// Runs the engine on the file as --incremental does, with its state in dir, returning its sorted output
+    /// A run with --sync on a copy of the state directory must write the same rows and save the same state
+    async fn run_incremental(dir: &std::path::Path, path: &std::path::Path) -> Vec<String> {
+        let sync_dir = dir.with_extension("sync");
+        let _ = std::fs::remove_dir_all(&sync_dir);
+        std::fs::create_dir_all(&sync_dir).unwrap();
+        for entry in std::fs::read_dir(dir).into_iter().flatten() {
+            let entry = entry.unwrap().path();
+            std::fs::copy(&entry, sync_dir.join(entry.file_name().unwrap())).unwrap();
+        }
+        let mut sync = Engine::new(Config {
+            state_dir: Some(sync_dir.clone()),
+            ..Config::default()
+        });
+        let mut reader = BlockingReader::open(path).unwrap();
+        let mut writer = Writer::from_inner(Vec::new());
+        sync.process_incremental_blocking(&mut reader, &mut writer)
+            .unwrap();
+        let expected = String::from_utf8(writer.into_inner()).unwrap();
+
+        let mut engine = Engine::new(Config {
+            state_dir: Some(dir.to_owned()),
+            ..Config::default()
+        });
+        let mut reader = Reader::new(path.to_owned()).await.unwrap();
+        let mut writer = Writer::from_inner(Vec::new());
+        engine
+            .process_incremental(&mut reader, &mut writer)
+            .await
+            .unwrap();
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        assert_eq!(expected, output, "the run with --sync differs");
+        let states = |dir: &Path| -> Vec<Vec<u8>> {
+            let paths = checkpoint::list(dir).unwrap_or_default();
+            paths
+                .iter()
+                .map(|path| std::fs::read(path).unwrap())
+                .collect()
+        };
+        assert_eq!(states(&sync_dir), states(dir));
+        std::fs::remove_dir_all(&sync_dir).unwrap();
+        let mut lines: Vec<String> = output.lines().skip(1).map(String::from).collect();
+        lines.sort();
+        lines
+    }
+
+    /// Appended rows are applied to the state of the last run, a row still being written is left for the next one,
+    /// and an input rotated, rewritten or cut since the last run is processed from the start
+    #[tokio::test]
+    async fn test_incremental() {
+        let dir = std::env::temp_dir().join("test_incremental_state");
+        let path = std::env::temp_dir().join("test_incremental.csv");
+        let _ = std::fs::remove_dir_all(&dir);
+        let full = |input: &str| {
+            let input = input.to_owned();
+            async move { run(&mut Engine::new(Config::default()), &input).await }
+        };
+        let input = String::from_utf8(workload(Scenario::Disputes, 3_000, 21)).unwrap();
+        let rows: Vec<&str> = input.lines().collect();
+        let first: String = rows[..2_000]
+            .iter()
+            .map(|row| format!("{}\n", row))
+            .collect();
+        let whole: String = rows.iter().map(|row| format!("{}\n", row)).collect();
+
+        //a row cut off while it is appended is not applied yet
+        let partial = &rows[2_000][..rows[2_000].len() - 2];
+        std::fs::write(&path, format!("{}{}", first, partial)).unwrap();
+        assert_eq!(run_incremental(&dir, &path).await, full(&first).await);
+        assert_eq!(checkpoint::list(&dir).unwrap().len(), 1);
+        //nothing appended
+        assert_eq!(run_incremental(&dir, &path).await, full(&first).await);
+
+        //rows before the compared bytes are not read again, so this edit goes unseen
+        let middle = first.find(rows[500]).unwrap();
+        assert!(
+            middle as u64 > OVERLAP && middle + rows[500].len() + (OVERLAP as usize) < first.len()
+        );
+        let unseen = format!("{}xxxx{}", &first[..middle], &whole[middle + 4..]);
+        std::fs::write(&path, unseen).unwrap();
+        assert_eq!(run_incremental(&dir, &path).await, full(&whole).await);
+
+        //an amount rewritten just before where the last run stopped
+        let row = rows
+            .iter()
+            .rev()
+            .find(|row| row.starts_with("deposit"))
+            .unwrap();
+        let at = whole.rfind(row).unwrap() + row.len() - 1;
+        assert!(at as u64 > whole.len() as u64 - OVERLAP);
+        let digit = if row.ends_with('1') { "2" } else { "1" };
+        let rewritten = format!("{}{}{}", &whole[..at], digit, &whole[at + 1..]);
+        std::fs::write(&path, &rewritten).unwrap();
+        let expected = full(&rewritten).await;
+        assert_ne!(expected, full(&whole).await);
+        assert_eq!(run_incremental(&dir, &path).await, expected);
+
+        //rotated: a new file, longer than where the last run stopped
+        let rotated = String::from_utf8(workload(Scenario::Disputes, 4_000, 22)).unwrap();
+        std::fs::write(&path, &rotated).unwrap();
+        assert_eq!(run_incremental(&dir, &path).await, full(&rotated).await);
+
+        //cut short of where the last run stopped
+        std::fs::write(&path, &rotated[..rotated.len() / 2]).unwrap();
+        let cut = &rotated[..rotated[..rotated.len() / 2].rfind('\n').unwrap() + 1];
+        assert_eq!(run_incremental(&dir, &path).await, full(cut).await);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Runs moving transactions to disk end the same as runs keeping them all in memory
+    #[tokio::test]
+    async fn test_spill() {
+        //every kind of stored transaction, referenced again long after it was applied
+        let mut input = String::from("type,client,tx,amount,to,tx_end\n");
+        let mut seed: u64 = 11;
+        for tx in 1..=30_000u64 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let client = (seed >> 33) % 37;
+            let earlier = 1 + (seed >> 17) % tx;
+            let row = match (seed >> 40) % 20 {
+                0..=6 => format!("deposit,{},{},{}.5,,", client, tx, (seed >> 20) % 100),
+                7 | 8 => format!("withdrawal,{},{},{}.25,,", client, tx, (seed >> 20) % 20),
+                9 => format!("withdrawal_pending,{},{},2.0,,", client, tx),
+                10 => format!("withdrawal_settle,{},{},,,", client, earlier),
+                11 => format!("withdrawal_fail,{},{},,,", client, earlier),
+                12 => format!("transfer,{},{},1.0,{},", client, tx, (seed >> 50) % 37),
+                13 | 14 => format!("dispute,{},{},,,", client, earlier),
+                15 => format!("resolve,{},{},,,", client, earlier),
+                16 => format!("chargeback,{},{},,,", client, earlier),
+                17 => format!("reversal,{},{},,,", client, earlier),
+                18 if tx.is_multiple_of(7) => {
+                    format!("settle,{},{},,,{}", client, earlier, earlier + 50)
+                }
+                _ => format!("deposit,{},{},1.0,,", client, earlier),
+            };
+            input.push_str(&row);
+            input.push('\n');
+        }
+        let spill_dir = std::env::temp_dir().join("test_spill");
+        std::fs::create_dir_all(&spill_dir).unwrap();
+        for config in [
+            Config {
+                extra_columns: true,
+                ..Config::default()
+            },
+            Config {
+                extra_columns: true,
+                allow_withdrawal_disputes: true,
+                dispute_window: Some(Window::Records(4000)),
+                ..Config::default()
+            },
+        ] {
+            let mut memory = Engine::new(config.clone());
+            let expected = run(&mut memory, &input).await;
+            let mut spilling = Engine::new(Config {
+                spill_dir: Some(spill_dir.clone()),
+                max_memory: 300 * STORED_BYTES,
+                ..config
+            });
+            assert_eq!(run(&mut spilling, &input).await, expected);
+            let report = spilling.report();
+            assert!(report.spilled > 0 && report.spill_reads > 0);
+            assert_eq!(report.spill_bytes % SPILL_RECORD as u64, 0);
+            assert_eq!(
+                RunReport {
+                    spilled: 0,
+                    spill_reads: 0,
+                    spill_bytes: 0,
+                    peak_memory: 0,
+                    ..report
+                },
+                counts(memory.report())
             );
-            account.handle_transaction(transaction).unwrap();
+            //the spill file goes with its engine
+            drop(spilling);
+            assert_eq!(std::fs::read_dir(&spill_dir).unwrap().count(), 0);
         }
+        std::fs::remove_dir(&spill_dir).unwrap();
+    }
 
-        assert_eq!(account.total, Decimal::new(55, PRECISION));
-        assert_eq!(account.available, Decimal::new(55, PRECISION));
-        assert_eq!(account.held, Decimal::new(0, PRECISION));
+    /// Generated workloads are the same for a seed and read without a malformed row
+    #[tokio::test]
+    async fn test_generated_workloads() {
+        for scenario in Scenario::ALL {
+            let input = workload(scenario, 2_000, 3);
+            assert_eq!(input, workload(scenario, 2_000, 3), "{:?}", scenario);
+            assert_ne!(input, workload(scenario, 2_000, 4), "{:?}", scenario);
+            let mut engine = Engine::new(Config::default());
+            run(&mut engine, std::str::from_utf8(&input).unwrap()).await;
+            assert_eq!(engine.records_processed, 2_000, "{:?}", scenario);
+        }
+        assert_eq!(Scenario::from_str("few-clients"), Ok(Scenario::FewClients));
+        assert!(Scenario::from_str("few").is_err());
     }
 
-    #[test]
-    fn test_simple_withdrawal() {
-        let client_id = 1;
-        let mut account = Account::new(client_id);
-        let deposit = Transaction::_new(
-            Action::Deposit,
-            client_id,
-            1,
-            Some(Decimal::new(1, PRECISION)),
-            false,
-        );
-        account.handle_transaction(deposit).unwrap();
+    /// A filter too small for the input takes most new ids for claimed ones, the index tells them apart so every
+    /// workload ends as it does with the owners in memory
+    #[tokio::test]
+    async fn test_id_filter() {
+        let dir = std::env::temp_dir().join("test_id_filter");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for scenario in Scenario::ALL {
+            let input = String::from_utf8(workload(scenario, 10_000, 7)).unwrap();
+            let mut single = Engine::new(Config::default());
+            let expected = run(&mut single, &input).await;
+            for (expected_ids, error_rate) in [(1, 0.5), (100_000, 0.01)] {
+                let mut filtered = Engine::new(Config {
+                    id_filter: Some(IdFilter {
+                        dir: dir.clone(),
+                        expected_ids,
+                        error_rate,
+                    }),
+                    ..Config::default()
+                });
+                assert_eq!(
+                    run(&mut filtered, &input).await,
+                    expected,
+                    "{}",
+                    scenario.as_str()
+                );
+                let report = filtered.report();
+                let stats = report.id_filter;
+                assert_eq!(
+                    RunReport {
+                        id_filter: FilterStats::default(),
+                        ..counts(report)
+                    },
+                    counts(single.report()),
+                    "{}",
+                    scenario.as_str()
+                );
+                assert_eq!(stats.checks, 10_000);
+                if expected_ids == 1 {
+                    //nearly every new id passes the filter, and none is rejected for it
+                    assert!(stats.false_positives > 5_000, "{:?}", stats);
+                } else {
+                    assert!(stats.false_positives < 500, "{:?}", stats);
+                }
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
 
-        let withdrawal = Transaction::_new(
-            Action::Withdrawal,
-            client_id,
-            2,
-            Some(Decimal::new(1, PRECISION)),
-            false,
-        );
+        for (config, expected) in [
+            (
+                Config {
+                    workers: 2,
+                    ..Config::default()
+                },
+                CustomError::UnsupportedWithWorkers {
+                    feature: "--id-index-dir",
+                },
+            ),
+            (
+                Config {
+                    group_by_client: true,
+                    ..Config::default()
+                },
+                CustomError::UnsupportedWithGroupByClient {
+                    feature: "--id-index-dir",
+                },
+            ),
+        ] {
+            let mut engine = Engine::new(Config {
+                id_filter: Some(IdFilter {
+                    dir: std::env::temp_dir(),
+                    expected_ids: 1_000,
+                    error_rate: 0.01,
+                }),
+                ..config
+            });
+            let mut reader = Reader::from_reader("type,client,tx,amount\n".as_bytes());
+            let mut writer = Writer::from_inner(Vec::new());
+            let err = engine.process(&mut reader, &mut writer).await.unwrap_err();
+            assert_eq!(err.to_string(), expected.to_string());
+        }
+    }
 
-        account.handle_transaction(withdrawal).unwrap();
+    /// Rows read in batches are applied in order, and a row that cannot be read is reported on its own line
+    /// wherever it falls in its batch, with a truncated last row told apart from the rows before it
+    #[tokio::test]
+    async fn test_batched_rows() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     type,client,tx,amount\n\
+                     withdrawal,1,2,4.0\n\
+                     deposit,1,x,1.0\n\
+                     deposit,2,3,2.0\n\
+                     withdrawal,2,4,";
+        for batch_size in [0, 1, 2, 3, 4096] {
+            let mut engine = Engine::new(Config {
+                batch_size,
+                ..Config::default()
+            });
+            let err = run_err(&mut engine, &input).await;
+            assert_eq!(
+                err.to_string(),
+                "row on line 5 could not be read: tx `x` of deposit row could not be parsed into int: invalid digit found in string",
+                "{}",
+                batch_size
+            );
 
-        assert_eq!(account.total, Decimal::new(0, PRECISION));
-        assert_eq!(account.available, Decimal::new(0, PRECISION));
-        assert_eq!(account.held, Decimal::new(0, PRECISION));
+            let mut engine = Engine::new(Config {
+                batch_size,
+                skip_malformed_rows: true,
+                tolerate_truncated_tail: true,
+                ..Config::default()
+            });
+            let output = run(&mut engine, input).await;
+            assert_eq!(
+                output,
+                vec![
+                    "1,6.0000,0.0000,6.0000,false",
+                    "2,2.0000,0.0000,2.0000,false"
+                ],
+                "{}",
+                batch_size
+            );
+            assert_eq!(engine.records_processed, 5, "{}", batch_size);
+            assert_eq!(engine.repeated_headers, 1, "{}", batch_size);
+            assert_eq!(engine.malformed_rows_skipped, 1, "{}", batch_size);
+            assert_eq!(
+                engine.report().rejected_by_reason["truncated input"],
+                1,
+                "{}",
+                batch_size
+            );
+        }
     }
 
-    #[test]
-    fn test_faulty_withdrawal() {
-        let client_id = 1;
-        let mut account = Account::new(client_id);
-        let deposit = Transaction::_new(
-            Action::Deposit,
-            client_id,
-            1,
-            Some(Decimal::new(1, PRECISION)),
-            false,
+    /// Inputs sharing no client give the accounts and counts of their concatenation when read in parallel
+    #[tokio::test]
+    async fn test_parallel_files() {
+        //each input has clients and ids of its own, disputes name deposits of the same input
+        let inputs: Vec<String> = (0..4u64)
+            .map(|input| {
+                let mut rows = String::from("type,client,tx,amount\n");
+                let mut deposits: Vec<(u64, u64)> = Vec::new();
+                let mut seed = input + 11;
+                for row in 1..=1500u64 {
+                    seed = seed
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    let client = input * 10 + (seed >> 33) % 10;
+                    let tx = input * 100_000 + row;
+                    let (disputed, owner) = match deposits.len() {
+                        0 => (tx, client),
+                        len => deposits[(seed >> 17) as usize % len],
+                    };
+                    let line = match (seed >> 40) % 8 {
+                        0..=3 => {
+                            deposits.push((tx, client));
+                            format!("deposit,{},{},{}.5", client, tx, (seed >> 20) % 100)
+                        }
+                        4 => format!("withdrawal,{},{},{}.25", client, tx, (seed >> 20) % 50),
+                        5 => format!("dispute,{},{},", owner, disputed),
+                        6 => format!("resolve,{},{},", owner, disputed),
+                        _ => format!("chargeback,{},{},", owner, disputed),
+                    };
+                    rows.push_str(&line);
+                    rows.push('\n');
+                }
+                rows
+            })
+            .collect();
+        let readers = |inputs: &[String]| {
+            inputs
+                .iter()
+                .map(|input| Reader::from_reader(std::io::Cursor::new(input.clone().into_bytes())))
+                .collect::<Vec<_>>()
+        };
+        let config = Config {
+            extra_columns: true,
+            ..Config::default()
+        };
+        //a window prunes at positions of the whole input, which only changes why late rows are rejected
+        let windowed = Config {
+            dispute_window: Some(Window::Records(400)),
+            ..config.clone()
+        };
+        for config in [&config, &windowed] {
+            let mut single = Engine::new(config.clone());
+            let expected = run(&mut single, &inputs.concat()).await;
+            let mut engine = Engine::new(config.clone());
+            let mut writer = Writer::from_inner(Vec::new());
+            engine
+                .process_files(readers(&inputs), &mut writer)
+                .await
+                .unwrap();
+            let output = String::from_utf8(writer.into_inner()).unwrap();
+            let mut lines: Vec<String> = output.lines().skip(1).map(String::from).collect();
+            lines.sort();
+            assert_eq!(lines, expected);
+            if config.dispute_window.is_none() {
+                //the headers of the later inputs are not repeated in any input of their own
+                assert_eq!(
+                    engine.report(),
+                    RunReport {
+                        repeated_headers: 0,
+                        ..single.report()
+                    }
+                );
+            }
+        }
+
+        //a client in two inputs breaks the promise the run relies on
+        let mut overlapping = inputs[..2].to_vec();
+        overlapping.push(String::from(
+            "type,client,tx,amount\ndeposit,13,900000,1.0\n",
+        ));
+        let mut engine = Engine::new(config.clone());
+        let mut writer = Writer::from_inner(Vec::new());
+        let err = engine
+            .process_files(readers(&overlapping), &mut writer)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "client 13 is in inputs 2 and 3, --parallel-files needs inputs that share no client"
         );
-        account.handle_transaction(deposit).unwrap();
+        assert_eq!(err.severity(), Severity::Fatal);
 
-        let withdrawal = Transaction::_new(
-            Action::Withdrawal,
-            client_id,
-            2,
-            Some(Decimal::new(2, PRECISION)),
-            false,
+        let mut engine = Engine::new(Config {
+            require_halt: true,
+            ..Config::default()
+        });
+        let mut writer = Writer::from_inner(Vec::new());
+        assert!(matches!(
+            engine.process_files(readers(&inputs), &mut writer).await,
+            Err(CustomError::UnsupportedWithParallelFiles {
+                feature: "--require-halt"
+            })
+        ));
+    }
+
+    /// Inputs sharing clients and sending transfers both ways between them end like one input holding them all
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_shared_accounts() {
+        //every client deposits first in every input, and sends less than that, so no order of them runs short
+        //a sender disputes a transfer and resolves it again on the same worker
+        let inputs: Vec<String> = (0..4u64)
+            .map(|input| {
+                let mut rng = Rng::new(input + 31);
+                let mut rows = String::from("type,client,tx,amount,to\n");
+                let mut tx = input * 100_000;
+                for client in 1..=20 {
+                    tx += 1;
+                    rows.push_str(&format!("deposit,{},{},100.0,\n", client, tx));
+                }
+                for _ in 0..1500 {
+                    tx += 1;
+                    let sender = 1 + rng.below(20);
+                    let recipient = 1 + (sender + rng.below(19)) % 20;
+                    let amount = 1 + rng.below(9);
+                    rows.push_str(&format!(
+                        "transfer,{},{},0.{},{}\n",
+                        sender, tx, amount, recipient
+                    ));
+                    if rng.below(10) == 0 {
+                        rows.push_str(&format!("dispute,{},{},,\n", sender, tx));
+                        rows.push_str(&format!("resolve,{},{},,\n", sender, tx));
+                    }
+                }
+                rows
+            })
+            .collect();
+        let readers = |inputs: &[String]| {
+            inputs
+                .iter()
+                .map(|input| Reader::from_reader(std::io::Cursor::new(input.clone().into_bytes())))
+                .collect::<Vec<_>>()
+        };
+        let expected = run(&mut Engine::new(Config::default()), &inputs.concat()).await;
+        for workers in [1, 3, 8] {
+            let mut engine = Engine::new(Config {
+                shared_accounts: true,
+                workers,
+                ..Config::default()
+            });
+            let mut writer = Writer::from_inner(Vec::new());
+            engine
+                .process_files(readers(&inputs), &mut writer)
+                .await
+                .unwrap();
+            let output = String::from_utf8(writer.into_inner()).unwrap();
+            let mut lines: Vec<String> = output.lines().skip(1).map(String::from).collect();
+            lines.sort();
+            assert_eq!(lines, expected, "{} workers", workers);
+            assert_eq!(engine.report().rejected_by_reason, BTreeMap::new());
+        }
+
+        //the recipient owns the id of a transfer, which its sender may still dispute but no other client
+        let transfer = "type,client,tx,amount,to\n\
+                        deposit,1,1,5.0,\n\
+                        transfer,1,2,1.0,2\n\
+                        deposit,3,2,1.0,\n\
+                        dispute,3,2,,\n\
+                        dispute,1,2,,\n\
+                        resolve,2,2,,\n\
+                        transfer,2,1,1.0,1\n";
+        let mut single = Engine::new(Config::default());
+        let expected = run(&mut single, transfer).await;
+        let mut engine = Engine::new(Config {
+            shared_accounts: true,
+            workers: 2,
+            ..Config::default()
+        });
+        let mut writer = Writer::from_inner(Vec::new());
+        engine
+            .process_files(readers(&[transfer.to_string()]), &mut writer)
+            .await
+            .unwrap();
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        let mut lines: Vec<String> = output.lines().skip(1).map(String::from).collect();
+        lines.sort();
+        assert_eq!(lines, expected);
+        assert_eq!(
+            engine.report().rejected_by_reason,
+            single.report().rejected_by_reason
         );
+        assert_eq!(engine.report().rejected_by_reason.values().sum::<u64>(), 3);
 
-        if let Ok(()) = account.handle_transaction(withdrawal) {
-            //value should not change
-            assert_eq!(account.total, Decimal::new(1, PRECISION));
-            assert_eq!(account.available, Decimal::new(1, PRECISION));
-            assert_eq!(account.held, Decimal::new(0, PRECISION));
-            panic!()
+        let reused = [transfer.to_string()];
+        for (config, feature) in [
+            (
+                Config {
+                    accrue_interest: Some(Decimal::new(1, 2)),
+                    ..Config::default()
+                },
+                "--accrue-interest",
+            ),
+            (
+                Config {
+                    transfer_chargeback_refunds_sender: true,
+                    ..Config::default()
+                },
+                "--transfer-chargeback-refunds-sender",
+            ),
+        ] {
+            let mut engine = Engine::new(Config {
+                shared_accounts: true,
+                workers: 2,
+                ..config
+            });
+            let mut writer = Writer::from_inner(Vec::new());
+            assert_eq!(
+                engine
+                    .process_files(readers(&reused), &mut writer)
+                    .await
+                    .unwrap_err()
+                    .to_string(),
+                format!("{} cannot be used with --shared-accounts", feature)
+            );
         }
+        //interest is posted to every account, which no worker holds
+        let mut engine = Engine::new(Config {
+            shared_accounts: true,
+            workers: 2,
+            ..Config::default()
+        });
+        let mut writer = Writer::from_inner(Vec::new());
+        let interest = [String::from(
+            "type,client,tx,amount,to\ndeposit,1,1,5.0,\naccrue_interest,0,2,0.01,\n",
+        )];
+        assert!(matches!(
+            engine.process_files(readers(&interest), &mut writer).await,
+            Err(CustomError::UnsupportedWithSharedAccounts {
+                feature: "interest rows"
+            })
+        ));
     }
 
+    /// Workers locking pairs of accounts in either order never wait on each other for good
     #[test]
-    fn test_dispute() {
-        let client_id = 1;
-        let mut account = Account::new(client_id);
-        let deposit = Transaction::_new(
-            Action::Deposit,
-            client_id,
-            1,
-            Some(Decimal::new(1, PRECISION)),
-            false,
+    fn test_shared_accounts_lock_order() {
+        let accounts: SharedAccounts<i64> = SharedAccounts::new(4);
+        let ordered = accounts.ordered(&[7, 3, 7, 1], |_| 100);
+        assert_eq!(
+            ordered
+                .iter()
+                .map(|(client, _)| *client)
+                .collect::<Vec<_>>(),
+            vec![1, 3, 7]
         );
-        account.handle_transaction(deposit).unwrap();
+        drop(ordered);
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let accounts = accounts.clone();
+                scope.spawn(move || {
+                    let mut rng = Rng::new(thread);
+                    for _ in 0..20_000 {
+                        //each pair is named in both orders across the threads
+                        let from = rng.below(8) as ClientId;
+                        let to = rng.below(8) as ClientId;
+                        if from == to {
+                            continue;
+                        }
+                        let ordered = accounts.ordered(&[from, to], |_| 100);
+                        let mut locked = lock_all(&ordered);
+                        for (client, balance) in &mut locked {
+                            **balance += if *client == from { -1 } else { 1 };
+                        }
+                    }
+                });
+            }
+        });
+        let mut balances = accounts.take();
+        balances.sort();
+        assert_eq!(balances.len(), 8);
+        assert_eq!(
+            balances.iter().map(|(_, balance)| balance).sum::<i64>(),
+            800
+        );
+    }
 
-        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None, false);
+    /// Source giving a fixed number of bytes of its input, then failing like a killed run
+    struct FailingSource {
+        input: Vec<u8>,
+        position: usize,
+        remaining: usize,
+    }
 
-        account.handle_transaction(dispute).unwrap();
-        assert_eq!(account.total, Decimal::new(1, PRECISION));
-        assert_eq!(account.available, Decimal::new(0, PRECISION));
-        assert_eq!(account.held, Decimal::new(1, PRECISION));
-        assert_eq!(account.transactions.get(&1).unwrap().is_under_dispute, true)
+    impl AsyncRead for FailingSource {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if self.remaining == 0 {
+                return std::task::Poll::Ready(Err(std::io::Error::other("killed")));
+            }
+            let end = self
+                .input
+                .len()
+                .min(self.position + self.remaining.min(buf.remaining()));
+            let read = end - self.position;
+            buf.put_slice(&self.input[self.position..end]);
+            self.position = end;
+            self.remaining -= read;
+            std::task::Poll::Ready(Ok(()))
+        }
     }
 
-    #[test]
-    fn test_faulty_dispute() {
-        let client_id = 1;
-        let mut account = Account::new(client_id);
-        let deposit = Transaction::_new(
-            Action::Deposit,
-            client_id,
-            1,
-            Some(Decimal::new(1, PRECISION)),
-            false,
-        );
-        account.handle_transaction(deposit).unwrap();
+    #[tokio::test]
+    async fn test_checkpoints() {
+        let mut input = String::from("type,client,tx,amount\n");
+        let mut seed = 5u64;
+        for tx in 1..=3000u64 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let client = (seed >> 33) % 40;
+            let disputed = 1 + (seed >> 17) % tx;
+            let line = match (seed >> 40) % 10 {
+                0..=4 => format!("deposit,{},{},{}.5", client, tx, (seed >> 20) % 100),
+                5 => format!("withdrawal,{},{},{}.25", client, tx, (seed >> 20) % 50),
+                6 => format!("dispute,{},{},", client, disputed),
+                7 => format!("resolve,{},{},", client, disputed),
+                8 => format!("chargeback,{},{},", client, disputed),
+                _ => format!("refund,{},{},1.0", client, tx),
+            };
+            input.push_str(&line);
+            input.push('\n');
+        }
+        let dir = std::env::temp_dir().join("test_checkpoints");
+        let config = Config {
+            dispute_window: Some(Window::Records(500)),
+            unknown_action: UnknownAction::Skip,
+            checkpoint_every: Some(250),
+            checkpoint_dir: Some(dir.clone()),
+            checkpoint_keep: 2,
+            //batches end on checkpoints, 64 does not divide 250
+            batch_size: 64,
+            ..Config::default()
+        };
+        let mut uninterrupted = Engine::new(config.clone());
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let expected = run(&mut uninterrupted, &input).await;
+        assert_eq!(checkpoint::list(&dir).unwrap().len(), 2);
 
-        let dispute = Transaction::_new(Action::Dispute, client_id, 2, None, false);
+        //killed at several offsets, within a row and past the last checkpoint
+        for killed_at in [9_000, 17_777, 30_001, input.len() - 10] {
+            std::fs::remove_dir_all(&dir).unwrap();
+            std::fs::create_dir_all(&dir).unwrap();
+            let mut killed = Engine::new(config.clone());
+            let mut reader = Reader::from_reader(FailingSource {
+                input: input.clone().into_bytes(),
+                position: 0,
+                remaining: killed_at,
+            });
+            let mut writer = Writer::from_inner(Vec::new());
+            assert!(killed.process(&mut reader, &mut writer).await.is_err());
+            assert!(writer.into_inner().is_empty());
 
-        if let Ok(()) = account.handle_transaction(dispute) {
-            panic!()
+            //with --sync, taking no checkpoint of its own so the run after it resumes from the same one
+            let mut resumed = Engine::new(Config {
+                resume_from: Some(dir.clone()),
+                checkpoint_every: None,
+                ..config.clone()
+            });
+            let mut reader = BlockingReader::from_reader(std::io::Cursor::new(input.as_bytes()));
+            assert!(resumed.resume_blocking(&mut reader).unwrap() > 0);
+            let mut writer = Writer::from_inner(Vec::new());
+            resumed.process_blocking(&mut reader, &mut writer).unwrap();
+            let output = String::from_utf8(writer.into_inner()).unwrap();
+            let mut lines: Vec<String> = output.lines().skip(1).map(String::from).collect();
+            lines.sort();
+            assert_eq!(lines, expected, "killed at byte {} with --sync", killed_at);
+
+            let mut resumed = Engine::new(Config {
+                resume_from: Some(dir.clone()),
+                ..config.clone()
+            });
+            let mut reader = Reader::from_reader(std::io::Cursor::new(input.clone().into_bytes()));
+            assert!(resumed.resume(&mut reader).await.unwrap() > 0);
+            let mut writer = Writer::from_inner(Vec::new());
+            resumed.process(&mut reader, &mut writer).await.unwrap();
+            let output = String::from_utf8(writer.into_inner()).unwrap();
+            let mut lines: Vec<String> = output.lines().skip(1).map(String::from).collect();
+            lines.sort();
+            assert_eq!(lines, expected, "killed at byte {}", killed_at);
+            assert_eq!(counts(resumed.report()), counts(uninterrupted.report()));
         }
-        assert_eq!(account.total, Decimal::new(1, PRECISION));
-        assert_eq!(account.available, Decimal::new(1, PRECISION));
-        assert_eq!(account.held, Decimal::new(0, PRECISION));
+
+        //a damaged newest checkpoint falls back to the one before it
+        let newest = checkpoint::list(&dir).unwrap()[0].clone();
+        let mut bytes = std::fs::read(&newest).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&newest, bytes).unwrap();
+        let mut resumed = Engine::new(Config {
+            resume_from: Some(dir.clone()),
+            ..config.clone()
+        });
+        let mut reader = Reader::from_reader(std::io::Cursor::new(input.clone().into_bytes()));
+        assert_eq!(resumed.resume(&mut reader).await.unwrap(), 2750);
+        let mut writer = Writer::from_inner(Vec::new());
+        resumed.process(&mut reader, &mut writer).await.unwrap();
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        let mut lines: Vec<String> = output.lines().skip(1).map(String::from).collect();
+        lines.sort();
+        assert_eq!(lines, expected);
+
+        //nothing to resume from
+        let empty = std::env::temp_dir().join("test_checkpoints_empty");
+        let _ = std::fs::remove_dir_all(&empty);
+        std::fs::create_dir_all(&empty).unwrap();
+        let mut resumed = Engine::new(Config {
+            resume_from: Some(empty.clone()),
+            ..Config::default()
+        });
+        let mut reader = Reader::from_reader(std::io::Cursor::new(input.clone().into_bytes()));
+        let err = resumed.resume(&mut reader).await.unwrap_err();
+        assert!(matches!(err, CustomError::NoCheckpoint { .. }));
+        assert_eq!(err.severity(), Severity::Fatal);
+
+        //a spill file is not in the checkpoint
+        let mut engine = Engine::new(Config {
+            spill_dir: Some(empty.clone()),
+            ..config.clone()
+        });
+        let mut reader = Reader::from_reader(input.as_bytes());
+        let mut writer = Writer::from_inner(Vec::new());
+        assert!(matches!(
+            engine.process(&mut reader, &mut writer).await,
+            Err(CustomError::UnsupportedWithCheckpoints {
+                feature: "--spill-dir"
+            })
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&empty).unwrap();
     }
 
+    /// Rejection counts read back under their reason, a reason no error gives is a damaged checkpoint
     #[test]
-    fn test_resolve() {
-        let client_id = 1;
-        let mut account = Account::new(client_id);
-        let deposit = Transaction::_new(
-            Action::Deposit,
-            client_id,
-            1,
-            Some(Decimal::new(1, PRECISION)),
-            false,
+    fn test_checkpoint_reasons() {
+        let mut engine = Engine::new(Config::default());
+        engine.rejections.insert("insufficient funds", 2);
+        engine.rejections.insert("unknown tx on dispute", 1);
+        let progress = Progress {
+            record_index: 0,
+            latest_timestamp: None,
+        };
+        let state = engine.encode_state([0; 3], progress);
+        let mut resumed = Engine::new(Config::default());
+        resumed.decode_state(&state).unwrap();
+        assert_eq!(resumed.rejections, engine.rejections);
+
+        //same length, so only the name is off
+        let at = state
+            .windows(b"insufficient funds".len())
+            .position(|window| window == b"insufficient funds")
+            .unwrap();
+        let mut damaged = state.clone();
+        damaged[at..at + b"insufficient funds".len()].copy_from_slice(b"insufficient fundz");
+        let mut resumed = Engine::new(Config::default());
+        assert!(matches!(
+            resumed.decode_state(&damaged),
+            Err(CustomError::Checkpoint { .. })
+        ));
+    }
+
+    /// Capacity hints reserve room up front without changing results, however large they are
+    #[tokio::test]
+    async fn test_capacity_hints() {
+        let input = String::from_utf8(workload(Scenario::ManyClients, 5_000, 3)).unwrap();
+        let expected = run(&mut Engine::new(Config::default()), &input).await;
+
+        let mut engine = Engine::new(Config {
+            expected_clients: Some(100),
+            expected_transactions_per_client: Some(10),
+            ..Config::default()
+        });
+        assert_eq!(run(&mut engine, &input).await, expected);
+        let reserved = engine
+            .clients
+            .values()
+            .filter(|account| account.transactions.capacity() >= 10)
+            .count();
+        assert!(reserved >= 100);
+
+        //absurd hints are held to what may be reserved
+        let mut engine = Engine::new(Config {
+            expected_clients: Some(usize::MAX),
+            expected_transactions_per_client: Some(usize::MAX),
+            ..Config::default()
+        });
+        assert_eq!(run(&mut engine, &input).await, expected);
+        assert!(engine.clients.capacity() < 2 * MAX_CLIENTS);
+        let stored: usize = engine
+            .clients
+            .values()
+            .map(|account| account.transactions.capacity())
+            .sum();
+        assert!(stored as u64 * STORED_BYTES <= 2 * RESERVED_BYTES);
+
+        //or to the memory limit when there is one
+        let mut engine = Engine::new(Config {
+            expected_transactions_per_client: Some(usize::MAX),
+            max_memory: 8 << 20,
+            ..Config::default()
+        });
+        assert_eq!(run(&mut engine, &input).await, expected);
+        //the other accounts grew their tables for what they store
+        let reserved = engine
+            .clients
+            .values()
+            .map(|account| account.transactions.capacity())
+            .max()
+            .unwrap();
+        assert!(reserved as u64 * STORED_BYTES <= 4 << 20);
+    }
+
+    /// Source repeating a row without end, counting the bytes it gave
+    struct EndlessSource {
+        row: &'static [u8],
+        given: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl AsyncRead for EndlessSource {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            while buf.remaining() >= self.row.len() {
+                buf.put_slice(self.row);
+                self.given
+                    .fetch_add(self.row.len(), std::sync::atomic::Ordering::Relaxed);
+            }
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Runs a pipelined engine over an in-memory csv and returns the output rows sorted by client id
+    async fn run_pipelined(engine: &mut Engine, input: &str) -> Result<Vec<String>, CustomError> {
+        let reader = Reader::from_reader(std::io::Cursor::new(input.as_bytes().to_vec()));
+        let mut writer = Writer::from_inner(Vec::new());
+        engine.process_pipelined(reader, &mut writer).await?;
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        let mut lines: Vec<String> = output.lines().skip(1).map(String::from).collect();
+        lines.sort();
+        Ok(lines)
+    }
+
+    /// Rows parsed on a task of their own give the same accounts, counts and errors as rows read in turn
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_pipeline() {
+        let mut input = String::from_utf8(workload(Scenario::Disputes, 3_000, 5)).unwrap();
+        input.push_str("type,client,tx,amount\nrefund,3,900001,1.0\ndeposit,3,900002,x\n");
+        input.push_str("deposit,4,900003,2.0\nhalt,,,\ndeposit,4,900004,2.0\ndeposit,4,900005,1.");
+        for config in [
+            Config {
+                skip_malformed_rows: true,
+                unknown_action: UnknownAction::Skip,
+                tolerate_truncated_tail: true,
+                pipeline: Some(1),
+                ..Config::default()
+            },
+            Config {
+                skip_malformed_rows: true,
+                unknown_action: UnknownAction::Skip,
+                tolerate_truncated_tail: true,
+                dispute_window: Some(Window::Records(500)),
+                workers: 2,
+                pipeline: Some(4),
+                ..Config::default()
+            },
+        ] {
+            let mut direct = Engine::new(config.clone());
+            let expected = run(&mut direct, &input).await;
+            let mut pipelined = Engine::new(config);
+            assert_eq!(
+                run_pipelined(&mut pipelined, &input).await.unwrap(),
+                expected
+            );
+            assert_eq!(pipelined.report(), direct.report());
+        }
+
+        //a row the reader could not parse stops the run as it does without a pipeline
+        let config = Config {
+            unknown_action: UnknownAction::Skip,
+            pipeline: Some(1),
+            ..Config::default()
+        };
+        let err = run_pipelined(&mut Engine::new(config.clone()), &input)
+            .await
+            .unwrap_err();
+        let mut reader = Reader::from_reader(input.as_bytes());
+        let mut writer = Writer::from_inner(Vec::new());
+        let direct = Engine::new(config.clone())
+            .process(&mut reader, &mut writer)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), direct.to_string());
+        assert!(err
+            .to_string()
+            .starts_with("row on line 3004 could not be read"));
+        assert_eq!(err.severity(), Severity::Fatal);
+
+        //the reader stops with the engine, having read no more than the batches allowed to wait
+        let given = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let reader = Reader::from_reader(tokio::io::AsyncReadExt::chain(
+            &b"type,client,tx,amount\ndeposit,1,1,x\n"[..],
+            EndlessSource {
+                row: b"deposit,2,2,1.0\n",
+                given: given.clone(),
+            },
+        ));
+        let mut engine = Engine::new(config);
+        let mut writer = Writer::from_inner(Vec::new());
+        assert!(matches!(
+            engine.process_pipelined(reader, &mut writer).await,
+            Err(CustomError::MalformedRow { line: 2, .. })
+        ));
+        //a batch waiting, one taken by the engine and one being filled, past the 8KB buffer of csv
+        let rows = given.load(std::sync::atomic::Ordering::Relaxed) / 16;
+        assert!(
+            rows <= 3 * PIPELINE_BATCH + 8 * 1024 / 16,
+            "{} rows were read ahead",
+            rows
         );
-        account.handle_transaction(deposit).unwrap();
 
-        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None, false);
+        let mut engine = Engine::new(Config {
+            checkpoint_every: Some(10),
+            checkpoint_dir: Some(std::env::temp_dir()),
+            pipeline: Some(1),
+            ..Config::default()
+        });
+        assert!(matches!(
+            run_pipelined(&mut engine, &input).await,
+            Err(CustomError::UnsupportedWithCheckpoints {
+                feature: "--pipeline"
+            })
+        ));
+    }
+
+    /// The memory estimate stays close to what the accounts allocate, and --max-memory stops the run on it
+    #[tokio::test]
+    async fn test_memory_estimate() {
+        for scenario in Scenario::ALL {
+            let input = workload(scenario, 20_000, 9);
+            let columns = Columns::default();
+            let mut reader = Reader::from_reader(&input[..]);
+            let mut record = ByteRecord::new();
+            let mut transactions = Vec::new();
+            while reader
+                .get_inner()
+                .read_byte_record(&mut record)
+                .await
+                .unwrap()
+            {
+                let fields = decode(&record, &columns, 0, false).unwrap();
+                transactions.push(Transaction::from_record(&fields, &columns, false).unwrap());
+            }
+            //only what the engine allocates while applying is counted
+            let before = LIVE_BYTES.with(Cell::get);
+            let mut engine = Engine::new(Config::default());
+            for (index, mut transaction) in transactions.drain(..).enumerate() {
+                transaction.record_index = index as u64 + 1;
+                engine.open_accounts(&transaction);
+                engine.apply(transaction).unwrap();
+            }
+            let allocated = (LIVE_BYTES.with(Cell::get) - before) as f64;
+            let estimate = engine.memory_estimate() as f64;
+            assert!(
+                (estimate / allocated - 1.0).abs() < 0.2,
+                "{}: estimated {} bytes, allocated {}",
+                scenario.as_str(),
+                estimate,
+                allocated
+            );
+        }
 
-        account.handle_transaction(dispute).unwrap();
+        let input = String::from_utf8(workload(Scenario::Deposits, 5_000, 9)).unwrap();
+        //the peak is reported with or without a limit, and a progress line tells the estimate on the way
+        let mut engine = Engine::new(Config::default());
+        run(&mut engine, &input).await;
+        let unlimited = engine.report().peak_memory;
+        assert!(unlimited > 100_000);
+        assert!(engine
+            .progress_line(5_000)
+            .starts_with("progress: 5000 records, 5000 applied, 0 rejected, memory "));
+        let mut engine = Engine::new(Config {
+            max_memory: 1 << 40,
+            ..Config::default()
+        });
+        run(&mut engine, &input).await;
+        let report = engine.report();
+        assert_eq!(report.peak_memory, unlimited);
+        assert!(report.to_string().contains(&format!(
+            "peak memory: {:.1} MB (estimated)",
+            report.peak_memory as f64 / 1e6
+        )));
 
-        let resolve = Transaction::_new(Action::Resolve, client_id, 1, None, false);
+        //half of what the run takes is not enough
+        let mut engine = Engine::new(Config {
+            max_memory: report.peak_memory / 2,
+            ..Config::default()
+        });
+        let mut reader = Reader::from_reader(input.as_bytes());
+        let mut writer = Writer::from_inner(Vec::new());
+        let err = engine.process(&mut reader, &mut writer).await.unwrap_err();
+        assert!(matches!(err, CustomError::MemoryLimit { .. }), "{}", err);
+        assert_eq!(err.severity(), Severity::Fatal);
+        assert!(writer.into_inner().is_empty());
 
-        account.handle_transaction(resolve).unwrap();
+        //with a spill directory the oldest transactions move to disk instead
+        let spill_dir = test_dir("test_memory_estimate_spill");
+        let mut engine = Engine::new(Config {
+            max_memory: 1000 * STORED_BYTES,
+            spill_dir: Some(spill_dir.clone()),
+            ..Config::default()
+        });
+        run(&mut engine, &input).await;
+        assert!(engine.report().spilled > 0);
+        std::fs::remove_dir_all(&spill_dir).unwrap();
+    }
 
-        assert_eq!(account.total, Decimal::new(1, PRECISION));
-        assert_eq!(account.available, Decimal::new(1, PRECISION));
-        assert_eq!(account.held, Decimal::new(0, PRECISION));
-        assert_eq!(
-            account.transactions.get(&1).unwrap().is_under_dispute,
-            false
-        )
+    /// Every amount read straight from the bytes is what from_str reads, scale and sign included, and every plain
+    /// amount from_str reads without rounding is read from the bytes, over random strings of the characters
+    /// amounts are made of
+    #[test]
+    fn test_byte_parsing_matches_from_str() {
+        let mut rng = crate::generate::Rng::new(195);
+        let alphabet = b"0123456789.+-e_ ";
+        let mut fast = 0;
+        for case in 0..1_000_000 {
+            //half are digits and points behind an optional sign, so many are valid amounts of every length
+            let (sign, characters) = match case % 6 {
+                0 => ("-", 11),
+                2 => ("+", 11),
+                4 => ("", 11),
+                _ => ("", alphabet.len() as u64),
+            };
+            let len = rng.below(40) as usize;
+            let amount: String = std::iter::once(sign)
+                .chain((0..len).map(|_| {
+                    let pick = rng.below(characters) as usize;
+                    std::str::from_utf8(&alphabet[pick..=pick]).unwrap()
+                }))
+                .collect();
+            let expected = Decimal::from_str(&amount);
+            match parse_plain_decimal(amount.as_bytes()) {
+                Some(parsed) => {
+                    fast += 1;
+                    assert_eq!(
+                        Ok(parsed.serialize()),
+                        expected.map(|decimal| decimal.serialize()),
+                        "{}",
+                        amount
+                    );
+                }
+                None => {
+                    //only what from_str refuses or rounds is left to it
+                    let digits = amount.trim_start_matches(['+', '-']).replace('.', "");
+                    if let Ok(expected) = expected {
+                        assert!(
+                            amount.len() - amount.find('.').map_or(amount.len(), |point| point + 1)
+                                > 28
+                                || digits.trim_start_matches('0').len() >= 29
+                                || amount.contains(['e', '_']),
+                            "{} parses to {} but not from the bytes",
+                            amount,
+                            expected
+                        );
+                    }
+                }
+            }
+        }
+        assert!(fast > 200_000, "only {} read from the bytes", fast);
     }
 
+    /// Counters follow re-disputes and partial disputes exactly
     #[test]
-    fn test_chargeback() {
+    fn test_dispute_stats() {
         let client_id = 1;
         let mut account = Account::new(client_id);
-        let deposit = Transaction::_new(
-            Action::Deposit,
-            client_id,
-            1,
-            Some(Decimal::new(1, PRECISION)),
-            false,
+        for i in 1..3 {
+            let deposit = Transaction::_new(
+                Action::Deposit,
+                client_id,
+                i,
+                Some(Decimal::new(10, PRECISION)),
+            );
+            account
+                .handle_transaction(deposit, &Config::default())
+                .unwrap();
+        }
+        let partial = Some(Decimal::new(4, PRECISION));
+        let script = [
+            (Action::Dispute, 1, None),
+            (Action::Resolve, 1, None),
+            (Action::Dispute, 1, partial),
+            (Action::Dispute, 1, None),
+            (Action::Chargeback, 1, None),
+            (Action::Dispute, 2, partial),
+            (Action::Resolve, 2, None),
+            (Action::Resolve, 2, None),
+        ];
+        for (action, transaction_id, amount) in script {
+            let transaction = Transaction::_new(action, client_id, transaction_id, amount);
+            //the second dispute of tx 1 and the second resolve of tx 2 are rejected
+            let _ = account.handle_transaction(transaction, &Config::default());
+        }
+        assert_eq!(account.dispute_stats.opened, 3);
+        assert_eq!(account.dispute_stats.resolved, 2);
+        assert_eq!(account.chargebacks.len(), 1);
+        assert_eq!(
+            account.dispute_stats.disputed_amount,
+            Decimal::new(18, PRECISION)
         );
-        account.handle_transaction(deposit).unwrap();
+    }
 
-        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None, false);
+    /// Input with quoted line breaks, quotes and commas in memos, carriage returns, blank lines, a repeated header
+    /// and rows that cannot be read, where parts of a few bytes often start inside quotes
+    fn quoted_input() -> String {
+        let mut input = String::from("type,client,tx,amount,memo\n");
+        for tx in 1..=300 {
+            let memo = match tx % 6 {
+                0 => String::from("\"two\nlines\""),
+                1 => String::from("\"crlf\r\ninside, with \"\"quotes\"\"\""),
+                2 => String::from("\"\n\n\""),
+                3 => String::from("plain"),
+                4 => String::from("\"\r\n,\"\"\n\""),
+                _ => String::new(),
+            };
+            let end = if tx % 4 == 0 { "\r\n" } else { "\n" };
+            let row = match tx % 50 {
+                17 => format!("deposit,1,x,1.0,{}{}", memo, end),
+                33 => format!("\ntype,client,tx,amount,memo{}", end),
+                41 => format!("dispute,{},{},,{}{}", tx % 7, tx - 1, memo, end),
+                _ => format!("deposit,{},{},{}.5,{}{}", tx % 7, tx, tx, memo, end),
+            };
+            input.push_str(&row);
+        }
+        input.push_str("deposit,1,1000,1.0,\"cut off");
+        input
+    }
 
-        account.handle_transaction(dispute).unwrap();
+    /// Rows of the input file as read in order, each as text telling all that was read of it
+    /// The csv crate of --sync and --parse-threads must read them as csv_async does
+    fn read_rows_of(path: &Path) -> Vec<String> {
+        let input = std::fs::read(path).unwrap();
+        let read = futures::executor::block_on(async {
+            let mut reader = Reader::from_reader(input.as_slice());
+            let headers = reader.get_inner().headers().await.unwrap().clone();
+            let mut rows = row_reader(headers);
+            let mut read = Vec::new();
+            while let Some(row) = rows.next(&mut reader).await {
+                read.push(describe(&row));
+            }
+            read
+        });
+        let mut reader = BlockingReader::open(path).unwrap();
+        let mut rows = row_reader(reader.headers().unwrap());
+        let mut blocking = Vec::new();
+        while let Some(row) = rows.next_blocking(&mut reader) {
+            blocking.push(describe(&row));
+        }
+        assert_eq!(blocking, read);
+        read
+    }
 
-        let chargeback = Transaction::_new(Action::Chargeback, client_id, 1, None, false);
+    /// Rows of the input file as --parse-threads reads them in parts of about part bytes, with how many parts
+    /// were parsed again
+    fn split_rows_of(path: &Path, part: u64, threads: usize) -> (Vec<String>, usize) {
+        let mut reader = BlockingReader::open(path).unwrap();
+        let rows = row_reader(reader.headers().unwrap());
+        let split = Split {
+            path: path.to_path_buf(),
+            points: split_points(
+                &mut std::fs::File::open(path).unwrap(),
+                reader.position().byte(),
+                part,
+            )
+            .unwrap(),
+            slack: part,
+            start: reader.position(),
+            rows,
+        };
+        let (sender, mut receiver) = mpsc::channel(split.points.len() + 1);
+        let parsed_again = std::thread::scope(|scope| {
+            scope
+                .spawn(|| split.send_all(threads, sender))
+                .join()
+                .unwrap()
+                .unwrap()
+        });
+        let mut read = Vec::new();
+        while let Ok(rows) = receiver.try_recv() {
+            read.extend(rows.iter().map(describe));
+        }
+        (read, parsed_again)
+    }
 
-        account.handle_transaction(chargeback).unwrap();
+    fn row_reader(headers: StringRecord) -> RowReader {
+        RowReader {
+            columns: Columns::from_headers(&headers),
+            headers,
+            record: ByteRecord::new(),
+            record_index: 0,
+            skip_malformed_rows: false,
+            allow_scientific: false,
+        }
+    }
 
-        assert_eq!(account.total, Decimal::new(0, PRECISION));
-        assert_eq!(account.available, Decimal::new(0, PRECISION));
-        assert_eq!(account.held, Decimal::new(0, PRECISION));
-        assert_eq!(
-            account.transactions.get(&1).unwrap().is_under_dispute,
-            false
-        );
-        assert_eq!(account.is_locked, true);
+    fn describe(row: &ReadRow) -> String {
+        match row {
+            ReadRow::Header { line } => format!("header on line {}", line),
+            ReadRow::Record(row) => format!(
+                "line {} byte {} truncated {}: {} => {:?}",
+                row.line, row.offset, row.is_truncated, row.raw, row.parsed
+            ),
+        }
     }
 
+    /// Parts split at line breaks inside quotes are parsed again from where the part before ends, so every row
+    /// reads as it does in order, its line, offset and record included
     #[test]
-    fn test_locked_account() {
-        let client_id = 1;
-        let mut account = Account::new(client_id);
-        let deposit = Transaction::_new(
-            Action::Deposit,
-            client_id,
-            1,
-            Some(Decimal::new(1, PRECISION)),
-            false,
-        );
-        account.handle_transaction(deposit).unwrap();
+    fn test_split_parts() {
+        let path = std::env::temp_dir().join("test_split_parts.csv");
+        let input = quoted_input();
+        std::fs::write(&path, &input).unwrap();
+        let expected = read_rows_of(&path);
+        assert_eq!(expected.len(), 301);
+        let mut parsed_again = 0;
+        for part in [1, 7, 16, 50, 333, input.len() as u64] {
+            for threads in [1, 3] {
+                let (rows, again) = split_rows_of(&path, part, threads);
+                assert_eq!(
+                    rows, expected,
+                    "parts of {} bytes on {} threads",
+                    part, threads
+                );
+                parsed_again += again;
+            }
+        }
+        assert!(parsed_again > 0);
 
-        let dispute = Transaction::_new(Action::Dispute, client_id, 1, None, false);
+        //a file without line feeds is a single part
+        std::fs::write(&path, input.replace('\n', "\r")).unwrap();
+        let expected = read_rows_of(&path);
+        let (rows, again) = split_rows_of(&path, 16, 3);
+        assert_eq!(rows, expected);
+        assert_eq!(again, 0);
+        std::fs::remove_file(&path).unwrap();
+    }
 
-        account.handle_transaction(dispute).unwrap();
+    /// Runs an engine over the file in parts of about part bytes and returns the output rows sorted by client id
+    async fn run_split(
+        engine: &mut Engine,
+        path: &Path,
+        part: u64,
+    ) -> Result<Vec<String>, CustomError> {
+        let mut writer = Writer::from_inner(Vec::new());
+        engine.has_processed = true;
+        let result = engine.read_split(path, part).await;
+        engine.finish(result, &mut writer).await?;
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        let mut lines: Vec<String> = output.lines().skip(1).map(String::from).collect();
+        lines.sort();
+        Ok(lines)
+    }
 
-        let chargeback = Transaction::_new(Action::Chargeback, client_id, 1, None, false);
+    /// Rows parsed in parts on threads give the same accounts, counts and errors as rows read in turn
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_parse_threads() {
+        let path = std::env::temp_dir().join("test_parse_threads.csv");
+        for scenario in Scenario::ALL {
+            let input = workload(scenario, 10_000, 11);
+            std::fs::write(&path, &input).unwrap();
+            let mut single = Engine::new(Config::default());
+            let expected = run(&mut single, std::str::from_utf8(&input).unwrap()).await;
+            for (parse_threads, workers, part) in [(2, 1, 4096), (3, 1, PARSE_PART), (3, 2, 1000)] {
+                let mut split = Engine::new(Config {
+                    parse_threads,
+                    workers,
+                    ..Config::default()
+                });
+                assert_eq!(
+                    run_split(&mut split, &path, part).await.unwrap(),
+                    expected,
+                    "{}",
+                    scenario.as_str()
+                );
+                assert_eq!(split.report(), single.report(), "{}", scenario.as_str());
+            }
+        }
 
-        account.handle_transaction(chargeback).unwrap();
+        //rows that cannot be read are skipped or stop the run on the lines they do in order
+        let input = quoted_input();
+        std::fs::write(&path, &input).unwrap();
+        for skip_malformed_rows in [true, false] {
+            let config = Config {
+                skip_malformed_rows,
+                tolerate_truncated_tail: true,
+                ..Config::default()
+            };
+            let mut single = Engine::new(config.clone());
+            let mut reader = Reader::from_reader(input.as_bytes());
+            let mut writer = Writer::from_inner(Vec::new());
+            let expected = single.process(&mut reader, &mut writer).await;
+            let mut split = Engine::new(Config {
+                parse_threads: 3,
+                ..config
+            });
+            let result = run_split(&mut split, &path, 7).await;
+            match (expected, result) {
+                (Ok(()), Ok(_)) => assert_eq!(split.report(), single.report()),
+                (Err(expected), Err(err)) => assert_eq!(err.to_string(), expected.to_string()),
+                (expected, result) => panic!("{:?} read in order, {:?} in parts", expected, result),
+            }
+        }
 
-        let deposit2 = Transaction::_new(
-            Action::Deposit,
-            client_id,
-            2,
-            Some(Decimal::new(1, PRECISION)),
-            false,
-        );
-        if let Ok(()) = account.handle_transaction(deposit2) {
-            panic!()
+        //the rows applied are behind those parsed, and compressed or unseekable inputs have no parts
+        std::fs::write(&path, b"\x1f\x8b\x08\x00\x00\x00\x00\x00").unwrap();
+        for (config, path, expected) in [
+            (
+                Config {
+                    checkpoint_every: Some(10),
+                    ..Config::default()
+                },
+                path.as_path(),
+                CustomError::UnsupportedWithCheckpoints {
+                    feature: "--parse-threads",
+                },
+            ),
+            (
+                Config {
+                    pipeline: Some(4),
+                    ..Config::default()
+                },
+                path.as_path(),
+                CustomError::UnsupportedWithParseThreads {
+                    feature: "--pipeline",
+                },
+            ),
+            (
+                Config::default(),
+                path.as_path(),
+                CustomError::UnsplittableInput {
+                    input: String::from("a gzip compressed input"),
+                },
+            ),
+            (
+                Config::default(),
+                std::env::temp_dir().as_path(),
+                CustomError::UnsplittableInput {
+                    input: String::from("an input it cannot seek in"),
+                },
+            ),
+        ] {
+            let mut engine = Engine::new(Config {
+                parse_threads: 2,
+                ..config
+            });
+            let err = run_split(&mut engine, path, PARSE_PART).await.unwrap_err();
+            assert_eq!(err.to_string(), expected.to_string());
         }
-        assert_eq!(account.total, Decimal::new(0, PRECISION));
-        assert_eq!(account.available, Decimal::new(0, PRECISION));
-        assert_eq!(account.held, Decimal::new(0, PRECISION));
+        std::fs::remove_file(&path).unwrap();
+
+        let mut engine = Engine::new(Config {
+            parse_threads: 2,
+            ..Config::default()
+        });
+        let mut writer = Writer::from_inner(Vec::new());
+        let err = engine
+            .process_files(Vec::<Reader<&[u8]>>::new(), &mut writer)
+            .await
+            .unwrap_err();
         assert_eq!(
-            account.transactions.get(&1).unwrap().is_under_dispute,
-            false
+            err.to_string(),
+            CustomError::UnsupportedWithParallelFiles {
+                feature: "--parse-threads"
+            }
+            .to_string()
         );
-        assert_eq!(account.is_locked, true);
     }
 }
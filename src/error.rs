@@ -1,31 +1,566 @@
-use std::num::ParseIntError;
+use std::{fmt, num::ParseIntError};
 
+use rust_decimal::Decimal;
 use thiserror::Error;
 use tokio::io;
 
+use crate::engine::{ClientId, TransactionId};
+
+/// Transaction an id was first applied as, reported when a later row reuses the id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginalTransaction {
+    pub(crate) action: &'static str,
+    pub(crate) client: ClientId,
+    pub(crate) amount: Option<Decimal>,
+    /// Position of the row that applied it
+    pub(crate) record_index: u64,
+}
+
+impl fmt::Display for OriginalTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "first applied as {}", self.action)?;
+        if let Some(amount) = self.amount {
+            write!(f, " of {}", amount)?;
+        }
+        write!(
+            f,
+            " by client {} at record {}",
+            self.client, self.record_index
+        )
+    }
+}
+
 #[derive(Error, Debug)]
-pub(crate) enum CustomError {
+pub enum CustomError {
     ///Following errors are not okay to happen, and should stop the engine since this means input file is corrupted
-    #[error("Undefined Action")]
-    UndefinedAction,
+    #[error("Undefined Action {action}")]
+    UndefinedAction { action: String },
     #[error("string could not be parsed into decimal")]
     DecimalParseError(#[from] rust_decimal::Error),
     #[error("string could not be parsed into int")]
     IntParseError(#[from] ParseIntError),
     #[error("file could not be opened")]
     FileOpenError(#[from] io::Error),
+    #[error("csv could not be read")]
+    CsvError(#[from] csv_async::Error),
+    ///Read by the csv crate with --sync
+    #[error("csv could not be read")]
+    BlockingCsvError(#[from] csv::Error),
+    ///Parse errors name the column, its raw value and the type of the row once it is known,
+    ///the line is added by MalformedRow
+    #[error("{column} `{value}`{} could not be parsed into decimal: {source}", of_row(.action))]
+    InvalidDecimal {
+        column: &'static str,
+        value: String,
+        action: Option<&'static str>,
+        source: rust_decimal::Error,
+    },
+    #[error("{column} `{value}`{} uses exponent notation, which is not accepted", of_row(.action))]
+    ExponentNotation {
+        column: &'static str,
+        value: String,
+        action: Option<&'static str>,
+    },
+    #[error("{column} `{value}`{} is out of range, it must be between 0 and {max}", of_row(.action))]
+    IntOutOfRange {
+        column: &'static str,
+        value: String,
+        action: Option<&'static str>,
+        max: u64,
+    },
+    #[error("{column} `{value}`{} could not be parsed into int: {source}", of_row(.action))]
+    InvalidInt {
+        column: &'static str,
+        value: String,
+        action: Option<&'static str>,
+        source: ParseIntError,
+    },
+    #[error("invalid UTF-8 in field {field} of record {record}, near byte {offset}")]
+    InvalidUtf8 {
+        field: usize,
+        record: u64,
+        offset: u64,
+    },
+    #[error(
+        "missing {column}{}",
+        .action.map(|action| format!(" on {} row", action)).unwrap_or_else(|| String::from(" column"))
+    )]
+    MissingField {
+        column: &'static str,
+        /// Type of the row, when it could be read
+        action: Option<&'static str>,
+    },
+    #[error("{action} of client {client} with tx {tx} has no amount")]
+    MissingAmount {
+        action: &'static str,
+        client: ClientId,
+        tx: TransactionId,
+    },
+    #[error("input ends mid-record at byte {offset}: {partial}")]
+    TruncatedInput { offset: u64, partial: String },
+    #[error("input ended without a halt row, it may be truncated")]
+    MissingHalt,
+    #[error("row on line {line} could not be read: {source}")]
+    MalformedRow { line: u64, source: Box<CustomError> },
+    #[error("output could not be written after {rows_written} account rows: {source}")]
+    OutputWrite {
+        rows_written: usize,
+        source: io::Error,
+    },
+    #[error("Client {client} would take {release} out of held {held} on {action} tx {tx}")]
+    NegativeHeld {
+        client: ClientId,
+        held: Decimal,
+        release: Decimal,
+        action: &'static str,
+        tx: TransactionId,
+    },
+    #[error("Client {client} has total {total} but available {available} and held {held} after {action} tx {tx}")]
+    InvariantViolation {
+        client: ClientId,
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+        action: &'static str,
+        tx: TransactionId,
+    },
+
+    #[error("the engine already processed an input, reset it before processing another one")]
+    EngineReused,
+    #[error("{feature} cannot be used with more than one worker")]
+    UnsupportedWithWorkers { feature: &'static str },
+    #[error("spill file could not be written or read: {source}")]
+    SpillFile { source: io::Error },
+    #[error("id index could not be written or read: {source}")]
+    IdIndex { source: io::Error },
+    #[error("checkpoint could not be written or read: {source}")]
+    Checkpoint { source: io::Error },
+    #[error("no valid checkpoint in {dir}")]
+    NoCheckpoint { dir: String },
+    #[error("{feature} cannot be used with checkpoints")]
+    UnsupportedWithCheckpoints { feature: &'static str },
+    #[error("{feature} cannot be used with --parallel-files")]
+    UnsupportedWithParallelFiles { feature: &'static str },
+    #[error("{feature} cannot be used with --sync")]
+    UnsupportedWithSync { feature: &'static str },
+    #[error("{feature} cannot be used with --shared-accounts")]
+    UnsupportedWithSharedAccounts { feature: &'static str },
+    #[error("{feature} cannot be used with --group-by-client")]
+    UnsupportedWithGroupByClient { feature: &'static str },
+    #[error("{feature} cannot be used with --parse-threads")]
+    UnsupportedWithParseThreads { feature: &'static str },
+    #[error("a worker stopped on an error, so the rows of this input were not all applied")]
+    WorkerStopped,
+    #[error("--two-pass reads the input twice, which cannot be done on an input it cannot seek in: {source}")]
+    UnseekableInput { source: io::Error },
+    #[error("--parse-threads reads parts of the input apart, which cannot be done on {input}")]
+    UnsplittableInput { input: String },
+    #[error("accounts took about {estimate} bytes, more than --max-memory {limit}, --spill-dir would move stored transactions to disk instead")]
+    MemoryLimit { estimate: u64, limit: u64 },
+    #[error("client {client} is in inputs {first} and {second}, --parallel-files needs inputs that share no client")]
+    OverlappingInputs {
+        client: ClientId,
+        first: usize,
+        second: usize,
+    },
 
     ///Following Errors are okay to happen and should not stop the engine
     #[error("Not enough account balance")]
     AccountBalanceNotEnough,
     #[error("Account is Locked")]
     LockedAccount,
-    #[error("Duplicated transcation id")]
-    DuplicatedTransactionId,
-    #[error("Non existing transaction id")]
-    NonExistingTransactionId,
+    #[error(
+        "Duplicated transcation id{}",
+        .original.as_ref().map(|original| format!(", {}", original)).unwrap_or_default()
+    )]
+    DuplicatedTransactionId {
+        /// Transaction the id was first applied as, None when it is no longer kept
+        original: Option<OriginalTransaction>,
+    },
+    #[error(
+        "Non existing transaction id {tx} for {action}{}",
+        if *.new_client { ", the client had no earlier rows" } else { "" }
+    )]
+    NonExistingTransactionId {
+        action: &'static str,
+        tx: TransactionId,
+        /// The row is the first one naming its client
+        new_client: bool,
+    },
     #[error("Undefined Behaviour")]
     UndefinedBehaviour,
     #[error("Not under dispute")]
     NotUnderDispute,
+    #[error("Already under dispute")]
+    AlreadyUnderDispute,
+    #[error("Transaction belongs to client {expected}, not client {found}")]
+    ClientMismatch { expected: ClientId, found: ClientId },
+    #[error("Account was locked by a chargeback")]
+    LockedByChargeback,
+    #[error(
+        "Transaction cannot be disputed again, at most {max_redisputes} redisputes are allowed"
+    )]
+    RedisputeLimitExceeded { max_redisputes: u32 },
+    #[error("Transaction is too old to be disputed")]
+    DisputeWindowExpired,
+    #[error("Timestamp {timestamp} is older than already applied timestamp {previous}")]
+    OutOfOrder { timestamp: u64, previous: u64 },
+    #[error("Fee must not be negative and must be smaller than a deposited amount")]
+    InvalidFee,
+    #[error("Account is closed")]
+    AccountClosed,
+    #[error("Account cannot be closed while funds are held")]
+    HeldFundsOnClose,
+    #[error("Amount exceeds the limit of {limit}")]
+    AmountLimitExceeded { limit: Decimal },
+    #[error("More than {max_withdrawals} withdrawals within the velocity window")]
+    VelocityLimitExceeded { max_withdrawals: usize },
+    #[error("Transaction is settled and can no longer be disputed")]
+    TransactionSettled,
+    #[error("Settle range start {start} is after its end {end}")]
+    InvalidSettleRange {
+        start: TransactionId,
+        end: TransactionId,
+    },
+    #[error("Amount must be positive")]
+    NonPositiveAmount,
+    #[error("Release exceeds the administrative hold of {held}")]
+    ReleaseExceedsHold { held: Decimal },
+    #[error("Account is not locked")]
+    AccountNotLocked,
+    #[error("Transaction was not charged back or is already reinstated")]
+    NotChargedBack,
+    #[error("Dispute exceeds the transaction amount of {amount}")]
+    DisputeExceedsAmount { amount: Decimal },
+    #[error("Transaction was already charged back")]
+    AlreadyChargedBack,
+    #[error("Transaction was already reversed")]
+    AlreadyReversed,
+    #[error("Escrow was already released")]
+    EscrowAlreadyReleased,
+    #[error("Release exceeds the escrowed {escrowed}")]
+    ReleaseExceedsEscrow { escrowed: Decimal },
+    #[error("Account is frozen")]
+    AccountFrozen,
+    #[error("Deposit would take the account over its tier cap of {cap}")]
+    DepositWouldExceedTierCap { cap: Decimal },
+    #[error("Withdrawal exceeds the tier limit of {limit}")]
+    WithdrawalOverTierLimit { limit: Decimal },
+    #[error("Withdrawal would take available below the minimum balance of {floor}")]
+    BelowMinimumBalance { floor: Decimal },
+    #[error("Withdrawal was never pending")]
+    WithdrawalNotPending,
+    #[error("Withdrawal was already settled")]
+    WithdrawalAlreadySettled,
+    #[error("Withdrawal already failed")]
+    WithdrawalAlreadyFailed,
+    #[error("Transaction id {found} is not above the previous id {previous}")]
+    NonMonotonicTransactionId {
+        previous: TransactionId,
+        found: TransactionId,
+    },
+    #[error("Account reached the cap of {cap} stored transactions")]
+    AccountTransactionCapExceeded { cap: usize },
+    #[error("Transfer needs a recipient other than the sender")]
+    InvalidTransferRecipient,
+    #[error("Account is quarantined after its balances became inconsistent")]
+    AccountQuarantined,
+    #[error("{column} {value} has more than {precision} decimal places")]
+    ExcessPrecision {
+        column: &'static str,
+        value: Decimal,
+        precision: u32,
+    },
+    #[error("Row echoes an amount of {found}, but the transaction amount is {expected}")]
+    DisputeAmountMismatch { expected: Decimal, found: Decimal },
+}
+
+/// Whether an error stops the engine or only rejects its row
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Severity {
+    /// The input is corrupt or cannot be read, the run stops
+    Fatal,
+    /// The row is rejected and the run goes on
+    Recoverable,
 }
+
+/// Names the type of the row a field belongs to, when it is known
+fn of_row(action: &Option<&'static str>) -> String {
+    action
+        .map(|action| format!(" of {} row", action))
+        .unwrap_or_default()
+}
+
+impl CustomError {
+    /// Sets the type of the row on a parse error of one of its fields, other errors are left as they are
+    pub(crate) fn of_action(mut self, row_action: &'static str) -> Self {
+        if let CustomError::InvalidDecimal { action, .. }
+        | CustomError::ExponentNotation { action, .. }
+        | CustomError::IntOutOfRange { action, .. }
+        | CustomError::InvalidInt { action, .. } = &mut self
+        {
+            *action = Some(row_action);
+        }
+        self
+    }
+
+    /// The only place errors are classified, the match has no wildcard so a new variant must be placed here
+    pub(crate) fn severity(&self) -> Severity {
+        match self {
+            CustomError::UndefinedAction { .. }
+            | CustomError::DecimalParseError(_)
+            | CustomError::IntParseError(_)
+            | CustomError::FileOpenError(_)
+            | CustomError::CsvError(_)
+            | CustomError::BlockingCsvError(_)
+            | CustomError::InvalidDecimal { .. }
+            | CustomError::ExponentNotation { .. }
+            | CustomError::IntOutOfRange { .. }
+            | CustomError::InvalidInt { .. }
+            | CustomError::InvalidUtf8 { .. }
+            | CustomError::MissingField { .. }
+            | CustomError::MissingAmount { .. }
+            | CustomError::MissingHalt
+            | CustomError::TruncatedInput { .. }
+            | CustomError::MalformedRow { .. }
+            | CustomError::OutputWrite { .. }
+            | CustomError::NegativeHeld { .. }
+            | CustomError::InvariantViolation { .. }
+            | CustomError::EngineReused
+            | CustomError::UnsupportedWithWorkers { .. }
+            | CustomError::SpillFile { .. }
+            | CustomError::IdIndex { .. }
+            | CustomError::Checkpoint { .. }
+            | CustomError::NoCheckpoint { .. }
+            | CustomError::UnsupportedWithCheckpoints { .. }
+            | CustomError::UnsupportedWithParallelFiles { .. }
+            | CustomError::UnsupportedWithSync { .. }
+            | CustomError::UnsupportedWithSharedAccounts { .. }
+            | CustomError::UnsupportedWithGroupByClient { .. }
+            | CustomError::UnsupportedWithParseThreads { .. }
+            | CustomError::WorkerStopped
+            | CustomError::UnseekableInput { .. }
+            | CustomError::UnsplittableInput { .. }
+            | CustomError::MemoryLimit { .. }
+            | CustomError::OverlappingInputs { .. } => Severity::Fatal,
+            CustomError::AccountBalanceNotEnough
+            | CustomError::LockedAccount
+            | CustomError::UndefinedBehaviour
+            | CustomError::NonExistingTransactionId { .. }
+            | CustomError::DuplicatedTransactionId { .. }
+            | CustomError::NotUnderDispute
+            | CustomError::ClientMismatch { .. }
+            | CustomError::LockedByChargeback
+            | CustomError::RedisputeLimitExceeded { .. }
+            | CustomError::AlreadyUnderDispute
+            | CustomError::DisputeWindowExpired
+            | CustomError::OutOfOrder { .. }
+            | CustomError::InvalidFee
+            | CustomError::AccountClosed
+            | CustomError::HeldFundsOnClose
+            | CustomError::AmountLimitExceeded { .. }
+            | CustomError::VelocityLimitExceeded { .. }
+            | CustomError::TransactionSettled
+            | CustomError::InvalidSettleRange { .. }
+            | CustomError::NonPositiveAmount
+            | CustomError::ReleaseExceedsHold { .. }
+            | CustomError::AccountNotLocked
+            | CustomError::NotChargedBack
+            | CustomError::DisputeExceedsAmount { .. }
+            | CustomError::AlreadyChargedBack
+            | CustomError::AlreadyReversed
+            | CustomError::EscrowAlreadyReleased
+            | CustomError::ReleaseExceedsEscrow { .. }
+            | CustomError::AccountFrozen
+            | CustomError::DepositWouldExceedTierCap { .. }
+            | CustomError::WithdrawalOverTierLimit { .. }
+            | CustomError::BelowMinimumBalance { .. }
+            | CustomError::WithdrawalNotPending
+            | CustomError::WithdrawalAlreadySettled
+            | CustomError::WithdrawalAlreadyFailed
+            | CustomError::NonMonotonicTransactionId { .. }
+            | CustomError::AccountTransactionCapExceeded { .. }
+            | CustomError::InvalidTransferRecipient
+            | CustomError::AccountQuarantined
+            | CustomError::DisputeAmountMismatch { .. }
+            | CustomError::ExcessPrecision { .. } => Severity::Recoverable,
+        }
+    }
+
+    /// Short label of the error kind, which counts rejected rows by reason in the run summary
+    pub(crate) fn reason(&self) -> &'static str {
+        match self {
+            CustomError::UndefinedAction { .. } => "undefined action",
+            CustomError::DecimalParseError(_) => "unparsable decimal",
+            CustomError::IntParseError(_) => "unparsable int",
+            CustomError::FileOpenError(_) => "file error",
+            CustomError::CsvError(_) | CustomError::BlockingCsvError(_) => "unreadable csv",
+            CustomError::InvalidDecimal { .. } => "invalid decimal",
+            CustomError::ExponentNotation { .. } => "exponent notation",
+            CustomError::IntOutOfRange { .. } => "int out of range",
+            CustomError::InvalidInt { .. } => "invalid int",
+            CustomError::InvalidUtf8 { .. } => "invalid utf-8",
+            CustomError::MissingField { .. } => "missing field",
+            CustomError::MissingAmount { .. } => "missing amount",
+            CustomError::MissingHalt => "missing halt",
+            CustomError::TruncatedInput { .. } => "truncated input",
+            CustomError::MalformedRow { .. } => "malformed row",
+            CustomError::OutputWrite { .. } => "output write",
+            CustomError::NegativeHeld { .. } => "negative held",
+            CustomError::InvariantViolation { .. } => "invariant violation",
+            CustomError::EngineReused => "engine reused",
+            CustomError::UnsupportedWithWorkers { .. } => "unsupported with workers",
+            CustomError::SpillFile { .. } => "spill file",
+            CustomError::IdIndex { .. } => "id index",
+            CustomError::Checkpoint { .. } => "checkpoint",
+            CustomError::NoCheckpoint { .. } => "no checkpoint",
+            CustomError::UnsupportedWithCheckpoints { .. } => "unsupported with checkpoints",
+            CustomError::UnsupportedWithParallelFiles { .. } => "unsupported with parallel files",
+            CustomError::UnsupportedWithSync { .. } => "unsupported with sync",
+            CustomError::UnsupportedWithSharedAccounts { .. } => "unsupported with shared accounts",
+            CustomError::UnsupportedWithGroupByClient { .. } => "unsupported with group by client",
+            CustomError::UnsupportedWithParseThreads { .. } => "unsupported with parse threads",
+            CustomError::WorkerStopped => "worker stopped",
+            CustomError::UnseekableInput { .. } => "unseekable input",
+            CustomError::UnsplittableInput { .. } => "unsplittable input",
+            CustomError::MemoryLimit { .. } => "memory limit",
+            CustomError::OverlappingInputs { .. } => "overlapping inputs",
+            CustomError::AccountBalanceNotEnough => "insufficient funds",
+            CustomError::LockedAccount => "locked account",
+            CustomError::DuplicatedTransactionId { .. } => "duplicate tx",
+            //lost disputes matter more than resolves arriving before their transaction, so they are told apart
+            CustomError::NonExistingTransactionId { action, .. } => match *action {
+                "dispute" => "unknown tx on dispute",
+                "resolve" => "unknown tx on resolve",
+                "chargeback" => "unknown tx on chargeback",
+                _ => "unknown tx",
+            },
+            CustomError::UndefinedBehaviour => "undefined behaviour",
+            CustomError::NotUnderDispute => "not under dispute",
+            CustomError::AlreadyUnderDispute => "already under dispute",
+            CustomError::ClientMismatch { .. } => "client mismatch",
+            CustomError::LockedByChargeback => "locked by chargeback",
+            CustomError::RedisputeLimitExceeded { .. } => "redispute limit",
+            CustomError::DisputeWindowExpired => "dispute window expired",
+            CustomError::OutOfOrder { .. } => "out of order",
+            CustomError::InvalidFee => "invalid fee",
+            CustomError::AccountClosed => "closed account",
+            CustomError::HeldFundsOnClose => "held funds on close",
+            CustomError::AmountLimitExceeded { .. } => "amount limit",
+            CustomError::VelocityLimitExceeded { .. } => "velocity limit",
+            CustomError::TransactionSettled => "settled tx",
+            CustomError::InvalidSettleRange { .. } => "invalid settle range",
+            CustomError::NonPositiveAmount => "non positive amount",
+            CustomError::ReleaseExceedsHold { .. } => "release exceeds hold",
+            CustomError::AccountNotLocked => "account not locked",
+            CustomError::NotChargedBack => "not charged back",
+            CustomError::DisputeExceedsAmount { .. } => "dispute exceeds amount",
+            CustomError::AlreadyChargedBack => "already charged back",
+            CustomError::AlreadyReversed => "already reversed",
+            CustomError::EscrowAlreadyReleased => "escrow already released",
+            CustomError::ReleaseExceedsEscrow { .. } => "release exceeds escrow",
+            CustomError::AccountFrozen => "frozen account",
+            CustomError::DepositWouldExceedTierCap { .. } => "tier cap",
+            CustomError::WithdrawalOverTierLimit { .. } => "tier limit",
+            CustomError::BelowMinimumBalance { .. } => "minimum balance",
+            CustomError::WithdrawalNotPending => "withdrawal not pending",
+            CustomError::WithdrawalAlreadySettled => "withdrawal already settled",
+            CustomError::WithdrawalAlreadyFailed => "withdrawal already failed",
+            CustomError::NonMonotonicTransactionId { .. } => "non monotonic tx",
+            CustomError::AccountTransactionCapExceeded { .. } => "account tx cap",
+            CustomError::InvalidTransferRecipient => "invalid transfer recipient",
+            CustomError::AccountQuarantined => "quarantined account",
+            CustomError::DisputeAmountMismatch { .. } => "dispute amount mismatch",
+            CustomError::ExcessPrecision { .. } => "excess precision",
+        }
+    }
+
+    /// The label reason gives an error kind, for a name read back from a checkpoint
+    /// None when no error kind has that label
+    pub(crate) fn reason_named(name: &str) -> Option<&'static str> {
+        REASONS.iter().find(|reason| **reason == name).copied()
+    }
+}
+
+/// Every label CustomError::reason gives, keep it in step with that match
+const REASONS: &[&str] = &[
+    "undefined action",
+    "unparsable decimal",
+    "unparsable int",
+    "file error",
+    "unreadable csv",
+    "invalid decimal",
+    "exponent notation",
+    "int out of range",
+    "invalid int",
+    "invalid utf-8",
+    "missing field",
+    "missing amount",
+    "missing halt",
+    "truncated input",
+    "malformed row",
+    "output write",
+    "negative held",
+    "invariant violation",
+    "engine reused",
+    "unsupported with workers",
+    "spill file",
+    "id index",
+    "checkpoint",
+    "no checkpoint",
+    "unsupported with checkpoints",
+    "unsupported with parallel files",
+    "unsupported with sync",
+    "unsupported with shared accounts",
+    "unsupported with group by client",
+    "unsupported with parse threads",
+    "worker stopped",
+    "unseekable input",
+    "unsplittable input",
+    "memory limit",
+    "overlapping inputs",
+    "insufficient funds",
+    "locked account",
+    "duplicate tx",
+    "unknown tx on dispute",
+    "unknown tx on resolve",
+    "unknown tx on chargeback",
+    "unknown tx",
+    "undefined behaviour",
+    "not under dispute",
+    "already under dispute",
+    "client mismatch",
+    "locked by chargeback",
+    "redispute limit",
+    "dispute window expired",
+    "out of order",
+    "invalid fee",
+    "closed account",
+    "held funds on close",
+    "amount limit",
+    "velocity limit",
+    "settled tx",
+    "invalid settle range",
+    "non positive amount",
+    "release exceeds hold",
+    "account not locked",
+    "not charged back",
+    "dispute exceeds amount",
+    "already charged back",
+    "already reversed",
+    "escrow already released",
+    "release exceeds escrow",
+    "frozen account",
+    "tier cap",
+    "tier limit",
+    "minimum balance",
+    "withdrawal not pending",
+    "withdrawal already settled",
+    "withdrawal already failed",
+    "non monotonic tx",
+    "account tx cap",
+    "invalid transfer recipient",
+    "quarantined account",
+    "dispute amount mismatch",
+    "excess precision",
+];
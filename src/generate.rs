@@ -0,0 +1,167 @@
+use std::{io::Write, str::FromStr};
+
+/// Shape of a generated workload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scenario {
+    /// Deposits only, over a thousand clients
+    Deposits,
+    /// Deposits and withdrawals, with a dispute, resolve or chargeback for about a third of the deposits
+    Disputes,
+    /// Deposits and withdrawals spread over every client id
+    ManyClients,
+    /// Deposits and withdrawals of four clients
+    FewClients,
+    /// Deposits with ids spread over the whole range, a tenth of them reusing an earlier id
+    DuplicateIds,
+}
+
+impl Scenario {
+    pub const ALL: [Scenario; 5] = [
+        Scenario::Deposits,
+        Scenario::Disputes,
+        Scenario::ManyClients,
+        Scenario::FewClients,
+        Scenario::DuplicateIds,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scenario::Deposits => "deposits",
+            Scenario::Disputes => "disputes",
+            Scenario::ManyClients => "many-clients",
+            Scenario::FewClients => "few-clients",
+            Scenario::DuplicateIds => "duplicate-ids",
+        }
+    }
+}
+
+impl FromStr for Scenario {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scenario::ALL
+            .into_iter()
+            .find(|scenario| scenario.as_str() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown scenario {}, expected deposits, disputes, many-clients, few-clients or duplicate-ids",
+                    s
+                )
+            })
+    }
+}
+
+/// xorshift64*, so a seed gives the same workload on every platform and version
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        //a zero state would only ever give zeros
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A value below bound, bound being above zero
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+/// Number of recent deposits a dispute picks from
+const RECENT: usize = 1024;
+
+/// Writes rows of the scenario as csv, header included, the same seed always writes the same rows
+pub(crate) fn generate(
+    scenario: Scenario,
+    rows: u64,
+    seed: u64,
+    out: &mut impl Write,
+) -> std::io::Result<()> {
+    let mut rng = Rng::new(seed);
+    //deposits the disputes scenario can dispute, as client and tx
+    let mut recent: Vec<(u64, u64)> = Vec::with_capacity(RECENT);
+    writeln!(out, "type,client,tx,amount")?;
+    for tx in 1..=rows {
+        let amount = rng.below(1_000_000);
+        let (whole, fraction) = (amount / 100, amount % 100);
+        match scenario {
+            Scenario::Deposits => {
+                let client = rng.below(1_000);
+                writeln!(out, "deposit,{},{},{}.{:02}", client, tx, whole, fraction)?;
+            }
+            Scenario::Disputes => {
+                let client = rng.below(10_000);
+                match (rng.below(10), recent.is_empty()) {
+                    (0..=5, _) | (_, true) => {
+                        writeln!(out, "deposit,{},{},{}.{:02}", client, tx, whole, fraction)?;
+                        if recent.len() < RECENT {
+                            recent.push((client, tx));
+                        } else {
+                            recent[rng.below(RECENT as u64) as usize] = (client, tx);
+                        }
+                    }
+                    (6, _) => writeln!(
+                        out,
+                        "withdrawal,{},{},{}.{:02}",
+                        client,
+                        tx,
+                        whole / 10,
+                        fraction
+                    )?,
+                    (step, false) => {
+                        let (client, disputed) = recent[rng.below(recent.len() as u64) as usize];
+                        let action = match step {
+                            7 | 8 => "dispute",
+                            _ if rng.below(4) == 0 => "chargeback",
+                            _ => "resolve",
+                        };
+                        writeln!(out, "{},{},{},", action, client, disputed)?;
+                    }
+                }
+            }
+            Scenario::ManyClients | Scenario::FewClients => {
+                let clients = match scenario {
+                    Scenario::FewClients => 4,
+                    _ => u64::from(u16::MAX) + 1,
+                };
+                let client = rng.below(clients);
+                let action = match rng.below(4) {
+                    0 => "withdrawal",
+                    _ => "deposit",
+                };
+                writeln!(
+                    out,
+                    "{},{},{},{}.{:02}",
+                    action, client, tx, whole, fraction
+                )?;
+            }
+            Scenario::DuplicateIds => {
+                let client = rng.below(1_000);
+                let id = match (rng.below(10), recent.is_empty()) {
+                    (0, false) => recent[rng.below(recent.len() as u64) as usize].1,
+                    _ => rng.below(u64::from(u32::MAX) + 1),
+                };
+                if recent.len() < RECENT {
+                    recent.push((client, id));
+                } else {
+                    recent[rng.below(RECENT as u64) as usize] = (client, id);
+                }
+                writeln!(out, "deposit,{},{},{}.{:02}", client, id, whole, fraction)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rows of the scenario in memory, for benchmarks that should not measure the filesystem
+pub fn workload(scenario: Scenario, rows: u64, seed: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    generate(scenario, rows, seed, &mut out).unwrap();
+    out
+}
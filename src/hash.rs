@@ -0,0 +1,15 @@
+//! Maps keyed by client and transaction ids
+//!
+//! Ids come from the input as small integers, so the default SipHash spends most of a lookup guarding
+//! against collisions crafted by an attacker. The `fast-hash` feature, on by default, hashes them with
+//! the FxHash of rustc-hash instead. Build with `--no-default-features` for the std hasher.
+
+#[cfg(feature = "fast-hash")]
+pub type IdMap<K, V> = rustc_hash::FxHashMap<K, V>;
+#[cfg(feature = "fast-hash")]
+pub type IdSet<K> = rustc_hash::FxHashSet<K>;
+
+#[cfg(not(feature = "fast-hash"))]
+pub type IdMap<K, V> = std::collections::HashMap<K, V>;
+#[cfg(not(feature = "fast-hash"))]
+pub type IdSet<K> = std::collections::HashSet<K>;
@@ -0,0 +1,109 @@
+//! Sets of transaction ids that mostly arrive in increasing order
+//!
+//! Ids are handed out in sequence by most exporters, so a set of them is kept as a sorted list the ids are appended
+//! to, 4 bytes each, where an id past the last one is known to be new without looking further and others are found
+//! with a binary search. An id arriving below the last one goes to a hash set of exceptions, so shuffled ids cost
+//! about what a hash set alone would.
+
+use crate::{
+    engine::{table_bytes, TransactionId},
+    hash::IdSet,
+};
+
+#[derive(Debug, Default, Clone)]
+pub struct IncreasingIds {
+    /// Ids that were past every id of the list when they arrived, sorted
+    sorted: Vec<TransactionId>,
+    /// Ids below the last id of the list when they arrived
+    exceptions: IdSet<TransactionId>,
+}
+
+impl IncreasingIds {
+    #[inline]
+    pub fn contains(&self, transaction_id: TransactionId) -> bool {
+        match self.sorted.last() {
+            None => false,
+            Some(&last) if transaction_id > last => false,
+            Some(&last) if transaction_id == last => true,
+            Some(_) => {
+                self.sorted.binary_search(&transaction_id).is_ok()
+                    || (!self.exceptions.is_empty() && self.exceptions.contains(&transaction_id))
+            }
+        }
+    }
+
+    /// Adds the id, false when it was already there
+    pub fn insert(&mut self, transaction_id: TransactionId) -> bool {
+        if self.contains(transaction_id) {
+            return false;
+        }
+        match self.sorted.last() {
+            Some(&last) if transaction_id < last => {
+                self.exceptions.insert(transaction_id);
+            }
+            _ => self.sorted.push(transaction_id),
+        }
+        true
+    }
+
+    /// Keeps the ids for which keep is true
+    /// The exceptions are merged into the list, as the last id may no longer be above them all
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(TransactionId) -> bool) {
+        self.sorted.extend(self.exceptions.drain());
+        self.sorted.retain(|id| keep(*id));
+        self.sorted.sort_unstable();
+        self.exceptions.shrink_to_fit();
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.sorted.len() + self.exceptions.len()
+    }
+
+    /// Every id, those that arrived in order first
+    pub(crate) fn iter(&self) -> impl Iterator<Item = TransactionId> + '_ {
+        self.sorted.iter().chain(&self.exceptions).copied()
+    }
+
+    /// Bytes allocated for the list and the exceptions
+    pub fn memory(&self) -> u64 {
+        (self.sorted.capacity() * std::mem::size_of::<TransactionId>()) as u64
+            + table_bytes::<TransactionId>(self.exceptions.capacity())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::Rng;
+
+    /// The ids of an account answer as a hash set would, whether they arrive in order, nearly so or shuffled
+    #[test]
+    fn test_increasing_ids_match_a_set() {
+        let mut rng = Rng::new(11);
+        for (pattern, out_of_order) in [("sequential", 0), ("nearly", 20), ("shuffled", 100)] {
+            let mut ids = IncreasingIds::default();
+            let mut set = IdSet::default();
+            let mut next = 0;
+            for step in 0..50_000u64 {
+                next += 1 + rng.below(3) as TransactionId;
+                let id = if rng.below(100) < out_of_order {
+                    rng.below(u64::from(next) + 100) as TransactionId
+                } else {
+                    next
+                };
+                assert_eq!(ids.insert(id), set.insert(id), "{}", pattern);
+                let probe = rng.below(u64::from(next) + 100) as TransactionId;
+                assert_eq!(ids.contains(probe), set.contains(&probe), "{}", pattern);
+                //as settling a range does
+                if step % 10_000 == 9_999 {
+                    let (start, end) = (next / 4, next / 2);
+                    ids.retain(|id| !(start..=end).contains(&id));
+                    set.retain(|id| !(start..=end).contains(id));
+                }
+            }
+            assert_eq!(ids.len(), set.len(), "{}", pattern);
+            assert!(set.iter().all(|id| ids.contains(*id)), "{}", pattern);
+            assert_eq!(ids.iter().collect::<IdSet<_>>(), set, "{}", pattern);
+        }
+    }
+}
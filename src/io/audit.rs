@@ -0,0 +1,60 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use rust_decimal::Decimal;
+
+use crate::{
+    engine::{ClientId, TransactionId},
+    error::CustomError,
+    io::rejects::quote,
+};
+
+/// Entry of the audit trail, written as one row of the audit csv
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AuditEntry {
+    /// What happened, `chargeback` or `adjustment`
+    pub(crate) event: &'static str,
+    pub(crate) client: ClientId,
+    pub(crate) tx: TransactionId,
+    /// Action of the transaction the event is about
+    pub(crate) action: &'static str,
+    pub(crate) amount: Decimal,
+    /// Position of the row of the event in the input
+    pub(crate) record: u64,
+    pub(crate) timestamp: Option<u64>,
+    /// Position of the Reinstate row that overturned a chargeback
+    pub(crate) reinstated_at: Option<u64>,
+    pub(crate) memo: Option<String>,
+}
+
+/// Writes the entries to a csv file, in the order given
+pub(crate) fn write(file_path: &Path, entries: &[AuditEntry]) -> Result<(), CustomError> {
+    let mut inner = BufWriter::new(File::create(file_path)?);
+    inner.write_all(b"event,client,tx,type,amount,record,timestamp,reinstated_at,memo\n")?;
+    for entry in entries {
+        writeln!(
+            inner,
+            "{},{},{},{},{},{},{},{},{}",
+            entry.event,
+            entry.client,
+            entry.tx,
+            entry.action,
+            entry.amount,
+            entry.record,
+            entry
+                .timestamp
+                .map(|timestamp| timestamp.to_string())
+                .unwrap_or_default(),
+            entry
+                .reinstated_at
+                .map(|record| record.to_string())
+                .unwrap_or_default(),
+            entry.memo.as_deref().map(quote).unwrap_or_default()
+        )?;
+    }
+    inner.flush()?;
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::error::CustomError;
+
+/// Start of every checkpoint file, the last byte is the version of the layout
+const MAGIC: &[u8; 8] = b"THCKPT\0\x01";
+
+/// Writes a checkpoint of the run at record_index, then removes all but the newest keep checkpoints
+/// The checkpoint is written aside and renamed into place, so a run killed while writing it leaves the
+/// previous ones whole
+pub(crate) fn write(
+    dir: &Path,
+    record_index: u64,
+    state: &[u8],
+    keep: usize,
+) -> Result<(), CustomError> {
+    let path = dir.join(format!("checkpoint-{:020}.ckpt", record_index));
+    let partial = path.with_extension("ckpt.partial");
+    let mut file = File::create(&partial).map_err(checkpoint_error)?;
+    file.write_all(MAGIC)
+        .and_then(|_| file.write_all(&(state.len() as u64).to_le_bytes()))
+        .and_then(|_| file.write_all(&checksum(state).to_le_bytes()))
+        .and_then(|_| file.write_all(state))
+        .and_then(|_| file.sync_all())
+        .and_then(|_| fs::rename(&partial, &path))
+        .map_err(checkpoint_error)?;
+    for stale in list(dir)?.into_iter().skip(keep.max(1)) {
+        fs::remove_file(stale).map_err(checkpoint_error)?;
+    }
+    Ok(())
+}
+
+/// Writes the state as the only checkpoint of the directory, whatever the records of those it replaces
+pub(crate) fn replace(dir: &Path, record_index: u64, state: &[u8]) -> Result<(), CustomError> {
+    fs::create_dir_all(dir).map_err(checkpoint_error)?;
+    let stale = list(dir)?;
+    write(dir, record_index, state, usize::MAX)?;
+    let path = dir.join(format!("checkpoint-{:020}.ckpt", record_index));
+    for stale in stale.into_iter().filter(|stale| *stale != path) {
+        fs::remove_file(stale).map_err(checkpoint_error)?;
+    }
+    Ok(())
+}
+
+/// Checkpoint files of the directory, newest first
+pub(crate) fn list(dir: &Path) -> Result<Vec<PathBuf>, CustomError> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).map_err(checkpoint_error)? {
+        let path = entry.map_err(checkpoint_error)?.path();
+        let is_checkpoint = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("checkpoint-") && name.ends_with(".ckpt"));
+        if is_checkpoint {
+            paths.push(path);
+        }
+    }
+    //the record index is zero padded, so names sort like the positions they were taken at
+    paths.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(paths)
+}
+
+/// State saved in a checkpoint file, None when the file is cut short or its bytes do not match their checksum
+pub(crate) fn read(path: &Path) -> Result<Option<Vec<u8>>, CustomError> {
+    let bytes = fs::read(path).map_err(checkpoint_error)?;
+    if bytes.len() < 24 || &bytes[..8] != MAGIC {
+        return Ok(None);
+    }
+    let len = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+    let expected = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+    let state = &bytes[24..];
+    if state.len() as u64 != len || checksum(state) != expected {
+        return Ok(None);
+    }
+    Ok(Some(state.to_vec()))
+}
+
+/// FNV-1a, which stays the same from one build to the next unlike the std hasher
+pub(crate) fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn checkpoint_error(source: io::Error) -> CustomError {
+    CustomError::Checkpoint { source }
+}
@@ -1,2 +1,9 @@
-pub(crate) mod reader;
-pub(crate) mod writer;
+pub(crate) mod audit;
+pub(crate) mod checkpoint;
+pub(crate) mod id_index;
+pub mod reader;
+pub(crate) mod rejects;
+pub(crate) mod report;
+pub(crate) mod sidecar;
+pub(crate) mod spill;
+pub mod writer;
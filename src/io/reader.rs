@@ -1,22 +1,366 @@
-use csv_async::AsyncReader;
-use std::path::PathBuf;
-use tokio::fs::File;
+use csv_async::{AsyncReader, ByteRecord, Position, StringRecord};
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf},
+};
 
 use crate::error::CustomError;
-pub(crate) struct Reader {
-    inner: AsyncReader<File>,
+pub struct Reader<R = File> {
+    inner: AsyncReader<Tail<R>>,
 }
 
 impl Reader {
     pub(crate) async fn new(file_path: PathBuf) -> Result<Reader, CustomError> {
         let file = File::open(file_path).await?;
+        Ok(Self::from_reader(file))
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> Reader<R> {
+    /// Wraps any async source, e.g. an in-memory buffer in tests
+    pub fn from_reader(rdr: R) -> Self {
+        //rows without an amount may leave out its trailing comma, so field counts can differ
         let reader = csv_async::AsyncReaderBuilder::new()
             .trim(csv_async::Trim::All)
-            .create_reader(file);
-        Ok(Self { inner: reader })
+            .flexible(true)
+            .create_reader(Tail::new(rdr));
+        Self { inner: reader }
     }
 
-    pub(crate) fn get_inner(&mut self) -> &mut AsyncReader<File> {
+    pub(crate) fn get_inner(&mut self) -> &mut AsyncReader<Tail<R>> {
         &mut self.inner
     }
+
+    /// Whether the record just read was cut off by the end of the input, before its line break
+    pub(crate) fn is_truncated(&self) -> bool {
+        self.inner.get_ref().ends_at(self.inner.position().byte())
+    }
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin + Send> Reader<R> {
+    /// Up to len bytes of the input from start, fewer at its end, read past the csv reader
+    /// The input is left at its start, where the csv reader expects it before it reads anything or seeks
+    pub(crate) async fn read_raw(&mut self, start: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        let input = self.inner.get_mut();
+        input.seek(SeekFrom::Start(start)).await?;
+        let mut bytes = Vec::new();
+        (&mut *input).take(len).read_to_end(&mut bytes).await?;
+        input.seek(SeekFrom::Start(0)).await?;
+        Ok(bytes)
+    }
+
+    /// Fails when the input cannot seek, as a pipe, before anything is read from it
+    pub(crate) async fn check_seekable(&mut self) -> std::io::Result<()> {
+        self.inner.get_mut().seek(SeekFrom::Current(0)).await?;
+        Ok(())
+    }
+
+    /// Moves to a position read before, its line and record included, the header is still read from the start
+    pub(crate) async fn seek(
+        &mut self,
+        byte: u64,
+        line: u64,
+        record: u64,
+    ) -> Result<(), CustomError> {
+        let mut position = Position::new();
+        position.set_byte(byte).set_line(line).set_record(record);
+        self.inner.headers().await?;
+        self.inner.seek(position).await?;
+        Ok(())
+    }
+}
+
+/// Reader of the input with blocking std calls for --sync, parsing it with the csv crate the way Reader does
+pub struct BlockingReader<R = fs::File> {
+    inner: csv::Reader<Tail<R>>,
+    /// Record the csv crate reads into, before it is copied into the record the engine parses
+    record: csv::ByteRecord,
+}
+
+impl BlockingReader {
+    pub(crate) fn open(file_path: &Path) -> Result<Self, CustomError> {
+        let file = fs::File::open(file_path)?;
+        Ok(Self::from_reader(file))
+    }
+}
+
+impl BlockingReader<Bounded<fs::File>> {
+    /// Opens the file for reading up to limit, as if it ended there
+    pub(crate) fn open_bounded(file_path: &Path, limit: u64) -> Result<Self, CustomError> {
+        let file = fs::File::open(file_path)?;
+        Ok(Self::from_reader(Bounded {
+            inner: file,
+            limit,
+            position: 0,
+        }))
+    }
+}
+
+impl<R: Read> BlockingReader<R> {
+    /// Wraps any std source, e.g. an in-memory buffer in tests
+    pub fn from_reader(rdr: R) -> Self {
+        //rows without an amount may leave out its trailing comma, so field counts can differ
+        let reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(Tail::new(rdr));
+        Self {
+            inner: reader,
+            record: csv::ByteRecord::new(),
+        }
+    }
+
+    pub(crate) fn headers(&mut self) -> Result<StringRecord, CustomError> {
+        Ok(StringRecord::from(
+            self.inner.headers()?.iter().collect::<Vec<_>>(),
+        ))
+    }
+
+    /// Reads the next record into record, with its position, false at the end of the input
+    pub(crate) fn read_byte_record(&mut self, record: &mut ByteRecord) -> csv::Result<bool> {
+        if !self.inner.read_byte_record(&mut self.record)? {
+            return Ok(false);
+        }
+        record.clear();
+        for field in &self.record {
+            record.push_field(field);
+        }
+        record.set_position(self.record.position().map(async_position));
+        Ok(true)
+    }
+
+    /// Position after the last record read
+    pub(crate) fn position(&self) -> Position {
+        async_position(self.inner.position())
+    }
+
+    /// Whether the record just read was cut off by the end of the input, before its line break
+    pub(crate) fn is_truncated(&self) -> bool {
+        self.inner.get_ref().ends_at(self.inner.position().byte())
+    }
+}
+
+impl<R: Read + Seek> BlockingReader<R> {
+    /// Up to len bytes of the input from start, fewer at its end, read past the csv reader
+    /// The input is left at its start, where the csv reader expects it before it reads anything or seeks
+    pub(crate) fn read_raw(&mut self, start: u64, len: u64) -> io::Result<Vec<u8>> {
+        let input = self.inner.get_mut();
+        input.seek(SeekFrom::Start(start))?;
+        let mut bytes = Vec::new();
+        (&mut *input).take(len).read_to_end(&mut bytes)?;
+        input.seek(SeekFrom::Start(0))?;
+        Ok(bytes)
+    }
+
+    /// Fails when the input cannot seek, as a pipe, before anything is read from it
+    pub(crate) fn check_seekable(&mut self) -> io::Result<()> {
+        self.inner.get_mut().stream_position()?;
+        Ok(())
+    }
+
+    /// Moves to a position read before, its line and record included, the header is still read from the start
+    pub(crate) fn seek(&mut self, byte: u64, line: u64, record: u64) -> Result<(), CustomError> {
+        let mut position = csv::Position::new();
+        position.set_byte(byte).set_line(line).set_record(record);
+        self.inner.headers()?;
+        self.inner.seek(position)?;
+        Ok(())
+    }
+}
+
+/// Position of the csv crate as the engine keeps it
+fn async_position(position: &csv::Position) -> Position {
+    let mut copy = Position::new();
+    copy.set_byte(position.byte())
+        .set_line(position.line())
+        .set_record(position.record());
+    copy
+}
+
+/// File that ends at limit for its readers, seeking is left as it is
+pub(crate) struct Bounded<R> {
+    inner: R,
+    limit: u64,
+    position: u64,
+}
+
+impl<R: Read> Read for Bounded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self
+            .limit
+            .saturating_sub(self.position)
+            .min(buf.len() as u64) as usize;
+        let read = self.inner.read(&mut buf[..left])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for Bounded<R> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.position = self.inner.seek(position)?;
+        Ok(self.position)
+    }
+}
+
+/// Format of a compressed input, told by the bytes it starts with
+pub(crate) fn compression(start: &[u8]) -> Option<&'static str> {
+    [
+        (&b"\x1f\x8b"[..], "gzip"),
+        (b"\x28\xb5\x2f\xfd", "zstd"),
+        (b"BZh", "bzip2"),
+        (b"\xfd7zXZ\x00", "xz"),
+        (b"PK\x03\x04", "zip"),
+    ]
+    .into_iter()
+    .find(|(magic, _)| start.starts_with(magic))
+    .map(|(_, format)| format)
+}
+
+/// Where the records of a file may be split, about every chunk bytes from start to its length
+/// Each point is where a csv reader goes on after the first line break from there, which is only the end of a
+/// record when the line break is not inside quotes: whoever reads up to it must check it got there
+pub(crate) fn split_points(file: &mut fs::File, start: u64, chunk: u64) -> io::Result<Vec<u64>> {
+    let len = file.metadata()?.len();
+    let mut points = vec![start];
+    let mut from = start.saturating_add(chunk.max(1));
+    while from < len {
+        let point = after_line_break(file, from)?;
+        if point > *points.last().unwrap() {
+            points.push(point);
+        }
+        from = point.max(from).saturating_add(chunk.max(1));
+    }
+    if *points.last().unwrap() < len {
+        points.push(len);
+    }
+    Ok(points)
+}
+
+/// Position a csv reader is at after the first line break from offset, the end of the file when there is none
+/// A reader stops after a carriage return and skips the line feed after it as an empty line
+fn after_line_break(file: &mut fs::File, offset: u64) -> io::Result<u64> {
+    //the byte before tells a line feed ending a record after a carriage return
+    let mut position = offset.saturating_sub(1);
+    file.seek(SeekFrom::Start(position))?;
+    let mut block = [0; 4096];
+    let mut previous = None;
+    loop {
+        let read = file.read(&mut block)?;
+        if read == 0 {
+            return Ok(position);
+        }
+        for (index, &byte) in block[..read].iter().enumerate() {
+            let at = position + index as u64;
+            if byte == b'\n' && at >= offset {
+                let after_return = match index {
+                    0 => previous == Some(b'\r'),
+                    _ => block[index - 1] == b'\r',
+                };
+                return Ok(if after_return { at } else { at + 1 });
+            }
+        }
+        previous = Some(block[read - 1]);
+        position += read as u64;
+    }
+}
+
+/// Source that remembers how it ended, so a last record cut off mid-row can be told apart
+pub(crate) struct Tail<R> {
+    inner: R,
+    /// Bytes read so far
+    bytes: u64,
+    /// Last byte read
+    last: Option<u8>,
+    eof: bool,
+}
+
+impl<R> Tail<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            last: None,
+            eof: false,
+        }
+    }
+
+    /// Whether the input ended at byte without a line break, so a record ending there was cut off
+    fn ends_at(&self, byte: u64) -> bool {
+        self.eof && !matches!(self.last, None | Some(b'\n') | Some(b'\r')) && byte == self.bytes
+    }
+
+    /// Takes note of the bytes a read gave
+    fn note_read(&mut self, read: &[u8]) {
+        match read.last() {
+            None => self.eof = true,
+            Some(&last) => {
+                self.bytes += read.len() as u64;
+                self.last = Some(last);
+            }
+        }
+    }
+
+    /// What comes after a new position has not been read yet
+    fn note_seek(&mut self, position: u64) {
+        self.bytes = position;
+        self.last = None;
+        self.eof = false;
+    }
+}
+
+impl<R: Read> Read for Tail<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        //an empty buffer tells nothing about the end
+        if !buf.is_empty() {
+            self.note_read(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for Tail<R> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = self.inner.seek(position)?;
+        self.note_seek(position);
+        Ok(position)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Tail<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = &buf.filled()[filled..];
+            self.note_read(read);
+        }
+        poll
+    }
+}
+
+impl<R: AsyncSeek + Unpin> AsyncSeek for Tail<R> {
+    fn start_seek(mut self: Pin<&mut Self>, position: std::io::SeekFrom) -> std::io::Result<()> {
+        Pin::new(&mut self.inner).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        let poll = Pin::new(&mut self.inner).poll_complete(cx);
+        if let Poll::Ready(Ok(position)) = poll {
+            self.note_seek(position);
+        }
+        poll
+    }
 }
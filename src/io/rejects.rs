@@ -0,0 +1,68 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::error::CustomError;
+
+/// Csv sidecar listing every row rejected with a recoverable error, next to the reason,
+/// the line of the row and its raw text
+/// A memo column is added when the input has one
+/// Clones write to the same file, so every worker of a run shares it
+#[derive(Clone)]
+pub(crate) struct RejectsWriter {
+    inner: Arc<Mutex<BufWriter<File>>>,
+    with_memo: bool,
+}
+
+impl RejectsWriter {
+    pub(crate) fn create(file_path: &Path, with_memo: bool) -> Result<Self, CustomError> {
+        let mut inner = BufWriter::new(File::create(file_path)?);
+        inner.write_all(b"type,client,tx,amount,error,line,raw")?;
+        if with_memo {
+            inner.write_all(b",memo")?;
+        }
+        inner.write_all(b"\n")?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(inner)),
+            with_memo,
+        })
+    }
+
+    /// Writes one rejected row, fields are quoted when needed
+    pub(crate) fn write(&mut self, fields: &[&str], memo: &str) -> Result<(), CustomError> {
+        let mut line = fields
+            .iter()
+            .map(|field| quote(field))
+            .collect::<Vec<_>>()
+            .join(",");
+        if self.with_memo {
+            line.push(',');
+            line.push_str(&quote(memo));
+        }
+        line.push('\n');
+        //a worker that panicked while holding the lock stops the run anyway
+        let mut inner = self.inner.lock().expect("rejects file lock is poisoned");
+        inner.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<(), CustomError> {
+        self.inner
+            .lock()
+            .expect("rejects file lock is poisoned")
+            .flush()?;
+        Ok(())
+    }
+}
+
+/// Quotes a csv field if it contains a separator, a quote or a line break
+pub(crate) fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::{error::CustomError, io::rejects::quote, owners::FilterStats};
+
+/// Counts of a run, printed to stderr once the output is written and kept in the report csv
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct RunReport {
+    /// Rows read before the end of the input or its halt row
+    pub(crate) records: u64,
+    pub(crate) applied: u64,
+    pub(crate) rejected: u64,
+    /// Replayed dispute, resolve and chargeback rows
+    pub(crate) ignored: u64,
+    /// Manual balance corrections among the applied rows, reported apart so they are easy to review
+    pub(crate) adjustments_applied: u64,
+    /// Stale disputes resolved by the engine at the end of the run
+    pub(crate) disputes_auto_resolved: u64,
+    /// Rejected rows by the reason of their error, sorted so runs compare line by line
+    pub(crate) rejected_by_reason: BTreeMap<&'static str, u64>,
+    /// Skipped rows of a type the engine does not know, by type
+    pub(crate) unknown_actions: BTreeMap<String, u64>,
+    /// Interest postings made to accounts
+    pub(crate) interest_postings: u64,
+    /// Accounts set aside after breaking an invariant, left out of the output
+    pub(crate) accounts_quarantined: u64,
+    /// Accounts that reached the cap of stored transactions
+    pub(crate) accounts_over_cap: u64,
+    /// Deposits and withdrawals those accounts rejected at the cap, also counted under their reason
+    pub(crate) rejected_over_cap: u64,
+    /// Header rows skipped inside the input, left by concatenated files
+    pub(crate) repeated_headers: u64,
+    /// Record of the halt row, None when the input has none
+    pub(crate) halted_at: Option<u64>,
+    /// Rows after the halt row, counted but not applied
+    pub(crate) records_after_halt: u64,
+    /// Transactions moved to the spill file, counting those moved again after being read back
+    pub(crate) spilled: u64,
+    /// Rows that had to read their transaction back from the spill file
+    pub(crate) spill_reads: u64,
+    /// Size the spill files reached, in bytes
+    pub(crate) spill_bytes: u64,
+    /// Highest estimate of the memory held by accounts and stored transactions, in bytes
+    pub(crate) peak_memory: u64,
+    /// Bytes held by the owners of transaction ids, 0 unless verbose
+    pub(crate) owner_bytes: u64,
+    /// Bytes a hash map of the same owners would hold, 0 unless verbose
+    pub(crate) owner_map_bytes: u64,
+    /// What the Bloom filter of --id-index-dir answered, all 0 without it
+    pub(crate) id_filter: FilterStats,
+}
+
+impl RunReport {
+    /// Writes the counts as `metric,reason,count` rows, the reason is only set on rejected rows
+    pub(crate) fn write(&self, file_path: &Path) -> Result<(), CustomError> {
+        let mut inner = BufWriter::new(File::create(file_path)?);
+        inner.write_all(b"metric,reason,count\n")?;
+        writeln!(inner, "records,,{}", self.records)?;
+        writeln!(inner, "applied,,{}", self.applied)?;
+        writeln!(inner, "rejected,,{}", self.rejected)?;
+        for (reason, count) in &self.rejected_by_reason {
+            writeln!(inner, "rejected,{},{}", quote(reason), count)?;
+        }
+        writeln!(inner, "ignored,,{}", self.ignored)?;
+        if self.adjustments_applied > 0 {
+            writeln!(inner, "adjustments applied,,{}", self.adjustments_applied)?;
+        }
+        if self.disputes_auto_resolved > 0 {
+            writeln!(
+                inner,
+                "disputes auto-resolved,,{}",
+                self.disputes_auto_resolved
+            )?;
+        }
+        if !self.unknown_actions.is_empty() {
+            writeln!(
+                inner,
+                "unknown,,{}",
+                self.unknown_actions.values().sum::<u64>()
+            )?;
+            for (action, count) in &self.unknown_actions {
+                writeln!(inner, "unknown,{},{}", quote(action), count)?;
+            }
+        }
+        if self.interest_postings > 0 {
+            writeln!(inner, "interest postings,,{}", self.interest_postings)?;
+        }
+        if self.accounts_quarantined > 0 {
+            writeln!(inner, "accounts quarantined,,{}", self.accounts_quarantined)?;
+        }
+        if self.accounts_over_cap > 0 {
+            writeln!(inner, "accounts over cap,,{}", self.accounts_over_cap)?;
+            writeln!(inner, "rejected over cap,,{}", self.rejected_over_cap)?;
+        }
+        if self.repeated_headers > 0 {
+            writeln!(inner, "repeated headers,,{}", self.repeated_headers)?;
+        }
+        if let Some(halted_at) = self.halted_at {
+            writeln!(inner, "halted at,,{}", halted_at)?;
+            writeln!(inner, "records after halt,,{}", self.records_after_halt)?;
+        }
+        if self.spilled > 0 {
+            writeln!(inner, "spilled,,{}", self.spilled)?;
+            writeln!(inner, "spill reads,,{}", self.spill_reads)?;
+            writeln!(inner, "spill bytes,,{}", self.spill_bytes)?;
+        }
+        if self.peak_memory > 0 {
+            writeln!(inner, "peak memory,,{}", self.peak_memory)?;
+        }
+        if self.owner_bytes > 0 {
+            writeln!(inner, "owner bytes,,{}", self.owner_bytes)?;
+            writeln!(inner, "owner map bytes,,{}", self.owner_map_bytes)?;
+        }
+        let filter = &self.id_filter;
+        if filter.filter_bytes > 0 {
+            writeln!(inner, "id filter checks,,{}", filter.checks)?;
+            writeln!(inner, "id index lookups,,{}", filter.index_lookups)?;
+            writeln!(
+                inner,
+                "id filter false positives,,{}",
+                filter.false_positives
+            )?;
+            writeln!(inner, "id filter bytes,,{}", filter.filter_bytes)?;
+            writeln!(inner, "id index bytes,,{}", filter.index_bytes)?;
+        }
+        inner.flush()?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for RunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "records: {}", self.records)?;
+        writeln!(f, "applied: {}", self.applied)?;
+        writeln!(f, "rejected: {}", self.rejected)?;
+        for (reason, count) in &self.rejected_by_reason {
+            writeln!(f, "  {}: {}", reason, count)?;
+        }
+        writeln!(f, "ignored: {}", self.ignored)?;
+        if self.adjustments_applied > 0 {
+            writeln!(f, "adjustments applied: {}", self.adjustments_applied)?;
+        }
+        if self.disputes_auto_resolved > 0 {
+            writeln!(f, "disputes auto-resolved: {}", self.disputes_auto_resolved)?;
+        }
+        if !self.unknown_actions.is_empty() {
+            writeln!(f, "unknown: {}", self.unknown_actions.values().sum::<u64>())?;
+            for (action, count) in &self.unknown_actions {
+                writeln!(f, "  {}: {}", action, count)?;
+            }
+        }
+        if self.interest_postings > 0 {
+            writeln!(f, "interest postings: {}", self.interest_postings)?;
+        }
+        if self.accounts_quarantined > 0 {
+            writeln!(f, "accounts quarantined: {}", self.accounts_quarantined)?;
+        }
+        if self.accounts_over_cap > 0 {
+            writeln!(
+                f,
+                "accounts over cap: {}, {} rows rejected",
+                self.accounts_over_cap, self.rejected_over_cap
+            )?;
+        }
+        if self.repeated_headers > 0 {
+            writeln!(f, "repeated headers: {}", self.repeated_headers)?;
+        }
+        if let Some(halted_at) = self.halted_at {
+            writeln!(f, "halted at record: {}", halted_at)?;
+            writeln!(f, "records after halt: {}", self.records_after_halt)?;
+        }
+        if self.spilled > 0 {
+            writeln!(f, "spilled: {}", self.spilled)?;
+            writeln!(f, "spill reads: {}", self.spill_reads)?;
+            writeln!(f, "spill bytes: {}", self.spill_bytes)?;
+        }
+        if self.peak_memory > 0 {
+            writeln!(
+                f,
+                "peak memory: {:.1} MB (estimated)",
+                self.peak_memory as f64 / 1e6
+            )?;
+        }
+        if self.owner_bytes > 0 {
+            writeln!(
+                f,
+                "transaction id owners: {:.1} MB, {:.1} MB as a hash map",
+                self.owner_bytes as f64 / 1e6,
+                self.owner_map_bytes as f64 / 1e6
+            )?;
+        }
+        let filter = &self.id_filter;
+        if filter.filter_bytes > 0 {
+            writeln!(
+                f,
+                "id filter: {} checks, {} went to the index, {} false positives",
+                filter.checks, filter.index_lookups, filter.false_positives
+            )?;
+            writeln!(
+                f,
+                "id filter: {:.1} MB in memory, index {:.1} MB on disk",
+                filter.filter_bytes as f64 / 1e6,
+                filter.index_bytes as f64 / 1e6
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,24 @@
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
+
+use crate::{engine::ClientId, error::CustomError};
+
+/// Reads a per-client sidecar csv with a header and two columns: client, value
+/// A client listed twice keeps its last value
+/// Sidecars are small and read before any row, so they are read with blocking calls in either mode
+pub(crate) fn read_client_values<T>(file_path: PathBuf) -> Result<HashMap<ClientId, T>, CustomError>
+where
+    T: FromStr,
+    CustomError: From<T::Err>,
+{
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(file_path)?;
+    let mut values = HashMap::new();
+    for record in reader.records() {
+        let record = record?;
+        let client_id = ClientId::from_str(record.get(0).unwrap_or(""))?;
+        let value = T::from_str(record.get(1).unwrap_or(""))?;
+        values.insert(client_id, value);
+    }
+    Ok(values)
+}
@@ -0,0 +1,69 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::error::CustomError;
+
+/// Spill files made by this process, so every worker of a run writes its own
+static SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Append-only file of fixed size records moved out of memory, read back by their offset
+/// A record read back and moved out again is appended anew, the file is removed once dropped
+pub(crate) struct SpillFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+impl SpillFile {
+    pub(crate) fn create(dir: &Path) -> Result<Self, CustomError> {
+        let path = dir.join(format!(
+            "transactions-{}-{}.spill",
+            std::process::id(),
+            SPILL_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        //writes always go to the end, whatever was read last
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .read(true)
+            .open(&path)
+            .map_err(spill_error)?;
+        Ok(Self { path, file, len: 0 })
+    }
+
+    /// Appends the record, returning its offset
+    pub(crate) fn append(&mut self, record: &[u8]) -> Result<u64, CustomError> {
+        let offset = self.len;
+        self.file.write_all(record).map_err(spill_error)?;
+        self.len += record.len() as u64;
+        Ok(offset)
+    }
+
+    /// Reads back the record at the offset, as long as the buffer
+    pub(crate) fn read(&mut self, offset: u64, record: &mut [u8]) -> Result<(), CustomError> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.file.read_exact(record))
+            .map_err(spill_error)
+    }
+
+    /// Bytes written so far
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        //nothing reads the file once the run is over, a leftover only costs disk space
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn spill_error(source: io::Error) -> CustomError {
+    CustomError::SpillFile { source }
+}
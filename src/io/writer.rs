@@ -1,16 +1,44 @@
+use std::io::BufWriter;
+
 use tokio::io::Stdout;
 
-pub(crate) struct Writer {
-    inner: Stdout,
+pub struct Writer<W = Stdout> {
+    inner: W,
+    /// Set once the output header is written, so outputs of several inputs share it
+    pub(crate) has_header: bool,
 }
 
 impl Writer {
     pub(crate) fn new() -> Self {
         let writer = tokio::io::stdout();
-        Self { inner: writer }
+        Self {
+            inner: writer,
+            has_header: false,
+        }
     }
+}
+
+impl Writer<BufWriter<std::io::Stdout>> {
+    /// Writes to stdout with blocking std calls, for --sync
+    pub(crate) fn blocking() -> Self {
+        Self::from_inner(BufWriter::with_capacity(64 * 1024, std::io::stdout()))
+    }
+}
 
-    pub(crate) fn get_inner(&mut self) -> &mut Stdout {
+impl<W> Writer<W> {
+    /// Wraps any sink, async or std, e.g. an in-memory buffer in tests
+    pub fn from_inner(inner: W) -> Self {
+        Self {
+            inner,
+            has_header: false,
+        }
+    }
+
+    pub(crate) fn get_inner(&mut self) -> &mut W {
         &mut self.inner
     }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
 }
@@ -0,0 +1,34 @@
+//! A transaction engine that takes transcation history as input
+//! and outputs the result of transactions in stdout
+//!
+//! #Input
+//!
+//! Input is a csv file with following format
+//! type, client, tx, Decimal
+//! deposit, 1, 1, 1.0
+//!
+//! #Output
+//!
+//! Output is a csv file with following format
+//! client, available, held, total, locked
+//! 1, 1.5, 0.0, 1.5, false
+//! 2, 2.0, 0.0, 2.0, false
+//!
+//! #How to run
+//! cargo run -- <path-for-input>
+//!
+//! #Benchmarks
+//! cargo bench, the benches reach the engine through the modules made public here
+
+pub mod cli;
+pub mod config;
+pub mod engine;
+pub mod error;
+pub mod generate;
+pub mod hash;
+pub mod ids;
+pub mod io;
+
+mod accounts;
+mod owners;
+mod policy;
@@ -0,0 +1,197 @@
+use std::{fmt, ops::Deref, str::FromStr, sync::Arc};
+
+use rust_decimal::Decimal;
+
+use crate::{config::Config, error::CustomError};
+
+/// Balances of the account a dispute, resolve or chargeback row applies to
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AccountView {
+    pub(crate) available: Decimal,
+}
+
+/// Kinds of transaction that can be disputed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DisputedKind {
+    Deposit,
+    Withdrawal,
+    /// Transfer kept by its recipient
+    Transfer,
+}
+
+/// Transaction referenced by a dispute, resolve or chargeback row
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TransactionView {
+    pub(crate) kind: DisputedKind,
+    /// Part of the amount under dispute, the dispute row's own amount when opening it
+    pub(crate) disputed_amount: Decimal,
+}
+
+/// Step of the dispute lifecycle being decided
+/// The account checks that steps come in a legal order before asking the policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DisputeStep {
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+/// Changes a dispute step makes to the account, which applies them and asserts its invariants
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct DisputeEffect {
+    pub(crate) available: Decimal,
+    pub(crate) held: Decimal,
+    pub(crate) total: Decimal,
+    /// Lock the account, as locked by a chargeback
+    pub(crate) lock: bool,
+}
+
+/// Decides what disputes, resolves and chargebacks do to an account
+/// Products embedding the engine may differ on these rules, so they can bring their own
+pub(crate) trait DisputePolicy: fmt::Debug + Send + Sync {
+    /// Balance changes of the step, or the error the row is rejected with
+    fn decide(
+        &self,
+        account: &AccountView,
+        transaction: &TransactionView,
+        step: DisputeStep,
+        config: &Config,
+    ) -> Result<DisputeEffect, CustomError>;
+
+    /// Whether a withdrawal may ever be disputed, accounts only keep withdrawals in memory when it may
+    fn disputes_withdrawals(&self, _config: &Config) -> bool {
+        true
+    }
+}
+
+/// Rules of the input contract
+/// Withdrawals are disputable when config allows it, and disputes may take available below zero
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct StandardPolicy;
+
+impl DisputePolicy for StandardPolicy {
+    fn decide(
+        &self,
+        _account: &AccountView,
+        transaction: &TransactionView,
+        step: DisputeStep,
+        config: &Config,
+    ) -> Result<DisputeEffect, CustomError> {
+        if transaction.kind == DisputedKind::Withdrawal && !config.allow_withdrawal_disputes {
+            return Err(CustomError::UndefinedBehaviour);
+        }
+        Ok(standard_effect(transaction, step))
+    }
+
+    fn disputes_withdrawals(&self, config: &Config) -> bool {
+        config.allow_withdrawal_disputes
+    }
+}
+
+/// Never disputes withdrawals, and only holds funds that are still available
+/// so a dispute can never take available below zero
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ConservativePolicy;
+
+impl DisputePolicy for ConservativePolicy {
+    fn decide(
+        &self,
+        account: &AccountView,
+        transaction: &TransactionView,
+        step: DisputeStep,
+        _config: &Config,
+    ) -> Result<DisputeEffect, CustomError> {
+        if transaction.kind == DisputedKind::Withdrawal {
+            return Err(CustomError::UndefinedBehaviour);
+        }
+        if step == DisputeStep::Dispute && account.available < transaction.disputed_amount {
+            return Err(CustomError::AccountBalanceNotEnough);
+        }
+        Ok(standard_effect(transaction, step))
+    }
+
+    fn disputes_withdrawals(&self, _config: &Config) -> bool {
+        false
+    }
+}
+
+/// Balance changes of the input contract
+fn standard_effect(transaction: &TransactionView, step: DisputeStep) -> DisputeEffect {
+    let amount = transaction.disputed_amount;
+    match (transaction.kind, step) {
+        //the client claims the withdrawn amount back, which is held until decided
+        (DisputedKind::Withdrawal, DisputeStep::Dispute) => DisputeEffect {
+            held: amount,
+            total: amount,
+            ..DisputeEffect::default()
+        },
+        //the claim is dropped, back to the state before the dispute
+        (DisputedKind::Withdrawal, DisputeStep::Resolve) => DisputeEffect {
+            held: -amount,
+            total: -amount,
+            ..DisputeEffect::default()
+        },
+        //the withdrawn amount is returned to the client
+        (DisputedKind::Withdrawal, DisputeStep::Chargeback) => DisputeEffect {
+            available: amount,
+            held: -amount,
+            lock: true,
+            ..DisputeEffect::default()
+        },
+        (DisputedKind::Deposit | DisputedKind::Transfer, DisputeStep::Dispute) => DisputeEffect {
+            available: -amount,
+            held: amount,
+            ..DisputeEffect::default()
+        },
+        (DisputedKind::Deposit | DisputedKind::Transfer, DisputeStep::Resolve) => DisputeEffect {
+            available: amount,
+            held: -amount,
+            ..DisputeEffect::default()
+        },
+        (DisputedKind::Deposit | DisputedKind::Transfer, DisputeStep::Chargeback) => {
+            DisputeEffect {
+                held: -amount,
+                total: -amount,
+                lock: true,
+                ..DisputeEffect::default()
+            }
+        }
+    }
+}
+
+/// Dispute policy shared by the engine and every account, the standard one by default
+#[derive(Debug, Clone)]
+pub(crate) struct SharedPolicy(Arc<dyn DisputePolicy>);
+
+impl SharedPolicy {
+    pub(crate) fn new(policy: impl DisputePolicy + 'static) -> Self {
+        Self(Arc::new(policy))
+    }
+}
+
+impl Default for SharedPolicy {
+    fn default() -> Self {
+        Self::new(StandardPolicy)
+    }
+}
+
+impl Deref for SharedPolicy {
+    type Target = dyn DisputePolicy;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+/// Built-in policies by name, for the command line
+impl FromStr for SharedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "standard" => Ok(Self::new(StandardPolicy)),
+            "conservative" => Ok(Self::new(ConservativePolicy)),
+            _ => Err(format!("unknown dispute policy {}", s)),
+        }
+    }
+}
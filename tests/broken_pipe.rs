@@ -0,0 +1,31 @@
+use std::{
+    fmt::Write,
+    process::{Command, Stdio},
+};
+
+/// Output piped to a reader that closes early, like `| head -5`, ends the run quietly with success
+#[test]
+fn test_broken_pipe() {
+    //enough accounts to outgrow the pipe buffer, so writes keep going after the reader is gone
+    let mut input = String::from("type,client,tx,amount\n");
+    for client in 1..=20000 {
+        writeln!(input, "deposit,{},{},1.0", client, client).unwrap();
+    }
+    let input_path = std::env::temp_dir().join("test_broken_pipe.csv");
+    std::fs::write(&input_path, input).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_transaction-handler"))
+        .arg(&input_path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    //close the read end before anything is read
+    drop(child.stdout.take());
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&input_path).unwrap();
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    assert_eq!(output.status.code(), Some(0));
+}
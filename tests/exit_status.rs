@@ -0,0 +1,308 @@
+use std::{
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+/// Fails on its third line, once client 1 got its deposit
+const CORRUPT: &str = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,x,1.0\n";
+const ERROR: &str =
+    "row on line 3 could not be read: tx `x` of deposit row could not be parsed into int: invalid digit found in string";
+
+/// Runs the binary on a file holding the input, or on a path that does not exist when there is none
+fn run(name: &str, input: Option<&str>, args: &[&str]) -> Output {
+    let input_path = std::env::temp_dir().join(name);
+    if let Some(input) = input {
+        std::fs::write(&input_path, input).unwrap();
+    }
+    let output = Command::new(env!("CARGO_BIN_EXE_transaction-handler"))
+        .arg(&input_path)
+        .args(args)
+        .output()
+        .unwrap();
+    if input.is_some() {
+        std::fs::remove_file(&input_path).unwrap();
+    }
+    output
+}
+
+#[test]
+fn test_success() {
+    let output = run(
+        "test_exit_status_success.csv",
+        Some("type,client,tx,amount\ndeposit,1,1,1.0\n"),
+        &[],
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n"
+    );
+}
+
+/// A missing input fails the run, with the error on stderr
+#[test]
+fn test_nonexistent_path() {
+    let output = run("test_exit_status_nonexistent.csv", None, &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with("error: file could not be opened"),
+        "{}",
+        stderr
+    );
+}
+
+/// A corrupt input fails the run without writing a partial output
+#[test]
+fn test_corrupt_file() {
+    let output = run("test_exit_status_corrupt.csv", Some(CORRUPT), &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        format!("error: {}\n", ERROR)
+    );
+}
+
+/// The accounts as they stood at the failure, the exit code still tells the run failed
+#[test]
+fn test_partial_output() {
+    let header = "client,available,held,total,locked\n";
+    let row = "1,1.0000,0.0000,1.0000,false\n";
+    for (mode, expected) in [
+        ("never", String::new()),
+        (
+            "mark",
+            format!(
+                "{}{}# partial output, the run stopped: {}\n",
+                header, row, ERROR
+            ),
+        ),
+        ("write", format!("{}{}", header, row)),
+    ] {
+        let output = run(
+            &format!("test_partial_output_{}.csv", mode),
+            Some(CORRUPT),
+            &["--partial-output", mode],
+        );
+        assert_eq!(output.status.code(), Some(1), "{}", mode);
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            expected,
+            "{}",
+            mode
+        );
+    }
+}
+
+/// Without any account the output is still its header, in every mode
+#[test]
+fn test_header_only() {
+    for mode in ["never", "mark", "write"] {
+        let output = run(
+            &format!("test_header_only_{}.csv", mode),
+            Some("type,client,tx,amount\n"),
+            &["--partial-output", mode],
+        );
+        assert_eq!(output.status.code(), Some(0), "{}", mode);
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "client,available,held,total,locked\n",
+            "{}",
+            mode
+        );
+    }
+}
+
+/// Without the tokio runtime the output, the errors and the exit code are the same
+#[test]
+fn test_sync() {
+    for (name, input, args) in [
+        (
+            "success",
+            Some("type,client,tx,amount\ndeposit,1,1,1.0\n"),
+            &[][..],
+        ),
+        ("nonexistent", None, &[]),
+        ("corrupt", Some(CORRUPT), &[]),
+        ("mark", Some(CORRUPT), &["--partial-output", "mark"]),
+        ("header_only", Some("type,client,tx,amount\n"), &[]),
+    ] {
+        let name = format!("test_sync_{}.csv", name);
+        let expected = run(&name, input, args);
+        let output = run(&name, input, &[args, &["--sync"]].concat());
+        assert_eq!(output.status.code(), expected.status.code(), "{}", name);
+        assert_eq!(output.stdout, expected.stdout, "{}", name);
+        assert_eq!(output.stderr, expected.stderr, "{}", name);
+    }
+
+    let output = run(
+        "test_sync_workers.csv",
+        Some(CORRUPT),
+        &["--sync", "--workers", "2"],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "error: --workers cannot be used with --sync\n"
+    );
+}
+
+/// Reading the input twice changes nothing but memory, and a pipe cannot be read twice
+#[test]
+fn test_two_pass() {
+    let input = "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,1,2,1.0\ndispute,1,1,\n";
+    let expected = run("test_two_pass_single.csv", Some(input), &[]);
+    for args in [&["--two-pass"][..], &["--two-pass", "--sync"]] {
+        let output = run("test_two_pass.csv", Some(input), args);
+        assert_eq!(output.status.code(), Some(0), "{:?}", args);
+        assert_eq!(output.stdout, expected.stdout, "{:?}", args);
+        assert_eq!(output.stderr, expected.stderr, "{:?}", args);
+    }
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_transaction-handler"))
+        .args(["/dev/stdin", "--two-pass"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    //the run may fail before it reads anything, closing the pipe
+    let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with(
+            "error: --two-pass reads the input twice, which cannot be done on an input it cannot seek in"
+        ),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_incremental() {
+    let state_dir = std::env::temp_dir().join("test_incremental_cli_state");
+    let _ = std::fs::remove_dir_all(&state_dir);
+    let state_dir = state_dir.to_str().unwrap();
+    let first = "type,client,tx,amount\ndeposit,1,1,2.0\n";
+    let whole = "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,1,2,1.0\ndispute,1,1,\n";
+    let args = ["--incremental", "--state-dir", state_dir];
+    let output = run("test_incremental_cli.csv", Some(first), &args);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        output.stdout,
+        run("test_incremental_first.csv", Some(first), &[]).stdout
+    );
+    let output = run("test_incremental_cli.csv", Some(whole), &args);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        output.stdout,
+        run("test_incremental_whole.csv", Some(whole), &[]).stdout
+    );
+
+    let output = run("test_incremental_cli.csv", Some(whole), &["--incremental"]);
+    assert_eq!(output.status.code(), Some(1));
+    std::fs::remove_dir_all(state_dir).unwrap();
+}
+
+/// Owners kept on disk behind the filter change nothing but memory, the index files are gone after the run
+#[test]
+fn test_id_index_dir() {
+    let dir = std::env::temp_dir().join("test_id_index_dir_cli");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,2,1,1.0\ndispute,1,1,\n";
+    let expected = run("test_id_index_dir_memory.csv", Some(input), &[]);
+    let output = run(
+        "test_id_index_dir.csv",
+        Some(input),
+        &[
+            "--id-index-dir",
+            dir.to_str().unwrap(),
+            "--id-filter-ids",
+            "10",
+        ],
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, expected.stdout);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+    let output = run(
+        "test_id_index_dir_rate.csv",
+        Some(input),
+        &[
+            "--id-index-dir",
+            dir.to_str().unwrap(),
+            "--id-filter-error-rate",
+            "1.5",
+        ],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Parsing parts of the input on threads changes nothing, a pipe or a compressed input has no parts to read apart
+#[test]
+fn test_parse_threads() {
+    let input = "type,client,tx,amount,memo\ndeposit,1,1,2.0,\"split\nhere\"\r\ndeposit,1,2,1.0,\ndispute,1,1,,\n";
+    let expected = run("test_parse_threads_single.csv", Some(input), &[]);
+    for args in [
+        &["--parse-threads", "3"][..],
+        &["--parse-threads", "3", "--sync"],
+    ] {
+        let output = run("test_parse_threads.csv", Some(input), args);
+        assert_eq!(output.status.code(), Some(0), "{:?}", args);
+        assert_eq!(output.stdout, expected.stdout, "{:?}", args);
+        assert_eq!(output.stderr, expected.stderr, "{:?}", args);
+    }
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_transaction-handler"))
+        .args(["/dev/stdin", "--parse-threads", "2"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    //the run fails before it reads anything, closing the pipe
+    let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "error: --parse-threads reads parts of the input apart, which cannot be done on an input it cannot seek in\n"
+    );
+
+    let path = std::env::temp_dir().join("test_parse_threads.csv.gz");
+    std::fs::write(&path, b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x00\x03").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_transaction-handler"))
+        .arg(&path)
+        .args(["--parse-threads", "2"])
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "error: --parse-threads reads parts of the input apart, which cannot be done on a gzip compressed input\n"
+    );
+}
+
+/// A window of no records would drop every transaction as soon as it is stored, so it is a usage error
+#[test]
+fn test_empty_dispute_window() {
+    let output = run(
+        "test_empty_dispute_window.csv",
+        Some("type,client,tx,amount\ndeposit,1,1,1.0\n"),
+        &["--dispute-window-records", "0"],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("--dispute-window-records"), "{}", stderr);
+    assert!(stderr.contains("must be above zero"), "{}", stderr);
+}
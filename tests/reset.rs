@@ -0,0 +1,30 @@
+use transaction_handler::{
+    config::Config,
+    engine::Engine,
+    io::{reader::Reader, writer::Writer},
+};
+
+/// A library user processes one file a day on the same engine, resetting it in between
+#[tokio::test]
+async fn test_reset() {
+    let days = [
+        "type,client,tx,amount\ndeposit,1,1,10.0\n",
+        "type,client,tx,amount\ndeposit,2,1,5.0\n",
+    ];
+    let mut engine = Engine::new(Config::default());
+    let mut outputs = Vec::new();
+    for day in days {
+        engine.reset();
+        let mut reader = Reader::from_reader(day.as_bytes());
+        let mut writer = Writer::from_inner(Vec::new());
+        engine.process(&mut reader, &mut writer).await.unwrap();
+        outputs.push(String::from_utf8(writer.into_inner()).unwrap());
+    }
+    assert_eq!(
+        outputs,
+        vec![
+            "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n",
+            "client,available,held,total,locked\n2,5.0000,0.0000,5.0000,false\n",
+        ]
+    );
+}